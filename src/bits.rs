@@ -1,54 +1,107 @@
-const MAX_INDEX: usize = 127;
+use std::cmp::Ordering;
 
+use smallvec::SmallVec;
+
+/// Битовое множество ключей интересов. До 128 ключей слова хранятся внутри без выделения памяти;
+/// старшие нулевые слова не хранятся, поэтому пустое множество - пустой words.
 #[derive(Clone)]
 pub struct Bits {
-    bits: u128,
+    words: SmallVec<[u64; 2]>,
 }
 
 impl Bits {
     pub fn new() -> Bits {
-        Bits { bits: 0 }
+        Bits { words: SmallVec::new() }
     }
 
-    pub fn from_vec(vec: Vec<i32>) -> Bits {
-        let mut bits: u128 = 0;
+}
+
+/// Операции над множеством интересов аккаунта, общие для Bits и SortedInterests.
+pub trait InterestSet: Sized {
+    fn from_vec(vec: Vec<i32>) -> Self;
+    fn is_empty(&self) -> bool;
+    fn contains(&self, index: i32) -> bool;
+    fn contains_all(&self, other: &Self) -> bool;
+    fn contains_any(&self, other: &Self) -> bool;
+    fn count(&self) -> u32;
+    fn count_common(&self, other: &Self) -> u32;
+}
+
+/// Представление Account.interests, по умолчанию Bits; с feature sorted-interests - SortedInterests.
+#[cfg(not(feature = "sorted-interests"))]
+pub type Interests = Bits;
+#[cfg(feature = "sorted-interests")]
+pub type Interests = SortedInterests;
+
+impl InterestSet for Bits {
+    fn from_vec(vec: Vec<i32>) -> Bits {
+        let mut words: SmallVec<[u64; 2]> = SmallVec::new();
         for interest in vec {
-            bits |= (1 as u128) << interest;
+            let word = interest as usize / 64;
+            if word >= words.len() {
+                words.resize(word + 1, 0);
+            }
+            words[word] |= 1 << (interest as usize % 64);
         }
-        Bits { bits }
+        Bits { words }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.bits == 0
+    fn is_empty(&self) -> bool {
+        self.words.is_empty()
     }
 
-    pub fn contains(&self, index: i32) -> bool {
-        (self.bits >> index as usize) & 1 != 0
+    fn contains(&self, index: i32) -> bool {
+        match self.words.get(index as usize / 64) {
+            Some(word) => (word >> (index as usize % 64)) & 1 != 0,
+            None => false,
+        }
     }
 
-    pub fn contains_all(&self, other: &Bits) -> bool {
-        if other.bits == 0 {
+    fn contains_all(&self, other: &Bits) -> bool {
+        if other.words.is_empty() {
             unimplemented!();
         }
-        (self.bits & other.bits) == other.bits
+        // старшее слово other ненулевое, так что более длинный other не может содержаться
+        if other.words.len() > self.words.len() {
+            return false;
+        }
+        let (a, b) = (self.words.as_slice(), other.words.as_slice());
+        if a.len() <= 2 {
+            return word(a, 0) & word(b, 0) == word(b, 0) && word(a, 1) & word(b, 1) == word(b, 1);
+        }
+        a.iter().zip(b.iter()).all(|(a, b)| a & b == *b)
     }
 
-    pub fn contains_any(&self, other: &Bits) -> bool {
-        if other.bits == 0 {
+    fn contains_any(&self, other: &Bits) -> bool {
+        if other.words.is_empty() {
             unimplemented!();
         }
-        (self.bits & other.bits) != 0
+        let (a, b) = (self.words.as_slice(), other.words.as_slice());
+        if a.len() <= 2 && b.len() <= 2 {
+            return (word(a, 0) & word(b, 0)) | (word(a, 1) & word(b, 1)) != 0;
+        }
+        a.iter().zip(b.iter()).any(|(a, b)| a & b != 0)
     }
 
-    pub fn count(&self) -> u32 {
-        self.bits.count_ones()
+    fn count(&self) -> u32 {
+        self.words.iter().map(|word| word.count_ones()).sum()
     }
 
-    pub fn count_common(&self, other: &Bits) -> u32 {
-        (self.bits & other.bits).count_ones()
+    fn count_common(&self, other: &Bits) -> u32 {
+        let (a, b) = (self.words.as_slice(), other.words.as_slice());
+        if a.len() <= 2 && b.len() <= 2 {
+            return (word(a, 0) & word(b, 0)).count_ones() + (word(a, 1) & word(b, 1)).count_ones();
+        }
+        a.iter().zip(b.iter()).map(|(a, b)| (a & b).count_ones()).sum()
     }
 }
 
+// быстрый путь для ключей до 128: недостающие слова - нули
+#[inline]
+fn word(words: &[u64], index: usize) -> u64 {
+    words.get(index).cloned().unwrap_or(0)
+}
+
 impl<'a> IntoIterator for &'a Bits {
     type Item = i32;
     type IntoIter = BitsIntoIterator<'a>;
@@ -56,37 +109,144 @@ impl<'a> IntoIterator for &'a Bits {
     fn into_iter(self) -> Self::IntoIter {
         BitsIntoIterator {
             bits: &self,
-            index: 1,
+            word_index: 0,
+            rest: self.words.first().cloned().unwrap_or(0),
         }
     }
 }
 
 pub struct BitsIntoIterator<'a> {
     bits: &'a Bits,
-    index: usize,
+    word_index: usize,
+    // еще не выданные биты текущего слова
+    rest: u64,
 }
 
 impl<'a> Iterator for BitsIntoIterator<'a> {
     type Item = i32;
 
     fn next(&mut self) -> Option<i32> {
-        while self.index <= MAX_INDEX {
-            let rest = self.bits.bits >> self.index;
-            let bit = (rest & 1) as i32;
-            if rest == 0 {
-                self.index = MAX_INDEX + 1; // не дало эффекта
-            } else {
-                self.index += 1;
-            }
-            if bit != 0 {
-                return Some(self.index as i32 - 1);
+        while self.rest == 0 {
+            self.word_index += 1;
+            if self.word_index >= self.bits.words.len() {
+                return None;
             }
+            self.rest = self.bits.words[self.word_index];
         }
-        None
+        let bit = self.rest.trailing_zeros();
+        self.rest &= self.rest - 1;
+        Some((self.word_index * 64) as i32 + bit as i32)
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (0, Some(MAX_INDEX + 1 - self.index))
+        (0, Some((self.bits.words.len() - self.word_index.min(self.bits.words.len())) * 64))
+    }
+}
+
+/// Отсортированные ключи интересов без повторов. Операции - слиянием за O(n + m) вместо O(1) у Bits,
+/// зато память не зависит от величины ключей, а до 4 интересов хранятся без выделения памяти.
+/// Оба представления по 32 байта, recommend с SortedInterests примерно вдвое медленнее,
+/// так что выигрыш только при словаре в сотни интересов, когда Bits выделяет слова в куче.
+#[derive(Clone, PartialEq)]
+pub struct SortedInterests {
+    keys: SmallVec<[i32; 4]>,
+}
+
+impl SortedInterests {
+    // число общих ключей, при stop_at останавливается, как только оно достигнуто
+    fn common(&self, other: &SortedInterests, stop_at: usize) -> usize {
+        let (mut i, mut j, mut common) = (0, 0, 0);
+        while i < self.keys.len() && j < other.keys.len() && common < stop_at {
+            match self.keys[i].cmp(&other.keys[j]) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    common += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        common
+    }
+}
+
+impl InterestSet for SortedInterests {
+    fn from_vec(mut vec: Vec<i32>) -> SortedInterests {
+        vec.sort();
+        vec.dedup();
+        SortedInterests { keys: SmallVec::from_vec(vec) }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn contains(&self, index: i32) -> bool {
+        self.keys.binary_search(&index).is_ok()
+    }
+
+    fn contains_all(&self, other: &SortedInterests) -> bool {
+        self.common(other, other.keys.len()) == other.keys.len()
+    }
+
+    fn contains_any(&self, other: &SortedInterests) -> bool {
+        self.common(other, 1) != 0
+    }
+
+    fn count(&self) -> u32 {
+        self.keys.len() as u32
+    }
+
+    fn count_common(&self, other: &SortedInterests) -> u32 {
+        self.common(other, usize::MAX) as u32
+    }
+}
+
+impl<'a> IntoIterator for &'a SortedInterests {
+    type Item = i32;
+    type IntoIter = std::iter::Cloned<std::slice::Iter<'a, i32>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.keys.iter().cloned()
+    }
+}
+
+impl std::fmt::Debug for SortedInterests {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.keys.fmt(f)
+    }
+}
+
+/// Множество id аккаунтов, бит на каждый id.
+pub struct IdSet {
+    words: Vec<u64>,
+}
+
+impl IdSet {
+    pub fn new() -> IdSet {
+        IdSet { words: Vec::new() }
+    }
+
+    pub fn insert(&mut self, id: i32) {
+        let word = id as usize / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (id as usize % 64);
+    }
+
+    pub fn remove(&mut self, id: i32) {
+        if let Some(word) = self.words.get_mut(id as usize / 64) {
+            *word &= !(1 << (id as usize % 64));
+        }
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        match self.words.get(id as usize / 64) {
+            Some(word) => (word >> (id as usize % 64)) & 1 != 0,
+            None => false,
+        }
     }
 }
 
@@ -117,16 +277,41 @@ mod tests {
             let bits = Bits::from_vec(vec!(1, 3, 127));
             assert_eq!(bits.into_iter().collect::<Vec<i32>>(), vec!(1, 3, 127));
             assert_eq!(bits.count(), 3);
-            assert_eq!(bits.contains(1), true);
-            assert_eq!(bits.contains(2), false);
-            assert_eq!(bits.contains(3), true);
-            assert_eq!(bits.contains(127), true);
-            assert_eq!(bits.contains_all(&Bits::from_vec(vec!(1, 127))), true);
-            assert_eq!(bits.contains_all(&Bits::from_vec(vec!(1, 3, 127))), true);
-            assert_eq!(bits.contains_all(&Bits::from_vec(vec!(1, 3, 5, 127))), false);
-            assert_eq!(bits.contains_all(&Bits::from_vec(vec!(1, 5, 127))), false);
-            assert_eq!(bits.contains_any(&Bits::from_vec(vec!(1, 127))), true);
-            assert_eq!(bits.contains_any(&Bits::from_vec(vec!(2, 5))), false);
+            assert!(bits.contains(1));
+            assert!(!bits.contains(2));
+            assert!(bits.contains(3));
+            assert!(bits.contains(127));
+            assert!(bits.contains_all(&Bits::from_vec(vec!(1, 127))));
+            assert!(bits.contains_all(&Bits::from_vec(vec!(1, 3, 127))));
+            assert!(!bits.contains_all(&Bits::from_vec(vec!(1, 3, 5, 127))));
+            assert!(!bits.contains_all(&Bits::from_vec(vec!(1, 5, 127))));
+            assert!(bits.contains_any(&Bits::from_vec(vec!(1, 127))));
+            assert!(!bits.contains_any(&Bits::from_vec(vec!(2, 5))));
+        }
+        {
+            let bits = Bits::from_vec(vec!(300, 1, 128, 200));
+            assert_eq!(bits.into_iter().collect::<Vec<i32>>(), vec!(1, 128, 200, 300));
+            assert_eq!(bits.count(), 4);
+            assert!(bits.contains(128));
+            assert!(bits.contains(200));
+            assert!(bits.contains(300));
+            assert!(!bits.contains(127));
+            assert!(!bits.contains(129));
+            assert!(!bits.contains(1000));
+            assert!(bits.contains_all(&Bits::from_vec(vec!(1, 300))));
+            assert!(bits.contains_all(&Bits::from_vec(vec!(128, 200))));
+            assert!(!bits.contains_all(&Bits::from_vec(vec!(128, 201))));
+            assert!(!bits.contains_all(&Bits::from_vec(vec!(1, 400))));
+            assert!(bits.contains_any(&Bits::from_vec(vec!(2, 200))));
+            assert!(!bits.contains_any(&Bits::from_vec(vec!(2, 301))));
+            assert_eq!(bits.count_common(&Bits::from_vec(vec!(1, 128, 300, 400))), 3);
+            // короткое множество против длинного и наоборот
+            let small = Bits::from_vec(vec!(1, 3));
+            assert!(!small.contains_all(&bits));
+            assert!(small.contains_any(&bits));
+            assert_eq!(small.count_common(&bits), 1);
+            assert!(!bits.contains_all(&small));
+            assert!(bits.contains_any(&small));
         }
         {
             let bits = Bits::from_vec(vec!(1, 3, 127));
@@ -135,4 +320,57 @@ mod tests {
             });
         }
     }
+
+    #[test]
+    fn test_sorted_interests() {
+        let empty = SortedInterests::from_vec(Vec::new());
+        assert!(empty.is_empty());
+        assert_eq!(empty.into_iter().collect::<Vec<i32>>(), Vec::<i32>::new());
+
+        let interests = SortedInterests::from_vec(vec!(127, 3, 1, 3, 1000));
+        assert_eq!(interests.into_iter().collect::<Vec<i32>>(), vec!(1, 3, 127, 1000));
+        assert_eq!(interests.count(), 4);
+        assert!(interests.contains(3));
+        assert!(!interests.contains(2));
+        assert!(interests.contains(1000));
+        assert!(interests.contains_all(&SortedInterests::from_vec(vec!(1, 1000))));
+        assert!(!interests.contains_all(&SortedInterests::from_vec(vec!(1, 5, 127))));
+        assert!(interests.contains_any(&SortedInterests::from_vec(vec!(2, 127))));
+        assert!(!interests.contains_any(&SortedInterests::from_vec(vec!(2, 5))));
+
+        // совпадает с Bits, в том числе на ключах за пределами встроенных слов
+        let sets = [vec!(1, 3, 127), vec!(3), vec!(2, 3, 5, 127), vec!(4, 6), vec!(3, 200), vec!(128, 300), vec!(1, 128, 200, 300)];
+        for a in &sets {
+            for b in &sets {
+                let (bits_a, bits_b) = (Bits::from_vec(a.clone()), Bits::from_vec(b.clone()));
+                let (sorted_a, sorted_b) = (SortedInterests::from_vec(a.clone()), SortedInterests::from_vec(b.clone()));
+                assert_eq!(sorted_a.contains_all(&sorted_b), bits_a.contains_all(&bits_b));
+                assert_eq!(sorted_a.contains_any(&sorted_b), bits_a.contains_any(&bits_b));
+                assert_eq!(sorted_a.count_common(&sorted_b), bits_a.count_common(&bits_b));
+                assert_eq!(sorted_a.into_iter().collect::<Vec<i32>>(), bits_a.into_iter().collect::<Vec<i32>>());
+            }
+        }
+    }
+
+    #[test]
+    fn test_id_set() {
+        let mut id_set = IdSet::new();
+        assert!(!id_set.contains(0));
+        assert!(!id_set.contains(1000));
+        id_set.insert(1);
+        id_set.insert(64);
+        id_set.insert(1000);
+        assert!(!id_set.contains(0));
+        assert!(id_set.contains(1));
+        assert!(!id_set.contains(63));
+        assert!(id_set.contains(64));
+        assert!(id_set.contains(1000));
+        assert!(!id_set.contains(1001));
+        assert!(!id_set.contains(100000));
+        id_set.remove(64);
+        id_set.remove(100000);
+        assert!(!id_set.contains(64));
+        assert!(id_set.contains(1));
+        assert!(id_set.contains(1000));
+    }
 }
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+/// Кэш готовых ответов по строке запроса. Для каждой записи считается число попаданий,
+/// по которому видно, какие запросы стоит прогревать.
+pub struct ResponseCache {
+    entries: HashMap<String, CacheEntry>,
+    // 0 - без ограничения; кэш сбрасывается при каждой записи, поэтому новые ключи сверх лимита просто не кэшируются
+    max_entries: usize,
+}
+
+struct CacheEntry {
+    response: Vec<u8>,
+    hits: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheSnapshot {
+    entries: usize,
+    hot: Vec<HotKey>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CacheKeys {
+    count: usize,
+    keys: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HotKey {
+    key: String,
+    hits: u32,
+}
+
+impl ResponseCache {
+    pub fn new() -> ResponseCache {
+        ResponseCache {
+            entries: HashMap::new(),
+            max_entries: 0,
+        }
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Vec<u8>> {
+        self.entries.get_mut(key).map(|entry| {
+            entry.hits += 1;
+            &entry.response
+        })
+    }
+
+    pub fn insert(&mut self, key: String, response: Vec<u8>) {
+        if self.max_entries != 0 && self.entries.len() >= self.max_entries && !self.entries.contains_key(&key) {
+            return;
+        }
+        self.entries.insert(key, CacheEntry { response, hits: 0 });
+    }
+
+    pub fn clear(&mut self) {
+        if log_enabled!(log::Level::Debug) && !self.entries.is_empty() {
+            debug!("cache clear: {:?}", self.hot_keys(5));
+        }
+        self.entries.clear();
+    }
+
+    /// Самые популярные ключи по убыванию попаданий, записи без попаданий не попадают.
+    pub fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        let mut hot: Vec<HotKey> = self.entries.iter()
+            .filter(|(_, entry)| entry.hits > 0)
+            .map(|(key, entry)| HotKey { key: key.clone(), hits: entry.hits })
+            .collect();
+        hot.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.key.cmp(&b.key)));
+        hot.truncate(n);
+        hot
+    }
+
+    /// Общее число ключей и первые n по алфавиту, без тел ответов.
+    pub fn keys(&self, n: usize) -> CacheKeys {
+        let mut keys: Vec<String> = self.entries.keys().cloned().collect();
+        keys.sort();
+        keys.truncate(n);
+        CacheKeys {
+            count: self.entries.len(),
+            keys,
+        }
+    }
+
+    pub fn snapshot(&self, n: usize) -> CacheSnapshot {
+        CacheSnapshot {
+            entries: self.entries.len(),
+            hot: self.hot_keys(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_keys() {
+        let mut cache = ResponseCache::new();
+        cache.insert("a".to_string(), b"1".to_vec());
+        cache.insert("b".to_string(), b"2".to_vec());
+        cache.insert("c".to_string(), b"3".to_vec());
+        assert_eq!(cache.get("b"), Some(&b"2".to_vec()));
+        cache.get("b");
+        cache.get("a");
+        cache.get("x");
+
+        assert_eq!(cache.hot_keys(10), vec![HotKey { key: "b".to_string(), hits: 2 }, HotKey { key: "a".to_string(), hits: 1 }]);
+        assert_eq!(cache.hot_keys(1), vec![HotKey { key: "b".to_string(), hits: 2 }]);
+        assert_eq!(serde_json::to_string(&cache.snapshot(1)).unwrap(), r#"{"entries":3,"hot":[{"key":"b","hits":2}]}"#);
+
+        // перезапись ответа сбрасывает счетчик
+        cache.insert("b".to_string(), b"4".to_vec());
+        assert_eq!(cache.hot_keys(1), vec![HotKey { key: "a".to_string(), hits: 1 }]);
+
+        cache.clear();
+        assert_eq!(cache.snapshot(10).entries, 0);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut cache = ResponseCache::new();
+        for key in &["F:b", "G:a", "F:a"] {
+            cache.insert(key.to_string(), Vec::new());
+        }
+        assert_eq!(serde_json::to_string(&cache.keys(2)).unwrap(), r#"{"count":3,"keys":["F:a","F:b"]}"#);
+        cache.clear();
+        assert_eq!(cache.keys(2), CacheKeys { count: 0, keys: Vec::new() });
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut cache = ResponseCache::new();
+        cache.set_max_entries(2);
+        for key in &["a", "b", "c"] {
+            cache.insert(key.to_string(), b"1".to_vec());
+        }
+        assert_eq!(cache.keys(10), CacheKeys { count: 2, keys: vec!["a".to_string(), "b".to_string()] });
+        // существующий ключ перезаписывается и при полном кэше
+        cache.insert("b".to_string(), b"2".to_vec());
+        assert_eq!(cache.get("b"), Some(&b"2".to_vec()));
+        cache.clear();
+        cache.insert("c".to_string(), b"3".to_vec());
+        assert_eq!(cache.get("c"), Some(&b"3".to_vec()));
+    }
+}
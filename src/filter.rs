@@ -1,22 +1,32 @@
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::iter::Rev;
+use std::slice;
+#[cfg(test)]
 use std::sync::Arc;
 
 use itertools::free::kmerge;
+use itertools::Either;
 use itertools::Itertools;
 use itertools::kmerge_by;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 
-use crate::bits::Bits;
+use crate::bits::InterestSet;
+use crate::bits::Interests;
 use crate::storage;
 use crate::storage::Account;
+#[cfg(test)]
 use crate::storage::AccountJson;
+#[cfg(test)]
 use crate::storage::AccountsJson;
 use crate::storage::NULL_DATE;
 use crate::storage::Premium;
 use crate::storage::Storage;
+use crate::utils::check_unique_params;
 use crate::utils::EMPTY_INT_LIST;
-use crate::utils::EMPTY_LIKE_LIST;
 use crate::utils::KeySet;
+use crate::utils::parse_limit;
+use crate::utils::PostingLists;
 use crate::utils::retain_all_sorted;
 use crate::utils::seconds_from_year;
 use crate::utils::StatusCode;
@@ -39,141 +49,385 @@ lazy_static! {
     };
 }
 
+/// Во сколько раз проверка кандидата из индекса дороже, чем при full scan: аккаунты читаются не подряд.
+const INDEX_CANDIDATE_COST: usize = 2;
+
+/// Способ получить кандидатов. Для применимых способов оценивается число проверяемых аккаунтов, и выполняется самый дешевый.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Strategy {
+    // готовый список из filter_index
+    FastIndex,
+    EmailEq,
+    LikesContains,
+    Sname,
+    SnamePrefix,
+    FnameStarts,
+    EmailDomain,
+    Interests2,
+    // пересечение списков interests_index для трех и больше интересов, начиная с самого короткого
+    InterestsAll,
+    CitySexStatus,
+    CityStatus,
+    City,
+    CityAny,
+    Interest,
+    Country,
+    BirthYear,
+    JoinedYear,
+    PhoneCode,
+    FnameAny,
+    InterestsAny,
+    PremiumNow,
+    FullScan,
+}
+
 #[inline(never)]
-pub fn filter(storage: &Storage, params: &Vec<(String, String)>) -> Result<AccountsJson, StatusCode> {
-    let matcher = match make_matcher(storage, &params)? {
+pub fn filter<'a>(storage: &'a Storage, params: &Vec<(String, String)>) -> Result<FilterResult<'a>, StatusCode> {
+    let matcher = make_matcher(storage, params)?;
+    // значение count уже проверил make_matcher
+    let count_only = params.iter().any(|(key, _)| key == "count");
+    let matcher = match matcher {
         Some(matcher) => matcher,
-        None => return Ok(AccountsJson { accounts: Vec::new() })
+        None => return Ok(FilterResult { storage, matcher: None, accounts: Vec::new(), count_only })
     };
 
-    Ok(try_fast_index(storage, &matcher)
-        .or_else(|| try_index(storage, &matcher))
-        .or_else(|| Some(full_scan(storage, &matcher)))
-        .unwrap())
+    // FullScan всегда последний из возможных и всегда дает результат
+    let accounts = plan(storage, &matcher).into_iter()
+        .filter_map(|strategy| execute(strategy, storage, &matcher))
+        .next()
+        .unwrap();
+    Ok(FilterResult { storage, matcher: Some(matcher), accounts, count_only })
 }
 
-#[inline(never)]
-fn try_fast_index(storage: &Storage, matcher: &Matcher) -> Option<AccountsJson> {
-    match storage.indexes.filter_index.get_result(&matcher) {
-        Some(ids) =>
-            Some(AccountsJson {
-                accounts: ids.iter().rev()
-                    .filter_map(|id| storage.accounts[*id as usize].as_ref())
-                    .filter(|account| matches(*account, &matcher, storage))
-                    .map(|account| {
-                        make_result(storage, &matcher, account)
-                    })
-                    .take(matcher.limit)
-                    .collect()
-            }),
-        None => None
-    }
+/// Интерес из interests_contains с самым коротким списком в индексе.
+fn rarest_interest(interests_index: &PostingLists, matcher: &Matcher) -> Option<i32> {
+    matcher.interests_contains.as_ref()
+        .and_then(|interests_contains| interests_contains.into_iter().min_by_key(|interest| interests_index.get(*interest).len()))
 }
 
-#[inline(never)]
-fn try_index(storage: &Storage, matcher: &Matcher) -> Option<AccountsJson> {
-    let (interest1, interest2) = match &matcher.interests_contains {
+fn first_interests(matcher: &Matcher) -> (Option<i32>, Option<i32>) {
+    match &matcher.interests_contains {
         Some(interests_contains) => {
             let mut iter = interests_contains.into_iter();
             (iter.next(), iter.next())
         }
         None => (None, None)
-    };
+    }
+}
 
+/// Применимые способы по возрастанию оценки, при равной оценке - в порядке приоритета из Strategy.
+fn plan(storage: &Storage, matcher: &Matcher) -> Vec<Strategy> {
+    let indexes = &storage.indexes;
+    let mut strategies: Vec<(Strategy, usize)> = Vec::new();
+    let mut index = |strategy: Strategy, candidates: usize| strategies.push((strategy, candidates * INDEX_CANDIDATE_COST));
+
+    // filter_index хранит только последние id, для order=1 нужно начало списков
+    if let (false, Some((ids, _))) = (matcher.ascending, indexes.filter_index.get_result(matcher)) {
+        index(Strategy::FastIndex, ids.len());
+    }
+    if matcher.email_eq.is_some() {
+        index(Strategy::EmailEq, 1);
+    }
     if !matcher.likes_contains.is_empty() {
-        let mut vec: Option<Vec<i32>> = None;
-//        let like = matcher.likes_contains[0];
-//        vec = Some(storage.indexes.likes_index_male.get(&like).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id)
-//            .merge(storage.indexes.likes_index_female.get(&like).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id))
-//            .dedup()
-//            .collect());
-        for like in &matcher.likes_contains {
-            let vec3 =
-                storage.indexes.likes_index_male.get(&like).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id)
-                    .merge(storage.indexes.likes_index_female.get(&like).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id))
-                    .dedup()
-                    .collect();
-            match vec.as_mut() {
-                None => vec = Some(vec3),
-                Some(mut ids) => retain_all_sorted(&mut ids, &vec3),
-            }
-        }
-        Some(process_rev_iter(vec.unwrap().iter().rev(), storage, matcher))
-    } else if interest1.is_some() && interest2.is_some() {
-        let interest1 = interest1.unwrap();
-        let interest2 = interest2.unwrap();
+        // likes_contains упорядочен от самого редкого
+        index(Strategy::LikesContains, indexes.likers_count(matcher.likes_contains[0]));
+    }
+    if let (true, Some(sname_index)) = (matcher.sname != 0, &indexes.sname_index) {
+        index(Strategy::Sname, sname_index.get(&matcher.sname).map_or(0, |ids| ids.len()));
+    }
+    if let Some(ids) = sname_prefix_ids(storage, matcher) {
+        index(Strategy::SnamePrefix, ids.len());
+    }
+    if matcher.email_domain.is_some() {
+        index(Strategy::EmailDomain, email_domain_ids(storage, matcher).len());
+    }
+    let (interest1, interest2) = first_interests(matcher);
+    if let (Some(interest1), Some(interest2)) = (interest1, interest2) {
         let key = if interest1 < interest2 { (interest1, interest2) } else { (interest2, interest1) };
-        Some(process_rev_iter(storage.indexes.interests2_index.get(&key).unwrap_or(&EMPTY_INT_LIST).iter().rev(), storage, matcher))
-    } else if matcher.city != 0 {
-        Some(process_rev_iter(storage.indexes.city_index.get(&matcher.city).unwrap_or(&EMPTY_INT_LIST).iter().rev(), storage, matcher))
-    } else if !matcher.city_any.is_empty() {
-        Some(process_rev_iter(kmerge_by(matcher.city_any.iter().map(|city| storage.indexes.city_index.get(&city).unwrap_or(&EMPTY_INT_LIST).iter().rev()), rev_id).dedup(), storage, matcher))
-    } else if let Some(interest) = interest1 {
-        if matcher.sex != 0 {
-            let interests_index = if matcher.sex == storage.consts.male { &storage.indexes.interests_index_male } else { &storage.indexes.interests_index_female };
-            Some(process_rev_iter(interests_index.get(&interest).unwrap_or(&EMPTY_INT_LIST).iter().rev(), storage, matcher))
+        let candidates = match indexes.interests2_index.get(&key) {
+            Some(ids) => ids.len(),
+            None if storage.config.interests2_fallback => indexes.interests_index.get(interest1).len().min(indexes.interests_index.get(interest2).len()),
+            None => 0,
+        };
+        index(Strategy::Interests2, candidates);
+    }
+    if let Some(interests_contains) = matcher.interests_contains.as_ref().filter(|interests_contains| interests_contains.count() > 2) {
+        let candidates = interests_contains.into_iter().map(|interest| indexes.interests_index.get(interest).len()).min().unwrap();
+        index(Strategy::InterestsAll, candidates);
+    }
+    if matcher.city != 0 {
+        let candidates = indexes.city_index.get(matcher.city).len();
+        if matcher.sex != 0 && matcher.status_eq != 0 {
+            index(Strategy::CitySexStatus, candidates);
+        } else if matcher.status_eq != 0 {
+            index(Strategy::CityStatus, candidates);
         } else {
-            Some(process_rev_iter(storage.indexes.interests_index.get(&interest).unwrap_or(&EMPTY_INT_LIST).iter().rev(), storage, matcher))
-        }
-    } else if matcher.country != 0 {
-        Some(process_rev_iter(storage.indexes.country_index.get(&matcher.country).unwrap_or(&EMPTY_INT_LIST).iter().rev(), storage, matcher))
-    } else if matcher.birth_year != 0 {
-        Some(process_rev_iter(storage.indexes.birth_index.get(&matcher.birth_year).unwrap_or(&EMPTY_INT_LIST).iter().rev(), storage, matcher))
-    } else if !matcher.fname_any.is_empty() {
-        Some(process_rev_iter(kmerge_by(matcher.fname_any.iter().map(|fname| storage.indexes.fname_index.get(&fname).unwrap_or(&EMPTY_INT_LIST).iter().rev()), rev_id).dedup(), storage, matcher))
-    } else if matcher.interests_any.is_some() {
-        Some(process_rev_iter(kmerge_by(matcher.interests_any.as_ref().unwrap().into_iter().map(|interest| storage.indexes.interests_index.get(&interest).unwrap_or(&EMPTY_INT_LIST).iter().rev()), rev_id).dedup(), storage, matcher))
+            index(Strategy::City, candidates);
+        }
+    }
+    if !matcher.city_any.is_empty() {
+        index(Strategy::CityAny, matcher.city_any.iter().map(|city| indexes.city_index.get(*city).len()).sum());
+    }
+    if matcher.interests_contains.is_some() {
+        let interests_index = sex_interests_index(storage, matcher);
+        index(Strategy::Interest, interests_index.get(rarest_interest(interests_index, matcher).unwrap()).len());
+    }
+    if matcher.country != 0 {
+        index(Strategy::Country, indexes.country_index.get(matcher.country).len());
+    }
+    if matcher.birth_year != 0 {
+        index(Strategy::BirthYear, indexes.birth_index.get(&matcher.birth_year).map_or(0, |ids| ids.len()));
+    }
+    if matcher.joined_year != 0 {
+        index(Strategy::JoinedYear, indexes.joined_index.get(&matcher.joined_year).map_or(0, |ids| ids.len()));
+    }
+    if matcher.phone_code != 0 {
+        index(Strategy::PhoneCode, indexes.phone_code_index.get(&matcher.phone_code).map_or(0, |ids| ids.len()));
+    }
+    if !matcher.fname_starts_keys.is_empty() {
+        index(Strategy::FnameStarts, matcher.fname_starts_keys.iter().map(|fname| indexes.fname_index.get(*fname).len()).sum());
+    }
+    if !matcher.fname_any.is_empty() {
+        index(Strategy::FnameAny, matcher.fname_any.iter().map(|fname| indexes.fname_index.get(*fname).len()).sum());
+    }
+    if let Some(interests_any) = &matcher.interests_any {
+        index(Strategy::InterestsAny, interests_any.into_iter().map(|interest| indexes.interests_index.get(interest).len()).sum());
+    }
+    if matcher.premium_now {
+        index(Strategy::PremiumNow, indexes.premium_now_ids.len());
+    }
+    let (from, to) = full_scan_range(storage, matcher);
+    strategies.push((Strategy::FullScan, (from + 1).saturating_sub(to)));
+
+    strategies.sort_by_key(|(_, cost)| *cost);
+    strategies.into_iter().map(|(strategy, _)| strategy).collect()
+}
+
+/// None - способ не дал верного результата, нужно пробовать следующий.
+#[inline(never)]
+fn execute<'a>(strategy: Strategy, storage: &'a Storage, matcher: &Matcher) -> Option<Vec<&'a Account>> {
+    let indexes = &storage.indexes;
+    let (interest1, interest2) = first_interests(matcher);
+    let accounts = match strategy {
+        Strategy::FastIndex => {
+            let (ids, complete) = indexes.filter_index.get_result(matcher).unwrap();
+            let accounts = process_iter(ordered(&ids, matcher), storage, matcher);
+            // в filter_index хранится только хвост списка: если он обрезан, а совпадений меньше limit, остальные могли в него не попасть
+            if !complete && accounts.len() < matcher.limit {
+                return None;
+            }
+            accounts
+        }
+        Strategy::EmailEq => {
+            // email уникален, аккаунт находится сразу
+            process_iter(indexes.known_emails.get(matcher.email_eq.as_ref().unwrap()).into_iter(), storage, matcher)
+        }
+        Strategy::LikesContains => {
+            // начинаем с самого редкого likee, остальные проверяются поиском в их списках, а не слиянием целиком
+            let mut ids: Vec<i32> = indexes.likers(matcher.likes_contains[0]).collect();
+            for like in &matcher.likes_contains[1..] {
+                if ids.is_empty() {
+                    break;
+                }
+                ids.retain(|id| indexes.is_liker(*like, *id));
+            }
+            process_iter(ordered(&ids, matcher), storage, matcher)
+        }
+        Strategy::Sname => {
+            let sname_index = indexes.sname_index.as_ref().unwrap();
+            process_iter(ordered(sname_index.get(&matcher.sname).unwrap_or(&EMPTY_INT_LIST), matcher), storage, matcher)
+        }
+        // полное совпадение начала проверяет matches
+        Strategy::SnamePrefix => process_iter(ordered(sname_prefix_ids(storage, matcher).unwrap(), matcher), storage, matcher),
+        Strategy::EmailDomain => process_iter(ordered(email_domain_ids(storage, matcher), matcher), storage, matcher),
+        Strategy::Interests2 => {
+            let interest1 = interest1.unwrap();
+            let interest2 = interest2.unwrap();
+            let key = if interest1 < interest2 { (interest1, interest2) } else { (interest2, interest1) };
+            match indexes.interests2_index.get(&key) {
+                Some(ids) => process_iter(ordered(ids, matcher), storage, matcher),
+                // пары нет в индексе: интересы не встречались вместе или пара не проиндексирована
+                None if storage.config.interests2_fallback => {
+                    let ids1 = indexes.interests_index.get(interest1);
+                    let ids2 = indexes.interests_index.get(interest2);
+                    let (mut ids, other) = if ids1.len() < ids2.len() { (ids1.clone(), ids2) } else { (ids2.clone(), ids1) };
+                    retain_all_sorted(&mut ids, other);
+                    process_iter(ordered(&ids, matcher), storage, matcher)
+                }
+                None => Vec::new(),
+            }
+        }
+        Strategy::InterestsAll => process_iter(interests_all_iter(storage, matcher), storage, matcher),
+        Strategy::CitySexStatus => process_iter(city_sex_status_iter(storage, matcher), storage, matcher),
+        Strategy::CityStatus => process_iter(city_status_iter(storage, matcher), storage, matcher),
+        Strategy::City => process_iter(ordered(indexes.city_index.get(matcher.city), matcher), storage, matcher),
+        Strategy::CityAny => process_iter(kmerge_by(matcher.city_any.iter().map(|city| ordered(indexes.city_index.get(*city), matcher)), id_order(matcher)).dedup(), storage, matcher),
+        Strategy::Interest => {
+            // остальные интересы проверяет contains_all в matches
+            let interests_index = sex_interests_index(storage, matcher);
+            process_iter(ordered(interests_index.get(rarest_interest(interests_index, matcher).unwrap()), matcher), storage, matcher)
+        }
+        Strategy::Country => process_iter(ordered(indexes.country_index.get(matcher.country), matcher), storage, matcher),
+        Strategy::BirthYear => process_iter(ordered(indexes.birth_index.get(&matcher.birth_year).unwrap_or(&EMPTY_INT_LIST), matcher), storage, matcher),
+        Strategy::JoinedYear => process_iter(ordered(indexes.joined_index.get(&matcher.joined_year).unwrap_or(&EMPTY_INT_LIST), matcher), storage, matcher),
+        Strategy::PhoneCode => process_iter(ordered(indexes.phone_code_index.get(&matcher.phone_code).unwrap_or(&EMPTY_INT_LIST), matcher), storage, matcher),
+        Strategy::FnameAny => process_iter(kmerge_by(matcher.fname_any.iter().map(|fname| ordered(indexes.fname_index.get(*fname), matcher)), id_order(matcher)).dedup(), storage, matcher),
+        Strategy::FnameStarts => process_iter(kmerge_by(matcher.fname_starts_keys.iter().map(|fname| ordered(indexes.fname_index.get(*fname), matcher)), id_order(matcher)).dedup(), storage, matcher),
+        Strategy::InterestsAny => process_iter(kmerge_by(matcher.interests_any.as_ref().unwrap().into_iter().map(|interest| ordered(indexes.interests_index.get(interest), matcher)), id_order(matcher)).dedup(), storage, matcher),
+        Strategy::PremiumNow => process_iter(ordered(&indexes.premium_now_ids, matcher), storage, matcher),
+        Strategy::FullScan => full_scan(storage, matcher),
+    };
+    Some(accounts)
+}
+
+/// Кандидаты sname_starts из sname_prefix_index; None, если индекс не включен или начало короче его ключа.
+fn sname_prefix_ids<'a>(storage: &'a Storage, matcher: &Matcher) -> Option<&'a Vec<i32>> {
+    let sname_prefix_index = storage.indexes.sname_prefix_index.as_ref()?;
+    let prefix = storage::sname_prefix(matcher.sname_starts.as_deref())?;
+    Some(sname_prefix_index.get(prefix).unwrap_or(&EMPTY_INT_LIST))
+}
+
+/// В matcher.email_domain домен хранится вместе с '@' для проверки ends_with.
+fn email_domain_ids<'a>(storage: &'a Storage, matcher: &Matcher) -> &'a Vec<i32> {
+    let domain = &matcher.email_domain.as_ref().unwrap()[1..];
+    storage.indexes.email_domain_index.get(domain).unwrap_or(&EMPTY_INT_LIST)
+}
+
+/// Самый короткий из списков interests_index в порядке выдачи, пересеченный с остальными.
+/// Пересечение ленивое: при limit просматривается только начало или хвост списков, аккаунты без какого-то интереса не читаются.
+fn interests_all_iter<'a>(storage: &'a Storage, matcher: &Matcher) -> impl Iterator<Item=&'a i32> {
+    let mut lists: Vec<&Vec<i32>> = matcher.interests_contains.as_ref().unwrap().into_iter()
+        .map(|interest| storage.indexes.interests_index.get(interest))
+        .collect();
+    lists.sort_by_key(|ids| ids.len());
+    // позиции в остальных списках двигаются только в сторону выдачи: id идут по убыванию, при order=1 - по возрастанию
+    let ascending = matcher.ascending;
+    let mut others: Vec<(&Vec<i32>, usize)> = lists[1..].iter().map(|ids| (*ids, if ascending { 0 } else { ids.len() })).collect();
+    ordered(lists[0], matcher).filter(move |id| others.iter_mut().all(|(ids, pos)| {
+        if ascending {
+            while *pos < ids.len() && ids[*pos] < **id {
+                *pos += 1;
+            }
+            *pos < ids.len() && ids[*pos] == **id
+        } else {
+            while *pos > 0 && ids[*pos - 1] > **id {
+                *pos -= 1;
+            }
+            *pos > 0 && ids[*pos - 1] == **id
+        }
+    }))
+}
+
+fn sex_interests_index<'a>(storage: &'a Storage, matcher: &Matcher) -> &'a PostingLists {
+    if matcher.sex == 0 {
+        &storage.indexes.interests_index
+    } else if matcher.sex == storage.consts.male {
+        &storage.indexes.interests_index_male
     } else {
-        None
+        &storage.indexes.interests_index_female
     }
 }
 
+/// city_index, пересеченный с множествами id по полу и статусу, чтобы не читать аккаунты заведомо неподходящих.
+fn city_sex_status_iter<'a>(storage: &'a Storage, matcher: &Matcher) -> impl Iterator<Item=&'a i32> {
+    let sex_ids = storage.indexes.sex_ids.get(&matcher.sex);
+    let status_ids = storage.indexes.status_ids.get(&matcher.status_eq);
+    ordered(storage.indexes.city_index.get(matcher.city), matcher)
+        .filter(move |id| sex_ids.is_some_and(|ids| ids.contains(**id)) && status_ids.is_some_and(|ids| ids.contains(**id)))
+}
+
+/// city_index, пересеченный с множеством id по статусу: статусов всего три, отдельный индекс город-статус не нужен.
+fn city_status_iter<'a>(storage: &'a Storage, matcher: &Matcher) -> impl Iterator<Item=&'a i32> {
+    let status_ids = storage.indexes.status_ids.get(&matcher.status_eq);
+    ordered(storage.indexes.city_index.get(matcher.city), matcher)
+        .filter(move |id| status_ids.is_some_and(|ids| ids.contains(**id)))
+}
+
+/// Список индекса, отсортированный по возрастанию id, в порядке выдачи: по умолчанию от больших id к меньшим, с order=1 - наоборот.
+fn ordered<'a>(ids: &'a [i32], matcher: &Matcher) -> Either<Rev<slice::Iter<'a, i32>>, slice::Iter<'a, i32>> {
+    if matcher.ascending { Either::Right(ids.iter()) } else { Either::Left(ids.iter().rev()) }
+}
+
+/// Сравнение для kmerge_by списков из ordered.
+fn id_order(matcher: &Matcher) -> fn(&&i32, &&i32) -> bool {
+    if matcher.ascending { id_asc } else { rev_id }
+}
+
+fn id_asc(a: &&i32, b: &&i32) -> bool {
+    a < b
+}
+
 fn rev_id(a: &&i32, b: &&i32) -> bool {
     a > b
 }
 
-fn process_rev_iter<'a, I>(iter: I, storage: &Storage, matcher: &Matcher) -> AccountsJson
-    where I: Iterator<Item=&'a i32> {
-    AccountsJson {
-        accounts: iter
-            .filter_map(|id| storage.accounts[*id as usize].as_ref())
-            .filter(|account| matches(account, &matcher, storage))
-            .map(|account| {
-                make_result(storage, &matcher, account)
-            })
-            .take(matcher.limit)
-            .collect()
+fn process_iter<'a, 'b, I>(iter: I, storage: &'a Storage, matcher: &Matcher) -> Vec<&'a Account>
+    where I: Iterator<Item=&'b i32> {
+    // ids идут в порядке выдачи, границы id_lt/id_gt отсекают начало и конец
+    let (skip_bound, take_bound) = if matcher.ascending { (matcher.id_gt, matcher.id_lt) } else { (matcher.id_lt, matcher.id_gt) };
+    let ascending = matcher.ascending;
+    iter
+        .skip_while(|id| skip_bound.is_some_and(|bound| if ascending { **id <= bound } else { **id >= bound }))
+        .take_while(|id| take_bound.is_none_or(|bound| if ascending { **id < bound } else { **id > bound }))
+        .filter_map(|id| storage.accounts[*id as usize].as_ref())
+        .filter(|account| matches(account, &matcher, storage))
+        .take(matcher.limit)
+        .collect()
+}
+
+/// Диапазон id для full scan, от большего к меньшему включительно: id_lt/id_gt сужают его вместо проверки в matches.
+/// Пустой диапазон - to > from.
+fn full_scan_range(storage: &Storage, matcher: &Matcher) -> (usize, usize) {
+    let to = matcher.id_gt.map_or(0, |id_gt| (id_gt.max(-1) as i64 + 1) as usize);
+    match matcher.id_lt {
+        Some(id_lt) if id_lt <= 0 => (0, 1),
+        Some(id_lt) => (storage.max_id.min(id_lt as usize - 1), to),
+        None => (storage.max_id, to),
     }
 }
 
 #[inline(never)]
-fn full_scan(storage: &Storage, matcher: &Matcher) -> AccountsJson {
-    AccountsJson {
-        accounts: (0..storage.max_id + 1).rev()
-            .filter_map(|id| storage.accounts[id].as_ref())
-            .filter(|account| matches(account, &matcher, storage))
-            .map(|account| {
-                make_result(storage, &matcher, account)
-            })
-            .take(matcher.limit)
-            .collect()
-    }
+fn full_scan<'a>(storage: &'a Storage, matcher: &Matcher) -> Vec<&'a Account> {
+    let (from, to) = full_scan_range(storage, matcher);
+    let ids = if matcher.ascending { Either::Right(to..from + 1) } else { Either::Left((to..from + 1).rev()) };
+    ids
+        .filter_map(|id| storage.accounts[id].as_ref())
+        .filter(|account| matches(account, &matcher, storage))
+        .take(matcher.limit)
+        .collect()
 }
 
+/// Ошибки разбора параметров:
+/// - 400 - запрос некорректен по форме: неизвестный ключ, нечисловое значение (limit, id_lt, birth_lt, joined_year,
+///   phone_code, likes_contains...), недопустимый флаг (*_null не 0/1, premium_now и count не 1), limit=0;
+///   в --strict-unknown также противоречивые условия из CONFLICTS;
+/// - 422 - только в --strict-unknown: значение вне закрытого набора (sex_eq не m/f, status_eq/status_neq/status_any не один из статусов);
+///   без --strict-unknown такой запрос, как и значение, которого нет в данных (city_eq, fname_eq...), отвечает пустым списком.
+///
+/// 400 важнее 422: при обеих ошибках в запросе отвечается 400.
 fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> Result<Option<Matcher>, StatusCode> {
+    check_unique_params(params)?;
     let mut matcher = Matcher {
         limit: 0,
+        ascending: false,
         conditions: Vec::new(),
         mode: Mode::Standard,
+        debug_interests: false,
 
+        id_lt: None,
+        id_gt: None,
         sex: 0,
         email_domain: None,
+        email_eq: None,
         email_lt: None,
         email_gt: None,
         status_eq: 0,
         status_neq: 0,
+        status_any: Vec::new(),
         fname: 0,
         fname_any: Vec::new(),
+        fname_starts: None,
+        fname_starts_keys: Vec::new(),
         fname_null0: false,
         fname_null1: false,
         sname: 0,
@@ -184,6 +438,7 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
         phone_null0: false,
         phone_null1: false,
         country: 0,
+        country_implied: false,
         country_null0: false,
         country_null1: false,
         city: 0,
@@ -191,10 +446,17 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
         city_null0: false,
         city_null1: false,
         birth_lt: NULL_DATE,
+        birth_lte: NULL_DATE,
         birth_gt: NULL_DATE,
+        birth_gte: NULL_DATE,
         birth_from: NULL_DATE,
         birth_to: NULL_DATE,
         birth_year: 0,
+        joined_lt: NULL_DATE,
+        joined_gt: NULL_DATE,
+        joined_from: NULL_DATE,
+        joined_to: NULL_DATE,
+        joined_year: 0,
         interests_contains: None,
         interests_any: None,
         likes_contains: Vec::new(),
@@ -204,28 +466,58 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
     };
 
     let mut empty_result = false;
+    // значение вне закрытого набора, в --strict-unknown - 422 после всех проверок на 400
+    let mut unprocessable = false;
 
     for (key, value) in params {
         match key.as_str() {
             "query_id" => {}
-            "limit" => {
-                matcher.limit = value.parse::<usize>().map_err(|_| StatusCode::BAD_REQUEST)?;
-                if matcher.limit == 0 {
+            "limit" => {}
+            "order" => {
+                // -1 - от новых к старым, как по умолчанию; limit отсчитывается в выбранном порядке
+                matcher.ascending = match value.as_str() {
+                    "1" => true,
+                    "-1" => false,
+                    _ => return Err(StatusCode::BAD_REQUEST),
+                };
+            }
+            "count" => {
+                // count=1 - в ответе только число подходящих аккаунтов, не больше limit; способ поиска тот же
+                if value != "1" {
                     return Err(StatusCode::BAD_REQUEST);
                 }
             }
+            "_debug_fields" => {
+                // отладочный вывод дополнительных полей, на выбор индекса не влияет
+                for field in value.split(',') {
+                    match field {
+                        "interests" => matcher.debug_interests = true,
+                        _ => return Err(StatusCode::BAD_REQUEST)
+                    }
+                }
+            }
             _ => {
                 match key.as_str() {
+                    "id_lt" => {
+                        matcher.id_lt = Some(value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?);
+                    }
+                    "id_gt" => {
+                        matcher.id_gt = Some(value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?);
+                    }
                     "sex_eq" => {
                         matcher.sex = storage.dict.get_existing_key(value).unwrap_or(0);
                         if matcher.sex == 0 {
                             empty_result = true;
                         }
+                        unprocessable |= matcher.sex != storage.consts.male && matcher.sex != storage.consts.female;
                     }
                     "email_domain" => {
                         // TODO check domain exists?
                         matcher.email_domain = Some("@".to_string() + value);
                     }
+                    "email_eq" => {
+                        matcher.email_eq = Some(value.clone());
+                    }
                     "email_lt" => {
                         matcher.email_lt = Some(value.clone());
                     }
@@ -237,12 +529,22 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
                         if matcher.status_eq == 0 {
                             empty_result = true;
                         }
+                        unprocessable |= !is_status(storage, matcher.status_eq);
                     }
                     "status_neq" => {
                         matcher.status_neq = storage.dict.get_existing_key(value).unwrap_or(0);
                         if matcher.status_neq == 0 {
                             empty_result = true;
                         }
+                        unprocessable |= !is_status(storage, matcher.status_neq);
+                    }
+                    "status_any" => {
+                        // статусов всего три, отдельной стратегии нет: объединение списков status_ids не короче полного перебора
+                        matcher.status_any = value.split(',').map(|v| storage.dict.get_existing_key(&v.to_string()).unwrap_or(0)).collect();
+                        if matcher.status_any.iter().all(|status| *status == 0) {
+                            empty_result = true;
+                        }
+                        unprocessable |= !matcher.status_any.iter().all(|status| is_status(storage, *status));
                     }
                     "fname_eq" => {
                         matcher.fname = storage.dict.get_existing_key(value).unwrap_or(0);
@@ -250,6 +552,16 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
                             empty_result = true;
                         }
                     }
+                    "fname_starts" => {
+                        // имен немного, поэтому вместо отдельного индекса по началу - все подходящие ключи fname_index
+                        matcher.fname_starts_keys = storage.indexes.fname_index.keys()
+                            .filter(|fname| storage.dict.get_value(*fname).is_some_and(|name| name.starts_with(value.as_str())))
+                            .collect();
+                        if matcher.fname_starts_keys.is_empty() {
+                            empty_result = true;
+                        }
+                        matcher.fname_starts = Some(value.clone());
+                    }
                     "fname_any" => {
                         matcher.fname_any = value.split(',').map(|v| storage.dict.get_existing_key(&v.to_string()).unwrap_or(0)).collect();
                     }
@@ -321,21 +633,38 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
                     "birth_gt" => {
                         matcher.birth_gt = value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
                     }
+                    "birth_lte" => {
+                        matcher.birth_lte = value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+                    }
+                    "birth_gte" => {
+                        matcher.birth_gte = value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+                    }
                     "birth_year" => {
                         matcher.birth_year = value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
                         matcher.birth_from = seconds_from_year(matcher.birth_year);
                         matcher.birth_to = seconds_from_year(matcher.birth_year + 1);
                     }
+                    "joined_lt" => {
+                        matcher.joined_lt = value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+                    }
+                    "joined_gt" => {
+                        matcher.joined_gt = value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+                    }
+                    "joined_year" => {
+                        matcher.joined_year = value.parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+                        matcher.joined_from = seconds_from_year(matcher.joined_year);
+                        matcher.joined_to = seconds_from_year(matcher.joined_year + 1);
+                    }
                     "interests_contains" => {
                         let vec: Vec<i32> = value.split(',').map(|v| storage.interest_dict.get_existing_key(&v.to_string()).unwrap_or(0)).collect();
                         if vec.contains(&0) {
                             empty_result = true;
                         }
-                        matcher.interests_contains = Some(Bits::from_vec(vec));
+                        matcher.interests_contains = Some(Interests::from_vec(vec));
                     }
                     "interests_any" => {
                         let vec = value.split(',').map(|v| storage.interest_dict.get_existing_key(&v.to_string()).unwrap_or(0)).collect();
-                        matcher.interests_any = Some(Bits::from_vec(vec));
+                        matcher.interests_any = Some(Interests::from_vec(vec));
                     }
                     "likes_contains" => {
                         // https://stackoverflow.com/questions/26368288/how-do-i-stop-iteration-and-return-an-error-when-iteratormap-returns-a-result
@@ -343,6 +672,8 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
                         matcher.likes_contains = parts.map_err(|_| StatusCode::BAD_REQUEST)?;
                         matcher.likes_contains.sort();
                         matcher.likes_contains.dedup();
+                        // сначала самые редкие: так меньше работы и у пересечения индексов, и у проверки аккаунта
+                        matcher.likes_contains.sort_by_key(|likee| storage.indexes.likers_count(*likee));
                     }
                     "premium_now" => {
                         match value.as_str() {
@@ -363,6 +694,21 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
             }
         }
     }
+    if storage.config.strict_unknown && has_conflicts(&matcher.conditions) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    matcher.limit = parse_limit(params, storage.config.default_limit)?;
+    if storage.config.strict_unknown && unprocessable {
+        return Err(StatusCode::UNPROCESSABLE);
+    }
+    // город всегда встречался с одной страной: другая страна - пустой ответ, та же - в matches ее можно не проверять
+    if matcher.city != 0 && matcher.country != 0 {
+        match storage.indexes.country_of_city(matcher.city) {
+            Some(country) if country != matcher.country => empty_result = true,
+            Some(_) => matcher.country_implied = true,
+            None => {}
+        }
+    }
     if empty_result {
         return Ok(None);
     }
@@ -370,6 +716,49 @@ fn make_matcher(storage: &storage::Storage, params: &Vec<(String, String)>) -> R
     Ok(Some(matcher))
 }
 
+// пары условий, которые вместе противоречат друг другу или одно из них лишнее
+const CONFLICTS: &[(&str, &str)] = &[
+    ("birth_year", "birth_lt"),
+    ("birth_year", "birth_gt"),
+    ("birth_year", "birth_lte"),
+    ("birth_year", "birth_gte"),
+    ("birth_lt", "birth_lte"),
+    ("birth_gt", "birth_gte"),
+    ("joined_year", "joined_lt"),
+    ("joined_year", "joined_gt"),
+    ("status_eq", "status_neq"),
+    ("status_eq", "status_any"),
+    ("fname_eq", "fname_any"),
+    ("fname_eq", "fname_null"),
+    ("fname_any", "fname_null"),
+    ("fname_eq", "fname_starts"),
+    ("fname_starts", "fname_null"),
+    ("sname_eq", "sname_starts"),
+    ("sname_eq", "sname_null"),
+    ("sname_starts", "sname_null"),
+    ("phone_code", "phone_null"),
+    ("country_eq", "country_null"),
+    ("city_eq", "city_any"),
+    ("city_eq", "city_null"),
+    ("city_any", "city_null"),
+    ("email_eq", "email_domain"),
+    ("email_eq", "email_lt"),
+    ("email_eq", "email_gt"),
+    ("interests_contains", "interests_any"),
+    ("premium_now", "premium_null"),
+];
+
+fn is_status(storage: &Storage, status: i32) -> bool {
+    let consts = &storage.consts;
+    status == consts.free_status || status == consts.taken_status || status == consts.hard_status
+}
+
+/// Пара из CONFLICTS, проверяется только в --strict-unknown; повторяющийся ключ - 400 всегда, см. check_unique_params.
+fn has_conflicts(conditions: &[String]) -> bool {
+    let has = |key: &str| conditions.iter().any(|c| c == key);
+    CONFLICTS.iter().any(|(a, b)| has(a) && has(b))
+}
+
 fn matches(account: &Account, matcher: &Matcher, storage: &Storage) -> bool {
     // TODO fast paths for popular combinations
     // TODO убрать, эффекта нет?
@@ -395,12 +784,21 @@ fn matches(account: &Account, matcher: &Matcher, storage: &Storage) -> bool {
             return true;
         }
         Mode::Standard => {
+            if matcher.id_lt.is_some() && account.id >= matcher.id_lt.unwrap() {
+                return false;
+            }
+            if matcher.id_gt.is_some() && account.id <= matcher.id_gt.unwrap() {
+                return false;
+            }
             if matcher.sex != 0 && matcher.sex != account.sex {
                 return false;
             }
             if matcher.email_domain.is_some() && !account.email.as_ref().unwrap().ends_with(matcher.email_domain.as_ref().unwrap()) {
                 return false; // TODO dict?
             }
+            if matcher.email_eq.is_some() && account.email.as_ref().unwrap().borrow() as &String != matcher.email_eq.as_ref().unwrap() {
+                return false;
+            }
             if matcher.email_lt.is_some() && account.email.as_ref().unwrap().borrow() as &String >= matcher.email_lt.as_ref().unwrap() {
                 return false;
             }
@@ -413,12 +811,19 @@ fn matches(account: &Account, matcher: &Matcher, storage: &Storage) -> bool {
             if matcher.status_neq != 0 && account.status == matcher.status_neq {
                 return false;
             }
+            if !matcher.status_any.is_empty() && !matcher.status_any.contains(&account.status) {
+                return false;
+            }
             if matcher.fname != 0 && account.fname != matcher.fname {
                 return false;
             }
             if !matcher.fname_any.is_empty() && (account.fname == 0 || !matcher.fname_any.contains(&account.fname)) {
                 return false;
             }
+            // ключи отобраны в make_matcher и отсортированы, словарь на каждый аккаунт не читается
+            if matcher.fname_starts.is_some() && matcher.fname_starts_keys.binary_search(&account.fname).is_err() {
+                return false;
+            }
             if matcher.fname_null0 && account.fname == 0 {
                 return false;
             }
@@ -446,7 +851,7 @@ fn matches(account: &Account, matcher: &Matcher, storage: &Storage) -> bool {
             if matcher.phone_null1 && account.phone_number != 0 {
                 return false;
             }
-            if matcher.country != 0 && account.country != matcher.country {
+            if matcher.country != 0 && !matcher.country_implied && account.country != matcher.country {
                 return false;
             }
             if matcher.country_null0 && account.country == 0 {
@@ -473,9 +878,24 @@ fn matches(account: &Account, matcher: &Matcher, storage: &Storage) -> bool {
             if matcher.birth_gt != NULL_DATE && account.birth <= matcher.birth_gt {
                 return false;
             }
+            if matcher.birth_lte != NULL_DATE && account.birth > matcher.birth_lte {
+                return false;
+            }
+            if matcher.birth_gte != NULL_DATE && account.birth < matcher.birth_gte {
+                return false;
+            }
             if matcher.birth_year != 0 && (account.birth < matcher.birth_from || account.birth >= matcher.birth_to) {
                 return false;
             }
+            if matcher.joined_lt != NULL_DATE && account.joined >= matcher.joined_lt {
+                return false;
+            }
+            if matcher.joined_gt != NULL_DATE && account.joined <= matcher.joined_gt {
+                return false;
+            }
+            if matcher.joined_year != 0 && (account.joined < matcher.joined_from || account.joined >= matcher.joined_to) {
+                return false;
+            }
             if matcher.interests_contains.is_some() {
                 if account.interests.is_empty() {
                     return false;
@@ -514,46 +934,147 @@ fn matches(account: &Account, matcher: &Matcher, storage: &Storage) -> bool {
     };
 }
 
+/// Результат filter сериализуется прямо из Account, без промежуточных AccountJson
+/// с клонами Arc<String> на каждое поле; набор полей определяет matcher.
+pub struct FilterResult<'a> {
+    storage: &'a Storage,
+    // None, если результат заведомо пустой
+    matcher: Option<Matcher>,
+    pub accounts: Vec<&'a Account>,
+    // {"count": N} вместо строк
+    count_only: bool,
+}
+
+impl<'a> Serialize for FilterResult<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.count_only {
+            let mut result = serializer.serialize_struct("CountJson", 1)?;
+            result.serialize_field("count", &self.accounts.len())?;
+            return result.end();
+        }
+        let mut result = serializer.serialize_struct("AccountsJson", 1)?;
+        result.serialize_field("accounts", &AccountsSer(self))?;
+        result.end()
+    }
+}
+
+struct AccountsSer<'a, 'b>(&'b FilterResult<'a>);
+
+impl<'a, 'b> Serialize for AccountsSer<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let FilterResult { storage, matcher, accounts, .. } = self.0;
+        serializer.collect_seq(accounts.iter().map(|account| AccountSer { storage, matcher: matcher.as_ref().unwrap(), account }))
+    }
+}
+
+struct AccountSer<'a> {
+    storage: &'a Storage,
+    matcher: &'a Matcher,
+    account: &'a Account,
+}
+
+impl<'a> Serialize for AccountSer<'a> {
+    /// Поля в том же порядке, что и в AccountJson.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let AccountSer { storage, matcher, account } = *self;
+        let mut json = serializer.serialize_struct("AccountJson", 14)?;
+        json.serialize_field("id", &account.id)?;
+        if let Some(email) = account.email.as_ref() {
+            json.serialize_field("email", email.as_str())?;
+        }
+        if let Some(sname) = Some(account.sname).filter(|_| matcher.show_sname()).and_then(|key| storage.dict.get_value(key)) {
+            json.serialize_field("sname", sname.as_str())?;
+        }
+        if let Some(fname) = Some(account.fname).filter(|_| matcher.show_fname()).and_then(|key| storage.dict.get_value(key)) {
+            json.serialize_field("fname", fname.as_str())?;
+        }
+        if matcher.show_phone() && account.phone_number != 0 {
+            json.serialize_field("phone", &PhoneSer(account))?;
+        }
+        if let Some(sex) = Some(account.sex).filter(|_| matcher.show_sex()).and_then(|key| storage.dict.get_value(key)) {
+            json.serialize_field("sex", sex.as_str())?;
+        }
+        if matcher.show_birth() {
+            json.serialize_field("birth", &account.birth)?;
+        }
+        if let Some(country) = Some(account.country).filter(|_| matcher.show_country()).and_then(|key| storage.dict.get_value(key)) {
+            json.serialize_field("country", country.as_str())?;
+        }
+        if let Some(city) = Some(account.city).filter(|_| matcher.show_city()).and_then(|key| storage.dict.get_value(key)) {
+            json.serialize_field("city", city.as_str())?;
+        }
+        if matcher.show_joined() {
+            json.serialize_field("joined", &account.joined)?;
+        }
+        if let Some(status) = Some(account.status).filter(|_| matcher.show_status()).and_then(|key| storage.dict.get_value(key)) {
+            json.serialize_field("status", status.as_str())?;
+        }
+        let emit_empty_arrays = storage.config.emit_empty_arrays;
+        if matcher.show_interests() && !account.interests.is_empty() {
+            json.serialize_field("interests", &InterestsSer(storage, account))?;
+        } else if emit_empty_arrays && account.interests.is_empty() {
+            json.serialize_field("interests", &[0; 0])?;
+        }
+        if emit_empty_arrays && account.likes.is_empty() {
+            json.serialize_field("likes", &[0; 0])?;
+        }
+        if matcher.show_premium() && account.premium_start != NULL_DATE {
+            json.serialize_field("premium", &Premium { start: account.premium_start, finish: account.premium_finish })?;
+        }
+        json.end()
+    }
+}
+
+struct PhoneSer<'a>(&'a Account);
+
+impl<'a> Serialize for PhoneSer<'a> {
+    /// phone_number хранится с ведущей 1, чтобы не терять ведущие нули номера.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut leading = 1;
+        let mut width = 0;
+        while leading * 10 <= self.0.phone_number {
+            leading *= 10;
+            width += 1;
+        }
+        serializer.collect_str(&format_args!("8({}){:0width$}", self.0.phone_code, self.0.phone_number - leading, width = width))
+    }
+}
+
+struct InterestsSer<'a>(&'a Storage, &'a Account);
+
+impl<'a> Serialize for InterestsSer<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // по возрастанию ключа словаря, как итерирует Interests
+        serializer.collect_seq(self.1.interests.into_iter().filter_map(|interest| self.0.interest_dict.get_value(interest)))
+    }
+}
+
+/// Прежнее построение результата через AccountJson, для сравнения с FilterResult.
+#[cfg(test)]
 fn make_result(storage: &Storage, matcher: &Matcher, account: &Account) -> AccountJson {
     AccountJson {
         id: Some(account.id),
         email: account.email.as_ref().map(|email| email.clone()),
-        sex: if matcher.sex != 0 { storage.dict.get_value(account.sex) } else { None },
-        sname: if matcher.sname != 0 || matcher.sname_starts.is_some() || matcher.sname_null0 || matcher.sname_null1 {
-            storage.dict.get_value(account.sname)
-        } else {
-            None
-        },
-        fname: if matcher.fname != 0 || !matcher.fname_any.is_empty() || matcher.fname_null0 || matcher.fname_null1 {
-            storage.dict.get_value(account.fname)
-        } else {
-            None
-        },
-        phone: if (matcher.phone_code != 0 || matcher.phone_null0 || matcher.phone_null1) && account.phone_number != 0 {
+        sex: if matcher.show_sex() { storage.dict.get_value(account.sex) } else { None },
+        sname: if matcher.show_sname() { storage.dict.get_value(account.sname) } else { None },
+        fname: if matcher.show_fname() { storage.dict.get_value(account.fname) } else { None },
+        phone: if matcher.show_phone() && account.phone_number != 0 {
             Some(Arc::new("8(".to_string() + account.phone_code.to_string().as_str() + ")" + &account.phone_number.to_string().as_str()[1..]))
         } else {
             None
         },
-        birth: if matcher.birth_lt != NULL_DATE || matcher.birth_gt != NULL_DATE || matcher.birth_year != 0 {
-            Some(account.birth)
+        birth: if matcher.show_birth() { Some(account.birth) } else { None },
+        country: if matcher.show_country() { storage.dict.get_value(account.country) } else { None },
+        city: if matcher.show_city() { storage.dict.get_value(account.city) } else { None },
+        joined: if matcher.show_joined() { Some(account.joined) } else { None },
+        status: if matcher.show_status() { storage.dict.get_value(account.status) } else { None },
+        interests: if matcher.show_interests() && !account.interests.is_empty() {
+            Some(account.interests.into_iter().filter_map(|interest| storage.interest_dict.get_value(interest)).collect())
         } else {
-            None
+            storage.config.hidden_array(account.interests.is_empty())
         },
-        country: if matcher.country != 0 || matcher.country_null0 || matcher.country_null1 {
-            storage.dict.get_value(account.country)
-        } else {
-            None
-        },
-        city: if matcher.city != 0 || !matcher.city_any.is_empty() || matcher.city_null0 || matcher.city_null1 {
-            storage.dict.get_value(account.city)
-        } else {
-            None
-        },
-        joined: None,
-        status: if matcher.status_eq != 0 || matcher.status_neq != 0 { storage.dict.get_value(account.status) } else { None },
-        interests: Vec::new(),
-        likes: Vec::new(),
-        premium: if (matcher.premium_now || matcher.premium_null0 || matcher.premium_null1) && account.premium_start != NULL_DATE {
+        likes: storage.config.hidden_array(account.likes.is_empty()),
+        premium: if matcher.show_premium() && account.premium_start != NULL_DATE {
             Some(Premium { start: account.premium_start, finish: account.premium_finish })
         } else {
             None
@@ -561,21 +1082,81 @@ fn make_result(storage: &Storage, matcher: &Matcher, account: &Account) -> Accou
     }
 }
 
+impl Matcher {
+    // в ответе выводятся поля, по которым есть условия, в том числе status для status_neq;
+    // interests и likes не выводятся (interests - только в отладочном _debug_fields), email и id - всегда
+    fn show_sex(&self) -> bool {
+        self.sex != 0
+    }
+
+    fn show_sname(&self) -> bool {
+        self.sname != 0 || self.sname_starts.is_some() || self.sname_null0 || self.sname_null1
+    }
+
+    fn show_fname(&self) -> bool {
+        self.fname != 0 || !self.fname_any.is_empty() || self.fname_starts.is_some() || self.fname_null0 || self.fname_null1
+    }
+
+    fn show_phone(&self) -> bool {
+        self.phone_code != 0 || self.phone_null0 || self.phone_null1
+    }
+
+    fn show_birth(&self) -> bool {
+        self.birth_lt != NULL_DATE || self.birth_gt != NULL_DATE || self.birth_lte != NULL_DATE || self.birth_gte != NULL_DATE ||
+            self.birth_year != 0
+    }
+
+    fn show_joined(&self) -> bool {
+        self.joined_lt != NULL_DATE || self.joined_gt != NULL_DATE || self.joined_year != 0
+    }
+
+    fn show_country(&self) -> bool {
+        self.country != 0 || self.country_null0 || self.country_null1
+    }
+
+    fn show_city(&self) -> bool {
+        self.city != 0 || !self.city_any.is_empty() || self.city_null0 || self.city_null1
+    }
+
+    fn show_status(&self) -> bool {
+        self.status_eq != 0 || self.status_neq != 0 || !self.status_any.is_empty()
+    }
+
+    fn show_interests(&self) -> bool {
+        self.debug_interests && (self.interests_any.is_some() || self.interests_contains.is_some())
+    }
+
+    fn show_premium(&self) -> bool {
+        self.premium_now || self.premium_null0 || self.premium_null1
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Matcher {
     limit: usize,
+    // order=1: от меньших id к большим
+    ascending: bool,
     pub conditions: Vec<String>,
     mode: Mode,
+    debug_interests: bool,
 
+    // границы id для постраничного обхода, не включая сами границы
+    id_lt: Option<i32>,
+    id_gt: Option<i32>,
     pub sex: i32,
     // включая @
     email_domain: Option<String>,
+    email_eq: Option<String>,
     pub email_lt: Option<String>,
     pub email_gt: Option<String>,
     pub status_eq: i32,
     pub status_neq: i32,
+    pub status_any: Vec<i32>,
     fname: i32,
     pub fname_any: Vec<i32>,
+    fname_starts: Option<String>,
+    // ключи fname_index по возрастанию, значения которых начинаются с fname_starts
+    fname_starts_keys: Vec<i32>,
     fname_null0: bool,
     fname_null1: bool,
     sname: i32,
@@ -586,22 +1167,877 @@ pub struct Matcher {
     phone_null0: bool,
     phone_null1: bool,
     country: i32,
+    // country следует из city по country_of_city
+    country_implied: bool,
     country_null0: bool,
     pub country_null1: bool,
     city: i32,
     pub city_any: Vec<i32>,
     city_null0: bool,
     pub city_null1: bool,
+    // границы birth: lt/gt строгие, lte/gte включительные. Все заданные границы и birth_year применяются вместе,
+    // то есть действует самая узкая из них, а несовместимые границы дают пустой ответ
+    // (в --strict-unknown lt с lte, gt с gte и любая граница с birth_year - 400)
     birth_lt: i32,
     birth_gt: i32,
+    birth_lte: i32,
+    birth_gte: i32,
     birth_from: i32,
     birth_to: i32,
     birth_year: i32,
-    pub interests_contains: Option<Bits>,
-    pub interests_any: Option<Bits>,
+    joined_lt: i32,
+    joined_gt: i32,
+    joined_from: i32,
+    joined_to: i32,
+    joined_year: i32,
+    pub interests_contains: Option<Interests>,
+    pub interests_any: Option<Interests>,
     // без дублей
     likes_contains: Vec<i32>,
     premium_now: bool,
     premium_null0: bool,
     premium_null1: bool,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::make_storage;
+    use crate::utils::params;
+
+    use super::*;
+
+    /// filter, принудительно выполненный через full scan.
+    fn filter_full_scan<'a>(storage: &'a Storage, params: &Vec<(String, String)>) -> FilterResult<'a> {
+        let matcher = make_matcher(storage, params).unwrap();
+        let accounts = matcher.as_ref().map_or(Vec::new(), |matcher| full_scan(storage, matcher));
+        FilterResult { storage, matcher, accounts, count_only: params.iter().any(|(key, _)| key == "count") }
+    }
+
+    /// Прежний путь: AccountJson для каждой строки и сериализация через serde_derive.
+    fn to_string_via_account_json(result: &FilterResult) -> String {
+        let accounts = result.accounts.iter().map(|account| make_result(result.storage, result.matcher.as_ref().unwrap(), account)).collect();
+        serde_json::to_string(&AccountsJson { accounts }).unwrap()
+    }
+
+    /// Параметры запроса в таблицах тестов.
+    type Query<'a> = &'a [(&'a str, &'a str)];
+
+    /// Аккаунт с обязательными полями и sex по четности id, extra дописывается в конец объекта.
+    fn account(id: usize, extra: &str) -> String {
+        format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"свободны","birth":600000000,"joined":1300000000{}}}"#,
+                id, id, if id.is_multiple_of(2) { "m" } else { "f" }, extra)
+    }
+
+    /// Storage из аккаунтов с id от 1 до count.
+    fn make_storage_of(config: storage::Config, count: usize, account: impl Fn(usize) -> String) -> Storage {
+        let mut storage = Storage::new(1545834028, config, count.max(999) + 1);
+        for id in 1..count + 1 {
+            storage.new_account(account(id).as_bytes(), &mut |_| {}).unwrap();
+        }
+        storage
+    }
+
+    /// id из filter; ответ совпадает с full scan байт в байт, план начинается со strategy, если она задана.
+    fn check(storage: &Storage, query: &[(&str, &str)], strategy: Option<Strategy>) -> Vec<i32> {
+        let query = params(query);
+        if let Some(strategy) = strategy {
+            let matcher = make_matcher(storage, &query).unwrap().unwrap();
+            assert_eq!(plan(storage, &matcher)[0], strategy, "{:?}", query);
+        }
+        let result = filter(storage, &query).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), serde_json::to_string(&filter_full_scan(storage, &query)).unwrap(), "{:?}", query);
+        result.accounts.iter().map(|account| account.id).collect()
+    }
+
+    #[test]
+    fn test_debug_fields_interests() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["b","c"]}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["c","a","b"]}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["d"]}"#,
+        ]);
+
+        let result = filter(&storage, &params(&[("interests_any", "a,b"), ("limit", "10")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"accounts":[{"id":2,"email":"a2@a.ru"},{"id":1,"email":"a1@a.ru"}]}"#);
+
+        let result = filter(&storage, &params(&[("interests_any", "a,b"), ("limit", "10"), ("_debug_fields", "interests")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(),
+                   r#"{"accounts":[{"id":2,"email":"a2@a.ru","interests":["b","c","a"]},{"id":1,"email":"a1@a.ru","interests":["b","c"]}]}"#);
+
+        let result = filter(&storage, &params(&[("interests_contains", "d"), ("limit", "10"), ("_debug_fields", "interests")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"accounts":[{"id":3,"email":"a3@a.ru","interests":["d"]}]}"#);
+
+        let result = filter(&storage, &params(&[("sex_eq", "f"), ("limit", "10"), ("_debug_fields", "interests")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(),
+                   r#"{"accounts":[{"id":3,"email":"a3@a.ru","sex":"f"},{"id":2,"email":"a2@a.ru","sex":"f"}]}"#);
+
+        assert_eq!(filter(&storage, &params(&[("limit", "10"), ("_debug_fields", "likes")])).err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn test_joined() {
+        let (year_start, next_year_start) = (seconds_from_year(2012), seconds_from_year(2013));
+        let joined = [year_start - 1, year_start, next_year_start - 1, next_year_start];
+        let storage = make_storage_of(storage::Config::new(), joined.len(), |id| {
+            format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":{}}}"#, id, id, joined[id - 1])
+        });
+        let (year_start, last_second, next_year_start) = (year_start.to_string(), (next_year_start - 1).to_string(), next_year_start.to_string());
+        let cases: &[(Query, &[i32])] = &[
+            // границы года: первая секунда входит, первая секунда следующего года - нет
+            (&[("joined_year", "2012")], &[3, 2]),
+            (&[("joined_year", "2011")], &[1]),
+            (&[("joined_year", "2013")], &[4]),
+            (&[("joined_year", "2020")], &[]),
+            // lt и gt строгие
+            (&[("joined_lt", &year_start)], &[1]),
+            (&[("joined_gt", &last_second)], &[4]),
+            (&[("joined_gt", &year_start), ("joined_lt", &next_year_start)], &[3]),
+        ];
+        for (query, expected) in cases {
+            let mut query = query.to_vec();
+            query.push(("limit", "10"));
+            let strategy = if query[0].0 == "joined_year" { Some(Strategy::JoinedYear) } else { None };
+            assert_eq!(check(&storage, &query, strategy), *expected, "{:?}", query);
+        }
+
+        let result = filter(&storage, &params(&[("joined_year", "2013"), ("limit", "10")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), format!(r#"{{"accounts":[{{"id":4,"email":"a4@a.ru","joined":{}}}]}}"#, next_year_start));
+        assert_eq!(filter(&storage, &params(&[("joined_year", "x"), ("limit", "10")])).err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn test_phone_code_index() {
+        // у каждого пятого нет телефона
+        let mut storage = make_storage_of(storage::Config::new(), 1000, |id| {
+            if id % 5 == 0 { account(id, "") } else { account(id, &format!(r#","phone":"8({}){:07}""#, if id % 100 == 1 { 999 } else { 900 + id % 3 }, id)) }
+        });
+        assert_eq!(storage.indexes.phone_code_index.get(&0), None);
+        // после update id остается в списке прежнего кода и отсекается matches
+        storage.update_account(101, r#"{"phone":"8(901)7654321"}"#.as_bytes(), &mut |_| {}).unwrap();
+
+        let cases: &[(Query, usize)] = &[
+            (&[("phone_code", "999"), ("limit", "50")], 9),
+            (&[("phone_code", "999"), ("sex_eq", "f"), ("order", "1"), ("limit", "3")], 3),
+            (&[("phone_code", "998"), ("limit", "50")], 0),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(check(&storage, query, Some(Strategy::PhoneCode)).len(), *expected, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn test_status_city() {
+        let statuses = ["свободны", "заняты", "всё сложно"];
+        let storage = make_storage_of(storage::Config::new(), 300, |id| {
+            format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"{}","birth":600000000,"joined":1300000000,"city":"c{}"}}"#,
+                    id, id, if id % 2 == 0 { "m" } else { "f" }, statuses[id % 3], id % 7)
+        });
+        for status in &statuses {
+            for city in &["c0", "c3", "c9"] {
+                for limit in &["1", "10", "50"] {
+                    let strategy = if *city != "c9" { Some(Strategy::CityStatus) } else { None };
+                    check(&storage, &[("status_eq", status), ("city_eq", city), ("limit", limit)], strategy);
+                }
+            }
+        }
+        assert_eq!(check(&storage, &[("sex_eq", "f"), ("status_eq", "свободны"), ("city_eq", "c3"), ("limit", "3")], None), vec![297, 255, 213]);
+    }
+
+    #[test]
+    fn test_id_lt_pagination() {
+        let storage = make_storage_of(storage::Config::new(), 7, |id| account(id, r#","city":"Москва""#));
+
+        // full scan
+        let mut pages = Vec::new();
+        let mut id_lt = i32::MAX.to_string();
+        loop {
+            let page = check(&storage, &[("limit", "3"), ("id_lt", &id_lt)], None);
+            if page.is_empty() {
+                break;
+            }
+            id_lt = page.last().unwrap().to_string();
+            pages.push(page);
+        }
+        assert_eq!(pages, vec![vec![7, 6, 5], vec![4, 3, 2], vec![1]]);
+
+        let cases: &[(Query, &[i32])] = &[
+            // city index
+            (&[("limit", "2"), ("city_eq", "Москва"), ("id_lt", "6")], &[5, 4]),
+            (&[("limit", "10"), ("sex_eq", "f"), ("id_lt", "6"), ("id_gt", "1")], &[5, 3]),
+            (&[("limit", "10"), ("id_lt", "0")], &[]),
+            (&[("limit", "10"), ("id_gt", "-5"), ("id_lt", "3")], &[2, 1]),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(check(&storage, query, None), *expected, "{:?}", query);
+        }
+        assert_eq!(filter(&storage, &params(&[("limit", "10"), ("id_lt", "x")])).err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn test_order() {
+        let storage = make_storage_of(storage::Config::new(), 11, |id| {
+            account(id, &format!(r#","city":"{}","interests":["a"{}{}]"#, if id % 3 == 0 { "c1" } else { "c2" },
+                                 if id % 2 == 1 { r#","b""# } else { "" }, if id % 4 == 1 { r#","c""# } else { "" }))
+        });
+        // filter_index хранит только последние id, для order=1 он не подходит
+        let fast_query = [("limit", "2"), ("sex_eq", "m"), ("city_null", "0")];
+        assert!(storage.indexes.filter_index.get_result(&make_matcher(&storage, &params(&fast_query)).unwrap().unwrap()).is_some());
+
+        let cases: &[(Query, Strategy, &[i32])] = &[
+            (&[("limit", "3")], Strategy::FullScan, &[11, 10, 9]),
+            (&[("limit", "3"), ("order", "-1")], Strategy::FullScan, &[11, 10, 9]),
+            // limit отсчитывается от начала в выбранном порядке
+            (&[("limit", "3"), ("order", "1")], Strategy::FullScan, &[1, 2, 3]),
+            (&[("limit", "3"), ("order", "1"), ("id_gt", "4"), ("id_lt", "7")], Strategy::FullScan, &[5, 6]),
+            (&[("limit", "2"), ("order", "1"), ("city_eq", "c1")], Strategy::City, &[3, 6]),
+            (&[("limit", "2"), ("order", "1"), ("city_any", "c1,c9"), ("id_gt", "3")], Strategy::CityAny, &[6, 9]),
+            (&[("limit", "2"), ("order", "1"), ("interests_contains", "a,b,c")], Strategy::InterestsAll, &[1, 5]),
+            (&[("limit", "2"), ("order", "1"), ("interests_contains", "c,b"), ("id_gt", "1")], Strategy::Interests2, &[5, 9]),
+            (&[("limit", "2"), ("order", "-1"), ("interests_contains", "a,b,c"), ("id_lt", "9")], Strategy::InterestsAll, &[5, 1]),
+            (&fast_query, Strategy::FastIndex, &[10, 8]),
+            (&[("limit", "2"), ("sex_eq", "m"), ("city_null", "0"), ("order", "1")], Strategy::FullScan, &[2, 4]),
+        ];
+        for (query, strategy, expected) in cases {
+            assert_eq!(check(&storage, query, Some(*strategy)), *expected, "{:?}", query);
+        }
+
+        assert_eq!(filter(&storage, &params(&[("limit", "2"), ("order", "0")])).err(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(filter(&storage, &params(&[("limit", "2"), ("order", "asc")])).err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn test_email_eq() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@b.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        assert_eq!(check(&storage, &[("email_eq", "a2@b.ru"), ("limit", "10")], None), vec![2]);
+        // известный email - одна запись из known_emails вместо обхода
+        assert_eq!(check(&storage, &[("email_eq", "a2@b.ru"), ("sex_eq", "m"), ("limit", "10")], Some(Strategy::EmailEq)), Vec::<i32>::new());
+        assert_eq!(check(&storage, &[("email_eq", "a3@b.ru"), ("limit", "10")], None), Vec::<i32>::new());
+
+        storage.update_account(2, br#"{"email":"a3@b.ru"}"#, &mut |_| {}).unwrap();
+        assert_eq!(check(&storage, &[("email_eq", "a2@b.ru"), ("limit", "10")], None), Vec::<i32>::new());
+        assert_eq!(check(&storage, &[("email_eq", "a3@b.ru"), ("limit", "10")], None), vec![2]);
+        assert!(storage.new_account(br#"{"id":3,"email":"a3@b.ru","sex":"f","status":"x","birth":600000000,"joined":1300000000}"#, &mut |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_sname_index() {
+        let mut config = storage::Config::new();
+        config.index_sname = true;
+        let mut storage = make_storage_of(config, 40, |id| account(id, &format!(r#","sname":"s{}","city":"c{}""#, id % 7, id % 3)));
+        storage.update_account(7, br#"{"sname":"s1"}"#, &mut |_| {}).unwrap();
+        storage.update_account(8, br#"{"sname":"s1"}"#, &mut |_| {}).unwrap();
+        assert!(!storage.indexes.sname_index.as_ref().unwrap()[&storage.dict.get_existing_key(&"s0".to_string()).unwrap()].contains(&7));
+
+        for sname in &["s0", "s1", "s6", "s9"] {
+            for filters in &[&[][..], &[("sex_eq", "m")], &[("city_eq", "c1")]] {
+                let mut query = vec![("sname_eq", *sname), ("limit", "5")];
+                query.extend_from_slice(filters);
+                check(&storage, &query, None);
+            }
+        }
+        assert_eq!(check(&storage, &[("sname_eq", "s1"), ("limit", "3")], None), vec![36, 29, 22]);
+        assert_eq!(check(&storage, &[("sname_eq", "s1"), ("id_lt", "16"), ("limit", "10")], None), vec![15, 8, 7, 1]);
+    }
+
+    #[test]
+    fn test_sname_prefix_index() {
+        let snames = ["Петров", "Петрова", "Пестов", "Иванов", "Ли", "Я"];
+        let mut config = storage::Config::new();
+        config.index_sname_prefix = true;
+        let mut storage = make_storage_of(config, 60, |id| {
+            if id % 10 == 0 { account(id, "") } else { account(id, &format!(r#","sname":"{}""#, snames[id % snames.len()])) }
+        });
+        storage.update_account(1, "{\"sname\":\"Иванова\"}".as_bytes(), &mut |_| {}).unwrap();
+        storage.update_account(3, "{\"sname\":\"Петрович\"}".as_bytes(), &mut |_| {}).unwrap();
+        assert!(!storage.indexes.sname_prefix_index.as_ref().unwrap()["Пе"].contains(&1));
+
+        for sname_starts in &["Петр", "Пе", "Пет", "Ив", "Иванова", "Ли", "Л", "Я", "Xy"] {
+            // начало из одной буквы индексом не покрывается
+            let strategy = if sname_starts.chars().count() < 2 { Strategy::FullScan } else { Strategy::SnamePrefix };
+            for filters in &[&[][..], &[("sex_eq", "m")]] {
+                let mut query = vec![("sname_starts", *sname_starts), ("limit", "50")];
+                query.extend_from_slice(filters);
+                check(&storage, &query, Some(strategy));
+            }
+        }
+        assert_eq!(check(&storage, &[("sname_starts", "Петров"), ("limit", "50")], None),
+                   vec![55, 54, 49, 48, 43, 42, 37, 36, 31, 25, 24, 19, 18, 13, 12, 7, 6, 3]);
+        assert_eq!(check(&storage, &[("sname_starts", "Иванова"), ("limit", "50")], None), vec![1]);
+    }
+
+    #[test]
+    fn test_fname_starts() {
+        let fnames = ["Алексей", "Александр", "Алина", "Анна", "Иван"];
+        let mut storage = make_storage_of(storage::Config::new(), 40, |id| {
+            if id % 8 == 0 { account(id, "") } else { account(id, &format!(r#","fname":"{}""#, fnames[id % fnames.len()])) }
+        });
+        storage.update_account(5, "{\"fname\":\"Алла\"}".as_bytes(), &mut |_| {}).unwrap();
+        storage.update_account(1, "{\"fname\":\"Иван\"}".as_bytes(), &mut |_| {}).unwrap();
+
+        for fname_starts in &["Ал", "Алекс", "А", "Алла", "Ив", "Я"] {
+            // частое начало выгоднее проверить полным перебором
+            let strategy = if ["Алекс", "Алла", "Ив"].contains(fname_starts) { Some(Strategy::FnameStarts) } else { None };
+            for filters in &[&[][..], &[("sex_eq", "m")]] {
+                let mut query = vec![("fname_starts", *fname_starts), ("limit", "50")];
+                query.extend_from_slice(filters);
+                check(&storage, &query, strategy);
+            }
+        }
+        let fnames = |fname_starts: &str| {
+            let mut fnames: Vec<String> = filter(&storage, &params(&[("fname_starts", fname_starts), ("limit", "50")])).unwrap()
+                .accounts.iter().map(|account| storage.dict.get_value(account.fname).unwrap().to_string()).collect();
+            fnames.sort();
+            fnames.dedup();
+            fnames
+        };
+        // общее начало у нескольких имен
+        assert_eq!(fnames("Ал"), vec!["Александр", "Алексей", "Алина", "Алла"]);
+        assert_eq!(fnames("Алекс"), vec!["Александр", "Алексей"]);
+        assert_eq!(fnames("Я"), Vec::<String>::new());
+        assert_eq!(check(&storage, &[("fname_starts", "Алл"), ("limit", "50")], None), vec![5]);
+        assert_eq!(check(&storage, &[("fname_starts", "Ив"), ("limit", "50")], None), vec![39, 34, 29, 19, 14, 9, 4, 1]);
+    }
+
+    #[test]
+    fn test_projection() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","fname":"f1","sname":"s1","phone":"8(900)1234567","country":"k1","city":"c1","birth":600000000,"joined":1300000000,"interests":["x"],"likes":[{"id":2,"ts":1}],"premium":{"start":1500000000,"finish":1600000000}}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"заняты","birth":600000000,"joined":1300000000}"#,
+        ]);
+        let table: &[(&str, &str, &[&str])] = &[
+            ("sex_eq", "m", &["sex"]),
+            ("email_domain", "a.ru", &[]),
+            ("email_lt", "a2", &[]),
+            ("email_gt", "a0", &[]),
+            ("status_eq", "свободны", &["status"]),
+            ("status_neq", "заняты", &["status"]),
+            ("status_any", "свободны,заняты", &["status"]),
+            ("fname_eq", "f1", &["fname"]),
+            ("fname_any", "f1,f2", &["fname"]),
+            ("fname_starts", "f", &["fname"]),
+            ("fname_null", "0", &["fname"]),
+            ("sname_eq", "s1", &["sname"]),
+            ("sname_starts", "s", &["sname"]),
+            ("sname_null", "0", &["sname"]),
+            ("phone_code", "900", &["phone"]),
+            ("phone_null", "0", &["phone"]),
+            ("country_eq", "k1", &["country"]),
+            ("country_null", "0", &["country"]),
+            ("city_eq", "c1", &["city"]),
+            ("city_any", "c1,c2", &["city"]),
+            ("city_null", "0", &["city"]),
+            ("birth_lt", "600000001", &["birth"]),
+            ("birth_gt", "599999999", &["birth"]),
+            ("birth_lte", "600000000", &["birth"]),
+            ("birth_gte", "600000000", &["birth"]),
+            ("birth_year", "1989", &["birth"]),
+            ("joined_lt", "1300000001", &["joined"]),
+            ("joined_gt", "1299999999", &["joined"]),
+            ("joined_year", "2011", &["joined"]),
+            ("interests_contains", "x", &[]),
+            ("interests_any", "x,y", &[]),
+            ("likes_contains", "2", &[]),
+            ("premium_now", "1", &["premium"]),
+            ("premium_null", "0", &["premium"]),
+        ];
+        for (key, value, fields) in table {
+            let query = params(&[(key, value), ("id_lt", "2"), ("limit", "5")]);
+            let result = filter(&storage, &query).unwrap();
+            let indexed = serde_json::to_value(&result).unwrap();
+            let scanned = serde_json::to_value(filter_full_scan(&storage, &query)).unwrap();
+            assert_eq!(indexed, scanned, "{}", key);
+            // make_result проецирует так же, как сериализация FilterResult
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&to_string_via_account_json(&result)).unwrap(), indexed, "{}", key);
+            let account = indexed["accounts"].as_array().unwrap().iter().next().expect(key);
+            let mut expected: Vec<&str> = vec!["email", "id"];
+            expected.extend(fields.iter());
+            expected.sort();
+            let mut actual: Vec<&str> = account.as_object().unwrap().keys().map(|field| field.as_str()).collect();
+            actual.sort();
+            assert_eq!(actual, expected, "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_birth_bounds_status_any() {
+        // 599616000 - начало 1989 года
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":599615999,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"заняты","birth":599616000,"joined":1300000000}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"m","status":"всё сложно","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":4,"email":"a4@a.ru","sex":"f","status":"свободны","birth":600000001,"joined":1300000000}"#,
+        ]);
+        let cases: &[(Query, &[i32])] = &[
+            (&[("birth_gte", "600000000")], &[4, 3]),
+            (&[("birth_gt", "600000000")], &[4]),
+            (&[("birth_lte", "600000000")], &[3, 2, 1]),
+            (&[("birth_lt", "600000000")], &[2, 1]),
+            (&[("birth_gte", "600000000"), ("birth_lte", "600000000")], &[3]),
+            // строгая и включительная границы вместе - действует более узкая
+            (&[("birth_gte", "600000000"), ("birth_gt", "600000000")], &[4]),
+            (&[("birth_lte", "600000000"), ("birth_lt", "600000001")], &[3, 2, 1]),
+            (&[("birth_gte", "600000001"), ("birth_lte", "600000000")], &[]),
+            (&[("birth_year", "1989"), ("birth_lte", "600000000")], &[3, 2]),
+            (&[("birth_year", "1989"), ("birth_gte", "599616000")], &[4, 3, 2]),
+            (&[("status_any", "свободны,заняты")], &[4, 2, 1]),
+            (&[("status_any", "всё сложно")], &[3]),
+            // неизвестные значения не совпадают ни с чем
+            (&[("status_any", "x,всё сложно")], &[3]),
+            (&[("status_any", "x,y")], &[]),
+            (&[("status_any", "свободны,всё сложно"), ("sex_eq", "m")], &[3, 1]),
+            (&[("status_any", "свободны,заняты"), ("status_neq", "заняты")], &[4, 1]),
+        ];
+        for (query, expected) in cases {
+            let mut query = query.to_vec();
+            query.push(("limit", "10"));
+            assert_eq!(check(&storage, &query, None), *expected, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn test_count_only() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"city":"c1"}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"city":"c1"}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"m","status":"заняты","birth":600000000,"joined":1300000000,"city":"c2"}"#,
+            r#"{"id":4,"email":"a4@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        let count = |query: &[(&str, &str)]| filter(&storage, &params(query)).map(|result| serde_json::to_string(&result).unwrap());
+        assert_eq!(count(&[("sex_eq", "m"), ("count", "1"), ("limit", "10")]), Ok(r#"{"count":3}"#.to_string()));
+        // limit ограничивает и число
+        assert_eq!(count(&[("sex_eq", "m"), ("count", "1"), ("limit", "2")]), Ok(r#"{"count":2}"#.to_string()));
+        assert_eq!(count(&[("city_eq", "c1"), ("count", "1"), ("limit", "10")]), Ok(r#"{"count":2}"#.to_string()));
+        // заведомо пустой результат
+        assert_eq!(count(&[("city_eq", "c9"), ("count", "1"), ("limit", "10")]), Ok(r#"{"count":0}"#.to_string()));
+        assert_eq!(count(&[("count", "0"), ("limit", "10")]), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(count(&[("count", "1")]), Err(StatusCode::BAD_REQUEST));
+        for query in &[vec![("sex_eq", "m")], vec![("city_any", "c1,c2")], vec![("status_eq", "свободны"), ("city_null", "0")]] {
+            let mut query = query.clone();
+            query.push(("limit", "10"));
+            let expected = filter(&storage, &params(&query)).unwrap().accounts.len();
+            query.push(("count", "1"));
+            assert_eq!(count(&query), Ok(format!(r#"{{"count":{}}}"#, expected)), "{:?}", query);
+            assert_eq!(count(&query).unwrap(), serde_json::to_string(&filter_full_scan(&storage, &params(&query))).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_strict_conflicts() {
+        let mut storage = make_storage(&[r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#]);
+        let conflicting = [
+            vec![("status_eq", "свободны"), ("status_neq", "заняты")],
+            vec![("birth_year", "1989"), ("birth_lt", "600000001")],
+            vec![("birth_year", "1989"), ("birth_gte", "600000000")],
+            vec![("birth_gt", "599999999"), ("birth_gte", "600000000")],
+            vec![("joined_year", "2011"), ("joined_gt", "1300000000")],
+            vec![("city_eq", "c1"), ("city_null", "1")],
+        ];
+        // по умолчанию такие запросы допустимы
+        for query in &conflicting {
+            let mut query = query.clone();
+            query.push(("limit", "5"));
+            assert!(filter(&storage, &params(&query)).is_ok(), "{:?}", query);
+        }
+        storage.config.strict_unknown = true;
+        for query in &conflicting {
+            let mut query = query.clone();
+            query.push(("limit", "5"));
+            assert_eq!(filter(&storage, &params(&query)).err(), Some(StatusCode::BAD_REQUEST), "{:?}", query);
+        }
+        let result = filter(&storage, &params(&[("sex_eq", "m"), ("birth_year", "1989"), ("limit", "5")])).unwrap();
+        assert_eq!(result.accounts.len(), 1);
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        let mut storage = make_storage(&[r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"city":"c1"}"#]);
+        // по форме некорректны - 400 в любом режиме
+        let malformed = [
+            vec![("birth_lt", "abc")],
+            vec![("joined_year", "2011.5")],
+            vec![("id_lt", "")],
+            vec![("likes_contains", "1,x")],
+            vec![("unknown_key", "1")],
+            vec![("premium_now", "0")],
+            vec![("fname_null", "2")],
+            vec![("sex_eq", "x"), ("phone_code", "abc")],
+            // повторный параметр, limit добавляет run
+            vec![("limit", "3")],
+            vec![("interests_contains", "a"), ("interests_contains", "b")],
+            vec![("sex_eq", "m"), ("sex_eq", "m")],
+        ];
+        // значения вне закрытого набора - 422 в --strict-unknown, иначе обычный ответ (для *_eq пустой)
+        let unprocessable = [
+            vec![("sex_eq", "x")],
+            vec![("sex_eq", "свободны")],
+            vec![("status_eq", "x")],
+            vec![("status_neq", "m")],
+            vec![("status_any", "свободны,m")],
+        ];
+        // допустимые значения, которых нет в данных - всегда пустой ответ
+        let missing = [
+            vec![("city_eq", "c2")],
+            vec![("fname_eq", "f1")],
+            vec![("sex_eq", "f")],
+            vec![("status_eq", "заняты")],
+        ];
+        for strict_unknown in &[false, true] {
+            storage.config.strict_unknown = *strict_unknown;
+            let run = |query: &Vec<(&str, &str)>| {
+                let mut query = query.clone();
+                query.push(("limit", "5"));
+                filter(&storage, &params(&query)).map(|result| result.accounts.len())
+            };
+            for query in &malformed {
+                assert_eq!(run(query), Err(StatusCode::BAD_REQUEST), "{:?}", query);
+            }
+            for query in &unprocessable {
+                if *strict_unknown {
+                    assert_eq!(run(query), Err(StatusCode::UNPROCESSABLE), "{:?}", query);
+                } else {
+                    assert_eq!(run(query), Ok(if query[0].0 == "status_neq" || query[0].0 == "status_any" { 1 } else { 0 }), "{:?}", query);
+                }
+            }
+            for query in &missing {
+                assert_eq!(run(query), Ok(0), "{:?}", query);
+            }
+            assert_eq!(run(&vec![("sex_eq", "m"), ("status_neq", "заняты")]), Ok(1));
+            assert_eq!(run(&vec![("query_id", "1"), ("sex_eq", "m"), ("query_id", "2")]), Ok(1));
+            assert_eq!(filter(&storage, &params(&[("sex_eq", "x"), ("limit", "0")])).err(), Some(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    #[test]
+    fn test_strategy_choice() {
+        let storage = make_storage_of(storage::Config::new(), 1200, |id| {
+            // 600 аккаунтов без города - больше, чем хранит filter_index для city_null=1
+            let city = if id <= 600 { "" } else if id % 100 == 0 { r#","city":"small""# } else { r#","city":"big""# };
+            account(id, &format!(r#","country":"{}","interests":["x"]{}"#, if id % 10 == 0 { "k2" } else { "k1" }, city))
+        });
+        let cases: &[(Query, Strategy, usize)] = &[
+            // filter_index: 300 мужчин с городом, список полный
+            (&[("sex_eq", "m"), ("city_null", "0"), ("limit", "50")], Strategy::FastIndex, 50),
+            // список city_null=1 обрезан до 500: для limit 10 его хватает, для 550 - нет, и результат берется из full scan
+            (&[("city_null", "1"), ("limit", "10")], Strategy::FastIndex, 10),
+            (&[("city_null", "1"), ("limit", "550")], Strategy::FastIndex, 550),
+            // из нескольких индексов выбирается самый короткий список, а не первый по порядку
+            (&[("city_eq", "small"), ("limit", "50")], Strategy::City, 6),
+            (&[("interests_contains", "x"), ("country_eq", "k2"), ("limit", "50")], Strategy::Country, 50),
+            // список почти из всех аккаунтов или узкий диапазон id дешевле проверить подряд
+            (&[("country_eq", "k1"), ("limit", "50")], Strategy::FullScan, 50),
+            (&[("city_eq", "big"), ("id_lt", "650"), ("limit", "50")], Strategy::FullScan, 49),
+        ];
+        for (query, strategy, expected) in cases {
+            assert_eq!(check(&storage, query, Some(*strategy)).len(), *expected, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn test_email_domain_index() {
+        let mut storage = make_storage_of(storage::Config::new(), 1000, |id| {
+            format!(r#"{{"id":{},"email":"a{}@{}","sex":"{}","status":"свободны","birth":600000000,"joined":1300000000}}"#,
+                    id, id, if id % 100 == 0 { "rare.ru" } else { "a.ru" }, if id % 2 == 0 { "m" } else { "f" })
+        });
+        storage.update_account(500, br#"{"email":"b500@a.ru"}"#, &mut |_| {}).unwrap();
+        storage.update_account(7, br#"{"email":"b7@rare.ru"}"#, &mut |_| {}).unwrap();
+
+        // 10 кандидатов из индекса вместо 1000 аккаунтов
+        let cases: &[(Query, &[i32])] = &[
+            (&[("email_domain", "rare.ru"), ("limit", "4")], &[1000, 900, 800, 700]),
+            (&[("email_domain", "rare.ru"), ("sex_eq", "f"), ("limit", "5")], &[7]),
+            (&[("email_domain", "rare.ru"), ("order", "1"), ("limit", "6")], &[7, 100, 200, 300, 400, 600]),
+            (&[("email_domain", "none.ru"), ("limit", "5")], &[]),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(check(&storage, query, Some(Strategy::EmailDomain)), *expected, "{:?}", query);
+        }
+        assert_eq!(storage.indexes.email_domain_index[&"rare.ru".to_string()].len(), 10);
+    }
+
+    #[test]
+    fn test_premium_now_index() {
+        let now = 1545834028;
+        let mut storage = make_storage_of(storage::Config::new(), 1000, |id| {
+            // премиум сейчас у каждого 50-го, у каждого 25-го нечетного он уже закончился
+            let premium = match id % 50 {
+                0 => format!(r#","premium":{{"start":{},"finish":{}}}"#, now - 1000, now + 1000),
+                25 => format!(r#","premium":{{"start":{},"finish":{}}}"#, now - 2000, now - 1000),
+                _ => String::new(),
+            };
+            format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"свободны","birth":600000000,"joined":1300000000{}}}"#,
+                    id, id, if id % 4 == 0 { "m" } else { "f" }, premium)
+        });
+        // премиум появился и закончился через update
+        storage.update_account(7, format!(r#"{{"premium":{{"start":{},"finish":{}}}}}"#, now - 10, now + 10).as_bytes(), &mut |_| {}).unwrap();
+        storage.update_account(100, format!(r#"{{"premium":{{"start":{},"finish":{}}}}}"#, now - 10, now - 5).as_bytes(), &mut |_| {}).unwrap();
+
+        let cases: &[(Query, &[i32])] = &[
+            (&[("premium_now", "1"), ("limit", "3")], &[1000, 950, 900]),
+            (&[("premium_now", "1"), ("order", "1"), ("limit", "3")], &[7, 50, 150]),
+            (&[("premium_now", "1"), ("sex_eq", "f"), ("limit", "5")], &[950, 850, 750, 650, 550]),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(check(&storage, query, Some(Strategy::PremiumNow)), *expected, "{:?}", query);
+        }
+        assert_eq!(storage.indexes.premium_now_ids.len(), 20);
+    }
+
+    #[test]
+    fn test_interests2_fallback() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a","b"]}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a","b","c"]}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a","c"]}"#,
+        ]);
+        let key = |storage: &Storage, interest: &str| storage.interest_dict.get_existing_key(&interest.to_string()).unwrap();
+        let (a, b) = (key(&storage, "a"), key(&storage, "b"));
+        // пара удалена из индекса, как при отсечении редких пар
+        storage.indexes.interests2_index.remove(&(a.min(b), a.max(b)));
+        let ids = |storage: &Storage, interests: &str| filter(storage, &params(&[("interests_contains", interests), ("limit", "10")])).unwrap()
+            .accounts.iter().map(|account| account.id).collect::<Vec<i32>>();
+
+        assert_eq!(ids(&storage, "a,b"), Vec::<i32>::new());
+        storage.config.interests2_fallback = true;
+        assert_eq!(ids(&storage, "a,b"), vec![2, 1]);
+        assert_eq!(ids(&storage, "b,a"), vec![2, 1]);
+        // пара есть в индексе - fallback не нужен
+        assert_eq!(ids(&storage, "a,c"), vec![3, 2]);
+        assert_eq!(ids(&storage, "b,d"), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_interests_contains_many() {
+        let storage = make_storage_of(storage::Config::new(), 999, |id| {
+            // "rare" у каждого пятого, "a" у всех, "b" у всех, кроме кратных 7
+            let mut interests = vec!["\"a\"", "\"b\""];
+            if id % 5 == 0 {
+                interests.push("\"rare\"");
+            }
+            if id % 7 == 0 {
+                interests.retain(|interest| *interest != "\"b\"");
+            }
+            account(id, &format!(r#","interests":[{}]"#, interests.join(",")))
+        });
+        let cases: &[(Query, Strategy, &[i32])] = &[
+            // пересечение начинается с самого короткого списка, порядок интересов в запросе не важен
+            (&[("interests_contains", "a,b,rare"), ("limit", "3")], Strategy::InterestsAll, &[995, 990, 985]),
+            (&[("interests_contains", "rare,b,a"), ("limit", "3")], Strategy::InterestsAll, &[995, 990, 985]),
+            // по одному полу список редкого интереса короче пересечения по всем аккаунтам
+            (&[("interests_contains", "a,b,rare"), ("sex_eq", "m"), ("limit", "3")], Strategy::Interest, &[990, 970, 960]),
+        ];
+        for (query, strategy, expected) in cases {
+            assert_eq!(check(&storage, query, Some(*strategy)), *expected, "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn test_country_of_city() {
+        let account = |id: i32, location: &str| format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000{}}}"#, id, id, location);
+        let accounts = [
+            account(1, r#","country":"Россия","city":"Москва""#), account(2, r#","country":"Россия","city":"Москва""#),
+            account(3, r#","country":"Беларусь","city":"Минск""#), account(4, r#","city":"Брест""#), account(5, r#","country":"Беларусь""#),
+        ];
+        let accounts: Vec<&str> = accounts.iter().map(|account| account.as_str()).collect();
+        let mut storage = make_storage(&accounts);
+        let ids = |storage: &Storage, country: &str, city: &str| -> Vec<i32> {
+            let result = filter(storage, &params(&[("country_eq", country), ("city_eq", city), ("limit", "10")])).unwrap();
+            result.accounts.iter().map(|account| account.id).collect()
+        };
+        let matcher = |storage: &Storage, country: &str, city: &str| make_matcher(storage, &params(&[("country_eq", country), ("city_eq", city), ("limit", "10")])).unwrap();
+
+        assert!(matcher(&storage, "Россия", "Москва").unwrap().country_implied);
+        assert_eq!(ids(&storage, "Россия", "Москва"), vec![2, 1]);
+        // несовместимые страна и город - пустой ответ без обхода
+        assert!(matcher(&storage, "Беларусь", "Москва").is_none());
+        assert!(matcher(&storage, "Беларусь", "Брест").is_none());
+        assert_eq!(ids(&storage, "Беларусь", "Брест"), Vec::<i32>::new());
+
+        // город с разными странами проверяется по аккаунтам
+        storage.new_account(account(6, r#","country":"Беларусь","city":"Брест""#).as_bytes(), &mut |_| {}).unwrap();
+        storage.update_account(1, r#"{"country":"Беларусь"}"#.as_bytes(), &mut |_| {}).unwrap();
+        assert!(!matcher(&storage, "Россия", "Москва").unwrap().country_implied);
+        assert_eq!(ids(&storage, "Беларусь", "Брест"), vec![6]);
+        assert_eq!(ids(&storage, "Россия", "Москва"), vec![2]);
+        assert_eq!(ids(&storage, "Беларусь", "Москва"), vec![1]);
+        assert_eq!(ids(&storage, "Беларусь", "Минск"), vec![3]);
+    }
+
+    #[test]
+    fn test_likes_contains_rarest_first() {
+        // 1 лайкают все, 2 - каждый третий, 3 - никто
+        let accounts: Vec<String> = (4..31).map(|id| {
+            let likes = if id % 3 == 0 { r#"[{"id":1,"ts":1},{"id":2,"ts":1}]"# } else { r#"[{"id":1,"ts":1}]"# };
+            format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"свободны","birth":600000000,"joined":1300000000,"likes":{}}}"#,
+                    id, id, if id % 2 == 0 { "m" } else { "f" }, likes)
+        }).collect();
+        let accounts: Vec<&str> = accounts.iter().map(|account| account.as_str()).collect();
+        let storage = make_storage(&accounts);
+
+        let matcher = make_matcher(&storage, &params(&[("likes_contains", "1,2,1"), ("limit", "20")])).unwrap().unwrap();
+        assert_eq!(matcher.likes_contains, vec![2, 1]);
+        assert_eq!(plan(&storage, &matcher)[0], Strategy::LikesContains);
+        let ids = |strategy: Strategy, matcher: &Matcher| execute(strategy, &storage, matcher).unwrap().iter().map(|account| account.id).collect::<Vec<i32>>();
+        assert_eq!(ids(Strategy::LikesContains, &matcher), vec![30, 27, 24, 21, 18, 15, 12, 9, 6]);
+        assert_eq!(ids(Strategy::LikesContains, &matcher), ids(Strategy::FullScan, &matcher));
+
+        let matcher = make_matcher(&storage, &params(&[("likes_contains", "1,2,3"), ("limit", "20")])).unwrap().unwrap();
+        assert_eq!(matcher.likes_contains, vec![3, 2, 1]);
+        assert!(ids(Strategy::LikesContains, &matcher).is_empty());
+    }
+
+    /// Случайные запросы: результат выбранного filter пути (fast index, index) совпадает с full scan байт в байт.
+    #[test]
+    fn test_index_paths_match_full_scan() {
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::SmallRng;
+
+        let mut rng = SmallRng::seed_from_u64(2203);
+        let statuses = ["свободны", "заняты", "всё сложно"];
+        let interests = ["a", "b", "c", "d", "e", "f"];
+        let count = 300;
+        let mut storage = Storage::new(1545834028, storage::Config::new(), count + 1);
+        for id in 1..count + 1 {
+            let mut account = format!(r#"{{"id":{},"email":"a{}@d{}.ru","sex":"{}","status":"{}","birth":{},"joined":{}"#,
+                                      id, id, rng.gen_range(0, 3), if rng.gen() { "m" } else { "f" }, statuses[rng.gen_range(0, 3)],
+                                      rng.gen_range(-300_000_000, 1_100_000_000), rng.gen_range(1_293_840_000, 1_514_764_800));
+            if rng.gen_range(0, 4) != 0 {
+                account += &format!(r#","city":"c{}""#, rng.gen_range(0, 5));
+            }
+            if rng.gen_range(0, 4) != 0 {
+                account += &format!(r#","country":"k{}""#, rng.gen_range(0, 3));
+            }
+            if rng.gen_range(0, 3) != 0 {
+                account += &format!(r#","fname":"f{}""#, rng.gen_range(0, 4));
+            }
+            if rng.gen_range(0, 3) != 0 {
+                account += &format!(r#","sname":"s{}""#, rng.gen_range(0, 6));
+            }
+            if rng.gen() {
+                account += &format!(r#","phone":"8({}){:07}""#, 900 + rng.gen_range(0, 3), id);
+            }
+            if rng.gen_range(0, 3) == 0 {
+                let start = 1545834028 - rng.gen_range(0, 20_000_000);
+                account += &format!(r#","premium":{{"start":{},"finish":{}}}"#, start, start + rng.gen_range(0, 40_000_000));
+            }
+            let account_interests: Vec<String> = interests.iter().filter(|_| rng.gen_range(0, 3) == 0).map(|interest| format!(r#""{}""#, interest)).collect();
+            account += &format!(r#","interests":[{}]"#, account_interests.join(","));
+            let mut likes: Vec<usize> = (0..rng.gen_range(0, 4)).map(|_| rng.gen_range(1, count + 1)).collect();
+            likes.sort();
+            likes.dedup();
+            let likes: Vec<String> = likes.iter().map(|likee| format!(r#"{{"id":{},"ts":1}}"#, likee)).collect();
+            account += &format!(r#","likes":[{}]}}"#, likes.join(","));
+            storage.new_account(account.as_bytes(), &mut |_| {}).unwrap();
+        }
+
+        type Condition<'a> = Box<dyn Fn(&mut SmallRng) -> (&'static str, String) + 'a>;
+        let conditions: Vec<Condition> = vec![
+            Box::new(|rng| ("sex_eq", if rng.gen() { "m" } else { "f" }.to_string())),
+            Box::new(|rng| ("status_eq", statuses[rng.gen_range(0, 3)].to_string())),
+            Box::new(|rng| ("status_neq", statuses[rng.gen_range(0, 3)].to_string())),
+            Box::new(|rng| ("status_any", format!("{},{}", statuses[rng.gen_range(0, 3)], statuses[rng.gen_range(0, 3)]))),
+            Box::new(|rng| ("email_domain", format!("d{}.ru", rng.gen_range(0, 3)))),
+            Box::new(|rng| ("email_lt", format!("a{}", rng.gen_range(1, 300)))),
+            Box::new(|rng| ("email_eq", format!("a{}@d{}.ru", rng.gen_range(1, 301), rng.gen_range(0, 3)))),
+            Box::new(|rng| ("fname_eq", format!("f{}", rng.gen_range(0, 5)))),
+            Box::new(|rng| ("fname_any", format!("f{},f{}", rng.gen_range(0, 5), rng.gen_range(0, 5)))),
+            Box::new(|rng| ("fname_null", rng.gen_range(0, 2).to_string())),
+            Box::new(|rng| ("fname_starts", ["f", "f1", "f4", "g"][rng.gen_range(0, 4)].to_string())),
+            Box::new(|rng| ("sname_eq", format!("s{}", rng.gen_range(0, 6)))),
+            Box::new(|rng| ("sname_starts", "s".to_string() + &rng.gen_range(0, 6).to_string())),
+            Box::new(|rng| ("phone_code", (900 + rng.gen_range(0, 3)).to_string())),
+            Box::new(|rng| ("country_eq", format!("k{}", rng.gen_range(0, 3)))),
+            Box::new(|rng| ("country_null", rng.gen_range(0, 2).to_string())),
+            Box::new(|rng| ("city_eq", format!("c{}", rng.gen_range(0, 5)))),
+            Box::new(|rng| ("city_any", format!("c{},c{},c9", rng.gen_range(0, 5), rng.gen_range(0, 5)))),
+            Box::new(|rng| ("city_null", rng.gen_range(0, 2).to_string())),
+            Box::new(|rng| ("birth_year", rng.gen_range(1960, 2005).to_string())),
+            Box::new(|rng| ("birth_lt", rng.gen_range(0, 1_000_000_000).to_string())),
+            Box::new(|rng| ("birth_gte", rng.gen_range(0, 1_000_000_000).to_string())),
+            Box::new(|rng| ("joined_year", rng.gen_range(2010, 2019).to_string())),
+            Box::new(|rng| ("joined_gt", rng.gen_range(1_293_840_000, 1_514_764_800).to_string())),
+            Box::new(|rng| ("interests_contains", format!("{},{}", interests[rng.gen_range(0, 6)], interests[rng.gen_range(0, 6)]))),
+            Box::new(|rng| ("interests_contains", interests[rng.gen_range(0, 6)].to_string())),
+            Box::new(|rng| ("interests_contains", format!("{},{},{}", interests[rng.gen_range(0, 6)], interests[rng.gen_range(0, 6)], interests[rng.gen_range(0, 6)]))),
+            Box::new(|rng| ("interests_any", format!("{},{}", interests[rng.gen_range(0, 6)], interests[rng.gen_range(0, 6)]))),
+            Box::new(|rng| ("likes_contains", format!("{},{}", rng.gen_range(1, 301), rng.gen_range(1, 301)))),
+            Box::new(|rng| ("likes_contains", rng.gen_range(1, 301).to_string())),
+            Box::new(|_| ("premium_now", "1".to_string())),
+            Box::new(|rng| ("order", if rng.gen() { "1" } else { "-1" }.to_string())),
+            Box::new(|rng| ("premium_null", rng.gen_range(0, 2).to_string())),
+        ];
+
+        for _ in 0..5000 {
+            let mut query: Vec<(String, String)> = Vec::new();
+            for _ in 0..rng.gen_range(1, 4) {
+                let (key, value) = conditions[rng.gen_range(0, conditions.len())](&mut rng);
+                if query.iter().all(|(k, _)| k != key) {
+                    query.push((key.to_string(), value));
+                }
+            }
+            query.push(("limit".to_string(), rng.gen_range(1, 30).to_string()));
+
+            let result = filter(&storage, &query).unwrap();
+            let scanned = filter_full_scan(&storage, &query);
+            assert_eq!(serde_json::to_string(&result).unwrap(), serde_json::to_string(&scanned).unwrap(), "{:?}", query);
+        }
+    }
+
+    #[test]
+    fn test_serialize_like_account_json() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"fname":"Иван","sname":"Иванов","phone":"8(900)0012345","country":"Россия","city":"Москва","interests":["b","a"],"premium":{"start":1545000000,"finish":1546000000}}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"заняты","birth":700000000,"joined":1300000000,"interests":["a"]}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"f","status":"всё сложно","birth":800000000,"joined":1300000000,"phone":"8(901)1234567"}"#,
+        ]);
+        let queries: &[&[(&str, &str)]] = &[
+            &[("limit", "10")],
+            &[("sex_eq", "f"), ("status_neq", "свободны"), ("limit", "10")],
+            &[("sname_null", "0"), ("fname_null", "0"), ("phone_null", "0"), ("limit", "10")],
+            &[("phone_null", "0"), ("limit", "10")],
+            &[("country_null", "1"), ("city_null", "1"), ("birth_lt", "900000000"), ("limit", "10")],
+            &[("interests_any", "a,b"), ("premium_null", "0"), ("_debug_fields", "interests"), ("limit", "10")],
+            &[("premium_null", "1"), ("limit", "10")],
+            &[("sex_eq", "x"), ("limit", "10")],
+        ];
+        for query in queries {
+            let result = filter(&storage, &params(query)).unwrap();
+            let json = serde_json::to_string(&result).unwrap();
+            if result.matcher.is_some() {
+                assert_eq!(json, to_string_via_account_json(&result), "{:?}", query);
+            } else {
+                assert_eq!(json, r#"{"accounts":[]}"#);
+            }
+        }
+        let result = filter(&storage, &params(&[("phone_null", "0"), ("limit", "10")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(),
+                   r#"{"accounts":[{"id":3,"email":"a3@a.ru","phone":"8(901)1234567"},{"id":1,"email":"a1@a.ru","phone":"8(900)0012345"}]}"#);
+    }
+
+    #[test]
+    fn test_emit_empty_arrays() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a"]}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a"],"likes":[{"id":1,"ts":1400000000}]}"#,
+        ]);
+        let query = params(&[("sex_eq", "f"), ("limit", "10")]);
+        // у id 3 интересы и лайки есть, но не выводятся: [] для них быть не должно
+        let expected = r#"{"accounts":[{"id":3,"email":"a3@a.ru","sex":"f"},{"id":2,"email":"a2@a.ru","sex":"f"}]}"#;
+        let expected_emit = r#"{"accounts":[{"id":3,"email":"a3@a.ru","sex":"f"},{"id":2,"email":"a2@a.ru","sex":"f","interests":[],"likes":[]}]}"#;
+        let debug_query = params(&[("interests_any", "a"), ("_debug_fields", "interests"), ("limit", "10")]);
+
+        let result = filter(&storage, &query).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), expected);
+        assert_eq!(to_string_via_account_json(&result), expected);
+
+        storage.config.emit_empty_arrays = true;
+        let result = filter(&storage, &query).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), expected_emit);
+        assert_eq!(to_string_via_account_json(&result), serde_json::to_string(&result).unwrap());
+        let result = filter(&storage, &debug_query).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"accounts":[{"id":3,"email":"a3@a.ru","interests":["a"]},{"id":1,"email":"a1@a.ru","interests":["a"],"likes":[]}]}"#);
+        assert_eq!(to_string_via_account_json(&result), serde_json::to_string(&result).unwrap());
+    }
+}
@@ -5,16 +5,17 @@ use std::sync::Arc;
 
 use enum_map::EnumMap;
 
+use crate::bits::InterestSet;
 use crate::filter::Matcher;
 use crate::storage::Account;
 use crate::storage::Consts;
-use crate::utils::EMPTY_INT_LIST;
 use crate::utils::insert_into_sorted_vec;
 use crate::utils::Key1;
 use crate::utils::Key2;
 use crate::utils::Key3;
 use crate::utils::KeySet;
 use crate::utils::merge_sorted;
+use crate::utils::remove_from_sorted_vec;
 
 const KEEP_TOP: usize = 500; // храним не все номера учеток, а только хвост
 const KEEP_TOP_EMAIL: usize = 5000; // эдесь хвост нужен больше, так как идут запросы lt/gt с двумя буквами
@@ -44,6 +45,24 @@ enum FilterType {
 
 impl Copy for FilterType {}
 
+/// Хвост списка id: наибольшие id и признак того, что начало списка отбрасывалось.
+/// По длине это не определить: после удаления id из обрезанного списка он становится короче лимита.
+#[derive(Default)]
+struct Tail {
+    ids: Vec<i32>,
+    trimmed: bool,
+}
+
+lazy_static! {
+    static ref EMPTY_TAIL: Tail = Tail::default();
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Insert,
+    Remove,
+}
+
 lazy_static! {
     static ref keys_to_filter_type: HashMap<KeySet, FilterType> = {
         let mut map: HashMap<KeySet, FilterType> = HashMap::new();
@@ -72,9 +91,9 @@ lazy_static! {
 
 pub struct FilterIndex {
     // filterType -> filterKey -> list
-    map1: EnumMap<FilterType, HashMap<Key1, Vec<i32>>>,
-    map2: EnumMap<FilterType, HashMap<Key2, Vec<i32>>>,
-    map3: EnumMap<FilterType, HashMap<Key3, Vec<i32>>>,
+    map1: EnumMap<FilterType, HashMap<Key1, Tail>>,
+    map2: EnumMap<FilterType, HashMap<Key2, Tail>>,
+    map3: EnumMap<FilterType, HashMap<Key3, Tail>>,
 }
 
 impl FilterIndex {
@@ -87,32 +106,43 @@ impl FilterIndex {
     }
 
     pub fn update_account(&mut self, account: &Account, consts: &Consts) {
-        update_filter(&mut self.map2, FilterType::SexCountryNull, Key2::new(account.sex, if account.country == 0 { 1 } else { 0 }), account);
-        update_filter(&mut self.map1, FilterType::CountryNull, Key1::new(if account.country == 0 { 1 } else { 0 }), account);
-        update_filter(&mut self.map2, FilterType::SexCityNull, Key2::new(account.sex, if account.city == 0 { 1 } else { 0 }), account);
-        update_filter(&mut self.map1, FilterType::CityNull, Key1::new(if account.city == 0 { 1 } else { 0 }), account);
+        self.apply(account, Op::Insert);
+    }
+
+    /// Убирает id аккаунта из списков по текущим значениям полей; вызывается до их изменения в update.
+    pub fn remove_account(&mut self, account: &Account) {
+        self.apply(account, Op::Remove);
+    }
+
+    fn apply(&mut self, account: &Account, op: Op) {
+        update_filter(&mut self.map2, FilterType::SexCountryNull, Key2::new(account.sex, if account.country == 0 { 1 } else { 0 }), account, op);
+        update_filter(&mut self.map1, FilterType::CountryNull, Key1::new(if account.country == 0 { 1 } else { 0 }), account, op);
+        update_filter(&mut self.map2, FilterType::SexCityNull, Key2::new(account.sex, if account.city == 0 { 1 } else { 0 }), account, op);
+        update_filter(&mut self.map1, FilterType::CityNull, Key1::new(if account.city == 0 { 1 } else { 0 }), account, op);
         for ch in first_letter2(&account.email)..'z' as i32 {
-            update_filter2(&mut self.map1, FilterType::EmailLt, Key1::new(ch), account, KEEP_TOP_EMAIL);
-            update_filter2(&mut self.map2, FilterType::EmailLtSex, Key2::new(ch, account.sex), account, KEEP_TOP_EMAIL);
-            update_filter2(&mut self.map2, FilterType::EmailLtCityNull, Key2::new(ch, if account.city == 0 { 1 } else { 0 }), account, KEEP_TOP_EMAIL);
-            update_filter2(&mut self.map3, FilterType::EmailLtCountryNullSex, Key3::new(ch, if account.country == 0 { 1 } else { 0 }, account.sex), account, KEEP_TOP_EMAIL);
+            update_filter2(&mut self.map1, FilterType::EmailLt, Key1::new(ch), account, KEEP_TOP_EMAIL, op);
+            update_filter2(&mut self.map2, FilterType::EmailLtSex, Key2::new(ch, account.sex), account, KEEP_TOP_EMAIL, op);
+            update_filter2(&mut self.map2, FilterType::EmailLtCityNull, Key2::new(ch, if account.city == 0 { 1 } else { 0 }), account, KEEP_TOP_EMAIL, op);
+            update_filter2(&mut self.map3, FilterType::EmailLtCountryNullSex, Key3::new(ch, if account.country == 0 { 1 } else { 0 }, account.sex), account, KEEP_TOP_EMAIL, op);
         }
         for ch in 'a' as i32..first_letter2(&account.email) + 1 {
-            update_filter2(&mut self.map1, FilterType::EmailGt, Key1::new(ch), account, KEEP_TOP_EMAIL);
-            update_filter2(&mut self.map2, FilterType::EmailGtSex, Key2::new(ch, account.sex), account, KEEP_TOP_EMAIL);
-            update_filter2(&mut self.map2, FilterType::EmailGtCityNull, Key2::new(ch, if account.city == 0 { 1 } else { 0 }), account, KEEP_TOP_EMAIL);
-            update_filter2(&mut self.map3, FilterType::EmailGtCountryNullSex, Key3::new(ch, if account.country == 0 { 1 } else { 0 }, account.sex), account, KEEP_TOP_EMAIL);
+            update_filter2(&mut self.map1, FilterType::EmailGt, Key1::new(ch), account, KEEP_TOP_EMAIL, op);
+            update_filter2(&mut self.map2, FilterType::EmailGtSex, Key2::new(ch, account.sex), account, KEEP_TOP_EMAIL, op);
+            update_filter2(&mut self.map2, FilterType::EmailGtCityNull, Key2::new(ch, if account.city == 0 { 1 } else { 0 }), account, KEEP_TOP_EMAIL, op);
+            update_filter2(&mut self.map3, FilterType::EmailGtCountryNullSex, Key3::new(ch, if account.country == 0 { 1 } else { 0 }, account.sex), account, KEEP_TOP_EMAIL, op);
         }
-        update_filter(&mut self.map2, FilterType::CountryNullPhoneCode, Key2::new(if account.country == 0 { 1 } else { 0 }, account.phone_code), account);
-        update_filter(&mut self.map2, FilterType::CityNullPhoneCode, Key2::new(if account.city == 0 { 1 } else { 0 }, account.phone_code), account);
-        update_filter(&mut self.map3, FilterType::FnameCountryNullSex, Key3::new(account.fname, if account.country == 0 { 1 } else { 0 }, account.sex), account);
-        update_filter(&mut self.map3, FilterType::FnameCityNullSex, Key3::new(account.fname, if account.city == 0 { 1 } else { 0 }, account.sex), account);
-        update_filter(&mut self.map2, FilterType::FnameCountryNull, Key2::new(account.fname, if account.country == 0 { 1 } else { 0 }), account);
-        update_filter(&mut self.map2, FilterType::FnameCityNull, Key2::new(account.fname, if account.city == 0 { 1 } else { 0 }), account);
-        update_filter(&mut self.map2, FilterType::FnameSex, Key2::new(account.fname, account.sex), account);
+        update_filter(&mut self.map2, FilterType::CountryNullPhoneCode, Key2::new(if account.country == 0 { 1 } else { 0 }, account.phone_code), account, op);
+        update_filter(&mut self.map2, FilterType::CityNullPhoneCode, Key2::new(if account.city == 0 { 1 } else { 0 }, account.phone_code), account, op);
+        update_filter(&mut self.map3, FilterType::FnameCountryNullSex, Key3::new(account.fname, if account.country == 0 { 1 } else { 0 }, account.sex), account, op);
+        update_filter(&mut self.map3, FilterType::FnameCityNullSex, Key3::new(account.fname, if account.city == 0 { 1 } else { 0 }, account.sex), account, op);
+        update_filter(&mut self.map2, FilterType::FnameCountryNull, Key2::new(account.fname, if account.country == 0 { 1 } else { 0 }), account, op);
+        update_filter(&mut self.map2, FilterType::FnameCityNull, Key2::new(account.fname, if account.city == 0 { 1 } else { 0 }), account, op);
+        update_filter(&mut self.map2, FilterType::FnameSex, Key2::new(account.fname, account.sex), account, op);
     }
 
-    pub fn get_result(&self, matcher: &Matcher) -> Option<Cow<[i32]>> {
+    /// Список id и признак того, что он ни разу не обрезался. В индексе хранится только хвост
+    /// из KEEP_TOP (KEEP_TOP_EMAIL) наибольших id, и в обрезанном списке могут быть не все подходящие аккаунты.
+    pub fn get_result(&self, matcher: &Matcher) -> Option<(Cow<'_, [i32]>, bool)> {
         let filter_type = keys_to_filter_type.get(&KeySet::new2(&matcher.conditions));
         if filter_type.is_none() {
             return None;
@@ -130,7 +160,8 @@ impl FilterIndex {
             FilterType::CityNull |
             FilterType::EmailLt |
             FilterType::EmailGt => {
-                Some(Cow::from(map1.get(&make_key1(*filter_type.unwrap(), &matcher)).unwrap_or(&EMPTY_INT_LIST)))
+                let ids = map1.get(&make_key1(*filter_type.unwrap(), matcher)).unwrap_or(&EMPTY_TAIL);
+                Some((Cow::from(&ids.ids), !ids.trimmed))
             }
             FilterType::SexCountryNull |
             FilterType::SexCityNull |
@@ -140,65 +171,93 @@ impl FilterIndex {
             FilterType::CityNullPhoneCode |
             FilterType::EmailLtCityNull |
             FilterType::EmailGtCityNull => {
-                Some(Cow::from(map2.get(&make_key2(*filter_type.unwrap(), &matcher)).unwrap_or(&EMPTY_INT_LIST)))
+                let ids = map2.get(&make_key2(*filter_type.unwrap(), matcher)).unwrap_or(&EMPTY_TAIL);
+                Some((Cow::from(&ids.ids), !ids.trimmed))
             }
             FilterType::EmailLtCountryNullSex |
             FilterType::EmailGtCountryNullSex => {
-                Some(Cow::from(map3.get(&make_key3(*filter_type.unwrap(), &matcher)).unwrap_or(&EMPTY_INT_LIST)))
+                let ids = map3.get(&make_key3(*filter_type.unwrap(), matcher)).unwrap_or(&EMPTY_TAIL);
+                Some((Cow::from(&ids.ids), !ids.trimmed))
             }
             FilterType::FnameCountryNullSex => {
                 let mut vec: Vec<i32> = Vec::new();
+                let mut complete = true;
                 for fname in &matcher.fname_any {
                     let key = Key3::new(*fname, if matcher.country_null1 { 1 } else { 0 }, matcher.sex);
-                    vec = merge_sorted(&vec, map3.get(&key).unwrap_or(&EMPTY_INT_LIST));
+                    let ids = map3.get(&key).unwrap_or(&EMPTY_TAIL);
+                    complete &= !ids.trimmed;
+                    vec = merge_sorted(&vec, &ids.ids);
                 }
-                Some(Cow::from(vec))
+                Some((Cow::from(vec), complete))
             }
             FilterType::FnameCityNullSex => {
                 let mut vec: Vec<i32> = Vec::new();
+                let mut complete = true;
                 for fname in &matcher.fname_any {
                     let key = Key3::new(*fname, if matcher.city_null1 { 1 } else { 0 }, matcher.sex);
-                    vec = merge_sorted(&vec, map3.get(&key).unwrap_or(&EMPTY_INT_LIST));
+                    let ids = map3.get(&key).unwrap_or(&EMPTY_TAIL);
+                    complete &= !ids.trimmed;
+                    vec = merge_sorted(&vec, &ids.ids);
                 }
-                Some(Cow::from(vec))
+                Some((Cow::from(vec), complete))
             }
             FilterType::FnameSex => {
                 let mut vec: Vec<i32> = Vec::new();
+                let mut complete = true;
                 for fname in &matcher.fname_any {
                     let key = Key2::new(*fname, matcher.sex);
-                    vec = merge_sorted(&vec, map2.get(&key).unwrap_or(&EMPTY_INT_LIST));
+                    let ids = map2.get(&key).unwrap_or(&EMPTY_TAIL);
+                    complete &= !ids.trimmed;
+                    vec = merge_sorted(&vec, &ids.ids);
                 }
-                Some(Cow::from(vec))
+                Some((Cow::from(vec), complete))
             }
             FilterType::FnameCountryNull => {
                 let mut vec: Vec<i32> = Vec::new();
+                let mut complete = true;
                 for fname in &matcher.fname_any {
                     let key = Key2::new(*fname, if matcher.country_null1 { 1 } else { 0 });
-                    vec = merge_sorted(&vec, map2.get(&key).unwrap_or(&EMPTY_INT_LIST));
+                    let ids = map2.get(&key).unwrap_or(&EMPTY_TAIL);
+                    complete &= !ids.trimmed;
+                    vec = merge_sorted(&vec, &ids.ids);
                 }
-                Some(Cow::from(vec))
+                Some((Cow::from(vec), complete))
             }
             FilterType::FnameCityNull => {
                 let mut vec: Vec<i32> = Vec::new();
+                let mut complete = true;
                 for fname in &matcher.fname_any {
                     let key = Key2::new(*fname, if matcher.city_null1 { 1 } else { 0 });
-                    vec = merge_sorted(&vec, map2.get(&key).unwrap_or(&EMPTY_INT_LIST));
+                    let ids = map2.get(&key).unwrap_or(&EMPTY_TAIL);
+                    complete &= !ids.trimmed;
+                    vec = merge_sorted(&vec, &ids.ids);
                 }
-                Some(Cow::from(vec))
+                Some((Cow::from(vec), complete))
             }
         }
     }
 }
 
-fn update_filter<K: Eq + Hash>(map: &mut EnumMap<FilterType, HashMap<K, Vec<i32>>>, filter_type: FilterType, filter_key: K, account: &Account) {
-    update_filter2(map, filter_type, filter_key, account, KEEP_TOP);
+fn update_filter<K: Eq + Hash>(map: &mut EnumMap<FilterType, HashMap<K, Tail>>, filter_type: FilterType, filter_key: K, account: &Account, op: Op) {
+    update_filter2(map, filter_type, filter_key, account, KEEP_TOP, op);
 }
 
-fn update_filter2<K: Eq + Hash>(map: &mut EnumMap<FilterType, HashMap<K, Vec<i32>>>, filter_type: FilterType, filter_key: K, account: &Account, limit: usize) {
-    let mut vec = map[filter_type].entry(filter_key).or_insert_with(|| Vec::new());
-    insert_into_sorted_vec(account.id, &mut vec);
-    if vec.len() > limit {
-        vec.remove(0);
+fn update_filter2<K: Eq + Hash>(map: &mut EnumMap<FilterType, HashMap<K, Tail>>, filter_type: FilterType, filter_key: K, account: &Account, limit: usize, op: Op) {
+    if op == Op::Remove {
+        if let Some(tail) = map[filter_type].get_mut(&filter_key) {
+            remove_from_sorted_vec(account.id, &mut tail.ids);
+        }
+        return;
+    }
+    let tail = map[filter_type].entry(filter_key).or_default();
+    // в обрезанный хвост нельзя вернуть id меньше его начала: между ним и началом были отброшенные id
+    if tail.trimmed && tail.ids.first().is_none_or(|first| account.id < *first) {
+        return;
+    }
+    insert_into_sorted_vec(account.id, &mut tail.ids);
+    if tail.ids.len() > limit {
+        tail.ids.remove(0);
+        tail.trimmed = true;
     }
 }
 
@@ -264,4 +323,58 @@ fn first_letter(opt_str: &Option<String>) -> i32 {
 
 fn first_letter2(opt_str: &Option<Arc<String>>) -> i32 {
     opt_str.as_ref().unwrap().as_bytes()[0] as i32
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::filter::filter;
+    use crate::storage::tests::make_storage;
+    use crate::storage::Storage;
+    use crate::utils::params;
+
+    use super::*;
+
+    #[test]
+    fn test_update_removes_old_keys() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"z1@a.ru","fname":"Иван","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@a.ru","fname":"Иван","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        let ivan = storage.dict.get_existing_key(&"Иван".to_string()).unwrap();
+        let male = storage.consts.male;
+        let fname_sex = |storage: &Storage, fname: i32| storage.indexes.filter_index.map2[FilterType::FnameSex].get(&Key2::new(fname, male)).map(|tail| tail.ids.clone());
+        assert_eq!(fname_sex(&storage, ivan), Some(vec![1, 2]));
+
+        storage.update_account(1, r#"{"fname":"Петр","email":"a1@a.ru"}"#.as_bytes(), &mut |_| {}).unwrap();
+        let petr = storage.dict.get_existing_key(&"Петр".to_string()).unwrap();
+        assert_eq!(fname_sex(&storage, ivan), Some(vec![2]));
+        assert_eq!(fname_sex(&storage, petr), Some(vec![1]));
+        // старая первая буква email больше не ведет к аккаунту
+        let email_gt = &storage.indexes.filter_index.map1[FilterType::EmailGt];
+        assert!(!email_gt[&Key1::new('b' as i32)].ids.contains(&1));
+        assert!(email_gt[&Key1::new('a' as i32)].ids.contains(&1));
+    }
+
+    #[test]
+    fn test_remove_keeps_trimmed() {
+        let count = 2 * KEEP_TOP as i32 - 1;
+        let accounts: Vec<String> = (1..count + 1)
+            .map(|id| format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}}"#, id, id))
+            .collect();
+        let accounts: Vec<&str> = accounts.iter().map(|account| account.as_str()).collect();
+        let mut storage = make_storage(&accounts);
+        let city_null = |storage: &Storage| storage.indexes.filter_index.map1[FilterType::CityNull].get(&Key1::new(1))
+            .map(|tail| (tail.ids.first().cloned(), tail.ids.len(), tail.trimmed));
+        assert_eq!(city_null(&storage), Some((Some(count - KEEP_TOP as i32 + 1), KEEP_TOP, true)));
+
+        // список стал короче KEEP_TOP, но начало его по-прежнему отброшено
+        storage.update_account(count, r#"{"city":"Москва"}"#.as_bytes(), &mut |_| {}).unwrap();
+        assert_eq!(city_null(&storage), Some((Some(count - KEEP_TOP as i32 + 1), KEEP_TOP - 1, true)));
+        // давно отброшенный id при переиндексации в хвост не возвращается
+        storage.update_account(1, r#"{"status":"заняты"}"#.as_bytes(), &mut |_| {}).unwrap();
+        assert_eq!(city_null(&storage), Some((Some(count - KEEP_TOP as i32 + 1), KEEP_TOP - 1, true)));
+
+        let query = params(&[("city_null", "1"), ("limit", &KEEP_TOP.to_string())]);
+        let ids: Vec<i32> = filter(&storage, &query).unwrap().accounts.iter().map(|account| account.id).collect();
+        assert_eq!(ids, (count - KEEP_TOP as i32..count).rev().collect::<Vec<i32>>());
+    }
+}
@@ -2,13 +2,13 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use itertools::Itertools;
-
+use crate::bits::InterestSet;
 use crate::storage::Account;
 use crate::storage::Storage;
 use crate::topn::TopN;
-use crate::utils::EMPTY_LIKE_LIST;
 use crate::utils::seconds_from_year;
+use crate::utils::check_unique_params;
+use crate::utils::parse_limit;
 use crate::utils::StatusCode;
 
 #[inline(never)]
@@ -18,24 +18,31 @@ pub fn group(storage: &Storage, params: &Vec<(String, String)>) -> Result<Groups
         None => return Ok(GroupsJson { groups: Vec::new() })
     };
 
+    // в том числе keys без фильтра (FilterType::None): счетчики group_index ведутся в update_account без сканирования;
+    // длины country_index/city_index для этого не годятся - после update в них остаются старые id, и нет группы без значения
     let groups: HashMap<GroupKey, i32> = match storage.indexes.group_index.get_result(&matcher) {
         Some(groups) => groups,
         None => {
+            // top-N по count можно выбрать только после подсчета всех групп, поэтому потоковый вариант
+            // без хранения всех групп невозможен - вместо него запрос с слишком большим числом групп отклоняется
+            let group_cap = storage.config.group_cap;
             let mut groups = HashMap::new();
 
             if matcher.like != 0 {
-                storage.indexes.likes_index_male.get(&matcher.like).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id)
-                    .merge(storage.indexes.likes_index_female.get(&matcher.like).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id))
-                    .dedup()
+                if !storage.indexes.has_likers(matcher.like) {
+                    return Ok(GroupsJson { groups: Vec::new() });
+                }
+                // из индекса приходят только лайкнувшие matcher.like, проверять likes не нужно
+                storage.indexes.likers(matcher.like)
                     .filter_map(|id| storage.accounts[id as usize].as_ref())
-                    .filter(|account| matches(account, &matcher))
-                    .for_each(|account| process_group(account, &matcher, &mut groups));
+                    .filter(|account| matches_except_like(account, &matcher))
+                    .try_for_each(|account| process_group(account, &matcher, group_cap, &mut groups))?;
             } else {
                 // full scan
                 (0..storage.max_id + 1)
                     .filter_map(|id| storage.accounts[id].as_ref())
                     .filter(|account| matches(account, &matcher))
-                    .for_each(|account| process_group(account, &matcher, &mut groups));
+                    .try_for_each(|account| process_group(account, &matcher, group_cap, &mut groups))?;
             }
             groups
         }
@@ -63,7 +70,7 @@ pub fn group(storage: &Storage, params: &Vec<(String, String)>) -> Result<Groups
     })
 }
 
-fn process_group(account: &Account, matcher: &Matcher, groups: &mut HashMap<GroupKey, i32>) {
+fn process_group(account: &Account, matcher: &Matcher, group_cap: usize, groups: &mut HashMap<GroupKey, i32>) -> Result<(), StatusCode> {
     if matcher.group_interests {
         account.interests.into_iter().for_each(|interest| {
             let count = groups.entry(GroupKey {
@@ -87,9 +94,15 @@ fn process_group(account: &Account, matcher: &Matcher, groups: &mut HashMap<Grou
         ).or_insert(0);
         *count += 1;
     }
+    if group_cap != 0 && groups.len() > group_cap {
+        // запрос слишком общий
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
 }
 
 fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Option<Matcher>, StatusCode> {
+    check_unique_params(params)?;
     let mut matcher = Matcher {
         limit: 0,
         order: 0,
@@ -124,6 +137,10 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
             "query_id" => {}
             "keys" => {
                 matcher.keys = value.split(",").map(|str| str.to_string()).collect();
+                // индексы группировки есть только для комбинаций из двух ключей
+                if matcher.keys.len() > 2 || matcher.keys.iter().enumerate().any(|(i, key)| matcher.keys[..i].contains(key)) {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
                 for key in &matcher.keys {
                     match key.as_str() {
                         "sex" => {
@@ -156,12 +173,7 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
                     return Err(StatusCode::BAD_REQUEST);
                 }
             }
-            "limit" => {
-                matcher.limit = value.parse::<usize>().map_err(|_| StatusCode::BAD_REQUEST)?;
-                if matcher.limit == 0 {
-                    return Err(StatusCode::BAD_REQUEST);
-                }
-            }
+            "limit" => {}
             _ => {
                 match key.as_str() {
                     "sex" => {
@@ -228,6 +240,7 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
             }
         }
     }
+    matcher.limit = parse_limit(params, storage.config.default_limit)?;
     if empty_result {
         return Ok(None);
     }
@@ -235,6 +248,13 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
 }
 
 fn matches(account: &Account, matcher: &Matcher) -> bool {
+    if matcher.like != 0 && account.likes.binary_search(&matcher.like).is_err() {
+        return false;
+    }
+    matches_except_like(account, matcher)
+}
+
+fn matches_except_like(account: &Account, matcher: &Matcher) -> bool {
     if matcher.sex != 0 && matcher.sex != account.sex {
         return false;
     }
@@ -261,14 +281,6 @@ fn matches(account: &Account, matcher: &Matcher) -> bool {
             return false;
         }
     }
-    if matcher.like != 0 {
-        if account.likes.is_empty() {
-            return false;
-        }
-        if !account.likes.contains(&matcher.like) { // TODO binary?
-            return false;
-        }
-    }
     return true;
 }
 
@@ -374,4 +386,161 @@ struct GroupJson {
     #[serde(skip_serializing_if = "Option::is_none")]
     interests: Option<Arc<String>>,
     count: i32,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::make_storage;
+    use crate::utils::params;
+
+    use super::*;
+
+    #[test]
+    fn test_group_likes() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":1,"ts":1}]}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"m","status":"заняты","birth":600000000,"joined":1300000000,"likes":[{"id":1,"ts":1},{"id":1,"ts":2}]}"#,
+            r#"{"id":4,"email":"a4@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":1,"ts":1},{"id":5,"ts":1}]}"#,
+            r#"{"id":5,"email":"a5@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+
+        let result = group(&storage, &params(&[("keys", "status"), ("likes", "1"), ("order", "-1"), ("limit", "10")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"groups":[{"status":"свободны","count":2},{"status":"заняты","count":1}]}"#);
+
+        let result = group(&storage, &params(&[("keys", "sex"), ("likes", "1"), ("order", "1"), ("limit", "10")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"groups":[{"sex":"f","count":1},{"sex":"m","count":2}]}"#);
+
+        let result = group(&storage, &params(&[("keys", "status"), ("likes", "2"), ("order", "1"), ("limit", "10")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"groups":[]}"#);
+    }
+
+    #[test]
+    fn test_likes_path_matches_scan() {
+        let mut storage = make_storage(&[]);
+        for id in 1..201 {
+            let likes: Vec<String> = (1..6).map(|i| format!(r#"{{"id":{},"ts":{}}}"#, (id * i * 7) % 40 + 1, i)).collect();
+            let account = format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"{}","birth":600000000,"joined":1300000000,"city":"c{}","interests":["i{}"],"likes":[{}]}}"#,
+                                  id, id, if id % 2 == 0 { "m" } else { "f" }, if id % 3 == 0 { "заняты" } else { "свободны" }, id % 5, id % 4, likes.join(","));
+            storage.new_account(account.as_bytes(), &mut |_| {}).unwrap();
+        }
+        storage.update_account(8, br#"{"likes":[{"id":1,"ts":1}]}"#, &mut |_| {}).unwrap();
+
+        let mut nonempty = 0;
+        for likee in &["1", "8", "15", "33", "41"] {
+            for query in &[vec![("keys", "sex")], vec![("keys", "city"), ("sex", "m")], vec![("keys", "interests"), ("status", "заняты")], vec![("keys", "status"), ("city", "c2")]] {
+                let mut query = query.clone();
+                query.extend_from_slice(&[("likes", likee), ("order", "-1"), ("limit", "50")]);
+                let matcher = make_matcher(&storage, &params(&query)).unwrap().unwrap();
+                // счетчики через full scan с проверкой likes
+                let mut scanned = HashMap::new();
+                storage.accounts.iter().filter_map(|account| account.as_ref())
+                    .filter(|account| matches(account, &matcher))
+                    .try_for_each(|account| process_group(account, &matcher, 0, &mut scanned)).unwrap();
+                let result = group(&storage, &params(&query)).unwrap();
+                let mut counts: Vec<i32> = result.groups.iter().map(|group| group.count).collect();
+                let mut expected: Vec<i32> = scanned.values().cloned().collect();
+                counts.sort();
+                expected.sort();
+                nonempty += if expected.is_empty() { 0 } else { 1 };
+                assert_eq!(counts, expected, "{:?}", query);
+            }
+        }
+        assert!(nonempty > 10);
+    }
+
+    #[test]
+    fn test_keys_validation() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"city":"Москва"}"#,
+        ]);
+        let result = group(&storage, &params(&[("keys", "sex,city"), ("order", "-1"), ("limit", "10")])).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"groups":[{"sex":"f","city":"Москва","count":1}]}"#);
+
+        assert_eq!(group(&storage, &params(&[("keys", "sex,status,city"), ("order", "-1"), ("limit", "10")])).err(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(group(&storage, &params(&[("keys", "city,city"), ("order", "-1"), ("limit", "10")])).err(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(group(&storage, &params(&[("keys", "sex,sex,sex"), ("order", "-1"), ("limit", "10")])).err(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(group(&storage, &params(&[("keys", "sex"), ("order", "-1"), ("limit", "10"), ("limit", "5")])).err(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(group(&storage, &params(&[("keys", "sex"), ("keys", "city"), ("order", "-1"), ("limit", "10")])).err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn test_single_key_without_filter() {
+        let mut storage = make_storage(&[]);
+        for id in 1..61 {
+            let mut account = format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000"#, id, id);
+            if id % 4 != 0 {
+                account += &format!(r#","country":"k{}""#, id % 3);
+            }
+            if id % 5 != 0 {
+                account += &format!(r#","city":"c{}""#, id % 7);
+            }
+            storage.new_account((account + "}").as_bytes(), &mut |_| {}).unwrap();
+        }
+        storage.update_account(1, br#"{"country":"k2","city":"c6"}"#, &mut |_| {}).unwrap();
+        storage.update_account(4, br#"{"country":"k0"}"#, &mut |_| {}).unwrap();
+        storage.new_account(r#"{"id":61,"email":"a61@a.ru","sex":"m","status":"заняты","birth":600000000,"joined":1300000000,"country":"k9"}"#.as_bytes(), &mut |_| {}).unwrap();
+
+        for (key, field) in &[("country", (|account: &Account| account.country) as fn(&Account) -> i32), ("city", |account: &Account| account.city)] {
+            let query = params(&[("keys", key), ("order", "1"), ("limit", "50")]);
+            assert!(storage.indexes.group_index.get_result(&make_matcher(&storage, &query).unwrap().unwrap()).is_some());
+
+            let mut tally: HashMap<Option<Arc<String>>, i32> = HashMap::new();
+            storage.accounts.iter().filter_map(|account| account.as_ref())
+                .for_each(|account| *tally.entry(storage.dict.get_value(field(account))).or_insert(0) += 1);
+            let result = group(&storage, &query).unwrap();
+            let counts: HashMap<Option<Arc<String>>, i32> = result.groups.iter()
+                .map(|group| (if *key == "country" { group.country.clone() } else { group.city.clone() }, group.count))
+                .collect();
+            assert_eq!(counts, tally, "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_city_interests_index() {
+        let mut storage = make_storage(&[]);
+        for id in 1..121 {
+            let interests: Vec<String> = (0..id % 4).map(|i| format!(r#""i{}""#, (id + i) % 6)).collect();
+            let account = format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"{}","birth":600000000,"joined":1300000000,"country":"k{}","city":"c{}","interests":[{}]}}"#,
+                                  id, id, if id % 2 == 0 { "m" } else { "f" }, if id % 3 == 0 { "заняты" } else { "свободны" }, id % 3, id % 5, interests.join(","));
+            storage.new_account(account.as_bytes(), &mut |_| {}).unwrap();
+        }
+        storage.update_account(2, br#"{"city":"c4","interests":["i1","i5"]}"#, &mut |_| {}).unwrap();
+        storage.update_account(7, br#"{"country":"k1","interests":[]}"#, &mut |_| {}).unwrap();
+
+        let mut nonempty = 0;
+        for filter in &[("city", "c1"), ("city", "c4"), ("country", "k1"), ("country", "k2")] {
+            for interest in &["i1", "i3", "i5"] {
+                for keys in &["interests", "sex", "status,city"] {
+                    let query = params(&[("keys", keys), *filter, ("interests", interest), ("order", "-1"), ("limit", "50")]);
+                    let matcher = make_matcher(&storage, &query).unwrap().unwrap();
+                    let indexed = storage.indexes.group_index.get_result(&matcher).expect("no index for query");
+                    let mut scanned = HashMap::new();
+                    storage.accounts.iter().filter_map(|account| account.as_ref())
+                        .filter(|account| matches(account, &matcher))
+                        .try_for_each(|account| process_group(account, &matcher, 0, &mut scanned)).unwrap();
+                    nonempty += if scanned.is_empty() { 0 } else { 1 };
+                    assert_eq!(indexed, scanned, "{:?}", query);
+                }
+            }
+        }
+        assert!(nonempty > 20);
+    }
+
+    #[test]
+    fn test_group_cap() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a","b"],"likes":[{"id":3,"ts":1}]}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["b","c"],"likes":[{"id":3,"ts":1}]}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"m","status":"заняты","birth":600000000,"joined":1300000000,"interests":["c"]}"#,
+        ]);
+        let query = params(&[("keys", "interests"), ("likes", "3"), ("order", "-1"), ("limit", "2")]);
+        assert_eq!(group(&storage, &query).unwrap().groups.len(), 2);
+
+        storage.config.group_cap = 3;
+        let result = group(&storage, &query).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), r#"{"groups":[{"interests":"b","count":2},{"interests":"c","count":1}]}"#);
+
+        storage.config.group_cap = 2;
+        assert_eq!(group(&storage, &query).err(), Some(StatusCode::BAD_REQUEST));
+    }
+}
@@ -30,6 +30,8 @@ enum FilterType {
     CityBirth,
     CountryJoined,
     CityJoined,
+    CityInterests,
+    CountryInterests,
 }
 
 impl Copy for FilterType {}
@@ -89,6 +91,8 @@ impl GroupIndex {
             self.update_filter(FilterType::Interests, Key::new1(interest), account, incr);
             self.update_filter(FilterType::JoinedInterests, Key::new2(year_from_seconds(account.joined), interest), account, incr);
             self.update_filter(FilterType::BirthInterests, Key::new2(year_from_seconds(account.birth), interest), account, incr);
+            self.update_filter(FilterType::CityInterests, Key::new2(account.city, interest), account, incr);
+            self.update_filter(FilterType::CountryInterests, Key::new2(account.country, interest), account, incr);
         });
         self.update_filter(FilterType::Birth, Key::new1(year_from_seconds(account.birth)), account, incr);
         self.update_filter(FilterType::Country, Key::new1(account.country), account, incr);
@@ -121,6 +125,47 @@ impl GroupIndex {
         });
     }
 
+    /// Пересчитывает индекс по accounts с нуля и сравнивает с инкрементальным.
+    /// Ok, если все ненулевые счетчики совпадают, иначе описания расхождений.
+    pub fn verify(&self, accounts: &[Option<Account>]) -> Result<(), Vec<String>> {
+        let mut expected = GroupIndex::new();
+        accounts.iter().filter_map(|account| account.as_ref()).for_each(|account| expected.update_account(account, 1));
+        let mut mismatches = Vec::new();
+        for (filter_type, filter_key, group_type, group_key, count) in self.counts() {
+            let expected_count = expected.count(filter_type, filter_key, group_type, group_key);
+            if count != expected_count {
+                mismatches.push(format!("{:?} {:?} {:?} {:?}: indexed {}, expected {}", filter_type, filter_key, group_type, group_key, count, expected_count));
+            }
+        }
+        for (filter_type, filter_key, group_type, group_key, count) in expected.counts() {
+            if self.count(filter_type, filter_key, group_type, group_key) == 0 {
+                mismatches.push(format!("{:?} {:?} {:?} {:?}: indexed 0, expected {}", filter_type, filter_key, group_type, group_key, count));
+            }
+        }
+        if mismatches.is_empty() { Ok(()) } else { Err(mismatches) }
+    }
+
+    /// Ненулевые счетчики: после -1 в map остаются записи с 0.
+    fn counts(&self) -> Vec<(FilterType, &Key, GroupType, &Key, i32)> {
+        let mut counts = Vec::new();
+        for (filter_type, filters) in &self.map {
+            for (filter_key, groups) in filters {
+                for (group_type, group_counts) in groups {
+                    for (group_key, count) in group_counts {
+                        if *count != 0 {
+                            counts.push((filter_type, filter_key, group_type, group_key, *count));
+                        }
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    fn count(&self, filter_type: FilterType, filter_key: &Key, group_type: GroupType, group_key: &Key) -> i32 {
+        self.map[filter_type].get(filter_key).and_then(|groups| groups[group_type].get(group_key)).cloned().unwrap_or(0)
+    }
+
     pub fn get_result(&self, matcher: &Matcher) -> Option<HashMap<GroupKey, i32>> {
         let filter_type = get_filter_type(matcher);
         let group_type = keys_to_group_type.get(&KeySet::new2(&matcher.keys)); // TODO avoid clone
@@ -163,6 +208,8 @@ fn make_filter_key(matcher: &Matcher, filter_type: &FilterType) -> Key {
         FilterType::CityBirth => Key::new2(matcher.city, matcher.birth),
         FilterType::CountryJoined => Key::new2(matcher.country, matcher.joined),
         FilterType::CityJoined => Key::new2(matcher.city, matcher.joined),
+        FilterType::CityInterests => Key::new2(matcher.city, matcher.interest),
+        FilterType::CountryInterests => Key::new2(matcher.country, matcher.interest),
     }
 }
 
@@ -366,6 +413,52 @@ fn get_filter_type(matcher: &Matcher) -> Option<FilterType> {
         matcher.interest == 0 &&
         matcher.like == 0 {
         return Some(FilterType::CityJoined);
+    } else if matcher.sex == 0 &&
+        matcher.status == 0 &&
+        matcher.city != 0 &&
+        matcher.country == 0 &&
+        matcher.birth == 0 &&
+        matcher.joined == 0 &&
+        matcher.interest != 0 &&
+        matcher.like == 0 {
+        return Some(FilterType::CityInterests);
+    } else if matcher.sex == 0 &&
+        matcher.status == 0 &&
+        matcher.city == 0 &&
+        matcher.country != 0 &&
+        matcher.birth == 0 &&
+        matcher.joined == 0 &&
+        matcher.interest != 0 &&
+        matcher.like == 0 {
+        return Some(FilterType::CountryInterests);
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::make_storage;
+
+    #[test]
+    fn test_verify() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"city":"c1","interests":["x","y"]}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"заняты","birth":700000000,"joined":1400000000,"country":"k1"}"#,
+        ]);
+        storage.new_account(r#"{"id":3,"email":"a3@a.ru","sex":"m","status":"всё сложно","birth":800000000,"joined":1450000000,"city":"c2","interests":["y"]}"#.as_bytes(), &mut |_| {}).unwrap();
+        storage.update_account(1, r#"{"city":"c2","interests":["z"],"status":"заняты"}"#.as_bytes(), &mut |_| {}).unwrap();
+        storage.update_account(2, r#"{"sex":"m","country":"k2","birth":650000000}"#.as_bytes(), &mut |_| {}).unwrap();
+        storage.update_account(3, r#"{"joined":1320000000,"interests":["x","z"]}"#.as_bytes(), &mut |_| {}).unwrap();
+        // ошибочный update не должен менять индекс
+        assert!(storage.update_account(3, br#"{"sex":"x"}"#, &mut |_| {}).is_err());
+        assert_eq!(storage.indexes.group_index.verify(&storage.accounts), Ok(()));
+
+        // расхождение находится в обе стороны
+        let account = storage.accounts[2].take().unwrap();
+        assert!(storage.indexes.group_index.verify(&storage.accounts).is_err());
+        storage.indexes.group_index.update_account(&account, -1);
+        assert_eq!(storage.indexes.group_index.verify(&storage.accounts), Ok(()));
+        storage.indexes.group_index.update_account(&account, -1);
+        assert!(storage.indexes.group_index.verify(&storage.accounts).unwrap_err().iter().any(|mismatch| mismatch.contains("indexed -1, expected 0")));
+    }
+}
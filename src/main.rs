@@ -11,10 +11,14 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use std::io;
 use std::io::ErrorKind;
+use std::io::Write;
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
 use mio::{IoVec, Poll, PollOpt, Ready, Token};
 #[cfg(target_os = "linux")]
@@ -26,16 +30,20 @@ use mio::net::TcpStream;
 use net2::TcpBuilder;
 #[cfg(unix)]
 use net2::unix::UnixTcpBuilderExt;
+#[cfg(any(not(feature = "unchecked-utf8"), test))]
 use percent_encoding::{DEFAULT_ENCODE_SET, percent_encode};
 use spin;
 
 use crate::storage::Storage;
+use crate::utils::HttpMethod;
+use crate::utils::read_lock;
 use crate::utils::StatusCode;
 
 mod storage;
 mod filter;
 mod group;
 mod recommend;
+mod recommend_cache;
 mod suggest;
 mod utils;
 mod topn;
@@ -44,25 +52,40 @@ mod stats;
 mod filter_index;
 mod bits;
 mod process;
+mod reload;
+mod cache;
+mod profile;
+mod wal;
 
 lazy_static! {
     static ref COMMON_HEADERS: Vec<&'static str> = vec![
         "content-type: application/json, charset=utf-8",
         "date: Sun, 13 Jan 2019 18:40:03 GMT",
         "server: hlc",
-        "connection: keep-alive", // вроде бы танк смотрит только на ответ
+        "connection: keep-alive",
     ];
     static ref COMMON_HEADERS_AS_STR: String = COMMON_HEADERS.join("\r\n") + "\r\n";
+    // для HTTP/1.0 без Connection: keep-alive и для Connection: close - соединение закрывается после ответа
+    static ref COMMON_HEADERS_CLOSE_AS_STR: String = COMMON_HEADERS[..3].join("\r\n") + "\r\nconnection: close\r\n";
     static ref STATUS_400: String = "HTTP/1.1 400 Bad Request\r\n".to_string() +
         &COMMON_HEADERS_AS_STR +
         "content-length: 0\r\n" +
         "\r\n";
 }
 
-fn main() {
-    env_logger::init();
+// выставляется обработчиком SIGTERM/SIGINT, потоки poll выходят из цикла
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+// сколько после сигнала ждать, пока уйдут недописанные ответы
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(1);
+// сколько раз подряд poll вызывает epoll_wait, пока тот возвращает полный batch
+const MAX_POLL_BATCHES: usize = 4;
+// как часто поток poll проверяет простаивающие соединения при --idle-timeout
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// настройки сокета, которые получились на деле, пишутся в лог по первому принятому соединению
+static SOCKET_OPTIONS_LOGGED: AtomicBool = AtomicBool::new(false);
 
-    let matches = clap::App::new("hlc2018")
+fn app<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::App::new("hlc2018")
         .arg(clap::Arg::with_name("PORT")
             .help("Port to listen at")
             .required(true)
@@ -80,18 +103,163 @@ fn main() {
         .arg(clap::Arg::with_name("no-stats")
             .help("Disable statistics")
             .long("no-stats"))
+        .arg(clap::Arg::with_name("no-affinity")
+            .help("Do not pin poll threads to CPUs (thread N to allowed CPU N modulo allowed CPU count)")
+            .long("no-affinity"))
+        .arg(clap::Arg::with_name("ipv6")
+            .help("Listen on :: with IPV6_V6ONLY off, accepting both IPv6 and IPv4 clients")
+            .long("ipv6")
+            .visible_alias("dual-stack"))
         .arg(clap::Arg::with_name("cache")
             .help("Use response cache")
             .long("cache")
             .takes_value(true)
             .possible_values(&["on", "off", "random"])
             .default_value("off"))
-        .get_matches();
+        .arg(clap::Arg::with_name("data-format")
+            .help("zip - DATA_DIR/data.zip, dir - .json/.json.gz files in DATA_DIR")
+            .long("data-format")
+            .takes_value(true)
+            .possible_values(&["zip", "dir"])
+            .default_value("zip"))
+        .arg(clap::Arg::with_name("interests-dict")
+            .help("File with interests order, written after load if missing")
+            .long("interests-dict")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("reuse-buffers")
+            .help("Reuse response buffer and grown read buffer across requests on a connection")
+            .long("reuse-buffers")
+            .takes_value(true)
+            .possible_values(&["on", "off"])
+            .default_value("on"))
+        .arg(clap::Arg::with_name("max-rps-per-conn")
+            .help("Max requests per second on a connection, 0 - unlimited")
+            .long("max-rps-per-conn")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(clap::Arg::with_name("load-threads")
+            .help("Threads parsing data files at startup, 0 - one per CPU")
+            .long("load-threads")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(clap::Arg::with_name("idle-timeout")
+            .help("Close connections without reads or writes for this many seconds, 0 - never")
+            .long("idle-timeout")
+            .takes_value(true)
+            .default_value("60"))
+        .arg(clap::Arg::with_name("tcp-keepalive")
+            .help("Seconds of inactivity before TCP keepalive probes on accepted connections, 0 - SO_KEEPALIVE off")
+            .long("tcp-keepalive")
+            .takes_value(true)
+            .default_value("30"))
+        .arg(clap::Arg::with_name("tcp-keepalive-interval")
+            .help("Seconds between TCP keepalive probes, Linux only")
+            .long("tcp-keepalive-interval")
+            .takes_value(true)
+            .default_value("10"))
+        .arg(clap::Arg::with_name("max-response-bytes")
+            .help("Respond 413 instead of filter, group, recommend or suggest response longer than this, 0 - unlimited")
+            .long("max-response-bytes")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(clap::Arg::with_name("self-likes")
+            .help("Likes with liker == likee: accept, reject - 400 on POST, drop - silently ignore")
+            .long("self-likes")
+            .takes_value(true)
+            .possible_values(&["accept", "reject", "drop"])
+            .default_value("accept"))
+        .arg(clap::Arg::with_name("interests2-fallback")
+            .help("Intersect single-interest indexes when a pair is missing from the two-interest index")
+            .long("interests2-fallback"))
+        .arg(clap::Arg::with_name("admin")
+            .help("Enable debug endpoints: /admin/stats, /admin/cache (GET lists keys, DELETE clears), POST /admin/reindex?index=<name>, /admin/verify, /admin/export and POST /admin/reload")
+            .long("admin"))
+        .arg(clap::Arg::with_name("strict-unknown")
+            .help("Return 400 for filters with conflicting predicates, e.g. birth_year with birth_lt, and 422 for sex_eq/status_eq/status_neq/status_any outside the allowed values")
+            .long("strict-unknown"))
+        .arg(clap::Arg::with_name("default-limit")
+            .help("Limit for filter, group, recommend and suggest without limit parameter, 0 - respond 400")
+            .long("default-limit")
+            .default_value("0"))
+        .arg(clap::Arg::with_name("wal")
+            .help("Append successful new/update/likes requests to this file")
+            .long("wal")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("wal-fsync")
+            .help("fsync --wal after every record before responding: acknowledged writes survive an OS crash, not only a process crash")
+            .long("wal-fsync"))
+        .arg(clap::Arg::with_name("replay")
+            .help("Apply writes from this --wal file after loading data, may be the same file as --wal")
+            .long("replay")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("isolate-writes")
+            .help("Respond 500 to a write that panics instead of losing the worker thread")
+            .long("isolate-writes"))
+        .arg(clap::Arg::with_name("close-on-rate-limit")
+            .help("Close connection exceeding --max-rps-per-conn")
+            .long("close-on-rate-limit"))
+        .arg(clap::Arg::with_name("profile")
+            .help("Defaults for buffer sizes, events, backlog and cache size, auto - by CPU count; see src/profile.rs for values")
+            .long("profile")
+            .takes_value(true)
+            .possible_values(&["small", "medium", "large", "auto"])
+            .default_value("medium"))
+        .arg(clap::Arg::with_name("read-buffer")
+            .help("Request read buffer per connection in bytes [default: from --profile]")
+            .long("read-buffer")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("max-request-bytes")
+            .help("Grow the read buffer up to this many bytes for long requests, respond 413 beyond it")
+            .long("max-request-bytes")
+            .takes_value(true)
+            .default_value("1048576"))
+        .arg(clap::Arg::with_name("events")
+            .help("epoll events per epoll_wait call, a full batch is followed by another call [default: from --profile, 1024 for medium]")
+            .long("events")
+            .visible_alias("events-capacity")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("backlog")
+            .help("listen backlog [default: from --profile, 1024 for medium]")
+            .long("backlog")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("cache-size")
+            .help("Max response cache entries, 0 - unlimited [default: from --profile]")
+            .long("cache-size")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("recommend-cap")
+            .help("Max recommend candidates per order as a multiple of limit, 0 - unlimited")
+            .long("recommend-cap")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(clap::Arg::with_name("group-cap")
+            .help("Max distinct groups in a group query without index, 0 - unlimited")
+            .long("group-cap")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(clap::Arg::with_name("index-set")
+            .help("Optional indexes to build, comma separated")
+            .long("index-set")
+            .takes_value(true)
+            .use_delimiter(true)
+            .possible_values(&["sname", "sname_prefix"]))
+        .arg(clap::Arg::with_name("emit-empty-arrays")
+            .help("Always include interests and likes in account responses, even if empty")
+            .long("emit-empty-arrays"))
+        .arg(clap::Arg::with_name("batch-writes")
+            .help("Apply POST requests in batches between poll cycles")
+            .long("batch-writes"))
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = app().get_matches();
 
     let port = matches.value_of("PORT").unwrap().parse::<u16>().unwrap();
     let data_dir = matches.value_of("DATA_DIR").unwrap();
     let num_threads = matches.value_of("threads").unwrap().parse::<usize>().unwrap();
     let record_stats = !matches.is_present("no-stats");
+    let affinity = !matches.is_present("no-affinity");
 
     let cache = match matches.value_of("cache").unwrap() {
         "on" => true,
@@ -100,6 +268,31 @@ fn main() {
         _ => unreachable!(),
     };
     info!("using response cache: {}", cache);
+    let profile = profile_from_matches(&matches);
+    info!("profile: {:?}", profile);
+    process::set_cache_size(profile.cache_size);
+    let conn_options = ConnOptions {
+        read_buffer: profile.read_buffer,
+        max_request: matches.value_of("max-request-bytes").unwrap().parse::<usize>().unwrap(),
+        reuse_buffers: matches.value_of("reuse-buffers").unwrap() == "on",
+        max_rps: matches.value_of("max-rps-per-conn").unwrap().parse::<u32>().unwrap(),
+        close_on_rate_limit: matches.is_present("close-on-rate-limit"),
+        idle_timeout: match matches.value_of("idle-timeout").unwrap().parse::<u64>().unwrap() {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+        keepalive_idle: match matches.value_of("tcp-keepalive").unwrap().parse::<u64>().unwrap() {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+        keepalive_interval: Duration::from_secs(matches.value_of("tcp-keepalive-interval").unwrap().parse::<u64>().unwrap()),
+        record_stats,
+        cache,
+    };
+    info!("socket options: backlog {}, TCP_NODELAY, SO_KEEPALIVE {:?}, keepalive interval {:?}",
+          profile.backlog, conn_options.keepalive_idle, conn_options.keepalive_interval);
+
+    let config = config_from_matches(&matches);
 
     #[cfg(target_os = "linux")]
         {
@@ -134,29 +327,43 @@ fn main() {
             }
         }
 
-    let storage = Arc::new(RwLock::new(storage::Storage::load(data_dir)));
-    debug!("{:?}", storage.read().unwrap().accounts[1]);
+    let storage = Arc::new(RwLock::new(storage::Storage::load(data_dir, config)));
+    debug!("{:?}", read_lock(&storage).accounts[1]);
 
-    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
+    let addr: SocketAddr = if matches.is_present("ipv6") {
+        (std::net::Ipv6Addr::UNSPECIFIED, port).into()
+    } else {
+        ([0, 0, 0, 0], port).into()
+    };
+    info!("listening on {}", addr);
 
     // TODO accept4? tcp_defer_accept?
 
     const SERVER: Token = Token(0);
 
+    let events_capacity = profile.events_capacity;
+    install_shutdown_handler().unwrap();
     let mut threads = Vec::new();
     for thread_id in 0..num_threads {
         // poll threads
         let storage = storage.clone();
         let thread_data = Arc::new(ThreadData {
-            server: bind(&addr).unwrap(),
+            server: bind(&addr, profile.backlog).unwrap(),
             poll: Poll::new().unwrap(),
             connections: spin::Mutex::new(HashMap::new()),
         });
         thread_data.poll.register(&thread_data.server, SERVER, Ready::readable(), PollOpt::edge()).unwrap();
         threads.push(thread::spawn(move || {
+            if affinity {
+                match pin_to_cpu(thread_id) {
+                    Ok(cpu) => info!("poll thread {} pinned to cpu {}", thread_id, cpu),
+                    Err(err) => warn!("poll thread {}: sched_setaffinity error: {}", thread_id, err),
+                }
+            }
             let thread_data = thread_data.clone();
-            let mut events = Events::with_capacity(1024);
-            loop {
+            let mut events = Events::with_capacity(events_capacity);
+            let mut next_idle_sweep = Instant::now();
+            while !SHUTDOWN.load(Ordering::Relaxed) {
                 poll(&thread_data.poll, &mut events); // epoll 0
                 for event in events.iter() {
 //                    debug!("{} {:?}", i, event);
@@ -166,21 +373,22 @@ fn main() {
                                 match thread_data.server.accept() {
                                     Ok((stream, addr2)) => {
                                         // debug!("accepted thread_id {} {:?}", thread_id, addr2);
-                                        stream.set_nodelay(true).unwrap();
+                                        if let Err(err) = set_socket_options(&stream, &conn_options) {
+                                            warn!("accepted socket options error: {}", err);
+                                        } else if !SOCKET_OPTIONS_LOGGED.swap(true, Ordering::Relaxed) {
+                                            info!("accepted socket: {}", describe_socket_options(&stream));
+                                        }
                                         if record_stats {
-                                            storage.read().unwrap().stats.register_accept(thread_id);
+                                            read_lock(&storage).stats.register_accept(thread_id);
                                         }
                                         let token = Token(addr2.port() as usize);
-                                        thread_data.poll.register(&stream, token, Ready::readable() /*| Ready::writable()*/, PollOpt::edge()).unwrap(); // TODO EPOLLEXCLUSIVE ?
+                                        thread_data.poll.register(&stream, token, Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap(); // TODO EPOLLEXCLUSIVE ?
                                         let conn_id = token.0;
-                                        {
-                                            thread_data.connections.lock().insert(conn_id, Connection { stream, buf: [0; 8192], len: 0 });
-                                            let mut remove_conn = false;
-                                            try_read_and_process(&thread_data.connections, &storage, true, record_stats, cache, &mut remove_conn, thread_id, conn_id);
-                                            if remove_conn {
-                                                //warn!("remove_conn1 {}", conn_id);
-                                                thread_data.connections.lock().remove(&conn_id);
-                                            }
+                                        let mut conn = Connection::new(stream, conn_options);
+                                        let mut remove_conn = false;
+                                        try_read_and_process(&mut conn, &thread_data.connections, &storage, true, conn_options, &mut remove_conn, thread_id, conn_id);
+                                        if !remove_conn {
+                                            thread_data.connections.lock().insert(conn_id, conn);
                                         }
                                     }
                                     Err(err) => {
@@ -197,102 +405,559 @@ fn main() {
 
                         Token(conn_id) => {
                             // debug!("poll thread_id {}: {}/{} conn_id {}", thread_id, index + 1, events.events.len(), conn_id);
-                            let mut remove_conn = false;
-                            try_read_and_process(&thread_data.connections, &storage, false, record_stats, cache, &mut remove_conn, thread_id, conn_id);
-                            if remove_conn {
-                                // warn!("remove_conn2 {}", conn_id);
-                                thread_data.connections.lock().remove(&conn_id);
-                            }
+                            handle_event(&thread_data.connections, &storage, event.readiness(), conn_options, thread_id, conn_id);
                         }
                     }
                 }
+                // в режиме batch_writes ответы на POST отправляются после применения всей пачки
+                for (conn_id, status_code) in process::apply_pending_writes(&storage, record_stats) {
+                    with_connection(&thread_data.connections, conn_id, |conn, remove_conn| {
+                        write_and_send(conn, conn_options.reuse_buffers, remove_conn, &storage, |response, keep_alive| write_status_response(response, keep_alive, status_code));
+                        conn.awaiting_reply = false;
+                        // запросы, пришедшие вслед за отложенным
+                        if !*remove_conn {
+                            process_buffered(conn, &thread_data.connections, &storage, conn_options, remove_conn, thread_id, conn_id);
+                        }
+                    });
+                }
+                if let Some(idle_timeout) = conn_options.idle_timeout {
+                    let now = Instant::now();
+                    if now >= next_idle_sweep {
+                        close_idle(&thread_data.connections, now, idle_timeout);
+                        next_idle_sweep = now + IDLE_SWEEP_INTERVAL;
+                    }
+                }
             }
+            drain_connections(&thread_data.connections, &storage, Instant::now() + SHUTDOWN_DRAIN);
         }));
     }
 
-    thread::sleep(Duration::from_secs(std::u64::MAX));
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    info!("shutdown");
+    let storage = read_lock(&storage);
+    storage.stats.print();
+    storage.stats.print_net();
+}
+
+/// Настройки storage из флагов; ограничения, меняющие результат (--recommend-cap, --group-cap), от --profile не зависят.
+fn config_from_matches(matches: &clap::ArgMatches) -> storage::Config {
+    let mut config = storage::Config::new();
+    config.recommend_cap_factor = matches.value_of("recommend-cap").unwrap().parse::<usize>().unwrap();
+    config.group_cap = matches.value_of("group-cap").unwrap().parse::<usize>().unwrap();
+    config.interests_dict_path = matches.value_of("interests-dict").map(|path| path.to_string());
+    config.data_format = match matches.value_of("data-format").unwrap() {
+        "zip" => storage::DataFormat::Zip,
+        "dir" => storage::DataFormat::Dir,
+        _ => unreachable!(),
+    };
+    config.batch_writes = matches.is_present("batch-writes");
+    config.interests2_fallback = matches.is_present("interests2-fallback");
+    config.admin = matches.is_present("admin");
+    config.strict_unknown = matches.is_present("strict-unknown");
+    config.isolate_writes = matches.is_present("isolate-writes");
+    config.default_limit = matches.value_of("default-limit").unwrap().parse::<usize>().unwrap();
+    config.wal_path = matches.value_of("wal").map(|path| path.to_string());
+    config.wal_fsync = matches.is_present("wal-fsync");
+    config.replay_path = matches.value_of("replay").map(|path| path.to_string());
+    config.load_threads = matches.value_of("load-threads").unwrap().parse::<usize>().unwrap();
+    config.emit_empty_arrays = matches.is_present("emit-empty-arrays");
+    config.max_response_bytes = matches.value_of("max-response-bytes").unwrap().parse::<usize>().unwrap();
+    config.self_likes = match matches.value_of("self-likes").unwrap() {
+        "accept" => storage::SelfLikes::Accept,
+        "reject" => storage::SelfLikes::Reject,
+        "drop" => storage::SelfLikes::Drop,
+        _ => unreachable!(),
+    };
+    for index in matches.values_of("index-set").into_iter().flatten() {
+        match index {
+            "sname" => config.index_sname = true,
+            "sname_prefix" => config.index_sname_prefix = true,
+            _ => unreachable!(),
+        }
+    }
+    config
+}
+
+/// --profile с переопределениями из отдельных флагов.
+fn profile_from_matches(matches: &clap::ArgMatches) -> profile::Profile {
+    let mut profile = profile::Profile::by_name(matches.value_of("profile").unwrap()).unwrap();
+    if let Some(read_buffer) = matches.value_of("read-buffer") {
+        profile.read_buffer = read_buffer.parse::<usize>().unwrap();
+    }
+    if let Some(events) = matches.value_of("events") {
+        profile.events_capacity = events.parse::<usize>().unwrap();
+    }
+    if let Some(backlog) = matches.value_of("backlog") {
+        profile.backlog = backlog.parse::<i32>().unwrap();
+    }
+    if let Some(cache_size) = matches.value_of("cache-size") {
+        profile.cache_size = cache_size.parse::<usize>().unwrap();
+    }
+    profile
+}
+
+extern "C" fn handle_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+fn install_shutdown_handler() -> nix::Result<()> {
+    use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+
+    let action = SigAction::new(SigHandler::Handler(handle_shutdown), SaFlags::empty(), SigSet::empty());
+    unsafe {
+        sigaction(Signal::SIGTERM, &action)?;
+        sigaction(Signal::SIGINT, &action)?;
+    }
+    Ok(())
+}
+
+/// CPU, на которых потоку разрешено работать (cpuset контейнера, taskset), по возрастанию номера.
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> nix::Result<Vec<usize>> {
+    let mut cpu_set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    // pid 0 - вызывающий поток
+    if unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut cpu_set) } == -1 {
+        return Err(nix::Error::last());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize).filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &cpu_set) }).collect())
+}
+
+/// Привязывает текущий поток к разрешенному CPU номер thread_id по модулю их числа, чтобы connections потока
+/// не переезжали между кэшами ядер.
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(thread_id: usize) -> nix::Result<usize> {
+    use nix::sched::{CpuSet, sched_setaffinity};
+    use nix::unistd::Pid;
+
+    let cpus = allowed_cpus()?;
+    let cpu = cpus[thread_id % cpus.len()];
+    let mut cpu_set = CpuSet::new();
+    cpu_set.set(cpu)?;
+    // pid 0 - вызывающий поток
+    sched_setaffinity(Pid::from_raw(0), &cpu_set)?;
+    Ok(cpu)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_thread_id: usize) -> nix::Result<usize> {
+    Err(nix::Error::UnsupportedOperation)
+}
+
+/// Закрывает соединения без чтения и записи дольше idle_timeout, возвращает их число. Вызывается из того же потока poll,
+/// что и try_read_and_process, так что с обработкой запросов не пересекается.
+fn close_idle(connections: &spin::Mutex<HashMap<usize, Connection>>, now: Instant, idle_timeout: Duration) -> usize {
+    let mut connections = connections.lock();
+    let before = connections.len();
+    connections.retain(|_, conn| now.duration_since(conn.last_active) < idle_timeout);
+    let closed = before - connections.len();
+    if closed > 0 {
+        debug!("closed {} idle connections", closed);
+    }
+    closed
+}
+
+/// Дописывает недописанные ответы всех соединений потока, пока они есть и не наступил deadline.
+fn drain_connections(connections: &spin::Mutex<HashMap<usize, Connection>>, storage: &Arc<RwLock<Storage>>, deadline: Instant) {
+    loop {
+        let mut pending = false;
+        for conn in connections.lock().values_mut() {
+            let mut remove_conn = false;
+            flush_pending(conn, &mut remove_conn, storage);
+            pending |= !remove_conn && conn.output_pending();
+        }
+        if !pending || Instant::now() >= deadline {
+            return;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Соединение на время обработки события вынимается из map и возвращается, если его не нужно закрывать:
+/// map блокируется дважды на событие, а не на каждое обращение к соединению. Удаленное соединение закрывается при drop.
+fn with_connection<F: FnOnce(&mut Connection, &mut bool)>(connections: &spin::Mutex<HashMap<usize, Connection>>, conn_id: usize, f: F) {
+    let conn = connections.lock().remove(&conn_id);
+    if let Some(mut conn) = conn {
+        let mut remove_conn = false;
+        f(&mut conn, &mut remove_conn);
+        if !remove_conn {
+            connections.lock().insert(conn_id, conn);
+        }
+    }
+}
+
+fn handle_event(connections: &spin::Mutex<HashMap<usize, Connection>>, storage: &Arc<RwLock<storage::Storage>>, readiness: Ready, conn_options: ConnOptions, thread_id: usize, conn_id: usize) {
+    with_connection(connections, conn_id, |conn, remove_conn| {
+        if readiness.is_writable() {
+            flush_pending(conn, remove_conn, storage);
+        }
+        // readable пришел, пока чтение стояло, и при edge-triggered не повторится: продолжаем по writable
+        if (readiness.is_readable() || conn.read_paused) && !*remove_conn {
+            try_read_and_process(conn, connections, storage, false, conn_options, remove_conn, thread_id, conn_id);
+        }
+    });
+}
+
+/// connections - остальные соединения потока, в них могут уйти ответы-потоки (process::take_streams).
+/// Пока в write_buf есть недописанный ответ или идет выгрузка, новые данные не читаются: запросы остаются в сокете,
+/// а не копят ответы в памяти.
+fn try_read_and_process(conn: &mut Connection, connections: &spin::Mutex<HashMap<usize, Connection>>, storage: &Arc<RwLock<storage::Storage>>, after_accept: bool, conn_options: ConnOptions, remove_conn: &mut bool, thread_id: usize, conn_id: usize) {
+    if conn.output_pending() {
+        conn.read_paused = true;
+        return;
+    }
+    // после паузы в буфере могут ждать уже прочитанные запросы
+    let resumed = mem::replace(&mut conn.read_paused, false);
+    match try_read(conn, storage, after_accept, conn_options.record_stats, conn_options.max_request) {
+        Ok(new_data) => if new_data || resumed {
+            process_buffered(conn, connections, storage, conn_options, remove_conn, thread_id, conn_id)
+        },
+        Err(_err) => *remove_conn = true,
+    }
+}
+
+/// Обрабатывает запросы, уже лежащие в буфере соединения: за одно чтение их может прийти несколько (pipelining).
+/// Пока ответ на запрос отложен (batch_writes), следующие ждут в буфере, чтобы ответы не поменялись местами.
+fn process_buffered(conn: &mut Connection, connections: &spin::Mutex<HashMap<usize, Connection>>, storage: &Arc<RwLock<storage::Storage>>, conn_options: ConnOptions, remove_conn: &mut bool, thread_id: usize, conn_id: usize) {
+    while !*remove_conn && process_next(conn, connections, storage, conn_options, remove_conn, thread_id, conn_id) {}
 }
 
-fn try_read_and_process(connections: &spin::Mutex<HashMap<usize, Connection>>, storage: &Arc<RwLock<storage::Storage>>, after_accept: bool, record_stats: bool, cache: bool, remove_conn: &mut bool, thread_id: usize, conn_id: usize) {
+/// Один запрос из начала буфера; true - запрос обработан и ответ отправлен, можно брать следующий.
+fn process_next(conn: &mut Connection, connections: &spin::Mutex<HashMap<usize, Connection>>, storage: &Arc<RwLock<storage::Storage>>, conn_options: ConnOptions, remove_conn: &mut bool, thread_id: usize, conn_id: usize) -> bool {
     let mut full_request: Option<Vec<u8>> = None;
-    if let Some(conn) = connections.lock().get_mut(&conn_id) {
-        match try_read(conn, &storage, after_accept, record_stats) {
-            Ok(new_data) => {
-                if new_data {
-                    let request = conn.buf[0..conn.len].to_vec(); // TODO avoid clone
-                    match can_process_request(request.as_slice()) {
-                        Ok(can_process) => if can_process {
-                            full_request = Some(request);
-                        },
-                        Err(status_code) => {
-                            send_response(&status_response2(status_code), conn, remove_conn, &storage);
-                        }
-                    };
-                } else {}
-            }
-            Err(_err) => {
+    {
+        if conn.len == 0 || conn.awaiting_reply {
+            return false;
+        }
+        if conn.output_pending() {
+            // предыдущий ответ не ушел, следующие запросы ждут writable
+            conn.read_paused = true;
+            return false;
+        }
+        let mut request = conn.buf[0..conn.len].to_vec(); // TODO avoid clone
+        #[cfg(feature = "unchecked-utf8")]
+            let can_process_result = can_process_request_bytes(request.as_slice());
+        #[cfg(not(feature = "unchecked-utf8"))]
+            let can_process_result = can_process_request(request.as_slice());
+        match can_process_result {
+            Ok(can_process) => if can_process {
+                // ответ может быть отложен до apply_pending_writes, буфер нужен для следующего запроса;
+                // начало следующего запроса, пришедшее вместе с этим, переносится в начало буфера
+                let rest = request.split_off(request_len(&request).unwrap_or(request.len()));
+                conn.len = rest.len();
+                conn.continue_sent = false;
+                if !conn_options.reuse_buffers && conn.buf.len() > conn_options.read_buffer {
+                    conn.buf = vec![0; conn_options.read_buffer.max(rest.len())];
+                }
+                conn.buf[..rest.len()].copy_from_slice(&rest);
+                if conn_options.max_rps != 0 && !conn.bucket.try_acquire(Instant::now()) {
+                    write_and_send(conn, conn_options.reuse_buffers, remove_conn, storage, |response, keep_alive| write_status_response(response, keep_alive, StatusCode::TOO_MANY_REQUESTS));
+                    if conn_options.close_on_rate_limit {
+                        *remove_conn = true;
+                    }
+                    return true;
+                }
+                full_request = Some(request);
+            } else if conn.len == conn.buf.len() {
+                // буфер вырос до max_request, а запрос не закончился: остаток тела уже не разобрать
+                conn.len = 0;
+                write_and_send(conn, conn_options.reuse_buffers, remove_conn, storage, |response, keep_alive| write_status_response(response, keep_alive, StatusCode::PAYLOAD_TOO_LARGE));
                 *remove_conn = true;
+            } else if !conn.continue_sent && expects_continue(request.as_slice()) {
+                send_continue(conn, remove_conn, storage);
+            },
+            Err(status_code) => {
+                // где кончается испорченный запрос, неизвестно, буфер сбрасывается целиком
+                conn.len = 0;
+                conn.continue_sent = false;
+                write_and_send(conn, conn_options.reuse_buffers, remove_conn, storage, |response, keep_alive| write_status_response(response, keep_alive, status_code));
             }
-        }
+        };
     }
-    if full_request.is_some() {
-        let result = process_request(full_request.unwrap().as_slice(), &storage, record_stats, cache, thread_id, conn_id, &mut |body: Result<Cow<[u8]>, StatusCode>| {
-            let storage = storage.clone();
-            let response = match body {
-                Ok(body) => "HTTP/1.1 200 ?\r\n".to_string() +
-                    &COMMON_HEADERS_AS_STR +
-                    "content-length: " + &body.len().to_string() + "\r\n\r\n" +
-                    std::str::from_utf8(&body).expect("from_utf8(&body)"),
-                Err(status_code) => status_response2(status_code)
-            };
-            if let Some(conn) = connections.lock().get_mut(&conn_id) {
-                send_response(&response, conn, remove_conn, &storage);
-            }
+    if full_request.is_none() {
+        return false;
+    }
+    let mut replied = false;
+    {
+        let request = dechunk_request(full_request.unwrap());
+        let result = parse_full_request(request.as_slice()).and_then(|(path, query, body, keep_alive, method)| {
+            conn.keep_alive = keep_alive;
+            conn.head_only = method == HttpMethod::Head;
+            process::process(method, path, query, body, storage, conn_options.record_stats, conn_options.cache, thread_id, conn_id, &mut |body: Result<Cow<[u8]>, StatusCode>| {
+                replied = true;
+                write_and_send(conn, conn_options.reuse_buffers, remove_conn, storage, |response, keep_alive| match body {
+                    Ok(body) => write_ok_response(response, keep_alive, &body),
+                    Err(status_code) => write_status_response(response, keep_alive, status_code),
+                });
+            })
         });
         if result.is_err() {
-            if let Some(conn) = connections.lock().get_mut(&conn_id) {
-                send_response(&status_response2(result.unwrap_err()), conn, remove_conn, &storage);
+            replied = true;
+            write_and_send(conn, conn_options.reuse_buffers, remove_conn, storage, |response, keep_alive| write_status_response(response, keep_alive, result.unwrap_err()));
+        }
+        for (stream_conn_id, cursor) in process::take_streams() {
+            if stream_conn_id == conn_id {
+                replied = true;
+                send_stream(conn, remove_conn, storage, cursor);
+            } else {
+                // ответ другому соединению потока, у него свой признак закрытия
+                let mut connections = connections.lock();
+                if let Some(other) = connections.get_mut(&stream_conn_id) {
+                    let mut remove_other = false;
+                    send_stream(other, &mut remove_other, storage, cursor);
+                    if remove_other {
+                        connections.remove(&stream_conn_id);
+                    }
+                }
             }
         }
     }
+    if !replied {
+        // ответ придет из apply_pending_writes
+        conn.awaiting_reply = true;
+    }
+    replied
+}
+
+/// Ответ собирается в буфер соединения, который при reuse_buffers переиспользуется следующим запросом.
+fn write_and_send<WF: FnOnce(&mut Vec<u8>, bool)>(conn: &mut Connection, reuse_buffers: bool, remove_conn: &mut bool, storage: &Arc<RwLock<Storage>>, write_f: WF) {
+    let mut response = mem::take(&mut conn.response);
+    response.clear();
+    write_f(&mut response, conn.keep_alive);
+    send_response(&response, conn, remove_conn, storage);
+    if reuse_buffers {
+        conn.response = response;
+    }
 }
 
-fn send_response(response: &String, conn: &mut Connection, remove_conn: &mut bool, storage: &Arc<RwLock<Storage>>) {
-    conn.len = 0;
-    match conn.stream.write_bufs(&[response.as_bytes().into()]) {
+/// Не поместившийся в сокет остаток ответа копируется в write_buf и дописывается flush_pending по writable.
+/// На HEAD уходят только статус и заголовки, content-length остается от полного тела.
+fn send_response(response: &[u8], conn: &mut Connection, remove_conn: &mut bool, storage: &Arc<RwLock<Storage>>) {
+    let response = if conn.head_only {
+        let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").map_or(response.len(), |index| index + 4);
+        &response[..head_end]
+    } else {
+        response
+    };
+    if conn.output_pending() {
+        // предыдущий ответ еще не ушел, новый встает за ним
+        conn.write_buf.extend_from_slice(response);
+        return;
+    }
+    match conn.stream.write_bufs(&[response.into()]) {
         Ok(len) => {
 //            debug!("write {}", len);
+            conn.last_active = Instant::now();
             if len != response.len() {
-                error!("failed to write full result");
-                panic!("failed to write full result"); // TODO
+                debug!("partial write {}/{}", len, response.len());
+                buffer_pending(conn, &response[len..]);
+            } else if !conn.keep_alive {
+                *remove_conn = true;
             }
         }
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+            buffer_pending(conn, response);
+        }
         Err(err) => {
-            // TODO WouldBlock ?
             error!("write error: {}", err);
-            storage.read().expect("storage.read()").stats.register_write_error(err.kind());
+            read_lock(storage).stats.register_write_error(err.kind());
             *remove_conn = true;
         }
     }
 }
 
-// based on mio
-fn bind(addr: &SocketAddr) -> io::Result<TcpListener> {
-    let tcp_builder = TcpBuilder::new_v4()?;
+fn buffer_pending(conn: &mut Connection, tail: &[u8]) {
+    conn.write_buf.clear();
+    conn.write_pos = 0;
+    conn.write_buf.extend_from_slice(tail);
+}
 
-    tcp_builder.reuse_address(true)?;
-    #[cfg(unix)]
-        tcp_builder.reuse_port(true)?;
+/// Дописывает write_buf, пока сокет принимает данные; когда он дописан, а выгрузка не закончена - берет ее следующую часть.
+/// Буфер остается выделенным для следующих частичных записей.
+fn flush_pending(conn: &mut Connection, remove_conn: &mut bool, storage: &Arc<RwLock<Storage>>) {
+    loop {
+        while conn.write_pos < conn.write_buf.len() {
+            match conn.stream.write(&conn.write_buf[conn.write_pos..]) {
+                Ok(0) => {
+                    *remove_conn = true;
+                    return;
+                }
+                Ok(len) => {
+                    conn.write_pos += len;
+                    conn.last_active = Instant::now();
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    error!("write error: {}", err);
+                    read_lock(storage).stats.register_write_error(err.kind());
+                    *remove_conn = true;
+                    return;
+                }
+            }
+        }
+        if conn.write_buf.is_empty() && conn.export.is_none() {
+            return;
+        }
+        conn.write_buf.clear();
+        conn.write_pos = 0;
+        if conn.export.is_none() {
+            if !conn.keep_alive {
+                *remove_conn = true;
+            }
+            return;
+        }
+        next_export_chunk(conn, remove_conn, storage);
+        if *remove_conn {
+            return;
+        }
+    }
+}
+
+/// Ответ с transfer-encoding: chunked на /admin/export. Сразу уходят только заголовки, тело по частям
+/// дописывает flush_pending: в памяти одна часть, блокировка storage берется на каждую часть отдельно.
+fn send_stream(conn: &mut Connection, remove_conn: &mut bool, storage: &Arc<RwLock<Storage>>, cursor: process::ExportCursor) {
+    // недописанные ответы уходят перед выгрузкой: она встает в write_buf за ними
+    conn.write_buf.extend_from_slice(b"HTTP/1.1 200 ?\r\ncontent-type: application/x-ndjson\r\n");
+    // content-type свой, остальные общие
+    conn.write_buf.extend_from_slice(common_headers(conn.keep_alive).split_once("\r\n").unwrap().1.as_bytes());
+    conn.write_buf.extend_from_slice(b"transfer-encoding: chunked\r\n\r\n");
+    if !conn.head_only {
+        conn.export = Some(cursor);
+    }
+    flush_pending(conn, remove_conn, storage);
+}
+
+/// Следующая часть выгрузки отдельным chunk в пустой write_buf, после последней - завершающий пустой chunk.
+fn next_export_chunk(conn: &mut Connection, remove_conn: &mut bool, storage: &Arc<RwLock<Storage>>) {
+    let mut batch = Vec::new();
+    let more = match conn.export.as_mut().unwrap().next_batch(storage, &mut batch) {
+        Ok(more) => more,
+        Err(err) => {
+            error!("export error: {}", err);
+            *remove_conn = true;
+            return;
+        }
+    };
+    if !batch.is_empty() {
+        conn.write_buf.extend_from_slice(format!("{:x}\r\n", batch.len()).as_bytes());
+        conn.write_buf.extend_from_slice(&batch);
+        conn.write_buf.extend_from_slice(b"\r\n");
+    }
+    if !more {
+        conn.write_buf.extend_from_slice(b"0\r\n\r\n");
+        conn.export = None;
+    }
+}
+
+/// Клиент с Expect: 100-continue не отправит тело, пока не получит 100 Continue. Полученная часть запроса остается в буфере.
+fn send_continue(conn: &mut Connection, remove_conn: &mut bool, storage: &Arc<RwLock<Storage>>) {
+    let response: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+    if conn.output_pending() {
+        conn.write_buf.extend_from_slice(response);
+        conn.continue_sent = true;
+        return;
+    }
+    match conn.stream.write_bufs(&[response.into()]) {
+        Ok(len) => {
+            if len != response.len() {
+                error!("failed to write full 100 Continue");
+                *remove_conn = true;
+            }
+            conn.continue_sent = true;
+        }
+        Err(err) => {
+            error!("write error: {}", err);
+            read_lock(storage).stats.register_write_error(err.kind());
+            *remove_conn = true;
+        }
+    }
+}
+
+/// TCP_NODELAY и SO_KEEPALIVE с временем простоя до первой пробы (TCP_KEEPIDLE через net2) и, на Linux, интервалом между пробами.
+fn set_socket_options(stream: &TcpStream, conn_options: &ConnOptions) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_keepalive(conn_options.keepalive_idle)?;
+    #[cfg(target_os = "linux")]
+    {
+        if conn_options.keepalive_idle.is_some() {
+            set_tcp_option(stream, libc::TCP_KEEPINTVL, conn_options.keepalive_interval.as_secs() as libc::c_int)?;
+        }
+    }
+    Ok(())
+}
+
+/// Значения из getsockopt, а не из аргументов: видно, что ядро их приняло.
+fn describe_socket_options(stream: &TcpStream) -> String {
+    let mut description = format!("TCP_NODELAY {:?}, SO_KEEPALIVE {:?}", stream.nodelay(), stream.keepalive());
+    #[cfg(target_os = "linux")]
+    {
+        description += &format!(", TCP_KEEPINTVL {:?}", tcp_option(stream, libc::TCP_KEEPINTVL));
+    }
+    description
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_option(stream: &TcpStream, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, option,
+                         &value as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result == -1 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_option(stream: &TcpStream, option: libc::c_int) -> io::Result<libc::c_int> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, option, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    if result == -1 { Err(io::Error::last_os_error()) } else { Ok(value) }
+}
+
+// based on mio
+fn bind(addr: &SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    let tcp_builder = listener_builder(addr)?;
 
     tcp_builder.bind(addr)?;
 
-    let listener = tcp_builder.listen(1024)?;
+    let listener = tcp_builder.listen(backlog)?;
     TcpListener::from_std(listener)
 }
 
-fn try_read(conn: &mut Connection, storage: &Arc<RwLock<storage::Storage>>, after_accept: bool, record_stats: bool) -> Result<bool, io::Error> {
+/// Сокет под адрес: для IPv6 - dual stack, IPv4-клиенты приходят на него как ::ffff:a.b.c.d.
+fn listener_builder(addr: &SocketAddr) -> io::Result<TcpBuilder> {
+    let tcp_builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let tcp_builder = TcpBuilder::new_v6()?;
+            tcp_builder.only_v6(false)?;
+            tcp_builder
+        }
+    };
+
+    tcp_builder.reuse_address(true)?;
+    #[cfg(unix)]
+        tcp_builder.reuse_port(true)?;
+    Ok(tcp_builder)
+}
+
+/// Заполненный буфер удваивается, но не больше max_request; дальше чтение останавливается на полном буфере.
+fn try_read(conn: &mut Connection, storage: &Arc<RwLock<storage::Storage>>, after_accept: bool, record_stats: bool, max_request: usize) -> Result<bool, io::Error> {
     let mut new_data = false;
     loop {
+        if conn.len == conn.buf.len() {
+            if conn.buf.len() >= max_request {
+                return Ok(new_data);
+            }
+            let size = (conn.buf.len() * 2).max(1).min(max_request);
+            conn.buf.resize(size, 0);
+        }
         match conn.stream.read_bufs(&mut [IoVec::from_bytes_mut(&mut conn.buf[conn.len..]).expect("IoVec::from_bytes_mut")]) {
             Ok(len2) => {
 //                debug!("{}+{}", conn.len, len2);
@@ -302,12 +967,13 @@ fn try_read(conn: &mut Connection, storage: &Arc<RwLock<storage::Storage>>, afte
                 new_data = true;
                 if record_stats {
                     if after_accept {
-                        storage.read().expect("storage.read()").stats.register_accept_and_read();
+                        read_lock(storage).stats.register_accept_and_read();
                     } else {
-                        storage.read().expect("storage.read()").stats.register_read();
+                        read_lock(storage).stats.register_read();
                     }
                 }
                 conn.len += len2;
+                conn.last_active = Instant::now();
             }
             Err(err) => {
                 if err.kind() == ErrorKind::WouldBlock {
@@ -315,7 +981,7 @@ fn try_read(conn: &mut Connection, storage: &Arc<RwLock<storage::Storage>>, afte
                     return Ok(new_data);
                 } else {
                     error!("read error: {}", err);
-                    storage.read().expect("storage.read()").stats.register_read_error(err.kind());
+                    read_lock(storage).stats.register_read_error(err.kind());
                     return Err(err);
                 }
             }
@@ -323,12 +989,30 @@ fn try_read(conn: &mut Connection, storage: &Arc<RwLock<storage::Storage>>, afte
     }
 }
 
-fn status_response2(status_code: StatusCode) -> String {
-    "HTTP/1.1 ".to_string() + status_code.as_str() + " ?\r\n" +
-        &COMMON_HEADERS_AS_STR +
-        "content-length: 0\r\n\r\n"
+fn common_headers(keep_alive: bool) -> &'static str {
+    if keep_alive { &COMMON_HEADERS_AS_STR } else { &COMMON_HEADERS_CLOSE_AS_STR }
 }
 
+fn write_ok_response(response: &mut Vec<u8>, keep_alive: bool, body: &[u8]) {
+    response.extend_from_slice(b"HTTP/1.1 200 ?\r\n");
+    response.extend_from_slice(common_headers(keep_alive).as_bytes());
+    write!(response, "content-length: {}\r\n\r\n", body.len()).unwrap();
+    response.extend_from_slice(body);
+}
+
+fn write_status_response(response: &mut Vec<u8>, keep_alive: bool, status_code: StatusCode) {
+    response.extend_from_slice(b"HTTP/1.1 ");
+    response.extend_from_slice(status_code.as_str().as_bytes());
+    response.extend_from_slice(b" ?\r\n");
+    response.extend_from_slice(common_headers(keep_alive).as_bytes());
+    if status_code == StatusCode::SERVICE_UNAVAILABLE {
+        // storage подменяется после reload, повторить можно почти сразу
+        response.extend_from_slice(b"retry-after: 1\r\n");
+    }
+    response.extend_from_slice(b"content-length: 0\r\n\r\n");
+}
+
+#[cfg(any(not(feature = "unchecked-utf8"), test))]
 fn can_process_request(request: &[u8]) -> Result<bool, StatusCode> {
     // TODO from_utf8_unchecked
     // TODO для этой функции не нужны строки
@@ -342,20 +1026,20 @@ fn can_process_request(request: &[u8]) -> Result<bool, StatusCode> {
     };
 //    debug!("head {}", head);
 //    debug!("body {}", body);
-    if head.starts_with("GET ") {
+    // у DELETE тело не ожидается, как у GET
+    if head.starts_with("GET ") || head.starts_with("HEAD ") || head.starts_with("DELETE ") {
         return Ok(true);
     }
     if !head.starts_with("POST ") {
-        error!("only GET and POST are supported: #{}#", head);
+        error!("only GET, HEAD, POST and DELETE are supported: #{}#", head);
         return Err(StatusCode::BAD_REQUEST);
     }
+    if is_chunked(head.as_bytes()) {
+        return decode_chunked(body.as_bytes()).map(|decoded| decoded.is_some());
+    }
     for line in head.split("\n") {
 //        debug!("line {}", line);
-        if line.contains("Content-Length") {
-            let index = line.find(':').ok_or_else(|| {
-                error!("bad content-length: {}", line);
-                StatusCode::BAD_REQUEST
-            })?;
+        if let Some(index) = header_colon(line.as_bytes(), b"content-length") {
             let value = line[index + 1..].trim();
 //            debug!("value {}", value);
             let length = value.parse::<usize>().or_else(|_| {
@@ -363,22 +1047,90 @@ fn can_process_request(request: &[u8]) -> Result<bool, StatusCode> {
                 Err(StatusCode::BAD_REQUEST)
             })?;
 //            debug!("{} -> {} {}", line, length, body.len());
-            if length < body.len() && body[length..].trim() != "" {
-                error!("extra content: {}", &body[length..]);
-            }
+            // после тела может идти следующий запрос, его отделяет request_len
             return Ok(length <= body.len());
         }
     }
     Ok(false)
 }
 
-fn process_request<RF: FnMut(Result<Cow<[u8]>, StatusCode>)>(request: &[u8], storage: &Arc<RwLock<storage::Storage>>, record_stats: bool, cache: bool, thread_id: usize, conn_id: usize, resp_f: RF) -> Result<(), StatusCode> {
-    let (path, query, body) = parse_request(request)?;
-    process::process(path, query, body, storage, record_stats, cache, thread_id, conn_id, resp_f)
-//    Err(StatusCode::BAD_REQUEST)
+/// can_process_request без проверки UTF-8: заголовки разбираются как байты.
+#[cfg(any(feature = "unchecked-utf8", test))]
+fn can_process_request_bytes(request: &[u8]) -> Result<bool, StatusCode> {
+    let index0 = match find_bytes(request, b"\r\n\r\n") {
+        Some(index0) => index0,
+        None => return Ok(false),
+    };
+    let head = trim_start_bytes(&request[..index0]);
+    let body = &request[index0 + 4..];
+    if head.starts_with(b"GET ") || head.starts_with(b"HEAD ") || head.starts_with(b"DELETE ") {
+        return Ok(true);
+    }
+    if !head.starts_with(b"POST ") {
+        error!("only GET, HEAD, POST and DELETE are supported: #{}#", String::from_utf8_lossy(head));
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if is_chunked(head) {
+        return decode_chunked(body).map(|decoded| decoded.is_some());
+    }
+    for line in head.split(|b| *b == b'\n') {
+        if let Some(index) = header_colon(line, b"content-length") {
+            // только сама длина проверяется как строка
+            let length = std::str::from_utf8(&line[index + 1..]).ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .ok_or_else(|| {
+                    error!("bad content-length: {}", String::from_utf8_lossy(line));
+                    StatusCode::BAD_REQUEST
+                })?;
+            return Ok(length <= body.len());
+        }
+    }
+    Ok(false)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+impl HttpVersion {
+    /// Все, кроме явного HTTP/1.0, считается 1.1 с keep-alive.
+    fn parse(version: &[u8]) -> HttpVersion {
+        if version == b"HTTP/1.0" { HttpVersion::Http10 } else { HttpVersion::Http11 }
+    }
+
+    /// Заголовок Connection важнее версии: close закрывает и 1.1, keep-alive оставляет открытым и 1.0.
+    fn keep_alive(self, head: &[u8]) -> bool {
+        let mut keep_alive = self == HttpVersion::Http11;
+        for line in head.split(|b| *b == b'\n') {
+            if let Some(index) = header_colon(line, b"connection") {
+                for token in std::str::from_utf8(&line[index + 1..]).unwrap_or("").split(',') {
+                    if token.trim().eq_ignore_ascii_case("close") {
+                        return false;
+                    }
+                    if token.trim().eq_ignore_ascii_case("keep-alive") {
+                        keep_alive = true;
+                    }
+                }
+            }
+        }
+        keep_alive
+    }
+}
+
+/// Путь, query, тело, keep-alive после ответа и метод.
+type ParsedRequest<'a> = (&'a str, Option<&'a str>, Option<&'a [u8]>, bool, HttpMethod);
+
+fn parse_full_request(request: &[u8]) -> Result<ParsedRequest<'_>, StatusCode> {
+    #[cfg(feature = "unchecked-utf8")]
+        return parse_request_bytes(request);
+    #[cfg(not(feature = "unchecked-utf8"))]
+        return parse_request(request);
 }
 
-fn parse_request(request: &[u8]) -> Result<(&str, Option<&str>, Option<&[u8]>), StatusCode> {
+#[cfg(any(not(feature = "unchecked-utf8"), test))]
+fn parse_request(request: &[u8]) -> Result<ParsedRequest<'_>, StatusCode> {
     // TODO from_utf8_unchecked
     // TODO для этой функции не нужны строки
     let request = std::str::from_utf8(request).or_else(|_| Err(StatusCode::BAD_REQUEST))?;
@@ -401,11 +1153,14 @@ fn parse_request(request: &[u8]) -> Result<(&str, Option<&str>, Option<&[u8]>),
         StatusCode::BAD_REQUEST
     })?;
     let url = &line[index1 + 1..index2];
+    let method = HttpMethod::parse(&line.as_bytes()[..index1]);
+    let version = HttpVersion::parse(&line.as_bytes()[index2 + 1..]);
 //    debug!("url: {}", url);
-    let index3 = url.find('?').ok_or(StatusCode::NOT_FOUND)?;
-    let path = &url[0..index3];
+    let (path, query) = match url.find('?') {
+        Some(index3) => (&url[0..index3], Some(&url[index3 + 1..])),
+        None => (url, None), // отсутствие query process считает пустым списком параметров
+    };
 //    debug!("path: {}", path);
-    let query = Some(&url[index3 + 1..]);
 //    debug!("query: {}", query.unwrap());
     let index4 = match request.find("\r\n\r\n") {
         Some(index) => index + 4,
@@ -420,7 +1175,151 @@ fn parse_request(request: &[u8]) -> Result<(&str, Option<&str>, Option<&[u8]>),
     } else {
 //        debug!("body empty");
     }
-    Ok((path, query, body.map(|b| b.as_bytes())))
+    Ok((path, query, body.map(|b| b.as_bytes()), version.keep_alive(&request.as_bytes()[..index4]), method))
+}
+
+/// parse_request без проверки UTF-8 всего запроса: первая строка разбирается как байты,
+/// проверяется только url, который дальше декодируется как строка.
+#[cfg(any(feature = "unchecked-utf8", test))]
+fn parse_request_bytes(request: &[u8]) -> Result<ParsedRequest<'_>, StatusCode> {
+    let request = trim_start_bytes(request);
+    let index0 = find_bytes(request, b"\r\n").ok_or_else(|| {
+        error!("bad request (first line 1): {}", String::from_utf8_lossy(request));
+        StatusCode::BAD_REQUEST
+    })?;
+    let line = &request[..index0];
+    let index1 = line.iter().position(|b| *b == b' ');
+    let index2 = line.iter().rposition(|b| *b == b' ');
+    let url = match (index1, index2) {
+        (Some(index1), Some(index2)) if index1 < index2 => &line[index1 + 1..index2],
+        (Some(index1), Some(_)) => &line[index1 + 1..index1 + 1],
+        _ => {
+            error!("bad request (first line 2): {}", String::from_utf8_lossy(line));
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    let method = HttpMethod::parse(&line[..index1.unwrap()]);
+    let version = HttpVersion::parse(&line[index2.unwrap() + 1..]);
+    let url = std::str::from_utf8(url).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let (path, query) = match url.find('?') {
+        Some(index3) => (&url[0..index3], Some(&url[index3 + 1..])),
+        None => (url, None),
+    };
+    let index4 = match find_bytes(request, b"\r\n\r\n") {
+        Some(index) => index + 4,
+        None => {
+            error!("bad request (head -> body): {}", String::from_utf8_lossy(request));
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    let body = if index4 == request.len() { None } else { Some(&request[index4..]) };
+    Ok((path, query, body, version.keep_alive(&request[..index4]), method))
+}
+
+/// Позиция ':' в строке заголовка name; имена заголовков сравниваются без учета регистра.
+fn header_colon(line: &[u8], name: &[u8]) -> Option<usize> {
+    let index = line.iter().position(|b| *b == b':')?;
+    if line[..index].eq_ignore_ascii_case(name) { Some(index) } else { None }
+}
+
+/// Среди заголовков есть Transfer-Encoding, последнее кодирование которого - chunked.
+fn is_chunked(head: &[u8]) -> bool {
+    head.split(|b| *b == b'\n').any(|line| header_colon(line, b"transfer-encoding").is_some_and(|index| {
+        std::str::from_utf8(&line[index + 1..]).is_ok_and(|value| value.trim().to_ascii_lowercase().ends_with("chunked"))
+    }))
+}
+
+/// Тело Transfer-Encoding: chunked без размеров чанков и число занятых им байт.
+/// None - завершающий чанк 0 и пустая строка после него еще не пришли.
+fn decode_chunked(body: &[u8]) -> Result<Option<(Vec<u8>, usize)>, StatusCode> {
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    loop {
+        let line_end = match find_bytes(&body[pos..], b"\r\n") {
+            Some(index) => pos + index,
+            None => return Ok(None),
+        };
+        let line = &body[pos..line_end];
+        // расширения после ';' не нужны
+        let size = std::str::from_utf8(line.split(|b| *b == b';').next().unwrap()).ok()
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or_else(|| {
+                error!("bad chunk size: {}", String::from_utf8_lossy(line));
+                StatusCode::BAD_REQUEST
+            })?;
+        pos = line_end + 2;
+        if size == 0 {
+            // трейлеры, если есть, заканчиваются пустой строкой
+            let rest = &body[pos..];
+            let trailers_end = if rest.starts_with(b"\r\n") { Some(2) } else { find_bytes(rest, b"\r\n\r\n").map(|index| index + 4) };
+            return Ok(trailers_end.map(|trailers_end| (decoded, pos + trailers_end)));
+        }
+        if body.len() - pos < size.saturating_add(2) {
+            return Ok(None);
+        }
+        if &body[pos + size..pos + size + 2] != b"\r\n" {
+            error!("chunk of {} bytes is not followed by CRLF", size);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        decoded.extend_from_slice(&body[pos..pos + size]);
+        pos += size + 2;
+    }
+}
+
+/// Запрос с chunked телом переписывается в запрос с обычным телом, дальше он разбирается как любой другой.
+/// Вызывается после can_process_request, так что тело уже проверено.
+fn dechunk_request(request: Vec<u8>) -> Vec<u8> {
+    let head_end = match find_bytes(&request, b"\r\n\r\n") {
+        Some(index) => index + 4,
+        None => return request,
+    };
+    if !is_chunked(&request[..head_end]) {
+        return request;
+    }
+    match decode_chunked(&request[head_end..]) {
+        Ok(Some((body, _))) => {
+            let mut dechunked = request[..head_end].to_vec();
+            dechunked.extend_from_slice(&body);
+            dechunked
+        }
+        _ => request,
+    }
+}
+
+/// Длина первого запроса в буфере, для которого can_process_request вернул true: заголовки и тело
+/// по content-length или chunked. Дальше может лежать следующий запрос того же соединения.
+fn request_len(request: &[u8]) -> Option<usize> {
+    let head_end = find_bytes(request, b"\r\n\r\n")? + 4;
+    let head = &request[..head_end];
+    if is_chunked(head) {
+        return decode_chunked(&request[head_end..]).ok()?.map(|(_, len)| head_end + len);
+    }
+    let length = head.split(|b| *b == b'\n')
+        .find_map(|line| header_colon(line, b"content-length").map(|index| &line[index + 1..]))
+        .map_or(Some(0), |value| std::str::from_utf8(value).ok().and_then(|value| value.trim().parse::<usize>().ok()))?;
+    Some((head_end + length).min(request.len()))
+}
+
+/// Заголовки получены полностью и среди них есть Expect: 100-continue.
+fn expects_continue(request: &[u8]) -> bool {
+    let head_end = match request.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(head_end) => head_end,
+        None => return false,
+    };
+    request[..head_end].split(|b| *b == b'\n').any(|line| {
+        line.len() > 7 && line[..7].eq_ignore_ascii_case(b"expect:") &&
+            std::str::from_utf8(&line[7..]).is_ok_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    })
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(any(feature = "unchecked-utf8", test))]
+fn trim_start_bytes(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(bytes.len());
+    &bytes[start..]
 }
 
 fn poll(poll: &mio::Poll, events: &mut Events) {
@@ -435,33 +1334,133 @@ fn poll(poll: &mio::Poll, events: &mut Events) {
             use libc::{self};
             use std::os::unix::io::AsRawFd;
 
+            // полный batch - признак, что готовых событий больше: они дочитываются следующими вызовами
+            // в тот же Events, не больше MAX_POLL_BATCHES раз, чтобы не задерживать обработку уже полученных
             events.events.clear();
-            unsafe {
-                let cnt = libc::epoll_wait(poll.as_raw_fd(),
-                                           events.events.as_mut_ptr(),
-                                           events.events.capacity() as i32,
-                                           0);
-                if cnt == -1 {
-                    panic!("epoll_wait error");
-                }
-                let cnt = cnt as usize;
-                events.events.set_len(cnt);
+            for _ in 0..MAX_POLL_BATCHES {
+                let len = events.events.len();
+                events.events.reserve(events.batch);
+                unsafe {
+                    let cnt = libc::epoll_wait(poll.as_raw_fd(),
+                                               events.events.as_mut_ptr().add(len),
+                                               events.batch as i32,
+                                               0);
+                    if cnt == -1 {
+                        panic!("epoll_wait error");
+                    }
+                    let cnt = cnt as usize;
+                    events.events.set_len(len + cnt);
 
-//                for i in 0..cnt {
-//                    if events.events[i].u64 as usize == usize::MAX {
-//                        events.events.remove(i);
-//                        return;
+//                    for i in 0..cnt {
+//                        if events.events[i].u64 as usize == usize::MAX {
+//                            events.events.remove(i);
+//                            return;
+//                        }
 //                    }
-//                }
+                    if cnt < events.batch {
+                        break;
+                    }
+                }
             }
         }
 }
 
 struct Connection {
     stream: TcpStream,
-    buf: [u8; 8192],
+    buf: Vec<u8>,
     len: usize,
-//    result: Vec<u8>,
+    response: Vec<u8>,
+    bucket: TokenBucket,
+    // 100 Continue уже отправлен для текущего запроса
+    continue_sent: bool,
+    // false для HTTP/1.0 и Connection: close: после ответа соединение закрывается
+    keep_alive: bool,
+    // текущий запрос - HEAD, тело ответа не отправляется
+    head_only: bool,
+    // ответ на запрос отложен, следующие запросы ждут его в буфере
+    awaiting_reply: bool,
+    // не поместившийся в сокет остаток ответов, дописывается по writable
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    // чтение и обработка остановлены, пока write_buf не допишется: клиент не забирает ответы
+    read_paused: bool,
+    // незаконченный /admin/export: следующая часть берется, когда write_buf допишется
+    export: Option<process::ExportCursor>,
+    // последнее чтение или запись, по нему закрываются простаивающие соединения
+    last_active: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, conn_options: ConnOptions) -> Connection {
+        Connection {
+            stream,
+            buf: vec![0; conn_options.read_buffer],
+            len: 0,
+            response: Vec::new(),
+            bucket: TokenBucket::new(conn_options.max_rps),
+            continue_sent: false,
+            keep_alive: true,
+            head_only: false,
+            awaiting_reply: false,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            export: None,
+            read_paused: false,
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Недописанный ответ или незаконченная выгрузка: следующие ответы встают за ними.
+    fn output_pending(&self) -> bool {
+        self.write_pos < self.write_buf.len() || self.export.is_some()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ConnOptions {
+    // начальный буфер чтения, растет для длинных запросов до max_request
+    read_buffer: usize,
+    max_request: usize,
+    reuse_buffers: bool,
+    // 0 - без ограничения
+    max_rps: u32,
+    close_on_rate_limit: bool,
+    // None - соединения без активности не закрываются
+    idle_timeout: Option<Duration>,
+    // None - SO_KEEPALIVE выключен
+    keepalive_idle: Option<Duration>,
+    keepalive_interval: Duration,
+    // статистика запросов (выключается --no-stats) и кэш ответов (--cache)
+    record_stats: bool,
+    cache: bool,
+}
+
+/// Ограничение частоты запросов на соединение: допускается всплеск до rate запросов, дальше rate в секунду.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> TokenBucket {
+        TokenBucket { rate: rate as f64, tokens: rate as f64, updated: Instant::now() }
+    }
+
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        if now > self.updated {
+            let elapsed = now - self.updated;
+            let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+            self.tokens = (self.tokens + elapsed_secs * self.rate).min(self.rate);
+            self.updated = now;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
 }
 
 struct ThreadData {
@@ -474,13 +1473,16 @@ struct ThreadData {
 pub struct Events {
     // based on mio
     events: Vec<libc::epoll_event>,
+    // событий за один epoll_wait
+    batch: usize,
 }
 
 #[cfg(target_os = "linux")]
 impl Events {
     pub fn with_capacity(capacity: usize) -> Events {
         Events {
-            events: Vec::with_capacity(capacity)
+            events: Vec::with_capacity(capacity),
+            batch: capacity,
         }
     }
 
@@ -544,3 +1546,964 @@ impl<'a> Iterator for EventIter<'a> {
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_conn_options() -> ConnOptions {
+        ConnOptions { read_buffer: 8192, max_request: 1 << 20, reuse_buffers: false, max_rps: 0, close_on_rate_limit: false, idle_timeout: None,
+                      keepalive_idle: None, keepalive_interval: Duration::from_secs(10), record_stats: false, cache: false }
+    }
+
+    #[test]
+    fn test_write_response_reuses_buffer() {
+        let mut response = Vec::new();
+        write_ok_response(&mut response, true, b"{\"accounts\":[]}");
+        assert!(std::str::from_utf8(&response).unwrap().starts_with("HTTP/1.1 200 ?\r\n"));
+        assert!(std::str::from_utf8(&response).unwrap().ends_with("content-length: 15\r\n\r\n{\"accounts\":[]}"));
+
+        // повторный ответ того же размера не должен перевыделять буфер
+        let ptr = response.as_ptr();
+        let capacity = response.capacity();
+        response.clear();
+        write_ok_response(&mut response, true, b"{\"accounts\":[]}");
+        assert_eq!(response.as_ptr(), ptr);
+        assert_eq!(response.capacity(), capacity);
+
+        response.clear();
+        write_status_response(&mut response, true, StatusCode::NOT_FOUND);
+        assert!(std::str::from_utf8(&response).unwrap().starts_with("HTTP/1.1 404 ?\r\n"));
+        assert_eq!(response.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_retry_after_on_503() {
+        let activity = Arc::new(reload::Activity::new());
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[])));
+        let request = activity.enter().unwrap();
+        let swap = {
+            let activity = activity.clone();
+            let storage = storage.clone();
+            thread::spawn(move || activity.swap(&storage, storage::tests::make_storage(&[])))
+        };
+        while !activity.is_draining() {
+            thread::yield_now();
+        }
+        let mut response = Vec::new();
+        write_status_response(&mut response, true, activity.enter().err().unwrap());
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 ?\r\n"));
+        assert!(response.contains("\r\nretry-after: 1\r\n"));
+        drop(request);
+        swap.join().unwrap();
+
+        let mut response = Vec::new();
+        write_status_response(&mut response, true, StatusCode::NOT_FOUND);
+        assert!(!String::from_utf8(response).unwrap().contains("retry-after"));
+    }
+
+    #[test]
+    fn test_shutdown_signal() {
+        install_shutdown_handler().unwrap();
+        let poll_thread = thread::spawn(|| {
+            let mut iterations = 0;
+            while !SHUTDOWN.load(Ordering::Relaxed) {
+                iterations += 1;
+                thread::yield_now();
+            }
+            iterations
+        });
+        thread::sleep(Duration::from_millis(10));
+        nix::sys::signal::raise(nix::sys::signal::Signal::SIGTERM).unwrap();
+        assert!(poll_thread.join().unwrap() > 0);
+        SHUTDOWN.store(false, Ordering::SeqCst);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pin_to_cpu() {
+        let cpus = allowed_cpus().unwrap();
+        assert!(!cpus.is_empty());
+        for thread_id in &[0, cpus.len() + 1] {
+            let thread_id = *thread_id;
+            let (cpu, allowed) = thread::spawn(move || {
+                let cpu = pin_to_cpu(thread_id).unwrap();
+                (cpu, allowed_cpus().unwrap())
+            }).join().unwrap();
+            assert_eq!(cpu, cpus[thread_id % cpus.len()]);
+            assert_eq!(allowed, vec![cpu]);
+        }
+
+        // маска уже сужена, как cpuset контейнера: выбирается CPU из нее, а не thread_id по модулю числа CPU
+        let last = *cpus.last().unwrap();
+        let cpu = thread::spawn(move || {
+            let mut cpu_set = nix::sched::CpuSet::new();
+            cpu_set.set(last).unwrap();
+            nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), &cpu_set).unwrap();
+            pin_to_cpu(0).unwrap()
+        }).join().unwrap();
+        assert_eq!(cpu, last);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(3);
+        let start = bucket.updated;
+        let results: Vec<bool> = (0..5).map(|_| bucket.try_acquire(start)).collect();
+        assert_eq!(results, vec![true, true, true, false, false]);
+
+        // за треть секунды восстанавливается один запрос
+        let later = start + Duration::from_millis(340);
+        assert!(bucket.try_acquire(later));
+        assert!(!bucket.try_acquire(later));
+
+        // после долгой паузы всплеск не больше rate
+        let much_later = later + Duration::from_secs(10);
+        let results: Vec<bool> = (0..4).map(|_| bucket.try_acquire(much_later)).collect();
+        assert_eq!(results, vec![true, true, true, false]);
+    }
+
+    /// Всплеск запросов одним пакетом: сверх max_rps клиент получает 429, с --close-on-rate-limit соединение закрывается.
+    #[test]
+    fn test_rate_limit_responses() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        for close_on_rate_limit in &[false, true] {
+            let conn_options = ConnOptions { max_rps: 2, close_on_rate_limit: *close_on_rate_limit, ..test_conn_options() };
+            let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+            let mut conn = Connection::new(TcpStream::from_stream(listener.accept().unwrap().0).unwrap(), conn_options);
+
+            client.write_all(&b"GET /accounts/1/ HTTP/1.1\r\n\r\n".repeat(4)).unwrap();
+            let mut remove_conn = false;
+            let mut received = String::new();
+            let mut buf = [0; 4096];
+            for _ in 0..500 {
+                if !remove_conn {
+                    try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+                }
+                if let Ok(len) = client.read(&mut buf) {
+                    received.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+                }
+                if received.matches("HTTP/1.1 ").count() >= if *close_on_rate_limit { 3 } else { 4 } {
+                    break;
+                }
+            }
+            let statuses: Vec<&str> = received.split("HTTP/1.1 ").skip(1).map(|response| &response[..3]).collect();
+            if *close_on_rate_limit {
+                // после первого 429 остаток буфера не обрабатывается
+                assert_eq!(statuses, vec!["200", "200", "429"], "{}", received);
+                assert!(remove_conn);
+            } else {
+                assert_eq!(statuses, vec!["200", "200", "429", "429"], "{}", received);
+                assert!(!remove_conn);
+            }
+        }
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let conn_options = test_conn_options();
+        let mut conn = Connection::new(stream, conn_options);
+
+        // обрабатывает то, что пришло от клиента, пока клиент не получит ответ
+        let mut read_response = |client: &mut std::net::TcpStream| -> String {
+            let mut buf = [0; 1024];
+            for _ in 0..500 {
+                let mut remove_conn = false;
+                try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+                assert!(!remove_conn);
+                if let Ok(len) = client.read(&mut buf) {
+                    return String::from_utf8(buf[..len].to_vec()).unwrap();
+                }
+            }
+            panic!("no response");
+        };
+
+        let body = r#"{"likes":[{"liker":1,"likee":2,"ts":1}]}"#;
+        let head = format!("POST /accounts/likes/?query_id=1 HTTP/1.1\r\nContent-Length: {}\r\nexpect: 100-Continue\r\n\r\n", body.len());
+        client.write_all(head.as_bytes()).unwrap();
+        assert_eq!(read_response(&mut client), "HTTP/1.1 100 Continue\r\n\r\n");
+        client.write_all(&body.as_bytes()[..10]).unwrap();
+        client.write_all(&body.as_bytes()[10..]).unwrap();
+        assert!(read_response(&mut client).starts_with("HTTP/1.1 202 ?\r\n"));
+        assert_eq!(storage.read().unwrap().accounts[1].as_ref().unwrap().likes, vec![2]);
+
+        assert!(expects_continue(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n"));
+        assert!(!expects_continue(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\n"));
+        assert!(!expects_continue(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_large_request() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let mut conn_options = test_conn_options();
+        conn_options.max_request = 32768;
+        let mut conn = Connection::new(stream, conn_options);
+
+        let read_response = |client: &mut std::net::TcpStream, conn: &mut Connection| -> (String, bool) {
+            let mut buf = [0; 1024];
+            for _ in 0..500 {
+                let mut remove_conn = false;
+                try_read_and_process(conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+                if let Ok(len) = client.read(&mut buf) {
+                    return (String::from_utf8(buf[..len].to_vec()).unwrap(), remove_conn);
+                }
+            }
+            panic!("no response");
+        };
+        let likes_request = |count: usize| -> String {
+            let likes: Vec<String> = (0..count).map(|ts| format!(r#"{{"liker":1,"likee":2,"ts":{}}}"#, ts)).collect();
+            let body = format!(r#"{{"likes":[{}]}}"#, likes.join(","));
+            format!("POST /accounts/likes/?query_id=1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+        };
+
+        // больше 8KB буфера чтения, но меньше max_request
+        let request = likes_request(400);
+        assert!(request.len() > 8192);
+        client.write_all(request.as_bytes()).unwrap();
+        let (response, remove_conn) = read_response(&mut client, &mut conn);
+        assert!(response.starts_with("HTTP/1.1 202 ?\r\n"), "{}", response);
+        assert!(!remove_conn);
+        assert_eq!(storage.read().unwrap().accounts[1].as_ref().unwrap().likes, vec![2]);
+        // после запроса буфер возвращается к исходному размеру
+        assert_eq!(conn.buf.len(), 8192);
+
+        let request = likes_request(1200);
+        assert!(request.len() > conn_options.max_request);
+        client.write_all(request.as_bytes()).unwrap();
+        let (response, remove_conn) = read_response(&mut client, &mut conn);
+        assert!(response.starts_with("HTTP/1.1 413 ?\r\n"), "{}", response);
+        assert!(remove_conn);
+    }
+
+    #[test]
+    fn test_chunked_request() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let conn_options = test_conn_options();
+        let mut conn = Connection::new(stream, conn_options);
+        let mut read_response = |client: &mut std::net::TcpStream, attempts: usize| -> Option<String> {
+            let mut buf = [0; 1024];
+            for _ in 0..attempts {
+                let mut remove_conn = false;
+                try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+                if let Ok(len) = client.read(&mut buf) {
+                    return Some(String::from_utf8(buf[..len].to_vec()).unwrap());
+                }
+            }
+            None
+        };
+
+        let first = r#"{"likes":[{"liker":1,"likee":2,"ts":5},"#;
+        let second = r#"{"liker":2,"likee":1,"ts":6}]}"#;
+        write!(client, "POST /accounts/likes/?query_id=1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n", first.len(), first).unwrap();
+        // без завершающего чанка запрос не закончен
+        assert_eq!(read_response(&mut client, 20), None);
+        write!(client, "{:x};ext=1\r\n{}\r\n0\r\n\r\n", second.len(), second).unwrap();
+        let response = read_response(&mut client, 500).expect("no response");
+        assert!(response.starts_with("HTTP/1.1 202 ?\r\n"), "{}", response);
+        assert_eq!(storage.read().unwrap().accounts[1].as_ref().unwrap().likes, vec![2]);
+        assert_eq!(storage.read().unwrap().accounts[2].as_ref().unwrap().likes, vec![1]);
+
+        client.write_all(b"POST /accounts/likes/?query_id=2 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").unwrap();
+        let response = read_response(&mut client, 500).expect("no response");
+        assert!(response.starts_with("HTTP/1.1 400 ?\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_socket_options() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let mut conn_options = test_conn_options();
+        conn_options.keepalive_idle = Some(Duration::from_secs(30));
+        conn_options.keepalive_interval = Duration::from_secs(5);
+        set_socket_options(&stream, &conn_options).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(30)));
+        #[cfg(target_os = "linux")]
+        assert_eq!(tcp_option(&stream, libc::TCP_KEEPINTVL).unwrap(), 5);
+        assert!(describe_socket_options(&stream).contains("SO_KEEPALIVE Ok(Some(30s))"), "{}", describe_socket_options(&stream));
+
+        conn_options.keepalive_idle = None;
+        set_socket_options(&stream, &conn_options).unwrap();
+        assert_eq!(stream.keepalive().unwrap(), None);
+    }
+
+    #[test]
+    fn test_pipelining() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let conn_options = test_conn_options();
+        let mut conn = Connection::new(stream, conn_options);
+        // ответы копятся, пока их не наберется count
+        let read_responses = |client: &mut std::net::TcpStream, count: usize, attempts: usize, process: &mut dyn FnMut()| -> Vec<String> {
+            let mut received = String::new();
+            let mut buf = [0; 4096];
+            for _ in 0..attempts {
+                process();
+                if let Ok(len) = client.read(&mut buf) {
+                    received.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+                }
+                if received.matches("HTTP/1.1 ").count() >= count {
+                    break;
+                }
+            }
+            received.split("HTTP/1.1 ").skip(1).map(|response| response.to_string()).collect()
+        };
+        let mut try_read = || {
+            let mut remove_conn = false;
+            try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+            assert!(!remove_conn);
+        };
+
+        // два GET одним пакетом
+        client.write_all(b"GET /accounts/1/ HTTP/1.1\r\n\r\nGET /accounts/2/ HTTP/1.1\r\n\r\n").unwrap();
+        let responses = read_responses(&mut client, 2, 500, &mut try_read);
+        assert_eq!(responses.len(), 2, "{:?}", responses);
+        assert!(responses[0].starts_with("200 ?") && responses[0].contains("a1@a.ru"), "{}", responses[0]);
+        assert!(responses[1].starts_with("200 ?") && responses[1].contains("a2@a.ru"), "{}", responses[1]);
+
+        // тело POST по content-length, за ним GET и начало третьего запроса
+        let body = r#"{"likes":[{"liker":1,"likee":2,"ts":5}]}"#;
+        write!(client, "POST /accounts/likes/?query_id=1 HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET /accounts/1/ HTTP/1.1\r\n\r\nGET /acc", body.len(), body).unwrap();
+        let responses = read_responses(&mut client, 2, 500, &mut try_read);
+        assert_eq!(responses.len(), 2, "{:?}", responses);
+        assert!(responses[0].starts_with("202 ?"), "{}", responses[0]);
+        assert!(responses[1].starts_with("200 ?") && responses[1].contains("a1@a.ru"), "{}", responses[1]);
+        assert_eq!(storage.read().unwrap().accounts[1].as_ref().unwrap().likes, vec![2]);
+        assert_eq!(conn.len, 8);
+
+        // пока ответ отложен, дочитанный запрос ждет в буфере
+        conn.awaiting_reply = true;
+        client.write_all(b"ounts/2/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_responses(&mut client, 1, 20, &mut || {
+            let mut remove_conn = false;
+            try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+        }).is_empty());
+        conn.awaiting_reply = false;
+        let responses = read_responses(&mut client, 1, 500, &mut || {
+            let mut remove_conn = false;
+            process_buffered(&mut conn, &connections, &storage, conn_options, &mut remove_conn, 0, 0);
+        });
+        assert_eq!(responses.len(), 1, "{:?}", responses);
+        assert!(responses[0].contains("a2@a.ru"), "{}", responses[0]);
+        assert_eq!(conn.len, 0);
+    }
+
+    /// Много клиентов параллельно, у каждого свое keep-alive соединение, и один поток poll:
+    /// соединения вынимаются из map на время события и возвращаются в нее после ответа.
+    #[test]
+    fn test_many_connections() {
+        use std::io::Read;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const CLIENTS: usize = 64;
+        const REQUESTS: usize = 20;
+        let accounts: Vec<String> = (1..11)
+            .map(|id| format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}}"#, id, id))
+            .collect();
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&accounts.iter().map(|account| account.as_str()).collect::<Vec<&str>>())));
+        let listener = TcpListener::from_std(std::net::TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let poll_ = Poll::new().unwrap();
+        poll_.register(&listener, Token(0), Ready::readable(), PollOpt::edge()).unwrap();
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        let clients: Vec<thread::JoinHandle<()>> = (0..CLIENTS).map(|client_id| {
+            let finished = finished.clone();
+            thread::spawn(move || {
+                let mut client = std::net::TcpStream::connect(addr).unwrap();
+                client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+                for i in 0..REQUESTS {
+                    let id = (client_id + i) % 10 + 1;
+                    write!(client, "GET /accounts/{}/ HTTP/1.1\r\n\r\n", id).unwrap();
+                    let mut response = Vec::new();
+                    let mut buf = [0; 1024];
+                    // ответ целиком: заголовки и тело по content-length
+                    while !find_bytes(&response, b"\r\n\r\n").is_some_and(|head_len| {
+                        let head = String::from_utf8_lossy(&response[..head_len]).to_string();
+                        let body_len: usize = head.split("\r\n").find(|line| line.starts_with("content-length: ")).unwrap()[16..].parse().unwrap();
+                        response.len() >= head_len + 4 + body_len
+                    }) {
+                        let len = client.read(&mut buf).unwrap();
+                        assert!(len > 0);
+                        response.extend_from_slice(&buf[..len]);
+                    }
+                    let response = String::from_utf8(response).unwrap();
+                    assert!(response.starts_with("HTTP/1.1 200 ?\r\n") && response.contains(&format!("\"a{}@a.ru\"", id)), "{}", response);
+                }
+                finished.fetch_add(1, Ordering::SeqCst);
+            })
+        }).collect();
+
+        let connections = spin::Mutex::new(HashMap::new());
+        let conn_options = test_conn_options();
+        let mut events = Events::with_capacity(16);
+        let mut next_conn_id = 1;
+        let mut accepted = 0;
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while finished.load(Ordering::SeqCst) < CLIENTS {
+            assert!(Instant::now() < deadline, "finished {}, accepted {}, open {}", finished.load(Ordering::SeqCst), accepted, connections.lock().len());
+            poll(&poll_, &mut events);
+            for event in events.iter() {
+                match event.token() {
+                    Token(0) => {
+                        while let Ok((stream, _)) = listener.accept() {
+                            poll_.register(&stream, Token(next_conn_id), Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
+                            connections.lock().insert(next_conn_id, Connection::new(stream, conn_options));
+                            next_conn_id += 1;
+                            accepted += 1;
+                        }
+                    }
+                    Token(conn_id) => handle_event(&connections, &storage, event.readiness(), conn_options, 0, conn_id),
+                }
+            }
+        }
+        for client in clients {
+            client.join().unwrap();
+        }
+        assert_eq!(accepted, CLIENTS);
+        // ни одно соединение не потерялось между событиями
+        assert_eq!(connections.lock().len(), CLIENTS);
+    }
+
+    #[test]
+    fn test_decode_chunked() {
+        assert_eq!(decode_chunked(b"3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"), Ok(Some((b"abcde".to_vec(), 20))));
+        // следующий запрос в длину не входит
+        assert_eq!(decode_chunked(b"A\r\n0123456789\r\n0\r\nX-Trailer: 1\r\n\r\nGET "), Ok(Some((b"0123456789".to_vec(), 34))));
+        assert_eq!(decode_chunked(b"0\r\n\r\n"), Ok(Some((Vec::new(), 5))));
+        for incomplete in &[&b""[..], b"3\r\nab", b"3\r\nabc\r\n", b"3\r\nabc\r\n0\r\n", b"ffffffffffffffff\r\nabc"] {
+            assert_eq!(decode_chunked(incomplete), Ok(None), "{:?}", String::from_utf8_lossy(incomplete));
+        }
+        assert_eq!(decode_chunked(b"x\r\nabc\r\n"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(decode_chunked(b"2\r\nabc\r\n"), Err(StatusCode::BAD_REQUEST));
+
+        assert!(is_chunked(b"POST / HTTP/1.1\r\ntransfer-encoding: gzip, Chunked\r\n"));
+        assert!(!is_chunked(b"POST / HTTP/1.1\r\nX-Transfer-Encoding: chunked\r\n"));
+        let request = b"POST /accounts/likes/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n".to_vec();
+        assert_eq!(dechunk_request(request), b"POST /accounts/likes/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}".to_vec());
+    }
+
+    #[test]
+    fn test_listener_builder_v6() {
+        use nix::sys::socket::{self, InetAddr, SockAddr};
+        use std::os::unix::io::AsRawFd;
+
+        // bind через nix: net2 собирает sockaddr по старой раскладке std::net::SocketAddr, и с текущим std его bind не работает
+        let listen = |addr: &SocketAddr| -> std::net::TcpListener {
+            let tcp_builder = listener_builder(addr).unwrap();
+            assert!(tcp_builder.get_reuse_port().unwrap());
+            socket::bind(tcp_builder.as_raw_fd(), &SockAddr::new_inet(InetAddr::from_std(addr))).unwrap();
+            tcp_builder.listen(16).unwrap()
+        };
+        // IPV6_V6ONLY читается через libc: в nix этой опции нет
+        let only_v6 = |listener: &std::net::TcpListener| {
+            let mut value: libc::c_int = -1;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let result = unsafe { libc::getsockopt(listener.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len) };
+            assert_eq!(result, 0);
+            value != 0
+        };
+        let first = listen(&"[::]:0".parse().unwrap());
+        assert!(!only_v6(&first));
+        let port = first.local_addr().unwrap().port();
+        // SO_REUSEPORT: второй listener на том же порту, как у потоков в режиме reuseport
+        let second = listen(&format!("[::]:{}", port).parse().unwrap());
+        let listener = TcpListener::from_std(first).unwrap();
+
+        for client_addr in &["127.0.0.1", "::1"] {
+            let client_addr: SocketAddr = (client_addr.parse::<std::net::IpAddr>().unwrap(), port).into();
+            let client = std::net::TcpStream::connect(client_addr).unwrap();
+            second.set_nonblocking(true).unwrap();
+            // соединение достается одному из двух listener
+            let accepted = (0..100).find_map(|_| {
+                let accepted = listener.accept().map(|(_, peer)| peer).or_else(|_| second.accept().map(|(_, peer)| peer)).ok();
+                if accepted.is_none() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                accepted
+            }).expect("not accepted");
+            assert_eq!(accepted.port(), client.local_addr().unwrap().port());
+            assert!(accepted.is_ipv6(), "{}", accepted);
+        }
+
+        let v4 = listener_builder(&"0.0.0.0:0".parse().unwrap()).unwrap();
+        assert!(v4.get_reuse_port().unwrap());
+    }
+
+    #[test]
+    fn test_events_capacity_flag() {
+        let profile = |args: &[&str]| profile_from_matches(&app().get_matches_from(["hlc2018", "80", "/tmp/data"].iter().chain(args)));
+        assert_eq!(profile(&[]).events_capacity, 1024);
+        assert_eq!(profile(&["--events-capacity", "4096"]).events_capacity, 4096);
+        assert_eq!(profile(&["--events", "64"]).events_capacity, 64);
+        assert_eq!(profile(&["--profile", "small", "--events-capacity", "512"]), profile::Profile { events_capacity: 512, ..profile::Profile::small() });
+    }
+
+    #[test]
+    fn test_profile_keeps_caps_off() {
+        let caps = |args: &[&str]| {
+            let config = config_from_matches(&app().get_matches_from(["hlc2018", "80", "/tmp/data"].iter().chain(args)));
+            (config.recommend_cap_factor, config.group_cap)
+        };
+        assert_eq!(caps(&[]), (0, 0));
+        for profile in &["small", "medium", "large", "auto"] {
+            assert_eq!(caps(&["--profile", profile]), (0, 0), "{}", profile);
+        }
+        assert_eq!(caps(&["--profile", "small", "--recommend-cap", "20"]), (20, 0));
+        assert_eq!(caps(&["--profile", "small", "--group-cap", "10000"]), (0, 10000));
+        assert_eq!(caps(&["--recommend-cap", "5", "--group-cap", "7", "--profile", "large"]), (5, 7));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_poll_drains_full_batch() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let poll_ = Poll::new().unwrap();
+        let mut clients = Vec::new();
+        let mut streams = Vec::new();
+        for i in 0..6 {
+            clients.push(std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+            let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+            poll_.register(&stream, Token(i), Ready::readable(), PollOpt::edge()).unwrap();
+            streams.push(stream);
+        }
+        for client in &mut clients {
+            client.write_all(b"x").unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        // 6 готовых соединений при batch 2 - три вызова epoll_wait за один poll
+        let mut events = Events::with_capacity(2);
+        poll(&poll_, &mut events);
+        let mut tokens: Vec<usize> = events.iter().map(|event| event.token().0).collect();
+        tokens.sort();
+        assert_eq!(tokens, (0..6).collect::<Vec<usize>>());
+        poll(&poll_, &mut events);
+        assert_eq!(events.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_connection_header() {
+        assert!(HttpVersion::Http11.keep_alive(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(!HttpVersion::Http10.keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(!HttpVersion::Http11.keep_alive(b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+        assert!(HttpVersion::Http10.keep_alive(b"GET / HTTP/1.0\r\nconnection: keep-alive\r\n\r\n"));
+        assert!(!HttpVersion::Http11.keep_alive(b"GET / HTTP/1.1\r\nConnection: keep-alive, close\r\n\r\n"));
+        assert!(!HttpVersion::Http10.keep_alive(b"GET / HTTP/1.0\r\nX-Connection: keep-alive\r\n\r\n"));
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn_options = test_conn_options();
+        for (request, keep_alive) in &[
+            (&b"GET /accounts/1/ HTTP/1.1\r\nConnection: close\r\n\r\n"[..], false),
+            (b"GET /accounts/1/ HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", true),
+            (b"GET /accounts/1/ HTTP/1.1\r\nConnection: keep-alive\r\n\r\n", true),
+        ] {
+            use std::io::Read;
+
+            let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+            let connections = spin::Mutex::new(HashMap::new());
+            let mut conn = Connection::new(TcpStream::from_stream(listener.accept().unwrap().0).unwrap(), conn_options);
+            client.write_all(request).unwrap();
+            let mut buf = [0; 1024];
+            let mut response = None;
+            for _ in 0..500 {
+                let mut remove_conn = false;
+                try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+                if let Ok(len) = client.read(&mut buf) {
+                    // после ответа с close соединение убирается
+                    assert_eq!(remove_conn, !keep_alive);
+                    response = Some(String::from_utf8(buf[..len].to_vec()).unwrap());
+                    break;
+                }
+            }
+            let response = response.expect("no response");
+            let header = if *keep_alive { "\r\nconnection: keep-alive\r\n" } else { "\r\nconnection: close\r\n" };
+            assert!(response.starts_with("HTTP/1.1 200 ?\r\n") && response.contains(header), "{}", response);
+        }
+    }
+
+    #[test]
+    fn test_http10_closes_connection() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let conn_options = test_conn_options();
+        let mut conn = Connection::new(stream, conn_options);
+
+        // без Host и Content-Length
+        client.write_all(b"GET /accounts/filter?limit=1 HTTP/1.0\r\n\r\n").unwrap();
+        let mut buf = [0; 1024];
+        for _ in 0..500 {
+            let mut remove_conn = false;
+            try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+            if let Ok(len) = client.read(&mut buf) {
+                assert!(remove_conn);
+                let response = String::from_utf8(buf[..len].to_vec()).unwrap();
+                assert!(response.starts_with("HTTP/1.1 200 ?\r\n"));
+                assert!(response.contains("\r\nconnection: close\r\n"));
+                assert!(!response.contains("keep-alive"));
+                assert!(response.ends_with("\r\n\r\n{\"accounts\":[{\"id\":1,\"email\":\"a1@a.ru\"}]}"));
+                return;
+            }
+            assert!(!remove_conn);
+        }
+        panic!("no response");
+    }
+
+    #[test]
+    fn test_head_request() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let conn_options = test_conn_options();
+        let mut conn = Connection::new(stream, conn_options);
+
+        let mut request = |client: &mut std::net::TcpStream, request: &[u8]| -> String {
+            client.write_all(request).unwrap();
+            let mut buf = [0; 1024];
+            for _ in 0..500 {
+                let mut remove_conn = false;
+                try_read_and_process(&mut conn, &connections, &storage, false, conn_options, &mut remove_conn, 0, 0);
+                assert!(!remove_conn);
+                if let Ok(len) = client.read(&mut buf) {
+                    return String::from_utf8(buf[..len].to_vec()).unwrap();
+                }
+            }
+            panic!("no response");
+        };
+
+        let body = "{\"accounts\":[{\"id\":1,\"email\":\"a1@a.ru\"}]}";
+        let head = request(&mut client, b"HEAD /accounts/filter/?limit=1 HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 ?\r\n"));
+        assert!(head.ends_with(&format!("\r\ncontent-length: {}\r\n\r\n", body.len())), "{}", head);
+        // тело не ушло следом: следующий ответ на том же соединении начинается со статуса
+        let get = request(&mut client, b"GET /accounts/filter/?limit=1 HTTP/1.1\r\n\r\n");
+        assert_eq!(get, format!("{}{}", head, body));
+
+        let head = request(&mut client, b"HEAD /accounts/filter/?limit=x HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 400 ?\r\n"));
+        assert!(head.ends_with("content-length: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_close_idle() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut idle_client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let idle = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let _active_client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let active = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let start = Instant::now();
+        connections.lock().insert(1, Connection::new(idle, test_conn_options()));
+        connections.lock().insert(2, Connection::new(active, test_conn_options()));
+        connections.lock().get_mut(&1).unwrap().last_active = start;
+        connections.lock().get_mut(&2).unwrap().last_active = start + Duration::from_secs(50);
+
+        assert_eq!(close_idle(&connections, start + Duration::from_secs(59), Duration::from_secs(60)), 0);
+        assert_eq!(close_idle(&connections, start + Duration::from_secs(60), Duration::from_secs(60)), 1);
+        assert_eq!(connections.lock().keys().cloned().collect::<Vec<usize>>(), vec![2]);
+        // сокет закрыт: клиент видит конец потока
+        idle_client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(idle_client.read(&mut [0; 16]).unwrap(), 0);
+        assert_eq!(close_idle(&connections, start + Duration::from_secs(110), Duration::from_secs(60)), 1);
+        assert!(connections.lock().is_empty());
+    }
+
+    #[test]
+    fn test_partial_write() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        stream.set_send_buffer_size(4096).unwrap();
+        let mut conn = Connection::new(stream, test_conn_options());
+
+        // ответ больше буферов сокета записывается частично, второй встает в очередь за ним
+        let first: Vec<u8> = (0..4_000_000).map(|i| (i % 251) as u8).collect();
+        let second = b"second".to_vec();
+        let mut remove_conn = false;
+        send_response(&first, &mut conn, &mut remove_conn, &storage);
+        assert!(conn.write_pos < conn.write_buf.len());
+        send_response(&second, &mut conn, &mut remove_conn, &storage);
+        assert!(!remove_conn);
+
+        let expected_len = first.len() + second.len();
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buf = [0; 65536];
+            while received.len() < expected_len {
+                let len = client.read(&mut buf).unwrap();
+                assert!(len > 0);
+                received.extend_from_slice(&buf[..len]);
+            }
+            received
+        });
+        while conn.write_pos < conn.write_buf.len() {
+            flush_pending(&mut conn, &mut remove_conn, &storage);
+            assert!(!remove_conn);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!((conn.write_buf.len(), conn.write_pos), (0, 0));
+        let received = reader.join().unwrap();
+        assert_eq!(&received[..first.len()], first.as_slice());
+        assert_eq!(&received[first.len()..], second.as_slice());
+    }
+
+    /// Клиент не забирает ответ: запросы остаются в сокете, пока write_buf не допишется, и обрабатываются по writable.
+    #[test]
+    fn test_backpressure() {
+        use std::io::Read;
+
+        let storage = Arc::new(RwLock::new(storage::tests::make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        stream.set_send_buffer_size(4096).unwrap();
+        let conn_options = test_conn_options();
+        let connections = spin::Mutex::new(HashMap::new());
+        let mut conn = Connection::new(stream, conn_options);
+        let first: Vec<u8> = (0..4_000_000).map(|i| (i % 251) as u8).collect();
+        let mut remove_conn = false;
+        send_response(&first, &mut conn, &mut remove_conn, &storage);
+        assert!(conn.write_pos < conn.write_buf.len());
+        let pending = conn.write_buf.len();
+        connections.lock().insert(1, conn);
+
+        client.write_all(&b"GET /accounts/1/ HTTP/1.1\r\n\r\n".repeat(2)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        handle_event(&connections, &storage, Ready::readable(), conn_options, 0, 1);
+        {
+            let connections = connections.lock();
+            let conn = connections.get(&1).unwrap();
+            assert!(conn.read_paused);
+            assert_eq!(conn.len, 0);
+            assert_eq!(conn.write_buf.len(), pending);
+        }
+
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buf = [0; 65536];
+            while received.len() < first.len() || String::from_utf8_lossy(&received[first.len()..]).matches("a1@a.ru").count() < 2 {
+                let len = client.read(&mut buf).unwrap();
+                assert!(len > 0);
+                received.extend_from_slice(&buf[..len]);
+            }
+            (first, received)
+        });
+        for _ in 0..5000 {
+            handle_event(&connections, &storage, Ready::writable(), conn_options, 0, 1);
+            if reader.is_finished() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let (first, received) = reader.join().unwrap();
+        assert_eq!(&received[..first.len()], first.as_slice());
+        let responses = String::from_utf8(received[first.len()..].to_vec()).unwrap();
+        assert_eq!(responses.matches("HTTP/1.1 200 ?").count(), 2, "{}", responses);
+        assert!(!connections.lock().get(&1).unwrap().read_paused);
+    }
+
+    #[test]
+    fn test_export_stream() {
+        use std::io::Read;
+
+        let account = |id: i32| format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"fname":"f{}","city":"c{}"}}"#, id, id, id % 10, id % 30);
+        let accounts: Vec<String> = (1..900).map(account).collect();
+        let mut storage = storage::tests::make_storage(&accounts.iter().map(|account| account.as_str()).collect::<Vec<&str>>());
+        storage.config.admin = true;
+        let storage = Arc::new(RwLock::new(storage));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+        stream.set_send_buffer_size(4096).unwrap();
+        net2::TcpStreamExt::set_recv_buffer_size(&client, 4096).unwrap();
+        let connections = spin::Mutex::new(HashMap::new());
+        let conn_options = test_conn_options();
+        connections.lock().insert(0, Connection::new(stream, conn_options));
+
+        client.write_all(b"GET /admin/export HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(20));
+        // клиент пока не читает: поток poll не ждет сокет, в write_buf только текущая часть выгрузки
+        handle_event(&connections, &storage, Ready::readable(), conn_options, 0, 0);
+        {
+            let connections = connections.lock();
+            let conn = connections.get(&0).unwrap();
+            assert!(conn.write_pos < conn.write_buf.len());
+            assert!(conn.export.is_some());
+        }
+        // блокировка берется на часть, запись между частями не ждет конца выгрузки и попадает в нее
+        storage.try_write().unwrap().new_account(account(900).as_bytes(), &mut |_| {}).unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut response = Vec::new();
+            let mut buf = [0; 65536];
+            while !response.ends_with(b"\r\n0\r\n\r\n") {
+                let len = client.read(&mut buf).unwrap();
+                assert!(len > 0);
+                response.extend_from_slice(&buf[..len]);
+            }
+            sender.send(response).unwrap();
+        });
+        let mut response = None;
+        for _ in 0..5000 {
+            handle_event(&connections, &storage, Ready::writable(), conn_options, 0, 0);
+            assert!(connections.lock().contains_key(&0));
+            response = receiver.recv_timeout(Duration::from_millis(1)).ok();
+            if response.is_some() {
+                break;
+            }
+        }
+        let response = response.expect("no response");
+
+        let head_len = find_bytes(&response, b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(response[..head_len].to_vec()).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 ?\r\n"));
+        assert!(head.contains("transfer-encoding: chunked\r\n"));
+        let mut body = Vec::new();
+        let mut chunks = 0;
+        let mut pos = head_len;
+        loop {
+            let line_len = find_bytes(&response[pos..], b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&response[pos..pos + line_len]).unwrap(), 16).unwrap();
+            pos += line_len + 2;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&response[pos..pos + size]);
+            pos += size + 2;
+            chunks += 1;
+        }
+        assert!(chunks > 1);
+        let mut expected = Vec::new();
+        process::export(&storage, &mut expected).unwrap();
+        assert_eq!(body, expected);
+        assert_eq!(String::from_utf8(body).unwrap().lines().count(), 900);
+    }
+
+    const REQUESTS: &[&[u8]] = &[
+        b"GET /accounts/filter/?sex_eq=m&limit=10&query_id=1 HTTP/1.1\r\nHost: localhost\r\nUser-Agent: tank\r\n\r\n",
+        b"\r\nPOST /accounts/new/?query_id=2 HTTP/1.1\r\nContent-Length: 10\r\n\r\n{\"id\":100}",
+        b"POST /accounts/likes/?query_id=3 HTTP/1.1\r\nContent-Length: 12\r\n\r\n{\"likes\":[]",
+        b"GET /admin/health HTTP/1.1\r\n\r\n",
+        b"POST /accounts/likes/?query_id=4 HTTP/1.1\r\ncontent-length: 12\r\n\r\n{\"likes\":[]}",
+        b"POST /accounts/likes/?query_id=5 HTTP/1.1\r\nCONTENT-length: 12\r\n\r\n{\"likes\":[",
+        b"HEAD /accounts/filter/?sex_eq=m&limit=10 HTTP/1.1\r\n\r\n",
+        b"POST /accounts/likes/?query_id=6 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n",
+        b"POST /accounts/likes/?query_id=7 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n",
+        b"GET /accounts/filter/?city_eq=%D0%9C HTTP/1.1\r\nX-Name: \xd0\x9c\r\n\r\n",
+        b"GET /accounts/\xff/ HTTP/1.1\r\n\r\n",
+        b"PUT /accounts/ HTTP/1.1\r\n\r\n",
+        b"DELETE /admin/cache HTTP/1.1\r\n\r\n",
+        b"GET\r\n\r\n",
+        b"GET /accounts/filter/ HTTP/1.1\r\n",
+    ];
+
+    #[test]
+    fn test_content_length_case() {
+        let check = |request: &[u8], expected: Result<bool, StatusCode>| {
+            assert_eq!(can_process_request(request), expected, "{:?}", String::from_utf8_lossy(request));
+            assert_eq!(can_process_request_bytes(request), expected, "{:?}", String::from_utf8_lossy(request));
+        };
+        for name in &["Content-Length", "content-length", "CONTENT-LENGTH", "Content-length", "cOnTeNt-LeNgTh"] {
+            check(format!("POST /accounts/new/ HTTP/1.1\r\n{}: 2\r\n\r\n{{}}", name).as_bytes(), Ok(true));
+            check(format!("POST /accounts/new/ HTTP/1.1\r\n{}: 3\r\n\r\n{{}}", name).as_bytes(), Ok(false));
+            check(format!("POST /accounts/new/ HTTP/1.1\r\n{}: x\r\n\r\n{{}}", name).as_bytes(), Err(StatusCode::BAD_REQUEST));
+        }
+        // имя заголовка только до ':', тело не разбирается как заголовки
+        check(b"POST /accounts/new/ HTTP/1.1\r\nX-Content-Length: 2\r\n\r\n{}", Ok(false));
+        check(b"POST /accounts/new/ HTTP/1.1\r\nX-Note: Content-Length: 2\r\n\r\n{}", Ok(false));
+        check(b"POST /accounts/new/ HTTP/1.1\r\nHost: a\r\n\r\nContent-Length: 0\r\n", Ok(false));
+    }
+
+    #[test]
+    fn test_parse_request_bytes() {
+        // итоговый результат обоих путей совпадает, хотя ошибка UTF-8 в url находится на разных шагах
+        for request in REQUESTS {
+            let str_result = can_process_request(request)
+                .and_then(|can_process| if can_process { parse_request(request).map(Some) } else { Ok(None) });
+            let bytes_result = can_process_request_bytes(request)
+                .and_then(|can_process| if can_process { parse_request_bytes(request).map(Some) } else { Ok(None) });
+            assert_eq!(bytes_result, str_result, "{:?}", String::from_utf8_lossy(request));
+        }
+        // заголовки не проверяются как UTF-8
+        let request = b"GET /accounts/filter/?limit=1 HTTP/1.1\r\nX-Name: \xff\r\n\r\n";
+        assert_eq!(can_process_request(request), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(can_process_request_bytes(request), Ok(true));
+        assert_eq!(parse_request_bytes(request), Ok(("/accounts/filter/", Some("limit=1"), None, true, HttpMethod::Get)));
+    }
+}
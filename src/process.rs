@@ -1,27 +1,68 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::cell::RefCell;
 use std::iter::Iterator;
-use std::sync::{Arc, RwLock};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 //use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use std::time::Instant;
 
 use percent_encoding::percent_decode;
 use regex::Regex;
+use serde::Serialize;
 use spin;
 
+use crate::bits::InterestSet;
+use crate::cache::CacheSnapshot;
+use crate::cache::ResponseCache;
 use crate::filter;
 use crate::group;
 use crate::recommend;
+use crate::recommend_cache::RecommendCache;
+use crate::recommend_cache::RecommendCacheSnapshot;
+use crate::recommend_cache::RecommendKey;
+use crate::reload;
+use crate::reload::Activity;
+use crate::stats::PanicsJson;
+use crate::stats::Stats;
 use crate::storage::Storage;
 use crate::suggest;
+use crate::utils::{read_lock, write_lock};
+use crate::utils::HttpMethod;
 use crate::utils::StatusCode;
 
+thread_local! {
+    // переиспользуется между запросами потока, чтобы не выделять память под каждый ответ
+    static BODY_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    // POST запросы потока в режиме batch_writes, применяются в apply_pending_writes
+    static PENDING_WRITES: RefCell<Vec<PendingWrite>> = const { RefCell::new(Vec::new()) };
+    // ответы, которые пишутся в сокет частями, а не собираются в буфер целиком, забираются в take_streams
+    static PENDING_STREAMS: RefCell<Vec<(usize, ExportCursor)>> = const { RefCell::new(Vec::new()) };
+}
+
+// аккаунтов в одной части /admin/export
+const EXPORT_BATCH: usize = 256;
+
+enum WriteKind {
+    New,
+    Update(i32),
+    Likes,
+}
+
+struct PendingWrite {
+    kind: WriteKind,
+    body: Vec<u8>,
+    conn_id: usize,
+}
+
 lazy_static! {
-    static ref CACHE: spin::Mutex<HashMap<String, Vec<u8>>> = spin::Mutex::new(HashMap::new());
+    static ref CACHE: spin::Mutex<ResponseCache> = spin::Mutex::new(ResponseCache::new());
+    static ref RECOMMEND_CACHE: spin::Mutex<RecommendCache> = spin::Mutex::new(RecommendCache::new());
+    static ref ACTIVITY: Activity = Activity::new();
 }
 
-pub fn process<RF: FnMut(Result<Cow<[u8]>, StatusCode>)>(path: &str, query: Option<&str>, body: Option<&[u8]>, storage: &Arc<RwLock<Storage>>, record_stats: bool, cache: bool, _thread_id: usize, _conn_id: usize, mut resp_f: RF) -> Result<(), StatusCode> {
+pub fn process<RF: FnMut(Result<Cow<[u8]>, StatusCode>)>(method: HttpMethod, path: &str, query: Option<&str>, body: Option<&[u8]>, storage: &Arc<RwLock<Storage>>, record_stats: bool, cache: bool, _thread_id: usize, conn_id: usize, mut resp_f: RF) -> Result<(), StatusCode> {
 //    static REQUEST_COUNT: AtomicUsize = AtomicUsize::new(0);
 //    let count = REQUEST_COUNT.fetch_add(1, Ordering::SeqCst) + 1;
 //    if count >= 0 && count < 700 {
@@ -32,90 +73,218 @@ pub fn process<RF: FnMut(Result<Cow<[u8]>, StatusCode>)>(path: &str, query: Opti
         static ref URL_RE: Regex = Regex::new(r"^/accounts/(?:(filter)|(group)|(\d+)/recommend|(\d+)/suggest|(new)|(\d+)|(likes))/?$").unwrap();
     }
 
+    match path {
+        "/admin/health" => {
+            // liveness: процесс жив и принимает запросы, даже во время reload
+            resp_f(Ok(Cow::from(&b"{}"[..])));
+            return Ok(());
+        }
+        "/admin/ready" => {
+            if ACTIVITY.is_draining() {
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+            resp_f(Ok(Cow::from(&b"{}"[..])));
+            return Ok(());
+        }
+        "/admin/stats" if read_lock(storage).config.admin => {
+            // самые частые попадания в кэш ответов, кандидаты для прогрева
+            let stats = StatsJson { cache: CACHE.lock().snapshot(20), recommend_cache: RECOMMEND_CACHE.lock().snapshot(), panics: read_lock(storage).stats.panics() };
+            resp_f(Ok(Cow::from(to_json(&stats)?)));
+            return Ok(());
+        }
+        "/admin/cache" if read_lock(storage).config.admin && method == HttpMethod::Delete => {
+            clear_caches();
+            resp_f(Err(StatusCode::ACCEPTED));
+            return Ok(());
+        }
+        "/admin/cache" if read_lock(storage).config.admin => {
+            let keys = CACHE.lock().keys(100);
+            resp_f(Ok(Cow::from(to_json(&keys)?)));
+            return Ok(());
+        }
+        // DELETE есть только у /admin/cache
+        _ if method == HttpMethod::Delete => return Err(StatusCode::METHOD_NOT_ALLOWED),
+        "/admin/reindex" if read_lock(storage).config.admin => {
+            require_post(method)?;
+            let params = parse_query(query.ok_or(StatusCode::BAD_REQUEST)?)?;
+            let name = params.iter().find(|(key, _)| key == "index").map(|(_, value)| value.as_str()).ok_or(StatusCode::BAD_REQUEST)?;
+            let _active_request = ACTIVITY.enter()?;
+            write_storage(storage, "REINDEX", record_stats).rebuild_index(name)?;
+            // ответы, посчитанные по старому индексу, могли отличаться
+            clear_caches();
+            resp_f(Err(StatusCode::ACCEPTED));
+            return Ok(());
+        }
+        "/admin/verify" if read_lock(storage).config.admin => {
+            // пересчет group_index с нуля, долгий: только для отладки
+            let verify = {
+                let storage = read_storage(storage, "VERIFY", record_stats);
+                VerifyJson { group_index: storage.indexes.group_index.verify(&storage.accounts).err().unwrap_or_default() }
+            };
+            resp_f(Ok(Cow::from(to_json(&verify)?)));
+            return Ok(());
+        }
+        "/admin/export" if read_lock(storage).config.admin => {
+            PENDING_STREAMS.with(|streams| streams.borrow_mut().push((conn_id, ExportCursor::new())));
+            return Ok(());
+        }
+        "/admin/reload" if read_lock(storage).config.admin => {
+            require_post(method)?;
+            reload::reload(&ACTIVITY, storage, clear_caches)?;
+            resp_f(Err(StatusCode::ACCEPTED));
+            return Ok(());
+        }
+        _ => {}
+    }
+
     let caps = URL_RE.captures(path);
 //    debug!("{:?}", caps);
 
+    // /accounts/<id>/: POST - обновление, GET и HEAD - аккаунт целиком, query не обязателен
+    if let (true, Some(id)) = (method == HttpMethod::Get || method == HttpMethod::Head, caps.as_ref().and_then(|caps| caps.get(6))) {
+        let id = parse_id(id.as_str())?;
+        let _active_request = ACTIVITY.enter()?;
+        let start = if record_stats { Some(Instant::now()) } else { None };
+        let body = {
+            let storage = read_storage(storage, "ACCOUNT", record_stats);
+            let account = storage.accounts.get(id as usize).and_then(|account| account.as_ref()).ok_or(StatusCode::NOT_FOUND)?;
+            to_json(&storage.get_account_json(account))?
+        };
+        resp_f(Ok(Cow::from(body)));
+        if record_stats {
+            read_lock(storage).stats.register("ACCOUNT", start.unwrap().elapsed(), &Vec::new());
+        }
+        return Ok(());
+    }
+
 //    debug!("{:?}", head.uri.query());
 //    debug!("{:?}", parse_query(head.uri.query().unwrap()));
 
+    // new, update и likes меняют данные, HEAD и GET их не вызывают
+    if caps.as_ref().is_some_and(|caps| caps.get(5).is_some() || caps.get(6).is_some() || caps.get(7).is_some()) {
+        require_post(method)?;
+    }
+
     if caps.is_some() {
-        let params = parse_query(query.unwrap());
+        // без query - как пустой список параметров: /accounts/new/ и likes его не требуют, filter и прочие отвечают по своим правилам
+        let params = query.map_or(Ok(Vec::new()), parse_query)?;
+        let _active_request = ACTIVITY.enter()?;
 
         let caps2 = caps.unwrap();
+        if read_lock(storage).config.batch_writes {
+            let kind = if caps2.get(5).is_some() {
+                Some(WriteKind::New)
+            } else if let Some(id) = caps2.get(6) {
+                Some(WriteKind::Update(parse_id(id.as_str())?))
+            } else if caps2.get(7).is_some() {
+                Some(WriteKind::Likes)
+            } else {
+                None
+            };
+            if let Some(kind) = kind {
+                let body = body.unwrap().to_vec();
+                PENDING_WRITES.with(|writes| writes.borrow_mut().push(PendingWrite { kind, body, conn_id }));
+                return Ok(());
+            }
+        }
         if caps2.get(1).is_some() {
             // filter
             execute_with_cache("FILTER", "FILTER_CACHED", storage, &params, record_stats, cache, resp_f,
                                || "F:".to_string() + query.unwrap_or(""),
-                               || filter::filter(&storage.read().unwrap(), &params),
-                               |r| serde_json::to_vec(r).unwrap(),
+                               |body| {
+                                   let storage = read_storage(storage, "FILTER", record_stats);
+                                   filter::filter(&storage, &params).and_then(|r| serialize(&storage, "FILTER", record_stats, body, &r))
+                               },
             )?;
             return Ok(());
         } else if caps2.get(2).is_some() {
             // group
             execute_with_cache("GROUP", "GROUP_CACHED", storage, &params, record_stats, cache, resp_f,
                                || "G:".to_string() + query.unwrap_or(""),
-                               || group::group(&storage.read().unwrap(), &params),
-                               |r| serde_json::to_vec(r).unwrap(),
+                               |body| {
+                                   let storage = read_storage(storage, "GROUP", record_stats);
+                                   group::group(&storage, &params).and_then(|r| serialize(&storage, "GROUP", record_stats, body, &r))
+                               },
             )?;
             return Ok(());
         } else if caps2.get(3).is_some() {
             // recommend
-            let id = caps2.get(3).unwrap().as_str().parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+            let id = parse_id(caps2.get(3).unwrap().as_str())?;
+            if cache {
+                return recommend_with_cache(storage, id, &params, record_stats, resp_f);
+            }
             execute_with_cache("RECOMMEND", "RECOMMEND_CACHED", storage, &params, record_stats, cache, resp_f,
                                || "R:".to_string() + &id.to_string() + ":" + query.unwrap_or(""),
-                               || recommend::recommend(&storage.read().unwrap(), id, &params),
-                               |r| serde_json::to_vec(r).unwrap(),
+                               |body| {
+                                   let storage = read_storage(storage, "RECOMMEND", record_stats);
+                                   recommend::recommend(&storage, id, &params).and_then(|r| serialize(&storage, "RECOMMEND", record_stats, body, &r))
+                               },
             )?;
             return Ok(());
         } else if caps2.get(4).is_some() {
             // suggest
-            let id = caps2.get(4).unwrap().as_str().parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+            let id = parse_id(caps2.get(4).unwrap().as_str())?;
             execute_with_cache("SUGGEST", "SUGGEST_CACHED", storage, &params, record_stats, cache, resp_f,
                                || "S:".to_string() + &id.to_string() + ":" + query.unwrap_or(""),
-                               || suggest::suggest(&storage.read().unwrap(), id, &params),
-                               |r| serde_json::to_vec(r).unwrap(),
+                               |body| {
+                                   let storage = read_storage(storage, "SUGGEST", record_stats);
+                                   suggest::suggest(&storage, id, &params).and_then(|r| serialize(&storage, "SUGGEST", record_stats, body, &r))
+                               },
             )?;
             return Ok(());
         } else if caps2.get(5).is_some() {
             // new
             let start = if record_stats { Some(Instant::now()) } else { None };
             let mut elapsed_early: Option<Duration> = None;
-            let result = storage.write().unwrap().new_account(body.unwrap(), &mut |status_code| {
-                if record_stats {
-                    elapsed_early = Some(start.unwrap().elapsed());
-                }
-                resp_f(Err(status_code));
+            let mut responded = false;
+            let (isolate_writes, stats) = isolate_options(storage);
+            let result = isolate(isolate_writes, &stats, || {
+                // строки интернируются под блокировкой на чтение, на запись - только изменение индексов
+                let parsed = read_storage(storage, "NEW", record_stats).parse_account(body.unwrap(), true)?;
+                write_storage(storage, "NEW", record_stats).new_parsed_account(parsed, body.unwrap(), &mut |status_code| {
+                    if record_stats {
+                        elapsed_early = Some(start.unwrap().elapsed());
+                    }
+                    responded = true;
+                    resp_f(Err(status_code));
+                })
             });
             CACHE.lock().clear();
             if record_stats {
-                if elapsed_early.is_some() {
-                    &storage.read().unwrap().stats.register("NEW_EARLY", elapsed_early.unwrap(), &params);
+                if let Some(elapsed_early) = elapsed_early {
+                    read_lock(storage).stats.register("NEW_EARLY", elapsed_early, &params);
                 }
-                &storage.read().unwrap().stats.register("NEW", start.unwrap().elapsed(), &params);
+                read_lock(storage).stats.register("NEW", start.unwrap().elapsed(), &params);
             }
-            if result.is_err() {
+            if result.is_err() && !responded {
                 resp_f(Err(result.unwrap_err()));
             }
             return Ok(());
         } else if caps2.get(6).is_some() {
             // update
-            let id = caps2.get(6).unwrap().as_str().parse::<i32>().map_err(|_| StatusCode::BAD_REQUEST)?;
+            let id = parse_id(caps2.get(6).unwrap().as_str())?;
             let start = if record_stats { Some(Instant::now()) } else { None };
             let mut elapsed_early: Option<Duration> = None;
-            let result = storage.write().unwrap().update_account(id, body.unwrap(), &mut |status_code| {
-                if record_stats {
-                    elapsed_early = Some(start.unwrap().elapsed());
-                }
-                resp_f(Err(status_code));
+            let mut responded = false;
+            let (isolate_writes, stats) = isolate_options(storage);
+            let result = isolate(isolate_writes, &stats, || {
+                let parsed = read_storage(storage, "UPDATE", record_stats).parse_account(body.unwrap(), false)?;
+                write_storage(storage, "UPDATE", record_stats).update_parsed_account(id, parsed, body.unwrap(), &mut |status_code| {
+                    if record_stats {
+                        elapsed_early = Some(start.unwrap().elapsed());
+                    }
+                    responded = true;
+                    resp_f(Err(status_code));
+                })
             });
             CACHE.lock().clear();
             if record_stats {
-                if elapsed_early.is_some() {
-                    &storage.read().unwrap().stats.register("UPDATE_EARLY", elapsed_early.unwrap(), &params);
+                if let Some(elapsed_early) = elapsed_early {
+                    read_lock(storage).stats.register("UPDATE_EARLY", elapsed_early, &params);
                 }
-                &storage.read().unwrap().stats.register("UPDATE", start.unwrap().elapsed(), &params);
+                read_lock(storage).stats.register("UPDATE", start.unwrap().elapsed(), &params);
             }
-            if result.is_err() {
+            if result.is_err() && !responded {
                 resp_f(Err(result.unwrap_err()));
             }
             return Ok(());
@@ -123,20 +292,23 @@ pub fn process<RF: FnMut(Result<Cow<[u8]>, StatusCode>)>(path: &str, query: Opti
             // likes
             let start = if record_stats { Some(Instant::now()) } else { None };
             let mut elapsed_early: Option<Duration> = None;
-            let result = storage.write().unwrap().update_likes(body.unwrap(), &mut |status_code| {
+            let mut responded = false;
+            let (isolate_writes, stats) = isolate_options(storage);
+            let result = isolate(isolate_writes, &stats, || write_storage(storage, "LIKES", record_stats).update_likes(body.unwrap(), &mut |status_code| {
                 if record_stats {
                     elapsed_early = Some(start.unwrap().elapsed());
                 }
+                responded = true;
                 resp_f(Err(status_code));
-            });
+            }));
             CACHE.lock().clear();
             if record_stats {
-                if elapsed_early.is_some() {
-                    &storage.read().unwrap().stats.register("LIKES_EARLY", elapsed_early.unwrap(), &params);
+                if let Some(elapsed_early) = elapsed_early {
+                    read_lock(storage).stats.register("LIKES_EARLY", elapsed_early, &params);
                 }
-                &storage.read().unwrap().stats.register("LIKES", start.unwrap().elapsed(), &params);
+                read_lock(storage).stats.register("LIKES", start.unwrap().elapsed(), &params);
             }
-            if result.is_err() {
+            if result.is_err() && !responded {
                 resp_f(Err(result.unwrap_err()));
             }
             return Ok(());
@@ -145,8 +317,193 @@ pub fn process<RF: FnMut(Result<Cow<[u8]>, StatusCode>)>(path: &str, query: Opti
     Err(StatusCode::NOT_FOUND)
 }
 
-fn execute_with_cache<R, RF, CF, PF, MRF>(name: &'static str, name_cache: &'static str, storage: &Arc<RwLock<Storage>>, params: &Vec<(String, String)>, record_stats: bool, cache: bool, mut resp_f: RF, cache_key_f: CF, process_f: PF, make_response_f: MRF) -> Result<(), StatusCode>
-    where RF: FnMut(Result<Cow<[u8]>, StatusCode>), CF: FnOnce() -> String, PF: FnOnce() -> Result<R, StatusCode>, MRF: FnOnce(&R) -> Vec<u8> {
+/// Пути, которые что-то меняют, принимают только POST: остальные методы - 405, а не изменение по HEAD или GET.
+fn require_post(method: HttpMethod) -> Result<(), StatusCode> {
+    if method != HttpMethod::Post {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    Ok(())
+}
+
+/// Ограничение числа записей в кэше ответов и в кэше recommend, 0 - без ограничения.
+pub fn set_cache_size(max_entries: usize) {
+    CACHE.lock().set_max_entries(max_entries);
+    RECOMMEND_CACHE.lock().set_max_entries(max_entries);
+}
+
+/// Сброс обоих кэшей: при reload и по DELETE /admin/cache. Записи меняют только общий кэш, recommend следит за ними сам.
+fn clear_caches() {
+    CACHE.lock().clear();
+    RECOMMEND_CACHE.lock().clear();
+}
+
+/// Ответы-потоки (conn_id, cursor), запрошенные в текущем потоке: /admin/export.
+pub fn take_streams() -> Vec<(usize, ExportCursor)> {
+    PENDING_STREAMS.with(|streams| mem::take(&mut *streams.borrow_mut()))
+}
+
+/// Позиция /admin/export: все аккаунты по возрастанию id в формате NDJSON, одна строка - один аккаунт, как в new_account.
+/// Каждая часть берет блокировку на чтение отдельно, поэтому записи между частями не ждут всю выгрузку;
+/// каждый аккаунт согласован, выгрузка целиком - нет.
+pub struct ExportCursor {
+    next_id: usize,
+}
+
+impl ExportCursor {
+    pub fn new() -> ExportCursor {
+        ExportCursor { next_id: 0 }
+    }
+
+    /// Дописывает в out следующие EXPORT_BATCH аккаунтов; false - аккаунтов больше нет.
+    pub fn next_batch(&mut self, storage: &RwLock<Storage>, out: &mut Vec<u8>) -> serde_json::Result<bool> {
+        let storage = read_lock(storage);
+        let end = storage.max_id + 1;
+        let mut count = 0;
+        while self.next_id < end && count < EXPORT_BATCH {
+            if let Some(account) = storage.accounts[self.next_id].as_ref() {
+                serde_json::to_writer(&mut *out, &storage.get_account_json(account))?;
+                out.push(b'\n');
+                count += 1;
+            }
+            self.next_id += 1;
+        }
+        Ok(self.next_id < end)
+    }
+}
+
+/// Выгрузка целиком, как ее получает клиент /admin/export.
+#[cfg(test)]
+pub fn export(storage: &RwLock<Storage>, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+    let mut cursor = ExportCursor::new();
+    let mut batch = Vec::new();
+    loop {
+        batch.clear();
+        let more = cursor.next_batch(storage, &mut batch)?;
+        out.write_all(&batch)?;
+        if !more {
+            return out.flush();
+        }
+    }
+}
+
+/// Применяет накопленные в потоке POST запросы под одной блокировкой на запись.
+/// Ответы (conn_id, статус) возвращаются в порядке запросов и отправляются уже после снятия блокировки.
+pub fn apply_pending_writes(storage: &RwLock<Storage>, record_stats: bool) -> Vec<(usize, StatusCode)> {
+    let writes = PENDING_WRITES.with(|writes| mem::take(&mut *writes.borrow_mut()));
+    if writes.is_empty() {
+        return Vec::new();
+    }
+    let _active_request = match ACTIVITY.enter() {
+        Ok(active_request) => active_request,
+        Err(status_code) => return writes.iter().map(|write| (write.conn_id, status_code.clone())).collect(),
+    };
+    let start = if record_stats { Some(Instant::now()) } else { None };
+    let mut responses = Vec::with_capacity(writes.len());
+    {
+        let mut storage = write_storage(storage, "WRITE_BATCH", record_stats);
+        let isolate_writes = storage.config.isolate_writes;
+        let stats = storage.stats.clone();
+        for write in &writes {
+            let responded = responses.len();
+            let result = isolate(isolate_writes, &stats, || {
+                let mut success_response_f = |status_code| responses.push((write.conn_id, status_code));
+                match write.kind {
+                    WriteKind::New => storage.new_account(&write.body, &mut success_response_f),
+                    WriteKind::Update(id) => storage.update_account(id, &write.body, &mut success_response_f),
+                    WriteKind::Likes => storage.update_likes(&write.body, &mut success_response_f),
+                }
+            });
+            if let Err(status_code) = result {
+                if responses.len() == responded {
+                    responses.push((write.conn_id, status_code));
+                }
+            }
+        }
+    }
+    CACHE.lock().clear();
+    if record_stats {
+        read_lock(storage).stats.register("WRITE_BATCH", start.unwrap().elapsed(), &Vec::new());
+    }
+    responses
+}
+
+/// При isolate_writes паника внутри f (обычно изменение storage) превращается в 500 вместо падения потока
+/// и попадает в stats. Блокировка storage при этом может остаться отравленной, read_lock/write_lock ее восстанавливают.
+fn isolate<T, F: FnOnce() -> Result<T, StatusCode>>(isolate_writes: bool, stats: &Stats, f: F) -> Result<T, StatusCode> {
+    if !isolate_writes {
+        return f();
+    }
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = payload.downcast_ref::<&str>().map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        error!("panic while applying a write, responding 500: {}", msg);
+        stats.register_panic(msg);
+        Err(StatusCode::INTERNAL_SERVER_ERROR)
+    })
+}
+
+/// config.isolate_writes и stats для isolate; читаются до блокировки на запись, а не в аргументах isolate,
+/// где временная блокировка на чтение дожила бы до конца вызова.
+fn isolate_options(storage: &RwLock<Storage>) -> (bool, Arc<Stats>) {
+    let storage = read_lock(storage);
+    (storage.config.isolate_writes, storage.stats.clone())
+}
+
+/// storage.read() с учетом времени ожидания блокировки в статистике.
+fn read_storage<'a>(storage: &'a RwLock<Storage>, request_type: &'static str, record_stats: bool) -> RwLockReadGuard<'a, Storage> {
+    if !record_stats {
+        return read_lock(storage);
+    }
+    let start = Instant::now();
+    let guard = read_lock(storage);
+    guard.stats.register_lock_wait(request_type, start.elapsed());
+    guard
+}
+
+/// storage.write() с учетом времени ожидания блокировки в статистике.
+fn write_storage<'a>(storage: &'a RwLock<Storage>, request_type: &'static str, record_stats: bool) -> RwLockWriteGuard<'a, Storage> {
+    if !record_stats {
+        return write_lock(storage);
+    }
+    let start = Instant::now();
+    let guard = write_lock(storage);
+    guard.stats.register_lock_wait(request_type, start.elapsed());
+    guard
+}
+
+/// serde_json::to_vec, ошибка сериализации - 500, а не паника в потоке poll.
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, StatusCode> {
+    serde_json::to_vec(value).map_err(|e| {
+        error!("response serialization failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// serde_json::to_writer с учетом времени сериализации и длины ответа в статистике.
+/// Ответ длиннее config.max_response_bytes не отправляется, вместо него 413; ошибка сериализации - 500.
+fn serialize<T: Serialize>(storage: &Storage, request_type: &'static str, record_stats: bool, body: &mut Vec<u8>, result: &T) -> Result<(), StatusCode> {
+    let start = if record_stats { Some(Instant::now()) } else { None };
+    serde_json::to_writer(&mut *body, result).map_err(|e| {
+        error!("{} response serialization failed: {}", request_type, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if record_stats {
+        storage.stats.register_serialize(request_type, start.unwrap().elapsed());
+        storage.stats.register_response_size(request_type, body.len());
+    }
+    let max_response_bytes = storage.config.max_response_bytes;
+    if max_response_bytes != 0 && body.len() > max_response_bytes {
+        warn!("{} response of {} bytes exceeds --max-response-bytes {}", request_type, body.len(), max_response_bytes);
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    Ok(())
+}
+
+/// process_f пишет ответ в буфер под той же блокировкой storage, в которой он посчитан:
+/// результат может ссылаться на аккаунты (filter), поэтому время в статистике включает сериализацию; отдельно она учитывается в serialize.
+fn execute_with_cache<RF, CF, PF>(name: &'static str, name_cache: &'static str, storage: &Arc<RwLock<Storage>>, params: &Vec<(String, String)>, record_stats: bool, cache: bool, mut resp_f: RF, cache_key_f: CF, process_f: PF) -> Result<(), StatusCode>
+    where RF: FnMut(Result<Cow<[u8]>, StatusCode>), CF: FnOnce() -> String, PF: FnOnce(&mut Vec<u8>) -> Result<(), StatusCode> {
 
     let start = if record_stats { Some(Instant::now()) } else { None };
     let cache_key: String;
@@ -155,32 +512,498 @@ fn execute_with_cache<R, RF, CF, PF, MRF>(name: &'static str, name_cache: &'stat
         if let Some(response) = CACHE.lock().get(&cache_key) {
             resp_f(Ok(Cow::from(response)));
             if record_stats {
-                &storage.read().unwrap().stats.register(name_cache, start.unwrap().elapsed(), &params);
+                let storage = read_lock(storage);
+                storage.stats.register(name_cache, start.unwrap().elapsed(), params);
+                storage.stats.register_cache_lookup(true);
             }
             return Ok(());
         }
+        if record_stats {
+            read_lock(storage).stats.register_cache_lookup(false);
+        }
     } else {
         cache_key = String::new();
     }
-    let process_result: R = process_f()?;
-    if record_stats {
-        &storage.read().unwrap().stats.register(name, start.unwrap().elapsed(), &params);
-    }
-    let response = make_response_f(&process_result);
-    resp_f(Ok(Cow::from(&response)));
-    if cache {
-        CACHE.lock().insert(cache_key, response);
+    BODY_BUFFER.with(|body| {
+        let mut body = body.borrow_mut();
+        body.clear();
+        process_f(&mut body)?;
+        if record_stats {
+            read_lock(storage).stats.register(name, start.unwrap().elapsed(), params);
+        }
+        resp_f(Ok(Cow::from(&body[..])));
+        if cache {
+            CACHE.lock().insert(cache_key, body.clone());
+        }
+        Ok(())
+    })
+}
+
+/// recommend через RECOMMEND_CACHE: запись не сбрасывается при записях, а проверяется по recommend_versions
+/// под той же блокировкой storage. person без интересов не кэшируется - ответ пустой и считается сразу.
+fn recommend_with_cache<RF: FnMut(Result<Cow<[u8]>, StatusCode>)>(storage: &Arc<RwLock<Storage>>, id: i32, params: &Vec<(String, String)>, record_stats: bool, mut resp_f: RF) -> Result<(), StatusCode> {
+    let start = if record_stats { Some(Instant::now()) } else { None };
+    let key = RecommendKey::new(id, params);
+    let storage = read_storage(storage, "RECOMMEND", record_stats);
+    let interests = storage.accounts.get(id as usize).and_then(|account| account.as_ref())
+        .map(|person| &person.interests)
+        .filter(|interests| !interests.is_empty());
+    if let Some(interests) = interests {
+        if let Some(response) = RECOMMEND_CACHE.lock().get(&key, interests, &storage.recommend_versions) {
+            resp_f(Ok(Cow::from(response)));
+            if record_stats {
+                storage.stats.register("RECOMMEND_CACHED", start.unwrap().elapsed(), params);
+            }
+            return Ok(());
+        }
     }
-    Ok(())
+    BODY_BUFFER.with(|body| {
+        let mut body = body.borrow_mut();
+        body.clear();
+        recommend::recommend(&storage, id, params).and_then(|r| serialize(&storage, "RECOMMEND", record_stats, &mut body, &r))?;
+        if record_stats {
+            storage.stats.register("RECOMMEND", start.unwrap().elapsed(), params);
+        }
+        resp_f(Ok(Cow::from(&body[..])));
+        if interests.is_some() {
+            RECOMMEND_CACHE.lock().insert(key, body.clone(), &storage.recommend_versions);
+        }
+        Ok(())
+    })
+}
+
+#[derive(Serialize)]
+struct StatsJson {
+    cache: CacheSnapshot,
+    // попадания в кэш recommend, см. recommend_with_cache
+    recommend_cache: RecommendCacheSnapshot,
+    // перехваченные при --isolate-writes паники
+    panics: PanicsJson,
+}
+
+// расхождения инкрементальных индексов с пересчитанными, пустой список - индекс верен
+#[derive(Serialize)]
+struct VerifyJson {
+    group_index: Vec<String>,
 }
 
-fn parse_query(query: &str) -> Vec<(String, String)> { // TODO avoid String creation
+/// Регулярка пропускает только цифры, поэтому ошибка разбора - это переполнение i32, то есть такого аккаунта нет.
+fn parse_id(str: &str) -> Result<i32, StatusCode> {
+    str.parse::<i32>().map_err(|_| StatusCode::NOT_FOUND)
+}
+
+fn parse_query(query: &str) -> Result<Vec<(String, String)>, StatusCode> { // TODO avoid String creation
     query.split('&').map(|part: &str| match part.find('=') {
-        Some(index) => (decode_query_part(&part[0..index]), decode_query_part(&part[index + 1..])),
-        None => (decode_query_part(&part), String::new())
+        Some(index) => Ok((decode_query_part(&part[0..index])?, decode_query_part(&part[index + 1..])?)),
+        None => Ok((decode_query_part(part)?, String::new()))
     }).collect()
 }
 
-fn decode_query_part(str: &str) -> String {
-    percent_decode(str.replace("+", " ").as_bytes()).decode_utf8().unwrap().to_string() // TODO faster replace?
+fn decode_query_part(str: &str) -> Result<String, StatusCode> {
+    percent_decode(str.replace("+", " ").as_bytes()).decode_utf8() // TODO faster replace?
+        .map(|decoded| decoded.to_string())
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::make_storage;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        {
+            let params = parse_query("city=%D0%9C%D0%BE%D1%81%D0%BA%D0%B2%D0%B0&limit=10").unwrap();
+            assert_eq!(params, vec![("city".to_string(), "Москва".to_string()), ("limit".to_string(), "10".to_string())]);
+        }
+        {
+            let params = parse_query("sname_starts=a+b&query_id").unwrap();
+            assert_eq!(params, vec![("sname_starts".to_string(), "a b".to_string()), ("query_id".to_string(), String::new())]);
+        }
+        {
+            assert_eq!(parse_query("city=%FF&limit=10"), Err(StatusCode::BAD_REQUEST));
+        }
+        {
+            assert_eq!(parse_query("%FF=1"), Err(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    #[test]
+    fn test_missing_query() {
+        let storage = Arc::new(RwLock::new(make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let run = |method: HttpMethod, path: &str, query: Option<&str>, body: Option<&[u8]>| {
+            let mut response = None;
+            process(method, path, query, body, &storage, false, false, 0, 0, |result| response = Some(result.map(|body| String::from_utf8(body.to_vec()).unwrap())))
+                .and_then(|_| response.unwrap())
+        };
+
+        assert_eq!(run(HttpMethod::Get, "/accounts/filter/", Some("sex_eq=m&limit=5"), None), Ok(r#"{"accounts":[{"id":1,"email":"a1@a.ru","sex":"m"}]}"#.to_string()));
+        // без query - как без параметров: нет обязательного limit
+        assert_eq!(run(HttpMethod::Get, "/accounts/filter/", None, None), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(run(HttpMethod::Get, "/accounts/group/", None, None), Err(StatusCode::BAD_REQUEST));
+
+        let account = r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#;
+        assert_eq!(run(HttpMethod::Post, "/accounts/new/", None, Some(account.as_bytes())), Err(StatusCode::CREATED));
+        assert_eq!(run(HttpMethod::Post, "/accounts/2/", None, Some(br#"{"city":"c1"}"#)), Err(StatusCode::ACCEPTED));
+        assert_eq!(run(HttpMethod::Get, "/accounts/filter/", Some("city_eq=c1&limit=5"), None), Ok(r#"{"accounts":[{"id":2,"email":"a2@a.ru","city":"c1"}]}"#.to_string()));
+    }
+
+    #[test]
+    fn test_write_methods() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        storage.config.admin = true;
+        let storage = Arc::new(RwLock::new(storage));
+        let run = |method: HttpMethod, path: &str, body: Option<&[u8]>| process(method, path, Some("query_id=1"), body, &storage, false, false, 0, 0, |_| {});
+
+        for method in &[HttpMethod::Get, HttpMethod::Head] {
+            assert_eq!(run(*method, "/accounts/new/", None), Err(StatusCode::METHOD_NOT_ALLOWED));
+            assert_eq!(run(*method, "/accounts/likes/", None), Err(StatusCode::METHOD_NOT_ALLOWED));
+            assert_eq!(run(*method, "/admin/reload", None), Err(StatusCode::METHOD_NOT_ALLOWED));
+            // аккаунт по id отдается и на HEAD
+            assert_eq!(run(*method, "/accounts/1/", None), Ok(()));
+        }
+        assert_eq!(run(HttpMethod::Delete, "/accounts/1/", None), Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(run(HttpMethod::Delete, "/accounts/filter/", None), Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(run(HttpMethod::Post, "/accounts/likes/", Some(br#"{"likes":[]}"#)), Ok(()));
+    }
+
+    #[test]
+    fn test_recommend_suggest_id() {
+        let storage = Arc::new(RwLock::new(make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let process_path = |path: &str| process(HttpMethod::Get, path, Some("limit=10"), None, &storage, false, false, 0, 0, |_| {});
+
+        assert_eq!(process_path("/accounts/abc/recommend/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process_path("/accounts/abc/suggest/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process_path("/accounts/999999999999/recommend/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process_path("/accounts/999999999999/suggest/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process_path("/accounts/1999999999/recommend/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process_path("/accounts/1999999999/suggest/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process_path("/accounts/2/recommend/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process_path("/accounts/1/recommend/"), Ok(()));
+        assert_eq!(process_path("/accounts/1/suggest/"), Ok(()));
+    }
+
+    #[test]
+    fn test_default_limit() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a"],"likes":[{"id":4,"ts":1}]}"#,
+            r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a"]}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a"]}"#,
+            r#"{"id":4,"email":"a4@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":5,"email":"a5@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":2,"ts":1},{"id":3,"ts":1},{"id":4,"ts":1}]}"#,
+        ]);
+        let paths = [("/accounts/filter/", "accounts"), ("/accounts/group/", "groups"), ("/accounts/1/recommend/", "accounts"), ("/accounts/1/suggest/", "accounts")];
+        let count = |storage: &Arc<RwLock<Storage>>, path: &str, field: &str, limit: Option<&str>| {
+            let query = limit.map(|limit| format!("limit={}&", limit)).unwrap_or_default() + if path.contains("group") { "keys=sex" } else { "query_id=1" };
+            let mut response = None;
+            process(HttpMethod::Get, path, Some(&query), None, storage, false, false, 0, 0, |result| response = Some(result.map(|body| body.to_vec())))?;
+            response.unwrap().map(|body| serde_json::from_slice::<serde_json::Value>(&body).unwrap()[field].as_array().unwrap().len())
+        };
+
+        storage.config.default_limit = 0;
+        let storage = Arc::new(RwLock::new(storage));
+        for (path, field) in &paths {
+            assert_eq!(count(&storage, path, field, None), Err(StatusCode::BAD_REQUEST), "{}", path);
+            assert_eq!(count(&storage, path, field, Some("0")), Err(StatusCode::BAD_REQUEST), "{}", path);
+            assert_eq!(count(&storage, path, field, Some("2")), Ok(2), "{}", path);
+        }
+
+        write_lock(&storage).config.default_limit = 1;
+        for (path, field) in &paths {
+            assert_eq!(count(&storage, path, field, None), Ok(1), "{}", path);
+            assert_eq!(count(&storage, path, field, Some("0")), Err(StatusCode::BAD_REQUEST), "{}", path);
+            assert_eq!(count(&storage, path, field, Some("2")), Ok(2), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_max_response_bytes() {
+        let accounts: Vec<String> = (1..201).map(|id| format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}}"#, id, id)).collect();
+        let accounts: Vec<&str> = accounts.iter().map(|account| account.as_str()).collect();
+        let mut storage = make_storage(&accounts);
+        storage.config.max_response_bytes = 1000;
+        let storage = Arc::new(RwLock::new(storage));
+        let filter = |limit: usize| {
+            let mut len = 0;
+            process(HttpMethod::Get, "/accounts/filter/", Some(&format!("sex_eq=m&limit={}", limit)), None, &storage, true, false, 0, 0, |result| len = result.unwrap().len())
+                .map(|_| len)
+        };
+
+        // ответ на 50 аккаунтов - около 1500 байт
+        assert_eq!(filter(50), Err(StatusCode::PAYLOAD_TOO_LARGE));
+        assert!(filter(10).unwrap() <= 1000);
+        write_lock(&storage).config.max_response_bytes = 0;
+        assert!(filter(50).unwrap() > 1000);
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+    }
+
+    #[test]
+    fn test_serialize_error() {
+        let storage = Arc::new(RwLock::new(make_storage(&[])));
+        assert_eq!(to_json(&Unserializable), Err(StatusCode::INTERNAL_SERVER_ERROR));
+
+        let mut calls = 0;
+        for _ in 0..2 {
+            let mut responded = false;
+            let result = execute_with_cache("TEST", "TEST_CACHED", &storage, &Vec::new(), true, true, |_| responded = true,
+                                            || "T:serialize-error".to_string(),
+                                            |body| {
+                                                calls += 1;
+                                                serialize(&read_lock(&storage), "TEST", true, body, &Unserializable)
+                                            });
+            assert_eq!(result, Err(StatusCode::INTERNAL_SERVER_ERROR));
+            assert!(!responded);
+        }
+        // ошибка не попадает в кэш
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn test_isolate_writes() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        storage.config.isolate_writes = true;
+        storage.config.admin = true;
+        let storage = Arc::new(RwLock::new(storage));
+
+        // паника под блокировкой на запись отравляет ее
+        let stats = read_lock(&storage).stats.clone();
+        let result: Result<(), StatusCode> = isolate(true, &stats, || {
+            let _storage = write_lock(&storage);
+            panic!("forced panic in write {}", 1);
+        });
+        assert_eq!(result, Err(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(storage.is_poisoned());
+        assert_eq!(stats.panics().count, 1);
+        assert_eq!(stats.panics().recent, vec!["forced panic in write 1".to_string()]);
+
+        let mut response = None;
+        process(HttpMethod::Get, "/admin/stats", None, None, &storage, false, false, 0, 0,
+                |result| response = Some(result.map(|body| String::from_utf8(body.to_vec()).unwrap()))).unwrap();
+        assert!(response.unwrap().unwrap().contains(r#""panics":{"count":1,"recent":["forced panic in write 1"]}"#));
+
+        let mut response = None;
+        process(HttpMethod::Get, "/accounts/filter/", Some("sex_eq=m&limit=10"), None, &storage, false, false, 0, 0,
+                |result| response = Some(result.map(|body| String::from_utf8(body.to_vec()).unwrap()))).unwrap();
+        assert!(response.unwrap().unwrap().contains("a1@a.ru"));
+
+        let mut response = None;
+        process(HttpMethod::Post, "/accounts/1/", Some("query_id=1"), Some(br#"{"email":"b1@a.ru"}"#), &storage, false, false, 0, 0,
+                |result| response = Some(result.map(|_| ()))).unwrap();
+        assert_eq!(response, Some(Err(StatusCode::ACCEPTED)));
+        assert_eq!(read_lock(&storage).accounts[1].as_ref().unwrap().email.as_ref().unwrap().as_str(), "b1@a.ru");
+    }
+
+    #[test]
+    fn test_get_account() {
+        let storage = Arc::new(RwLock::new(make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let account = r#"{"id":2,"email":"a2@a.ru","fname":"f2","sname":"s2","phone":"8(900)0234567","sex":"f","birth":-100,"country":"k1","city":"c1","joined":1400000000,"status":"заняты","interests":["x","y"],"likes":[{"id":1,"ts":5},{"id":1,"ts":7}],"premium":{"start":1500000000,"finish":1600000000}}"#;
+        let get = |path: &str, query: Option<&str>| {
+            let mut response = None;
+            process(HttpMethod::Get, path, query, None, &storage, false, true, 0, 0, |result| response = Some(result.map(|body| String::from_utf8(body.to_vec()).unwrap())))
+                .and_then(|()| response.unwrap())
+        };
+
+        let mut response = None;
+        process(HttpMethod::Post, "/accounts/new/", Some("query_id=1"), Some(account.as_bytes()), &storage, false, true, 0, 0, |result| response = Some(result.map(|_| ()))).unwrap();
+        assert_eq!(response, Some(Err(StatusCode::CREATED)));
+        let json = |body: &str| serde_json::from_str::<serde_json::Value>(body).unwrap();
+        assert_eq!(json(&get("/accounts/2/", Some("query_id=2")).unwrap()), json(account));
+        assert_eq!(json(&get("/accounts/2", None).unwrap()), json(account));
+
+        // POST на тот же путь по-прежнему обновляет
+        process(HttpMethod::Post, "/accounts/2/", Some("query_id=3"), Some(br#"{"city":"c2"}"#), &storage, false, true, 0, 0, |_| {}).unwrap();
+        assert!(get("/accounts/2/", None).unwrap().contains(r#""city":"c2""#));
+        assert_eq!(get("/accounts/3/", None), Err(StatusCode::NOT_FOUND));
+        assert_eq!(get("/accounts/99999999999/", None), Err(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let storage = RwLock::new(make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","fname":"f1","sname":"s1","phone":"8(900)0234567","country":"k1","city":"c1","birth":600000000,"joined":1300000000,"interests":["x","y"],"likes":[{"id":3,"ts":20},{"id":2,"ts":10},{"id":3,"ts":5}],"premium":{"start":1500000000,"finish":1600000000}}"#,
+            r#"{"id":3,"email":"a3@a.ru","sex":"f","status":"заняты","birth":-100,"joined":1400000000,"likes":[{"id":1,"ts":7}]}"#,
+            r#"{"id":5,"email":"a5@a.ru","sex":"m","status":"всё сложно","birth":700000000,"joined":1450000000}"#,
+        ]));
+        let mut exported = Vec::new();
+        export(&storage, &mut exported).unwrap();
+        let exported = String::from_utf8(exported).unwrap();
+        assert_eq!(exported.lines().count(), 3);
+        assert!(exported.ends_with("\n"));
+        assert!(exported.lines().next().unwrap().contains(r#""likes":[{"id":2,"ts":10},{"id":3,"ts":5},{"id":3,"ts":20}]"#));
+
+        let imported = RwLock::new(make_storage(&[]));
+        for line in exported.lines() {
+            imported.write().unwrap().new_account(line.as_bytes(), &mut |_| {}).unwrap();
+        }
+        let mut reexported = Vec::new();
+        export(&imported, &mut reexported).unwrap();
+        assert_eq!(String::from_utf8(reexported).unwrap(), exported);
+        let likers = |storage: &RwLock<Storage>| {
+            let mut likes: Vec<(i32, i32)> = read_lock(storage).indexes.likes_index_male[&3].iter().map(|like| (like.id, like.ts)).collect();
+            likes.sort();
+            likes
+        };
+        assert_eq!(likers(&imported), likers(&storage));
+    }
+
+    #[test]
+    fn test_admin_cache() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        storage.config.admin = true;
+        let storage = Arc::new(RwLock::new(storage));
+        let get = |path: &str, query: Option<&str>| {
+            let mut response = None;
+            process(HttpMethod::Get, path, query, None, &storage, false, true, 0, 0, |result| response = Some(result.map(|body| String::from_utf8(body.to_vec()).unwrap()))).unwrap();
+            response.unwrap()
+        };
+        let cached = |keys: Result<String, StatusCode>| keys.unwrap().contains("F:sex_eq=m&limit=10&query_id=2217");
+
+        get("/accounts/filter/", Some("sex_eq=m&limit=10&query_id=2217")).unwrap();
+        assert!(cached(get("/admin/cache", None)));
+
+        // после записи кэш сбрасывается
+        process(HttpMethod::Post, "/accounts/new/", Some("query_id=1"), Some(r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#.as_bytes()),
+                &storage, false, true, 0, 0, |_| {}).unwrap();
+        assert!(!cached(get("/admin/cache", None)));
+
+        get("/accounts/filter/", Some("sex_eq=m&limit=10&query_id=2217")).unwrap();
+        assert!(cached(get("/admin/cache", None)));
+        assert_eq!(process(HttpMethod::Delete, "/admin/cache", None, None, &storage, false, true, 0, 0, |_| {}), Ok(()));
+        assert!(!cached(get("/admin/cache", None)));
+
+        let reindex = |method: HttpMethod, query: &str| process(method, "/admin/reindex", Some(query), None, &storage, false, true, 0, 0, |_| {});
+        get("/accounts/filter/", Some("sex_eq=m&limit=10&query_id=2217")).unwrap();
+        assert_eq!(reindex(HttpMethod::Get, "index=city"), Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(reindex(HttpMethod::Head, "index=city"), Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert!(cached(get("/admin/cache", None)));
+        assert_eq!(reindex(HttpMethod::Post, "index=city"), Ok(()));
+        assert!(!cached(get("/admin/cache", None)));
+        assert_eq!(get("/admin/verify", None), Ok(r#"{"group_index":[]}"#.to_string()));
+        assert_eq!(reindex(HttpMethod::Post, "index=likes"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(reindex(HttpMethod::Post, "name=city"), Err(StatusCode::BAD_REQUEST));
+
+        storage.write().unwrap().config.admin = false;
+        assert_eq!(process(HttpMethod::Get, "/admin/cache", None, None, &storage, false, true, 0, 0, |_| {}), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process(HttpMethod::Delete, "/admin/cache", None, None, &storage, false, true, 0, 0, |_| {}), Err(StatusCode::METHOD_NOT_ALLOWED));
+        assert_eq!(process(HttpMethod::Post, "/admin/reindex", Some("index=city"), None, &storage, false, true, 0, 0, |_| {}), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process(HttpMethod::Get, "/admin/verify", None, None, &storage, false, true, 0, 0, |_| {}), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process(HttpMethod::Get, "/admin/stats", None, None, &storage, false, true, 0, 0, |_| {}), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process(HttpMethod::Get, "/admin/export", None, None, &storage, false, true, 0, 0, |_| {}), Err(StatusCode::NOT_FOUND));
+        assert_eq!(process(HttpMethod::Post, "/admin/reload", None, None, &storage, false, true, 0, 0, |_| {}), Err(StatusCode::NOT_FOUND));
+        assert!(take_streams().is_empty());
+    }
+
+    #[test]
+    fn test_cache_lookups() {
+        let storage = Arc::new(RwLock::new(make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+        let get = |query: &str, cache: bool| {
+            let mut response = None;
+            process(HttpMethod::Get, "/accounts/filter/", Some(query), None, &storage, true, cache, 0, 0, |result| response = Some(result.map(|body| body.to_vec()))).unwrap();
+            response.unwrap().unwrap()
+        };
+        let lookups = || read_lock(&storage).stats.cache_lookups();
+
+        // без --cache кэш не используется
+        get("sex_eq=m&limit=10&query_id=2300", false);
+        assert_eq!(lookups(), (0, 0));
+        let first = get("sex_eq=m&limit=10&query_id=2300", true);
+        assert_eq!(lookups(), (0, 1));
+        assert_eq!(get("sex_eq=m&limit=10&query_id=2300", true), first);
+        assert_eq!(lookups(), (1, 1));
+        get("sex_eq=m&limit=9&query_id=2300", true);
+        assert_eq!(lookups(), (1, 2));
+    }
+
+    #[test]
+    fn test_recommend_cache() {
+        let account = |id: i32, sex: &str, interests: &str| format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"свободны","birth":600000000,"joined":1300000000,"interests":[{}]}}"#, id, id, sex, interests);
+        let accounts = [account(1, "m", r#""a","b""#), account(2, "f", r#""a""#), account(3, "f", r#""a""#), account(4, "f", r#""c""#), account(5, "m", r#""a""#), account(7, "m", "")];
+        let accounts: Vec<&str> = accounts.iter().map(|account| account.as_str()).collect();
+        let storage = Arc::new(RwLock::new(make_storage(&accounts)));
+        let recommend = |id: i32, cache: bool| {
+            let mut response = None;
+            process(HttpMethod::Get, &format!("/accounts/{}/recommend/", id), Some("limit=17&query_id=5"), None, &storage, false, cache, 0, 0,
+                    |result| response = Some(result.map(|body| String::from_utf8(body.to_vec()).unwrap()))).unwrap();
+            response.unwrap().unwrap()
+        };
+        let post = |path: &str, body: &str| process(HttpMethod::Post, path, Some("query_id=1"), Some(body.as_bytes()), &storage, false, true, 0, 0, |_| {}).unwrap();
+        // меняет аккаунт в обход recommend_versions: по старому ответу видно попадание в кэш
+        let sneak_birth = |id: usize, birth: i32| storage.write().unwrap().accounts[id].as_mut().unwrap().birth = birth;
+
+        let cached = recommend(1, true);
+        assert!(cached.contains(r#""id":2"#) && cached.contains(r#""id":3"#), "{}", cached);
+        sneak_birth(2, 600000001);
+        assert_eq!(recommend(1, true), cached);
+        // likes и аккаунты без общих интересов запись не сбрасывают
+        post("/accounts/likes/", r#"{"likes":[{"liker":2,"likee":1,"ts":1}]}"#);
+        post("/accounts/4/", r#"{"sname":"s4"}"#);
+        assert_eq!(recommend(1, true), cached);
+
+        // аккаунт с общим интересом сбрасывает, даже если сам в ответ не попадает
+        post("/accounts/5/", r#"{"sname":"s5"}"#);
+        let fresh = recommend(1, true);
+        assert!(fresh.contains("600000001"), "{}", fresh);
+        assert_eq!(fresh, recommend(1, false));
+        post("/accounts/new/", &account(6, "f", r#""b""#));
+        assert!(recommend(1, true).contains(r#""id":6"#));
+        // интересы, которые аккаунт потерял, тоже сбрасывают
+        post("/accounts/3/", r#"{"interests":["c"]}"#);
+        assert!(!recommend(1, true).contains(r#""id":3"#));
+
+        // person без интересов не кэшируется
+        assert_eq!(recommend(7, true), r#"{"accounts":[]}"#);
+        post("/accounts/7/", r#"{"interests":["c"]}"#);
+        assert!(recommend(7, true).contains(r#""id":4"#));
+    }
+
+    #[test]
+    fn test_batch_writes() {
+        let mut storage = make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        storage.config.batch_writes = true;
+        let storage = Arc::new(RwLock::new(storage));
+        let process_post = |path: &str, body: &str, conn_id: usize| {
+            process(HttpMethod::Post, path, Some("query_id=1"), Some(body.as_bytes()), &storage, false, false, 0, conn_id, |_| panic!("response before apply"))
+        };
+
+        process_post("/accounts/new/", r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#, 10).unwrap();
+        process_post("/accounts/likes/", r#"{"likes":[{"liker":1,"likee":2,"ts":1}]}"#, 11).unwrap();
+        process_post("/accounts/2/", r#"{"email":"a1@a.ru"}"#, 10).unwrap();
+        process_post("/accounts/3/", r#"{"city":"Москва"}"#, 12).unwrap();
+        assert!(storage.read().unwrap().accounts[2].is_none());
+
+        let responses = apply_pending_writes(&storage, false);
+        assert_eq!(responses, vec![
+            (10, StatusCode::CREATED),
+            (11, StatusCode::ACCEPTED),
+            (10, StatusCode::BAD_REQUEST),
+            (12, StatusCode::NOT_FOUND),
+        ]);
+        assert!(storage.read().unwrap().accounts[2].is_some());
+        assert_eq!(storage.read().unwrap().accounts[1].as_ref().unwrap().likes, vec![2]);
+        assert_eq!(apply_pending_writes(&storage, false), vec![]);
+    }
 }
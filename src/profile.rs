@@ -0,0 +1,101 @@
+/// Согласованные размеры буферов для --profile; отдельные флаги (--read-buffer, --events, --backlog,
+/// --cache-size) переопределяют значения профиля.
+///
+/// | профиль | read buffer | events | backlog | cache size |
+/// |---------|-------------|--------|---------|------------|
+/// | small   | 4096        | 256    | 256     | 10000      |
+/// | medium  | 8192        | 1024   | 1024    | 0          |
+/// | large   | 16384       | 4096   | 4096    | 0          |
+///
+/// medium - прежние значения по умолчанию, 0 - без ограничения. auto выбирает профиль по числу CPU.
+/// Ограничения, меняющие результат (--recommend-cap, --group-cap), в профиль не входят и задаются только явно.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    // буфер чтения запроса на соединение, запрос длиннее не поместится
+    pub read_buffer: usize,
+    // емкость Events для одного вызова poll
+    pub events_capacity: usize,
+    // очередь listen
+    pub backlog: i32,
+    // максимум записей в кэше ответов
+    pub cache_size: usize,
+}
+
+impl Profile {
+    pub fn small() -> Profile {
+        Profile {
+            read_buffer: 4096,
+            events_capacity: 256,
+            backlog: 256,
+            cache_size: 10000,
+        }
+    }
+
+    pub fn medium() -> Profile {
+        Profile {
+            read_buffer: 8192,
+            events_capacity: 1024,
+            backlog: 1024,
+            cache_size: 0,
+        }
+    }
+
+    pub fn large() -> Profile {
+        Profile {
+            read_buffer: 16384,
+            events_capacity: 4096,
+            backlog: 4096,
+            cache_size: 0,
+        }
+    }
+
+    /// До 2 CPU - small, до 8 - medium, больше - large.
+    pub fn for_cpus(cpus: usize) -> Profile {
+        if cpus <= 2 {
+            Profile::small()
+        } else if cpus <= 8 {
+            Profile::medium()
+        } else {
+            Profile::large()
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Profile> {
+        match name {
+            "small" => Some(Profile::small()),
+            "medium" => Some(Profile::medium()),
+            "large" => Some(Profile::large()),
+            "auto" => Some(Profile::for_cpus(cpu_count())),
+            _ => None,
+        }
+    }
+}
+
+pub fn cpu_count() -> usize {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus > 0 { cpus as usize } else { 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let small = Profile::by_name("small").unwrap();
+        assert_eq!((small.read_buffer, small.events_capacity, small.backlog), (4096, 256, 256));
+        assert_eq!(small.cache_size, 10000);
+        let medium = Profile::by_name("medium").unwrap();
+        assert_eq!((medium.read_buffer, medium.events_capacity, medium.backlog), (8192, 1024, 1024));
+        assert_eq!(medium.cache_size, 0);
+        let large = Profile::by_name("large").unwrap();
+        assert_eq!((large.read_buffer, large.events_capacity, large.backlog), (16384, 4096, 4096));
+        assert_eq!(large.cache_size, 0);
+        assert_eq!(Profile::by_name("huge"), None);
+
+        assert_eq!(Profile::for_cpus(1), small);
+        assert_eq!(Profile::for_cpus(4), medium);
+        assert_eq!(Profile::for_cpus(32), large);
+        assert_eq!(Profile::by_name("auto"), Some(Profile::for_cpus(cpu_count())));
+    }
+}
@@ -1,5 +1,6 @@
 use std::cmp::Ordering;
 
+use crate::bits::InterestSet;
 use crate::storage::Account;
 use crate::storage::AccountJson;
 use crate::storage::AccountsJson;
@@ -7,13 +8,14 @@ use crate::storage::NULL_DATE;
 use crate::storage::Premium;
 use crate::storage::Storage;
 use crate::topn::TopN;
-use crate::utils::EMPTY_INT_LIST;
+use crate::utils::check_unique_params;
 use crate::utils::merge_sorted;
+use crate::utils::parse_limit;
 use crate::utils::StatusCode;
 
 #[inline(never)]
 pub fn recommend(storage: &Storage, id: i32, params: &Vec<(String, String)>) -> Result<AccountsJson, StatusCode> {
-    let person = storage.accounts[id as usize].as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let person = storage.accounts.get(id as usize).and_then(|account| account.as_ref()).ok_or(StatusCode::NOT_FOUND)?;
     let matcher = match make_matcher(storage, &params)? {
         Some(matcher) => matcher,
         None => return Ok(AccountsJson { accounts: Vec::new() })
@@ -28,9 +30,12 @@ pub fn recommend(storage: &Storage, id: i32, params: &Vec<(String, String)>) ->
 
     let mut result: TopN<OrderedAccount> = TopN::new(matcher.limit);
 
-    let city_ids = if matcher.city != 0 { Some(storage.indexes.city_index.get(&matcher.city).unwrap_or(&EMPTY_INT_LIST)) } else { None };
-    let country_ids = if matcher.country != 0 { Some(storage.indexes.country_index.get(&matcher.country).unwrap_or(&EMPTY_INT_LIST)) } else { None };
+    let city_ids = if matcher.city != 0 { Some(storage.indexes.city_index.get(matcher.city)) } else { None };
+    let country_ids = if matcher.country != 0 { Some(storage.indexes.country_index.get(matcher.country)) } else { None };
     let mut used_city = false;
+    // при ограничении берутся первые по порядку интересов кандидаты, что может изменить хвост результата,
+    // но при запасе относительно limit первые limit записей не меняются
+    let candidates_cap = storage.config.recommend_cap_factor * matcher.limit;
 
     for recommend_order in 0..6 {
 //        debug!("rorder {} interests len {}", recommend_order, person.interests.len());
@@ -54,6 +59,9 @@ pub fn recommend(storage: &Storage, id: i32, params: &Vec<(String, String)>) ->
                     break;
                 }
                 ids = merge_sorted(&ids, ids2);
+                if candidates_cap != 0 && ids.len() >= candidates_cap {
+                    break;
+                }
             }
         }
 //        debug!("ids len {}", ids.len());
@@ -89,8 +97,8 @@ pub fn recommend(storage: &Storage, id: i32, params: &Vec<(String, String)>) ->
                     country: None,
                     city: None,
                     joined: None,
-                    interests: vec![],
-                    likes: vec![],
+                    interests: storage.config.hidden_array(account.interests.is_empty()),
+                    likes: storage.config.hidden_array(account.likes.is_empty()),
                 }
             })
             .collect()
@@ -98,6 +106,7 @@ pub fn recommend(storage: &Storage, id: i32, params: &Vec<(String, String)>) ->
 }
 
 fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Option<Matcher>, StatusCode> {
+    check_unique_params(params)?;
     let mut matcher = Matcher {
         limit: 0,
         country: 0,
@@ -109,12 +118,7 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
     for (key, value) in params {
         match key.as_str() {
             "query_id" => {}
-            "limit" => {
-                matcher.limit = value.parse::<usize>().map_err(|_| StatusCode::BAD_REQUEST)?;
-                if matcher.limit == 0 {
-                    return Err(StatusCode::BAD_REQUEST);
-                }
-            }
+            "limit" => {}
             "country" => {
                 if value.is_empty() {
                     Err(StatusCode::BAD_REQUEST)?
@@ -136,6 +140,7 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
             _ => return Err(StatusCode::BAD_REQUEST)
         }
     }
+    matcher.limit = parse_limit(params, storage.config.default_limit)?;
     if empty_result {
         return Ok(None);
     }
@@ -190,3 +195,45 @@ struct Matcher {
     country: i32,
     city: i32,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::make_storage;
+    use crate::utils::params;
+
+    use super::*;
+
+    fn recommend_ids(storage: &Storage, id: i32, limit: usize) -> Vec<i32> {
+        recommend(storage, id, &params(&[("limit", &limit.to_string())])).unwrap()
+            .accounts.iter().map(|account| account.id.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_recommend_cap() {
+        let mut accounts = vec![
+            r#"{"id":1,"email":"p1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a","b","c"]}"#.to_string(),
+        ];
+        // лучшие кандидаты делят с person все интересы, остальные только по одному
+        for id in 2..6 {
+            accounts.push(format!(r#"{{"id":{},"email":"f{}@a.ru","sex":"f","status":"свободны","birth":{},"joined":1300000000,"interests":["a","b","c"]}}"#, id, id, 600000000 + id * 1000));
+        }
+        for id in 6..60 {
+            let interest = ["a", "b", "c"][id % 3];
+            accounts.push(format!(r#"{{"id":{},"email":"f{}@a.ru","sex":"f","status":"свободны","birth":{},"joined":1300000000,"interests":["{}","x"]}}"#, id, id, 600000000 + id * 1000, interest));
+        }
+        let accounts: Vec<&str> = accounts.iter().map(|account| account.as_str()).collect();
+        let mut storage = make_storage(&accounts);
+
+        let expected = recommend_ids(&storage, 1, 4);
+        assert_eq!(expected, vec![2, 3, 4, 5]);
+
+        storage.config.recommend_cap_factor = 4;
+        assert_eq!(recommend_ids(&storage, 1, 4), expected);
+        assert_eq!(recommend_ids(&storage, 1, 2), vec![2, 3]);
+        // огромный limit урезается parse_limit, recommend_cap_factor * limit не переполняется
+        assert_eq!(recommend_ids(&storage, 1, usize::MAX).len(), 58);
+
+        assert_eq!(recommend(&storage, 1, &params(&[("limit", "2"), ("limit", "3")])).err(), Some(StatusCode::BAD_REQUEST));
+        assert!(recommend(&storage, 1, &params(&[("query_id", "1"), ("limit", "2"), ("query_id", "1")])).is_ok());
+    }
+}
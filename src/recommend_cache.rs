@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bits::Interests;
+use crate::bits::InterestSet;
+
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Номера последних изменений по интересам. Ответ recommend для person зависит только от аккаунтов,
+/// у которых есть общий с ним интерес, поэтому new/update отмечают интересы аккаунта до и после изменения,
+/// а запись кэша верна, пока ни один интерес person не отмечен позже нее. likes на recommend не влияют.
+pub struct RecommendVersions {
+    // у каждого storage свое поколение: после reload записи старого storage не подходят
+    generation: u64,
+    seq: u64,
+    by_interest: Vec<u64>,
+}
+
+impl RecommendVersions {
+    pub fn new() -> RecommendVersions {
+        RecommendVersions {
+            generation: GENERATIONS.fetch_add(1, Ordering::Relaxed),
+            seq: 0,
+            by_interest: Vec::new(),
+        }
+    }
+
+    pub fn touch(&mut self, interests: &Interests) {
+        if interests.is_empty() {
+            return;
+        }
+        self.seq += 1;
+        for interest in interests {
+            let interest = interest as usize;
+            if interest >= self.by_interest.len() {
+                self.by_interest.resize(interest + 1, 0);
+            }
+            self.by_interest[interest] = self.seq;
+        }
+    }
+
+    pub fn stamp(&self) -> (u64, u64) {
+        (self.generation, self.seq)
+    }
+
+    fn is_fresh(&self, interests: &Interests, stamp: (u64, u64)) -> bool {
+        stamp.0 == self.generation &&
+            interests.into_iter().all(|interest| self.by_interest.get(interest as usize).is_none_or(|seq| *seq <= stamp.1))
+    }
+}
+
+/// Ключ - id и параметры запроса без query_id.
+#[derive(Hash, PartialEq, Eq, Debug)]
+pub struct RecommendKey {
+    id: i32,
+    params: Vec<(String, String)>,
+}
+
+impl RecommendKey {
+    pub fn new(id: i32, params: &[(String, String)]) -> RecommendKey {
+        let mut params: Vec<(String, String)> = params.iter().filter(|(key, _)| key != "query_id").cloned().collect();
+        params.sort();
+        RecommendKey { id, params }
+    }
+}
+
+/// Кэш ответов recommend, который не сбрасывается на каждую запись, в отличие от общего кэша ответов.
+/// Устаревшие записи не удаляются, а пересчитываются при следующем запросе.
+pub struct RecommendCache {
+    entries: HashMap<RecommendKey, (Vec<u8>, (u64, u64))>,
+    // 0 - без ограничения, новые ключи сверх лимита не кэшируются
+    max_entries: usize,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RecommendCacheSnapshot {
+    entries: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl RecommendCache {
+    pub fn new() -> RecommendCache {
+        RecommendCache {
+            entries: HashMap::new(),
+            max_entries: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+    }
+
+    /// Ответ, посчитанный после последнего изменения интересов person.
+    pub fn get(&mut self, key: &RecommendKey, interests: &Interests, versions: &RecommendVersions) -> Option<&Vec<u8>> {
+        match self.entries.get(key) {
+            Some((_, stamp)) if versions.is_fresh(interests, *stamp) => {
+                self.hits += 1;
+                self.entries.get(key).map(|(response, _)| response)
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: RecommendKey, response: Vec<u8>, versions: &RecommendVersions) {
+        if self.max_entries != 0 && self.entries.len() >= self.max_entries && !self.entries.contains_key(&key) {
+            return;
+        }
+        self.entries.insert(key, (response, versions.stamp()));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn snapshot(&self) -> RecommendCacheSnapshot {
+        RecommendCacheSnapshot {
+            entries: self.entries.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::params;
+
+    use super::*;
+
+    #[test]
+    fn test_invalidation() {
+        let mut versions = RecommendVersions::new();
+        let mut cache = RecommendCache::new();
+        let person = Interests::from_vec(vec![1, 2]);
+        let key = || RecommendKey::new(1, &params(&[("limit", "5"), ("city", "c"), ("query_id", "7")]));
+        assert_eq!(key(), RecommendKey::new(1, &params(&[("query_id", "8"), ("city", "c"), ("limit", "5")])));
+
+        cache.insert(key(), b"a".to_vec(), &versions);
+        assert_eq!(cache.get(&key(), &person, &versions), Some(&b"a".to_vec()));
+        // чужие интересы и аккаунты без интересов запись не трогают
+        versions.touch(&Interests::from_vec(vec![3]));
+        versions.touch(&Interests::from_vec(vec![]));
+        assert_eq!(cache.get(&key(), &person, &versions), Some(&b"a".to_vec()));
+        versions.touch(&Interests::from_vec(vec![2, 5]));
+        assert_eq!(cache.get(&key(), &person, &versions), None);
+
+        cache.insert(key(), b"b".to_vec(), &versions);
+        assert_eq!(cache.get(&key(), &person, &versions), Some(&b"b".to_vec()));
+        // тот же ключ в другом storage
+        assert_eq!(cache.get(&key(), &person, &RecommendVersions::new()), None);
+        assert_eq!(cache.snapshot(), RecommendCacheSnapshot { entries: 1, hits: 3, misses: 2 });
+    }
+}
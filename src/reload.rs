@@ -0,0 +1,179 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::storage::Storage;
+use crate::utils::{read_lock, write_lock};
+use crate::utils::StatusCode;
+
+/// Учет выполняющихся запросов для подмены storage при /admin/reload.
+/// Пока выставлен draining, новые запросы получают 503, а подмена ждет завершения уже начатых.
+pub struct Activity {
+    draining: AtomicBool,
+    active: AtomicUsize,
+    // идет загрузка для reload: второй reload в это время получает 409
+    reloading: AtomicBool,
+}
+
+pub struct ActiveRequest<'a> {
+    activity: &'a Activity,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            draining: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            reloading: AtomicBool::new(false),
+        }
+    }
+
+    pub fn enter(&self) -> Result<ActiveRequest<'_>, StatusCode> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        // флаг проверяется после увеличения счетчика, иначе подмена может не дождаться этого запроса
+        if self.draining.load(Ordering::SeqCst) {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Ok(ActiveRequest { activity: self })
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn active_count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn swap(&self, storage: &RwLock<Storage>, new_storage: Storage) {
+        self.draining.store(true, Ordering::SeqCst);
+        debug!("draining {} active requests", self.active_count());
+        while self.active.load(Ordering::SeqCst) != 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        *write_lock(storage) = new_storage;
+        self.draining.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<'a> Drop for ActiveRequest<'a> {
+    fn drop(&mut self) {
+        self.activity.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Reloading {
+    activity: &'static Activity,
+}
+
+impl Drop for Reloading {
+    fn drop(&mut self) {
+        self.activity.reloading.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Новые данные загружаются в отдельном потоке, пока старый storage продолжает обслуживать запросы.
+pub fn reload(activity: &'static Activity, storage: &Arc<RwLock<Storage>>, on_swapped: fn()) -> Result<(), StatusCode> {
+    let (path, config) = {
+        let storage = read_lock(storage);
+        (storage.path.clone(), storage.config.clone())
+    };
+    if path.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if activity.reloading.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return Err(StatusCode::CONFLICT);
+    }
+    let storage = storage.clone();
+    thread::spawn(move || {
+        // снимается и при панике в load, иначе reload больше не запустить
+        let _reloading = Reloading { activity };
+        info!("reloading from {}", path);
+        let new_storage = Storage::load(&path, config);
+        activity.swap(&storage, new_storage);
+        on_swapped();
+        info!("reload done");
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::mpsc;
+
+    use crate::storage::tests::make_data_dir;
+    use crate::storage::tests::make_storage;
+
+    use super::*;
+
+    #[test]
+    fn test_swap_waits_for_active_requests() {
+        lazy_static! {
+            static ref ACTIVITY: Activity = Activity::new();
+        }
+        let storage = Arc::new(RwLock::new(make_storage(&[
+            r#"{"id":1,"email":"old@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ])));
+
+        let active_request = ACTIVITY.enter().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                let new_storage = make_storage(&[
+                    r#"{"id":2,"email":"new@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+                ]);
+                ACTIVITY.swap(&storage, new_storage);
+                sender.send(()).unwrap();
+            });
+        }
+        while !ACTIVITY.is_draining() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // новые запросы отклоняются, а начатый видит старые данные целиком
+        assert_eq!(ACTIVITY.enter().err(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(ACTIVITY.active_count(), 1);
+        {
+            let storage = storage.read().unwrap();
+            assert!(storage.accounts[1].is_some());
+            assert!(storage.accounts[2].is_none());
+        }
+        assert!(receiver.try_recv().is_err());
+
+        drop(active_request);
+        receiver.recv().unwrap();
+
+        assert!(!ACTIVITY.is_draining());
+        let _active_request = ACTIVITY.enter().unwrap();
+        let storage = storage.read().unwrap();
+        assert!(storage.accounts[1].is_none());
+        assert!(storage.accounts[2].is_some());
+    }
+
+    #[test]
+    fn test_reload_serialized() {
+        lazy_static! {
+            static ref ACTIVITY: Activity = Activity::new();
+        }
+        let accounts = r#"{"accounts":[{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}]}"#;
+        let dir = make_data_dir("reload", &[("accounts_1.json", accounts)]);
+        let storage = Arc::new(RwLock::new(Storage::load(dir.to_str().unwrap(), crate::storage::Config::new())));
+
+        assert_eq!(reload(&ACTIVITY, &storage, || {}), Ok(()));
+        // пока первый reload не закончен, второй отклоняется
+        assert_eq!(reload(&ACTIVITY, &storage, || {}), Err(StatusCode::CONFLICT));
+        while ACTIVITY.reloading.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(reload(&ACTIVITY, &storage, || {}), Ok(()));
+        while ACTIVITY.reloading.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(read_lock(&storage).accounts[1].is_some());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::VecDeque;
 use std::io;
 use std::io::ErrorKind;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::time::Duration;
@@ -7,12 +9,34 @@ use std::time::Duration;
 use chashmap::CHashMap;
 
 const MICROS_PER_SEC: u64 = 1_000_000;
+// сколько последних сообщений о панике хранится
+const RECENT_PANICS: usize = 10;
 const NANOS_PER_MICRO: u32 = 1_000;
+// гистограмма: значения до LINEAR_MICROS - по одному в корзине, дальше SUB_BUCKETS корзин на каждую степень двойки (точность 12.5%)
+const LINEAR_MICROS: u64 = 16;
+const SUB_BUCKETS_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKETS_BITS;
+// старшая степень двойки, 2^36 мкс - почти сутки, все что дольше попадает в последнюю корзину
+const MAX_POWER: u32 = 36;
+const BUCKETS: usize = LINEAR_MICROS as usize + (MAX_POWER as usize - 4 + 1) * SUB_BUCKETS;
 
 pub struct Stats {
     requests: CHashMap<&'static str, StatValue>,
+    // распределение времени requests для перцентилей
+    latencies: CHashMap<&'static str, Histogram>,
     requests_with_params: CHashMap<String, StatValue>,
+    // ожидание storage.read()/storage.write() по типам запросов
+    lock_waits: CHashMap<&'static str, StatValue>,
+    // serde_json::to_writer ответа по типам запросов, входит и во время requests
+    serialize_times: CHashMap<&'static str, StatValue>,
+    // самый длинный ответ в байтах по типам запросов
+    max_response_sizes: CHashMap<&'static str, usize>,
+    panic_count: AtomicUsize,
+    recent_panics: spin::Mutex<VecDeque<String>>,
     count: AtomicUsize,
+    // обращения к общему кэшу ответов при --cache on/random
+    cache_hits: AtomicUsize,
+    cache_misses: AtomicUsize,
 
     count_net: AtomicUsize,
     count_accept: AtomicUsize,
@@ -27,8 +51,16 @@ impl Stats {
     pub fn new() -> Stats {
         Stats {
             requests: CHashMap::new(),
+            latencies: CHashMap::new(),
             requests_with_params: CHashMap::new(),
+            lock_waits: CHashMap::new(),
+            serialize_times: CHashMap::new(),
+            max_response_sizes: CHashMap::new(),
+            panic_count: AtomicUsize::new(0),
+            recent_panics: spin::Mutex::new(VecDeque::with_capacity(RECENT_PANICS)),
             count: AtomicUsize::new(0),
+            cache_hits: AtomicUsize::new(0),
+            cache_misses: AtomicUsize::new(0),
 
             count_net: AtomicUsize::new(0),
             count_accept: AtomicUsize::new(0),
@@ -44,7 +76,7 @@ impl Stats {
         let elapsed_micros = elapsed.as_secs() * MICROS_PER_SEC + (elapsed.subsec_nanos() / NANOS_PER_MICRO) as u64;
 
         let mut conditions: Vec<String> = params.iter()
-            .filter(|(k, _)| k != "limit" && k != "query_id" && k != "order" && k != "keys")
+            .filter(|(k, _)| k != "limit" && k != "query_id" && k != "order" && k != "keys" && k != "_debug_fields")
             .map(|(k, v)| if k.ends_with("_null") { k.clone() + "=" + v } else { k.clone() })
             .collect();
         conditions.sort();
@@ -59,6 +91,14 @@ impl Stats {
                                      stat.max_time_micros = i;
                                  }
                              });
+        // корзины атомарные: после первого запроса типа хватает read-блокировки CHashMap
+        match self.latencies.get(&request_type) {
+            Some(histogram) => histogram.record(elapsed_micros),
+            None => {
+                self.latencies.upsert(request_type, Histogram::new, |_| {});
+                self.latencies.get(&request_type).unwrap().record(elapsed_micros);
+            }
+        }
         self.requests_with_params.upsert(format!("{}_{:?}", request_type.to_string(), conditions),
                                          || StatValue { count: 1, total_time_micros: elapsed_micros, max_time_micros: elapsed_micros },
                                          |stat| {
@@ -76,11 +116,75 @@ impl Stats {
         }
     }
 
+    pub fn register_lock_wait(&self, request_type: &'static str, elapsed: Duration) {
+        register_time(&self.lock_waits, request_type, elapsed);
+    }
+
+    pub fn register_serialize(&self, request_type: &'static str, elapsed: Duration) {
+        register_time(&self.serialize_times, request_type, elapsed);
+    }
+
+    pub fn register_response_size(&self, request_type: &'static str, len: usize) {
+        self.max_response_sizes.upsert(request_type,
+                                       || len,
+                                       |max| if len > *max { *max = len; });
+    }
+
+    pub fn register_cache_lookup(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Попадания и промахи общего кэша ответов.
+    pub fn cache_lookups(&self) -> (usize, usize) {
+        (self.cache_hits.load(Ordering::SeqCst), self.cache_misses.load(Ordering::SeqCst))
+    }
+
+    /// Паника, перехваченная при обработке запроса; хранятся только последние RECENT_PANICS сообщений.
+    pub fn register_panic(&self, msg: String) {
+        self.panic_count.fetch_add(1, Ordering::SeqCst);
+        let mut recent_panics = self.recent_panics.lock();
+        if recent_panics.len() == RECENT_PANICS {
+            recent_panics.pop_front();
+        }
+        recent_panics.push_back(msg);
+    }
+
+    /// Число паник и последние сообщения, от старых к новым.
+    pub fn panics(&self) -> PanicsJson {
+        PanicsJson {
+            count: self.panic_count.load(Ordering::SeqCst),
+            recent: self.recent_panics.lock().iter().cloned().collect(),
+        }
+    }
+
     pub fn print(&self) {
         info!("*** stats requests: count: {}", self.count.load(Ordering::SeqCst));
+        let (cache_hits, cache_misses) = self.cache_lookups();
+        if cache_hits + cache_misses > 0 {
+            info!("response cache: hits: {}, misses: {}, hit rate: {:.1}%", cache_hits, cache_misses, cache_hits as f64 * 100.0 / (cache_hits + cache_misses) as f64);
+        }
         self.requests.clone().into_iter().for_each(|(k, v)| {
-            info!("{}: count: {}, mean: {:.2} ms, max: {:.2} ms", k, v.count, v.total_time_micros as f64 / v.count as f64 / 1000.0, v.max_time_micros as f64 / 1000.0);
+            let percentiles = match self.latencies.get(&k) {
+                Some(histogram) => [0.5, 0.95, 0.99, 0.999].iter()
+                    .map(|q| format!("{:.2}", histogram.percentile(*q) as f64 / 1000.0))
+                    .collect::<Vec<String>>()
+                    .join("/"),
+                None => "-".to_string(),
+            };
+            info!("{}: count: {}, mean: {:.2} ms, max: {:.2} ms, p50/p95/p99/p999: {} ms", k, v.count, v.total_time_micros as f64 / v.count as f64 / 1000.0,
+                  v.max_time_micros as f64 / 1000.0, percentiles);
         });
+        print_times("lock wait", &self.lock_waits);
+        print_times("serialize", &self.serialize_times);
+        if !self.max_response_sizes.is_empty() {
+            let mut max_response_sizes: Vec<(_, _)> = self.max_response_sizes.clone().into_iter().collect();
+            max_response_sizes.sort_by_key(|(k, _)| *k);
+            info!("max response bytes: {:?}", max_response_sizes);
+        }
         info!("top mean:");
         let mut requests_with_params: Vec<(_, _)> = self.requests_with_params.clone().into_iter().collect();
         requests_with_params.sort_by_key(|(_, v)| v.total_time_micros / v.count as u64);
@@ -196,6 +300,94 @@ impl Stats {
     }
 }
 
+fn register_time(times: &CHashMap<&'static str, StatValue>, request_type: &'static str, elapsed: Duration) {
+    let elapsed_micros = elapsed.as_secs() * MICROS_PER_SEC + (elapsed.subsec_nanos() / NANOS_PER_MICRO) as u64;
+    times.upsert(request_type,
+                 || StatValue { count: 1, total_time_micros: elapsed_micros, max_time_micros: elapsed_micros },
+                 |stat| {
+                     stat.count += 1;
+                     stat.total_time_micros += elapsed_micros;
+                     if elapsed_micros > stat.max_time_micros {
+                         stat.max_time_micros = elapsed_micros;
+                     }
+                 });
+}
+
+fn print_times(title: &str, times: &CHashMap<&'static str, StatValue>) {
+    if !times.is_empty() {
+        info!("{}:", title);
+        let mut times: Vec<(_, _)> = times.clone().into_iter().collect();
+        times.sort_by_key(|(k, _)| *k);
+        times.iter().for_each(|(k, v)| {
+            info!("{}: count: {}, mean: {:.3} ms, max: {:.2} ms, total: {:.2} ms", k, v.count, v.total_time_micros as f64 / v.count as f64 / 1000.0,
+                  v.max_time_micros as f64 / 1000.0, v.total_time_micros as f64 / 1000.0);
+        });
+    }
+}
+
+/// Гистограмма времен в микросекундах с логарифмическими корзинами, как в HdrHistogram.
+/// Число корзин фиксировано, запись - один fetch_add без блокировок.
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram { buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect() }
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < LINEAR_MICROS {
+            return micros as usize;
+        }
+        let power = (63 - micros.leading_zeros()).min(MAX_POWER);
+        let sub_bucket = if power == MAX_POWER && micros >> MAX_POWER > 1 {
+            SUB_BUCKETS - 1
+        } else {
+            (micros >> (power - SUB_BUCKETS_BITS)) as usize & (SUB_BUCKETS - 1)
+        };
+        LINEAR_MICROS as usize + (power as usize - 4) * SUB_BUCKETS + sub_bucket
+    }
+
+    /// Наибольшее значение, попадающее в корзину.
+    fn bucket_max(bucket: usize) -> u64 {
+        if bucket < LINEAR_MICROS as usize {
+            return bucket as u64;
+        }
+        let power = (bucket - LINEAR_MICROS as usize) / SUB_BUCKETS + 4;
+        let sub_bucket = ((bucket - LINEAR_MICROS as usize) % SUB_BUCKETS) as u64;
+        ((SUB_BUCKETS as u64 + sub_bucket + 1) << (power as u32 - SUB_BUCKETS_BITS)) - 1
+    }
+
+    fn record(&self, micros: u64) {
+        self.buckets[Histogram::bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Верхняя граница корзины, до которой набирается доля q всех значений; 0 для пустой гистограммы.
+    fn percentile(&self, q: f64) -> u64 {
+        let counts: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank = ((total as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Histogram::bucket_max(bucket);
+            }
+        }
+        Histogram::bucket_max(BUCKETS - 1)
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PanicsJson {
+    pub count: usize,
+    pub recent: Vec<String>,
+}
+
 #[derive(Hash, Eq, PartialEq, Debug)]
 struct StatKey {
     request: &'static str,
@@ -208,3 +400,94 @@ struct StatValue {
     total_time_micros: u64,
     max_time_micros: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_lock_wait() {
+        let stats = Stats::new();
+        stats.register_lock_wait("NEW", Duration::from_micros(300));
+        stats.register_lock_wait("NEW", Duration::from_micros(100));
+        stats.register_lock_wait("FILTER", Duration::from_micros(5));
+        let new = stats.lock_waits.get(&"NEW").unwrap();
+        assert_eq!((new.count, new.total_time_micros, new.max_time_micros), (2, 400, 300));
+        assert_eq!(stats.lock_waits.get(&"FILTER").unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_register_serialize() {
+        let stats = Stats::new();
+        stats.register_serialize("FILTER", Duration::from_micros(40));
+        stats.register_serialize("FILTER", Duration::from_micros(60));
+        let filter = stats.serialize_times.get(&"FILTER").unwrap();
+        assert_eq!((filter.count, filter.total_time_micros, filter.max_time_micros), (2, 100, 60));
+        assert!(stats.lock_waits.is_empty());
+    }
+
+    #[test]
+    fn test_register_response_size() {
+        let stats = Stats::new();
+        stats.register_response_size("FILTER", 100);
+        stats.register_response_size("FILTER", 3000);
+        stats.register_response_size("FILTER", 200);
+        stats.register_response_size("GROUP", 50);
+        assert_eq!(*stats.max_response_sizes.get(&"FILTER").unwrap(), 3000);
+        assert_eq!(*stats.max_response_sizes.get(&"GROUP").unwrap(), 50);
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let mut previous = 0;
+        for micros in (0..100_000).chain(vec![1 << 35, (1 << 36) - 1, 1 << 36, 1 << 40, u64::MAX]) {
+            let bucket = Histogram::bucket(micros);
+            assert!(bucket >= previous && bucket < BUCKETS, "{}", micros);
+            previous = bucket;
+            if bucket < BUCKETS - 1 {
+                // значение не больше границы своей корзины и больше границы предыдущей
+                assert!(micros <= Histogram::bucket_max(bucket), "{}", micros);
+                assert!(bucket == 0 || micros > Histogram::bucket_max(bucket - 1), "{}", micros);
+                assert!(Histogram::bucket_max(bucket) as f64 <= micros as f64 * 1.125 + 1.0, "{}", micros);
+            }
+        }
+        assert_eq!(Histogram::bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let stats = Stats::new();
+        // 1..=1000 мкс равномерно и 10 медленных по 50 мс
+        for micros in 1..1001 {
+            stats.register("FILTER", Duration::from_micros(micros), &Vec::new());
+        }
+        let histogram = stats.latencies.get(&"FILTER").unwrap();
+        assert_eq!(histogram.percentile(0.5), Histogram::bucket_max(Histogram::bucket(500)));
+        assert!((500..563).contains(&histogram.percentile(0.5)));
+        assert!((950..1069).contains(&histogram.percentile(0.95)));
+        assert!((990..1114).contains(&histogram.percentile(0.99)));
+        drop(histogram);
+        for _ in 0..10 {
+            stats.register("FILTER", Duration::from_millis(50), &Vec::new());
+        }
+        let histogram = stats.latencies.get(&"FILTER").unwrap();
+        assert!((50_000..56_250).contains(&histogram.percentile(0.999)));
+        assert!(histogram.percentile(0.99) < 1114);
+        assert_eq!(Histogram::new().percentile(0.99), 0);
+        assert!(stats.latencies.get(&"GROUP").is_none());
+    }
+
+    #[test]
+    fn test_register_panic() {
+        let stats = Stats::new();
+        assert_eq!(stats.panics(), PanicsJson { count: 0, recent: Vec::new() });
+        for i in 0..RECENT_PANICS + 2 {
+            stats.register_panic(format!("panic {}", i));
+        }
+        let panics = stats.panics();
+        assert_eq!(panics.count, RECENT_PANICS + 2);
+        assert_eq!(panics.recent.len(), RECENT_PANICS);
+        assert_eq!(panics.recent.first().unwrap(), "panic 2");
+        assert_eq!(panics.recent.last().unwrap(), &format!("panic {}", RECENT_PANICS + 1));
+    }
+}
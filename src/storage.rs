@@ -1,24 +1,55 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::panic;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc;
+use std::thread;
 
+use chashmap::CHashMap;
+use flate2::read::GzDecoder;
+use itertools::Itertools;
 use regex::Regex;
 use zip::ZipArchive;
 
-use crate::bits::Bits;
+use crate::bits::InterestSet;
+use crate::bits::Interests;
+use crate::bits::IdSet;
 use crate::filter_index::FilterIndex;
 use crate::group_index::GroupIndex;
+use crate::profile::cpu_count;
+use crate::recommend_cache::RecommendVersions;
 use crate::stats::Stats;
 use crate::utils::insert_into_sorted_vec;
+use crate::utils::PostingLists;
+use crate::utils::read_lock;
+use crate::utils::remove_from_sorted_vec;
+use crate::utils::EMPTY_LIKE_LIST;
 use crate::utils::StatusCode;
+use crate::utils::write_lock;
 use crate::utils::year_from_seconds;
+use crate::wal;
+use crate::wal::Wal;
+use crate::wal::WalKind;
 
 pub const NULL_DATE: i32 = core::i32::MIN;
+// границы дат из условий: birth с 01.01.1950 по 01.01.2005, joined с 01.01.2011 по 01.01.2018 включительно
+pub const MIN_BIRTH: i32 = -631152000;
+pub const MAX_BIRTH: i32 = 1104537600;
+pub const MIN_JOINED: i32 = 1293840000;
+pub const MAX_JOINED: i32 = 1514764800;
 const MAX_ID: usize = 2_000_000;
+const AMBIGUOUS_COUNTRY: i32 = -1;
+// длина ключа sname_prefix_index в символах: короче sname_starts почти не бывает
+pub const SNAME_PREFIX_CHARS: usize = 2;
 static VALID_SEXES: [&str; 2] = ["m", "f"];
 static VALID_STATUSES: [&str; 3] = ["свободны", "заняты", "всё сложно"];
 
@@ -35,7 +66,106 @@ pub struct Storage {
     pub interest_dict: Dict,
     pub consts: Consts,
     pub indexes: Indexes,
-    pub stats: Stats,
+    // Arc: isolate пишет в статистику панику, пока storage заблокирован на запись
+    pub stats: Arc<Stats>,
+    pub config: Config,
+    // каталог, из которого загружены данные, для /admin/reload
+    pub path: String,
+    // открывается в load после replay, чтобы примененные записи не попали в журнал второй раз
+    pub wal: Option<Wal>,
+    pub recommend_versions: RecommendVersions,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataFormat {
+    // data.zip, внутри json или json.gz
+    Zip,
+    // каталог с файлами json или json.gz
+    Dir,
+}
+
+/// Что делать с лайком самому себе (liker == likee).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelfLikes {
+    // сохраняются как обычные лайки
+    Accept,
+    // POST отвечает 400, при загрузке такие лайки отбрасываются
+    Reject,
+    // отбрасываются без ошибки
+    Drop,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    // recommend рассматривает не больше recommend_cap_factor * limit кандидатов на каждый recommend_order, 0 - без ограничения
+    pub recommend_cap_factor: usize,
+    // group без индекса отвечает 400, если различных групп больше group_cap, 0 - без ограничения
+    pub group_cap: usize,
+    // файл с порядком интересов (строка N - интерес с ключом N), чтобы индексы Bits не менялись между запусками;
+    // если файла нет, он записывается после загрузки
+    pub interests_dict_path: Option<String>,
+    pub data_format: DataFormat,
+    // POST запросы копятся в потоке и применяются пачкой в конце итерации poll
+    pub batch_writes: bool,
+    // индекс по фамилии: фамилий больше, чем имен, поэтому индекс большой и включается явно (--index-set sname)
+    pub index_sname: bool,
+    // индекс по первым двум буквам фамилии для sname_starts (--index-set sname_prefix)
+    pub index_sname_prefix: bool,
+    pub self_likes: SelfLikes,
+    // если пары интересов нет в interests2_index, пересекать списки interests_index, а не отвечать пустым списком
+    pub interests2_fallback: bool,
+    // отладочные /admin/stats, /admin/cache, /admin/reindex, /admin/verify, /admin/export и /admin/reload
+    pub admin: bool,
+    // 400 на противоречивые сочетания условий фильтра (birth_year вместе с birth_lt и т.п.),
+    // 422 на sex/status вне допустимых значений
+    pub strict_unknown: bool,
+    // паника в new/update/likes отвечает 500 вместо падения потока, отравленная блокировка storage восстанавливается
+    pub isolate_writes: bool,
+    // limit для filter/group/recommend/suggest без параметра limit, 0 - такой запрос отвечает 400
+    pub default_limit: usize,
+    // ответ filter/group/recommend/suggest длиннее отвечает 413, 0 - без ограничения
+    pub max_response_bytes: usize,
+    // журнал успешных new/update/likes, см. wal::Wal
+    pub wal_path: Option<String>,
+    // fsync после каждой записи журнала, без него запись переживает только падение процесса
+    pub wal_fsync: bool,
+    // журнал, применяемый после загрузки данных; может совпадать с wal_path, тогда журнал дописывается
+    pub replay_path: Option<String>,
+    // потоки разбора json при загрузке, 0 - по числу CPU
+    pub load_threads: usize,
+    // пустые interests/likes в ответах выводятся как [], а не опускаются
+    pub emit_empty_arrays: bool,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            recommend_cap_factor: 0,
+            group_cap: 0,
+            interests_dict_path: None,
+            data_format: DataFormat::Zip,
+            batch_writes: false,
+            index_sname: false,
+            index_sname_prefix: false,
+            self_likes: SelfLikes::Accept,
+            interests2_fallback: false,
+            admin: false,
+            strict_unknown: false,
+            isolate_writes: false,
+            default_limit: 0,
+            max_response_bytes: 0,
+            wal_path: None,
+            wal_fsync: false,
+            replay_path: None,
+            load_threads: 0,
+            emit_empty_arrays: false,
+        }
+    }
+
+    /// Значение не показываемого поля: [] только при --emit-empty-arrays и если у аккаунта оно действительно пустое.
+    pub fn hidden_array<T>(&self, empty: bool) -> Option<Vec<T>> {
+        if self.emit_empty_arrays && empty { Some(Vec::new()) } else { None }
+    }
 }
 
 pub struct Consts {
@@ -47,18 +177,37 @@ pub struct Consts {
 }
 
 pub struct Indexes {
-    pub known_emails: HashSet<Arc<String>>,
+    // email -> id, для проверки уникальности и filter email_eq
+    pub known_emails: HashMap<Arc<String>, i32>,
     pub known_phones: HashSet<(i32, i32)>,
+    // домен email (после последнего '@') -> id, при update старый домен удаляется
+    pub email_domain_index: HashMap<String, Vec<i32>>,
     pub likes_index_male: HashMap<i32, Vec<Like>>,
     pub likes_index_female: HashMap<i32, Vec<Like>>,
-    pub interests_index: HashMap<i32, Vec<i32>>,
-    pub interests_index_male: HashMap<i32, Vec<i32>>,
-    pub interests_index_female: HashMap<i32, Vec<i32>>,
+    pub interests_index: PostingLists,
+    pub interests_index_male: PostingLists,
+    pub interests_index_female: PostingLists,
     pub interests2_index: HashMap<(i32, i32), Vec<i32>>,
-    pub city_index: HashMap<i32, Vec<i32>>,
-    pub country_index: HashMap<i32, Vec<i32>>,
+    pub city_index: PostingLists,
+    pub country_index: PostingLists,
+    // город -> страна всех аккаунтов с этим городом (0 - без страны), AMBIGUOUS_COUNTRY - встречался с разными.
+    // После update старая пара остается, так что ошибка возможна только в сторону AMBIGUOUS_COUNTRY
+    pub country_of_city: HashMap<i32, i32>,
     pub birth_index: HashMap<i32, Vec<i32>>,
-    pub fname_index: HashMap<i32, Vec<i32>>,
+    pub joined_index: HashMap<i32, Vec<i32>>,
+    // код оператора -> id; аккаунты без телефона (phone_code 0) не попадают. После update старый код остается, его отсекает matches
+    pub phone_code_index: HashMap<i32, Vec<i32>>,
+    pub fname_index: PostingLists,
+    // id с is_premium. is_premium считается от now, который задан при загрузке и дальше не меняется,
+    // поэтому список меняют только new и update с premium
+    pub premium_now_ids: Vec<i32>,
+    // None, если индекс не включен в Config; в отличие от остальных индексов, при update старая фамилия удаляется
+    pub sname_index: Option<HashMap<i32, Vec<i32>>>,
+    // первые SNAME_PREFIX_CHARS букв фамилии -> id, так же удаляется при update; None, если не включен в Config
+    pub sname_prefix_index: Option<HashMap<String, Vec<i32>>>,
+    // для пересечения с city_index; после update возможны лишние id, они отсекаются matches
+    pub sex_ids: HashMap<i32, IdSet>,
+    pub status_ids: HashMap<i32, IdSet>,
     pub recommend_index_male: Vec<[Vec<i32>; 6]>,
     pub recommend_index_female: Vec<[Vec<i32>; 6]>,
     pub filter_index: FilterIndex,
@@ -66,9 +215,12 @@ pub struct Indexes {
     pub similarity: HashMap<(i32, i32), f32>,
 }
 
+/// Интернирование строк через &self: новые строки добавляются под блокировкой storage на чтение,
+/// параллельно с поиском. Список только дополняется, поэтому ключи стабильны.
 pub struct Dict {
-    map: HashMap<Arc<String>, i32>,
-    list: Vec<Arc<String>>,
+    // строка попадает в map только после того, как записана в list
+    map: CHashMap<Arc<String>, i32>,
+    list: RwLock<Vec<Arc<String>>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -100,10 +252,10 @@ pub struct AccountJson {
     pub joined: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<Arc<String>>,
-    #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    pub interests: Vec<Arc<String>>,
-    #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    pub likes: Vec<Like>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interests: Option<Vec<Arc<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub likes: Option<Vec<Like>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub premium: Option<Premium>,
 }
@@ -120,6 +272,12 @@ pub struct Premium {
     pub finish: i32,
 }
 
+/// Результат Storage::parse_account: строки уже в словарях.
+pub struct ParsedAccount {
+    json: AccountJson,
+    account: Account,
+}
+
 #[derive(Deserialize, Debug)]
 struct LikesJson {
     likes: Vec<LikeJson>,
@@ -146,7 +304,7 @@ pub struct Account {
     pub city: i32,
     pub joined: i32,
     pub status: i32,
-    pub interests: Bits,
+    pub interests: Interests,
     // unique, sorted by like.id
     pub likes: Vec<i32>,
     pub premium_start: i32,
@@ -157,14 +315,7 @@ pub struct Account {
 }
 
 impl Storage {
-    pub fn load(path: &str) -> Storage {
-        info!("loading data...");
-
-        let options_file = File::open(Path::new(path).join("options.txt")).unwrap();
-        let options_first_line = BufReader::new(options_file).lines().next().unwrap().unwrap();
-        let now = options_first_line.parse::<i32>().unwrap();
-        info!("options now: {}", now);
-
+    pub fn new(now: i32, config: Config, capacity: usize) -> Storage {
         let mut storage = Storage {
             accounts: Vec::new(),
             max_id: 0,
@@ -179,27 +330,40 @@ impl Storage {
                 female: 0,
             },
             indexes: Indexes {
-                known_emails: HashSet::new(),
+                known_emails: HashMap::new(),
                 known_phones: HashSet::new(),
+                email_domain_index: HashMap::new(),
                 likes_index_male: HashMap::new(),
                 likes_index_female: HashMap::new(),
-                interests_index: HashMap::new(),
-                interests_index_male: HashMap::new(),
-                interests_index_female: HashMap::new(),
+                interests_index: PostingLists::new(),
+                interests_index_male: PostingLists::new(),
+                interests_index_female: PostingLists::new(),
                 interests2_index: HashMap::new(),
-                city_index: HashMap::new(),
-                country_index: HashMap::new(),
+                city_index: PostingLists::new(),
+                country_index: PostingLists::new(),
+                country_of_city: HashMap::new(),
                 birth_index: HashMap::new(),
-                fname_index: HashMap::new(),
+                joined_index: HashMap::new(),
+                phone_code_index: HashMap::new(),
+                fname_index: PostingLists::new(),
+                premium_now_ids: Vec::new(),
+                sname_index: if config.index_sname { Some(HashMap::new()) } else { None },
+                sname_prefix_index: if config.index_sname_prefix { Some(HashMap::new()) } else { None },
+                sex_ids: HashMap::new(),
+                status_ids: HashMap::new(),
                 recommend_index_male: Vec::new(),
                 recommend_index_female: Vec::new(),
                 filter_index: FilterIndex::new(),
                 group_index: GroupIndex::new(),
                 similarity: HashMap::new(),
             },
-            stats: Stats::new(),
+            stats: Arc::new(Stats::new()),
+            config,
+            path: String::new(),
+            wal: None,
+            recommend_versions: RecommendVersions::new(),
         };
-        for _id in 0..MAX_ID {
+        for _id in 0..capacity {
             storage.accounts.push(None);
         }
         storage.consts.free_status = storage.dict.get_key(&Arc::new("свободны".to_string()));
@@ -207,55 +371,156 @@ impl Storage {
         storage.consts.taken_status = storage.dict.get_key(&Arc::new("заняты".to_string()));
         storage.consts.male = storage.dict.get_key(&Arc::new("m".to_string()));
         storage.consts.female = storage.dict.get_key(&Arc::new("f".to_string()));
+        storage
+    }
 
-        let zip_file = File::open(Path::new(path).join("data.zip")).unwrap();
-        let mut zip = ZipArchive::new(BufReader::new(zip_file)).unwrap();
-        let mut count = 0;
-        for i in 0..zip.len() {
-            let file = zip.by_index(i).unwrap();
-            debug!("loading {}", file.name());
-            let accounts_json: AccountsJson = serde_json::from_reader(BufReader::new(file)).unwrap();
-            for account_json in accounts_json.accounts.iter() {
-                let id = account_json.id.unwrap() as usize;
-                let account_option = &mut storage.accounts[id];
-                *account_option = Some(account_from_json(account_json, &mut storage.dict, &mut storage.interest_dict, true).unwrap());
-                calc_account_fields(account_option.as_mut().unwrap(), storage.now, storage.consts.free_status, storage.consts.hard_status);
-                for like in &account_json.likes {
-                    update_likes_index(&storage.consts, &mut storage.indexes, account_option.as_ref().unwrap(), like.id, like.ts)
-                }
-                count += 1;
-                if id > storage.max_id {
-                    storage.max_id = id;
+    pub fn load(path: &str, config: Config) -> Storage {
+        info!("loading data...");
+
+        let options_file = File::open(Path::new(path).join("options.txt")).unwrap();
+        let options_first_line = BufReader::new(options_file).lines().next().unwrap().unwrap();
+        let now = options_first_line.parse::<i32>().unwrap();
+        info!("options now: {}", now);
+
+        let mut storage = Storage::new(now, config, MAX_ID);
+        storage.path = path.to_string();
+
+        let interests_dict_path = storage.config.interests_dict_path.clone();
+        let mut interests_dict_loaded = false;
+        if let Some(interests_dict_path) = &interests_dict_path {
+            if let Ok(interests_dict_file) = File::open(interests_dict_path) {
+                for line in BufReader::new(interests_dict_file).lines() {
+                    storage.interest_dict.get_key(&Arc::new(line.unwrap()));
                 }
+                interests_dict_loaded = true;
+                info!("loaded {} interests from {}", storage.interest_dict.max_key(), interests_dict_path);
             }
         }
+
+        // файлы разбираются параллельно, а аккаунты добавляются по порядку файлов в одном потоке:
+        // ключи словарей и замена дублей id те же, что при последовательной загрузке
+        let mut zip = match storage.config.data_format {
+            DataFormat::Zip => Some(ZipArchive::new(BufReader::new(File::open(Path::new(path).join("data.zip")).unwrap())).unwrap()),
+            DataFormat::Dir => None,
+        };
+        let files: Box<dyn Iterator<Item=(String, Vec<u8>)>> = match zip.as_mut() {
+            Some(zip) => Box::new((0..zip.len()).map(move |i| {
+                let mut file = zip.by_index(i).unwrap();
+                let mut bytes = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut bytes).unwrap();
+                (file.name().to_string(), bytes)
+            })),
+            None => {
+                let mut names: Vec<String> = fs::read_dir(path).unwrap()
+                    .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                    .filter(|name| name.ends_with(".json") || name.ends_with(".json.gz"))
+                    .collect();
+                names.sort();
+                Box::new(names.into_iter().map(move |name| {
+                    let bytes = fs::read(Path::new(path).join(&name)).unwrap();
+                    (name, bytes)
+                }))
+            }
+        };
+        let load_threads = if storage.config.load_threads == 0 { cpu_count() } else { storage.config.load_threads };
+        info!("parsing files in {} threads...", load_threads);
+        let mut count = 0;
+        parse_files(files, load_threads, |name, accounts_json| {
+            debug!("loading {}", name);
+            count += storage.add_accounts(&name, accounts_json);
+        });
         info!("loaded {} accounts, max id {}", count, storage.max_id);
 
         info!("dict size {}", storage.dict.max_key());
         info!("interests dict size {}", storage.interest_dict.max_key());
+        if let Some(interests_dict_path) = &interests_dict_path {
+            if !interests_dict_loaded {
+                let mut interests_dict_file = File::create(interests_dict_path).unwrap();
+                storage.interest_dict.write_values(&mut interests_dict_file).unwrap();
+                info!("saved interests to {}", interests_dict_path);
+            }
+        }
 
         info!("indexing...");
         // likes уже проиндексированы при загрузке
         for account in storage.accounts.iter() {
             if account.is_some() {
-                update_account_index(&storage.consts, &mut storage.indexes, account.as_ref().unwrap());
+                update_account_index(&storage.consts, &storage.dict, &mut storage.indexes, account.as_ref().unwrap());
                 update_group_index(&mut storage.indexes, account.as_ref().unwrap(), 1);
             }
         }
         info!("indexing done");
 
+        if let Some(replay_path) = storage.config.replay_path.clone() {
+            match wal::replay(&mut storage, &replay_path) {
+                Ok(applied) => info!("replayed {} writes from {}", applied, replay_path),
+                Err(err) => error!("wal replay {}: {}", replay_path, err),
+            }
+        }
+        if let Some(wal_path) = &storage.config.wal_path {
+            storage.wal = Some(Wal::open(wal_path, storage.config.wal_fsync).unwrap());
+            info!("writes are logged to {}", wal_path);
+        }
+
         storage
     }
 
+
+    /// Добавляет аккаунты одного файла, интернируя строки в словари. Возвращает количество загруженных аккаунтов,
+    /// аккаунты с невалидными полями пропускаются.
+    fn add_accounts(&mut self, name: &str, accounts_json: AccountsJson) -> usize {
+        let mut loaded = 0;
+        for account_json in accounts_json.accounts.iter() {
+            let id = account_json.id.unwrap() as usize;
+            let account = match account_from_json(account_json, &self.dict, &self.interest_dict, true) {
+                Ok(account) => account,
+                Err(err) => {
+                    // проверки POST (например, границы дат) не должны останавливать загрузку
+                    warn!("account id {} in {} skipped: {}", id, name, err);
+                    continue;
+                }
+            };
+            loaded += 1;
+            let account_option = &mut self.accounts[id];
+            if let Some(previous) = account_option.as_ref() {
+                // остальные индексы строятся после загрузки, при загрузке проиндексированы только likes
+                warn!("duplicate account id {} in {}, replacing previous", id, name);
+                remove_likes_index(&self.consts, &mut self.indexes, previous);
+            }
+            *account_option = Some(account);
+            if self.config.self_likes != SelfLikes::Accept {
+                remove_from_sorted_vec(id as i32, &mut account_option.as_mut().unwrap().likes);
+            }
+            calc_account_fields(account_option.as_mut().unwrap(), self.now, self.consts.free_status, self.consts.hard_status);
+            for like in account_json.likes.iter().flatten() {
+                update_likes_index(&self.consts, &mut self.indexes, self.config.self_likes, account_option.as_ref().unwrap(), like.id, like.ts)
+            }
+            if id > self.max_id {
+                self.max_id = id;
+            }
+        }
+        loaded
+    }
+
+    /// Разбор тела new/update с интернированием строк. Словари пополняются через &self,
+    /// поэтому разбор идет под блокировкой storage на чтение и не останавливает другие запросы.
+    pub fn parse_account(&self, bytes: &[u8], new_account: bool) -> Result<ParsedAccount, StatusCode> {
+        let json: AccountJson = serde_json::from_slice(bytes).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let account = account_from_json(&json, &self.dict, &self.interest_dict, new_account).map_err(|_| StatusCode::BAD_REQUEST)?;
+        Ok(ParsedAccount { json, account })
+    }
+
     pub fn new_account(&mut self, bytes: &[u8], success_response_f: &mut FnMut(StatusCode) -> ()) -> Result<(), StatusCode> {
-        let account_json: AccountJson = serde_json::from_slice(bytes).map_err(|_| StatusCode::BAD_REQUEST)?;
-        let id = match account_json.id {
-            Some(id) => id,
-            None => Err(StatusCode::BAD_REQUEST)?,
-        };
-        let account_option = &mut self.accounts[id as usize];
-        if account_option.is_some() ||
-            self.indexes.known_emails.contains(account_json.email.as_ref().unwrap()) {
+        let parsed = self.parse_account(bytes, true)?;
+        self.new_parsed_account(parsed, bytes, success_response_f)
+    }
+
+    /// new для уже разобранного parse_account тела, bytes - тело для журнала.
+    pub fn new_parsed_account(&mut self, parsed: ParsedAccount, bytes: &[u8], success_response_f: &mut dyn FnMut(StatusCode)) -> Result<(), StatusCode> {
+        let ParsedAccount { json: account_json, account } = parsed;
+        let id = account.id;
+        if self.accounts[id as usize].is_some() ||
+            self.indexes.known_emails.contains_key(account_json.email.as_ref().unwrap()) {
             Err(StatusCode::BAD_REQUEST)?;
         }
         if account_json.phone.is_some() {
@@ -265,30 +530,45 @@ impl Storage {
                 }
             }
         }
+        if self.config.self_likes == SelfLikes::Reject && account_json.likes.iter().flatten().any(|like| like.id == id) {
+            Err(StatusCode::BAD_REQUEST)?;
+        }
 
+        // в журнал до ответа: подтвержденная клиенту запись переживает падение
+        log_write(&mut self.wal, WalKind::New, bytes);
         success_response_f(StatusCode::CREATED);
 
-        *account_option = Some(account_from_json(&account_json, &mut self.dict, &mut self.interest_dict, true).map_err(|_| StatusCode::BAD_REQUEST)?);
+        let account_option = &mut self.accounts[id as usize];
+        *account_option = Some(account);
+        if self.config.self_likes == SelfLikes::Drop {
+            remove_from_sorted_vec(id, &mut account_option.as_mut().unwrap().likes);
+        }
         if id as usize > self.max_id {
             self.max_id = id as usize;
         }
 
         calc_account_fields(account_option.as_mut().unwrap(), self.now, self.consts.free_status, self.consts.hard_status);
-        update_account_index(&self.consts, &mut self.indexes, account_option.as_ref().unwrap());
+        self.recommend_versions.touch(&account_option.as_ref().unwrap().interests);
+        update_account_index(&self.consts, &self.dict, &mut self.indexes, account_option.as_ref().unwrap());
         update_group_index(&mut self.indexes, account_option.as_ref().unwrap(), 1);
-        for like in &account_json.likes {
-            update_likes_index(&self.consts, &mut self.indexes, account_option.as_ref().unwrap(), like.id, like.ts)
+        for like in account_json.likes.iter().flatten() {
+            update_likes_index(&self.consts, &mut self.indexes, self.config.self_likes, account_option.as_ref().unwrap(), like.id, like.ts)
         }
         Ok(())
     }
 
     pub fn update_account(&mut self, id: i32, bytes: &[u8], success_response_f: &mut FnMut(StatusCode) -> ()) -> Result<(), StatusCode> {
-        let account_json: AccountJson = serde_json::from_slice(bytes).map_err(|_| StatusCode::BAD_REQUEST)?;
-        let update = account_from_json(&account_json, &mut self.dict, &mut self.interest_dict, false).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let parsed = self.parse_account(bytes, false)?;
+        self.update_parsed_account(id, parsed, bytes, success_response_f)
+    }
 
-        let account = self.accounts[id as usize].as_mut().ok_or(StatusCode::NOT_FOUND)?;
-        if update.email.is_some() && update.email.as_ref().unwrap() != account.email.as_ref().unwrap() {
-            if self.indexes.known_emails.contains(update.email.as_ref().unwrap()) {
+    /// update для уже разобранного parse_account тела, bytes - тело для журнала.
+    pub fn update_parsed_account(&mut self, id: i32, parsed: ParsedAccount, bytes: &[u8], success_response_f: &mut dyn FnMut(StatusCode)) -> Result<(), StatusCode> {
+        let update = parsed.account;
+
+        let account = self.accounts.get_mut(id as usize).and_then(|account| account.as_mut()).ok_or(StatusCode::NOT_FOUND)?;
+        if let Some(email) = update.email.as_ref().filter(|email| *email != account.email.as_ref().unwrap()) {
+            if self.indexes.known_emails.contains_key(email) {
                 Err(StatusCode::BAD_REQUEST)?;
             } else {
                 self.indexes.known_emails.remove(account.email.as_ref().unwrap());
@@ -299,18 +579,34 @@ impl Storage {
             if self.indexes.known_phones.contains(&phone_pair) {
                 Err(StatusCode::BAD_REQUEST)?;
             } else {
-                self.indexes.known_phones.remove(&phone_pair);
+                self.indexes.known_phones.remove(&(account.phone_code, account.phone_number));
             }
         }
 
+        log_write(&mut self.wal, WalKind::Update(id), bytes);
         success_response_f(StatusCode::ACCEPTED);
 
+        // recommend зависит от интересов и до, и после изменения
+        self.recommend_versions.touch(&account.interests);
         update_group_index(&mut self.indexes, account, -1);
+        // ключи filter_index считаются по старым значениям полей, поэтому до их изменения
+        self.indexes.filter_index.remove_account(account);
 
         if update.email.is_some() {
+            if let Some(vec) = self.indexes.email_domain_index.get_mut(email_domain(account.email.as_ref().unwrap())) {
+                remove_from_sorted_vec(account.id, vec);
+            }
             account.email = update.email.clone();
         }
         if update.sname != 0 {
+            if let Some(vec) = self.indexes.sname_index.as_mut().and_then(|sname_index| sname_index.get_mut(&account.sname)) {
+                remove_from_sorted_vec(account.id, vec);
+            }
+            if let (Some(sname_prefix_index), Some(prefix)) = (self.indexes.sname_prefix_index.as_mut(), sname_prefix(self.dict.get_value(account.sname).as_ref().map(|sname| sname.as_str()))) {
+                if let Some(vec) = sname_prefix_index.get_mut(prefix) {
+                    remove_from_sorted_vec(account.id, vec);
+                }
+            }
             account.sname = update.sname;
         }
         if update.fname != 0 {
@@ -321,6 +617,9 @@ impl Storage {
             account.phone_code = update.phone_code;
         }
         if update.sex != 0 {
+            if let Some(ids) = self.indexes.sex_ids.get_mut(&account.sex) {
+                ids.remove(account.id);
+            }
             account.sex = update.sex;
         }
         if update.birth != NULL_DATE {
@@ -336,6 +635,9 @@ impl Storage {
             account.joined = update.joined;
         }
         if update.status != 0 {
+            if let Some(ids) = self.indexes.status_ids.get_mut(&account.status) {
+                ids.remove(account.id);
+            }
             account.status = update.status;
         }
         if !update.interests.is_empty() {
@@ -346,31 +648,161 @@ impl Storage {
             account.premium_finish = update.premium_finish;
         }
         calc_account_fields(account, self.now, self.consts.free_status, self.consts.hard_status);
-        update_account_index(&self.consts, &mut self.indexes, account);
+        self.recommend_versions.touch(&account.interests);
+        update_account_index(&self.consts, &self.dict, &mut self.indexes, account);
         update_group_index(&mut self.indexes, account, 1);
         Ok(())
     }
 
+    /// Пересоздает один индекс по текущим аккаунтам без перезагрузки данных, заодно убирая id,
+    /// оставшиеся в индексе после update. likes не пересоздается: у аккаунта не хранится ts лайков.
+    pub fn rebuild_index(&mut self, name: &str) -> Result<(), StatusCode> {
+        let indexes = &mut self.indexes;
+        match name {
+            "recommend" => {
+                indexes.recommend_index_male.clear();
+                indexes.recommend_index_female.clear();
+            }
+            "group" => indexes.group_index = GroupIndex::new(),
+            "filter" => indexes.filter_index = FilterIndex::new(),
+            "interests" => {
+                indexes.interests_index = PostingLists::new();
+                indexes.interests_index_male = PostingLists::new();
+                indexes.interests_index_female = PostingLists::new();
+                indexes.interests2_index.clear();
+            }
+            "city" => indexes.city_index = PostingLists::new(),
+            "country" => indexes.country_index = PostingLists::new(),
+            "country_of_city" => indexes.country_of_city.clear(),
+            "email_domain" => indexes.email_domain_index.clear(),
+            "birth" => indexes.birth_index.clear(),
+            "joined" => indexes.joined_index.clear(),
+            "phone_code" => indexes.phone_code_index.clear(),
+            "fname" => indexes.fname_index = PostingLists::new(),
+            "premium_now" => indexes.premium_now_ids.clear(),
+            "sname" => indexes.sname_index.as_mut().ok_or(StatusCode::BAD_REQUEST)?.clear(),
+            "sname_prefix" => indexes.sname_prefix_index.as_mut().ok_or(StatusCode::BAD_REQUEST)?.clear(),
+            "sex" => indexes.sex_ids.clear(),
+            "status" => indexes.status_ids.clear(),
+            _ => Err(StatusCode::BAD_REQUEST)?,
+        }
+        for account in self.accounts.iter().filter_map(|account| account.as_ref()) {
+            match name {
+                "recommend" => update_recommend_index_all(&self.consts, indexes, account),
+                "group" => update_group_index(indexes, account, 1),
+                "filter" => indexes.filter_index.update_account(account, &self.consts),
+                "interests" => update_interests_index(&self.consts, indexes, account),
+                "city" => indexes.city_index.insert(account.city, account.id),
+                "country" => indexes.country_index.insert(account.country, account.id),
+                "country_of_city" => update_country_of_city(indexes, account),
+                "email_domain" => update_email_domain_index(indexes, account),
+                "birth" => update_index(&mut indexes.birth_index, year_from_seconds(account.birth), account.id),
+                "joined" => update_index(&mut indexes.joined_index, year_from_seconds(account.joined), account.id),
+                "phone_code" => update_phone_code_index(indexes, account),
+                "fname" => indexes.fname_index.insert(account.fname, account.id),
+                "premium_now" => update_premium_now_ids(indexes, account),
+                "sname" => update_index(indexes.sname_index.as_mut().unwrap(), account.sname, account.id),
+                "sname_prefix" => update_sname_prefix_index(&self.dict, indexes, account),
+                "sex" => indexes.sex_ids.entry(account.sex).or_insert_with(IdSet::new).insert(account.id),
+                "status" => indexes.status_ids.entry(account.status).or_insert_with(IdSet::new).insert(account.id),
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
     pub fn update_likes(&mut self, bytes: &[u8], success_response_f: &mut FnMut(StatusCode) -> ()) -> Result<(), StatusCode> {
         let likes_json: LikesJson = serde_json::from_slice(bytes).map_err(|_| StatusCode::BAD_REQUEST)?;
         for like in &likes_json.likes {
             if self.accounts[like.liker as usize].is_none() || self.accounts[like.likee as usize].is_none() {
                 Err(StatusCode::BAD_REQUEST)?;
             }
+            if like.liker == like.likee && self.config.self_likes == SelfLikes::Reject {
+                Err(StatusCode::BAD_REQUEST)?;
+            }
         }
 
+        log_write(&mut self.wal, WalKind::Likes, bytes);
         success_response_f(StatusCode::ACCEPTED);
 
+        // вставка по одному лайку сдвигает списки на каждый лайк: вместо этого лайки группируются по liker и likee,
+        // и каждый список дополняется и сортируется один раз
+        let mut likees_by_liker: HashMap<i32, Vec<i32>> = HashMap::new();
+        let mut likes_by_likee: HashMap<(bool, i32), Vec<Like>> = HashMap::new();
         for like in &likes_json.likes {
-            let account = self.accounts[like.liker as usize].as_mut().unwrap();
-            insert_into_sorted_vec(like.likee, &mut account.likes);
-            update_likes_index(&self.consts, &mut self.indexes, account, like.likee, like.ts);
+            if like.liker == like.likee && self.config.self_likes == SelfLikes::Drop {
+                continue;
+            }
+            likees_by_liker.entry(like.liker).or_default().push(like.likee);
+            let male = self.accounts[like.liker as usize].as_ref().unwrap().sex == self.consts.male;
+            likes_by_likee.entry((male, like.likee)).or_default().push(Like { id: like.liker, ts: like.ts });
+        }
+        for (liker, likees) in likees_by_liker {
+            let account = self.accounts[liker as usize].as_mut().unwrap();
+            account.likes.extend(likees);
+            account.likes.sort_unstable();
+            account.likes.dedup();
+        }
+        for ((male, likee), likes) in likes_by_likee {
+            let likes_index = if male { &mut self.indexes.likes_index_male } else { &mut self.indexes.likes_index_female };
+            let vec = likes_index.entry(likee).or_default();
+            vec.extend(likes);
+            // сортировка устойчивая: повторы той же пары сохраняются, как и в insert_like_into_sorted_vec
+            vec.sort_by_key(|like| like.id);
         }
         Ok(())
     }
 }
 
-fn account_from_json(account_json: &AccountJson, dict: &mut Dict, interest_dict: &mut Dict, new_account: bool) -> Result<Account, String> {
+impl Storage {
+    /// Все поля аккаунта в формате входных данных, new_account примет результат обратно.
+    pub fn get_account_json(&self, account: &Account) -> AccountJson {
+        let likes_index = if account.sex == self.consts.male { &self.indexes.likes_index_male } else { &self.indexes.likes_index_female };
+        // повторные лайки той же пары хранятся только в индексе, ts берется оттуда
+        let mut likes: Vec<Like> = account.likes.iter()
+            .flat_map(|likee| likes_from(likes_index.get(likee).unwrap_or(&EMPTY_LIKE_LIST), account.id).iter().map(move |like| Like { id: *likee, ts: like.ts }))
+            .collect();
+        // порядок повторов в индексе зависит от порядка вставки
+        likes.sort_by_key(|like| (like.id, like.ts));
+        AccountJson {
+            id: Some(account.id),
+            email: account.email.clone(),
+            sname: self.dict.get_value(account.sname),
+            fname: self.dict.get_value(account.fname),
+            phone: if account.phone_number != 0 {
+                Some(Arc::new("8(".to_string() + account.phone_code.to_string().as_str() + ")" + &account.phone_number.to_string().as_str()[1..]))
+            } else {
+                None
+            },
+            sex: self.dict.get_value(account.sex),
+            birth: Some(account.birth).filter(|birth| *birth != NULL_DATE),
+            country: self.dict.get_value(account.country),
+            city: self.dict.get_value(account.city),
+            joined: Some(account.joined).filter(|joined| *joined != NULL_DATE),
+            status: self.dict.get_value(account.status),
+            interests: Some(account.interests.into_iter().filter_map(|interest| self.interest_dict.get_value(interest)).collect())
+                .filter(|interests: &Vec<Arc<String>>| !interests.is_empty() || self.config.emit_empty_arrays),
+            likes: Some(likes).filter(|likes| !likes.is_empty() || self.config.emit_empty_arrays),
+            premium: if account.premium_start != NULL_DATE {
+                Some(Premium { start: account.premium_start, finish: account.premium_finish })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Лайки liker в списке likes_index, отсортированном по id лайкающего.
+pub fn likes_from(likes: &[Like], liker: i32) -> &[Like] {
+    // Ordering::Greater на равных id не дает поиску остановиться на середине группы
+    let from = match likes.binary_search_by(|like| like.id.cmp(&liker).then(Ordering::Greater)) {
+        Ok(pos) | Err(pos) => pos,
+    };
+    let len = likes[from..].iter().take_while(|like| like.id == liker).count();
+    &likes[from..from + len]
+}
+
+fn account_from_json(account_json: &AccountJson, dict: &Dict, interest_dict: &Dict, new_account: bool) -> Result<Account, String> {
     if new_account && account_json.id.is_none() {
         return Err("empty id".to_string());
     }
@@ -392,6 +824,8 @@ fn account_from_json(account_json: &AccountJson, dict: &mut Dict, interest_dict:
     if new_account && account_json.joined.is_none() {
         return Err("empty joined".to_string());
     }
+    let birth = checked_date(account_json.birth, MIN_BIRTH, MAX_BIRTH, new_account, "birth")?;
+    let joined = checked_date(account_json.joined, MIN_JOINED, MAX_JOINED, new_account, "joined")?;
     let mut phone_number = 0;
     let mut phone_code = 0;
     if account_json.phone.is_some() {
@@ -408,14 +842,14 @@ fn account_from_json(account_json: &AccountJson, dict: &mut Dict, interest_dict:
         phone_number,
         phone_code,
         sex: dict.get_key_from_option(&account_json.sex),
-        birth: account_json.birth.unwrap_or(NULL_DATE),
+        birth,
         country: dict.get_key_from_option(&account_json.country),
         city: dict.get_key_from_option(&account_json.city),
-        joined: account_json.joined.unwrap_or(NULL_DATE),
+        joined,
         status: dict.get_key_from_option(&account_json.status),
-        interests: Bits::from_vec(account_json.interests.iter().map(|interest| interest_dict.get_key(&interest)).collect()),
+        interests: Interests::from_vec(account_json.interests.iter().flatten().map(|interest| interest_dict.get_key(interest)).collect()),
         likes: {
-            let mut vec: Vec<i32> = account_json.likes.iter().map(|like| &like.id).cloned().collect();
+            let mut vec: Vec<i32> = account_json.likes.iter().flatten().map(|like| &like.id).cloned().collect();
             vec.sort();
             vec.dedup();
             vec
@@ -428,6 +862,15 @@ fn account_from_json(account_json: &AccountJson, dict: &mut Dict, interest_dict:
     })
 }
 
+/// Дата вне [min, max] у нового аккаунта - ошибка, в update она не меняется.
+fn checked_date(date: Option<i32>, min: i32, max: i32, new_account: bool, name: &str) -> Result<i32, String> {
+    match date {
+        Some(date) if date >= min && date <= max => Ok(date),
+        Some(date) if new_account => Err(format!("{} {} out of range", name, date)),
+        _ => Ok(NULL_DATE),
+    }
+}
+
 fn parse_phone(phone: &str) -> Result<Option<(i32, i32)>, String> {
     if let Some(caps) = PHONE_PATTERN.captures(phone) {
         let phone_number = ("1".to_string() + caps.get(2).unwrap().as_str()).parse().or(Err("cannot parse phone"))?;
@@ -450,17 +893,159 @@ fn calc_account_fields(account: &mut Account, now: i32, free_status: i32, hard_s
     }
 }
 
-fn update_account_index(consts: &Consts, indexes: &mut Indexes, account: &Account) -> () {
-    indexes.known_emails.insert(account.email.as_ref().unwrap().clone());
+/// Файлы .gz распаковываются, остальные разбираются как json.
+fn parse_file(name: &str, bytes: &[u8]) -> AccountsJson {
+    if name.ends_with(".gz") {
+        serde_json::from_reader(BufReader::new(GzDecoder::new(bytes))).unwrap()
+    } else {
+        serde_json::from_slice(bytes).unwrap()
+    }
+}
+
+/// Разбирает файлы в threads потоках и отдает результаты consume в исходном порядке файлов. Прочитанных, но еще
+/// не отданных файлов не больше окна 2 * threads: следующий файл читается, только когда consume забрал очередной.
+fn parse_files<I: Iterator<Item=(String, Vec<u8>)>, F: FnMut(String, AccountsJson)>(files: I, threads: usize, mut consume: F) {
+    let threads = threads.max(1);
+    // у каждого потока разбирается один файл и один ждет в очереди
+    let window = 2 * threads;
+    let (job_sender, job_receiver) = mpsc::sync_channel::<(usize, String, Vec<u8>)>(window);
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    let (result_sender, result_receiver) = mpsc::channel();
+    let workers: Vec<thread::JoinHandle<()>> = (0..threads).map(|_| {
+        let job_receiver = job_receiver.clone();
+        let result_sender = result_sender.clone();
+        thread::spawn(move || {
+            loop {
+                let job = job_receiver.lock().unwrap().recv();
+                let (index, name, bytes) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                // паника разбора передается в поток загрузки, иначе он ждал бы этот файл вечно
+                let accounts_json = panic::catch_unwind(panic::AssertUnwindSafe(|| parse_file(&name, &bytes)));
+                drop(bytes);
+                result_sender.send((index, name, accounts_json)).unwrap();
+            }
+        })
+    }).collect();
+    drop(result_sender);
+
+    let mut files = files.enumerate();
+    let mut parsed: HashMap<usize, (String, AccountsJson)> = HashMap::new();
+    let mut sent = 0;
+    let mut next = 0;
+    loop {
+        while sent - next < window {
+            match files.next() {
+                Some((index, (name, bytes))) => job_sender.send((index, name, bytes)).unwrap(),
+                None => break,
+            }
+            sent += 1;
+        }
+        if next == sent {
+            break;
+        }
+        while !parsed.contains_key(&next) {
+            let (index, name, accounts_json) = result_receiver.recv().expect("load workers stopped");
+            let accounts_json = accounts_json.unwrap_or_else(|_| panic!("failed to parse {}", name));
+            parsed.insert(index, (name, accounts_json));
+        }
+        let (name, accounts_json) = parsed.remove(&next).unwrap();
+        consume(name, accounts_json);
+        next += 1;
+    }
+    drop(job_sender);
+    for worker in workers {
+        worker.join().expect("load worker panicked");
+    }
+}
+
+fn update_account_index(consts: &Consts, dict: &Dict, indexes: &mut Indexes, account: &Account) {
+    indexes.known_emails.insert(account.email.as_ref().unwrap().clone(), account.id);
     indexes.known_phones.insert((account.phone_code, account.phone_number));
+    update_email_domain_index(indexes, account);
+    update_interests_index(consts, indexes, account);
+    update_recommend_index_all(consts, indexes, account);
+    indexes.city_index.insert(account.city, account.id);
+    indexes.country_index.insert(account.country, account.id);
+    update_country_of_city(indexes, account);
+    update_index(&mut indexes.birth_index, year_from_seconds(account.birth), account.id);
+    update_index(&mut indexes.joined_index, year_from_seconds(account.joined), account.id);
+    update_phone_code_index(indexes, account);
+    indexes.fname_index.insert(account.fname, account.id);
+    update_premium_now_ids(indexes, account);
+    if let Some(sname_index) = indexes.sname_index.as_mut() {
+        update_index(sname_index, account.sname, account.id);
+    }
+    update_sname_prefix_index(dict, indexes, account);
+    indexes.sex_ids.entry(account.sex).or_insert_with(IdSet::new).insert(account.id);
+    indexes.status_ids.entry(account.status).or_insert_with(IdSet::new).insert(account.id);
+    indexes.filter_index.update_account(account, consts);
+}
+
+/// Начало фамилии - ключ sname_prefix_index; у фамилии короче SNAME_PREFIX_CHARS ключа нет.
+pub fn sname_prefix(sname: Option<&str>) -> Option<&str> {
+    let sname = sname?;
+    match sname.char_indices().nth(SNAME_PREFIX_CHARS) {
+        Some((end, _)) => Some(&sname[..end]),
+        None if sname.chars().count() == SNAME_PREFIX_CHARS => Some(sname),
+        None => None,
+    }
+}
+
+fn update_sname_prefix_index(dict: &Dict, indexes: &mut Indexes, account: &Account) {
+    if let (Some(sname_prefix_index), Some(prefix)) = (indexes.sname_prefix_index.as_mut(), sname_prefix(dict.get_value(account.sname).as_ref().map(|sname| sname.as_str()))) {
+        let vec = sname_prefix_index.entry(prefix.to_string()).or_default();
+        insert_into_sorted_vec(account.id, vec);
+    }
+}
+
+/// После update premium может и закончиться, тогда id убирается.
+fn update_premium_now_ids(indexes: &mut Indexes, account: &Account) {
+    if account.is_premium {
+        insert_into_sorted_vec(account.id, &mut indexes.premium_now_ids);
+    } else {
+        remove_from_sorted_vec(account.id, &mut indexes.premium_now_ids);
+    }
+}
+
+/// Часть email после последнего '@', ее же проверяет filter email_domain.
+pub fn email_domain(email: &str) -> &str {
+    email.rfind('@').map_or("", |index| &email[index + 1..])
+}
+
+fn update_email_domain_index(indexes: &mut Indexes, account: &Account) {
+    let domain = email_domain(account.email.as_ref().unwrap()).to_string();
+    let vec = indexes.email_domain_index.entry(domain).or_default();
+    insert_into_sorted_vec(account.id, vec);
+}
+
+fn update_country_of_city(indexes: &mut Indexes, account: &Account) {
+    if account.city == 0 {
+        return;
+    }
+    let country = indexes.country_of_city.entry(account.city).or_insert(account.country);
+    if *country != account.country {
+        *country = AMBIGUOUS_COUNTRY;
+    }
+}
+
+/// Успешный запрос в журнал, если он включен; ответ уже отправлен, поэтому ошибка записи только логируется.
+fn log_write(wal: &mut Option<Wal>, kind: WalKind, bytes: &[u8]) {
+    if let Some(wal) = wal.as_mut() {
+        if let Err(err) = wal.append(kind, bytes) {
+            error!("wal append error: {}", err);
+        }
+    }
+}
+
+fn update_interests_index(consts: &Consts, indexes: &mut Indexes, account: &Account) {
     for interest in &account.interests {
-        update_index(&mut indexes.interests_index, interest, account.id);
+        indexes.interests_index.insert(interest, account.id);
         if account.sex == consts.male {
-            update_recommend_index(&mut indexes.recommend_index_male, account, interest);
-            update_index(&mut indexes.interests_index_male, interest, account.id);
+            indexes.interests_index_male.insert(interest, account.id);
         } else {
-            update_recommend_index(&mut indexes.recommend_index_female, account, interest);
-            update_index(&mut indexes.interests_index_female, interest, account.id);
+            indexes.interests_index_female.insert(interest, account.id);
         }
         for interest2 in &account.interests {
             if interest < interest2 {
@@ -469,11 +1054,19 @@ fn update_account_index(consts: &Consts, indexes: &mut Indexes, account: &Accoun
             }
         }
     }
-    update_index(&mut indexes.city_index, account.city, account.id);
-    update_index(&mut indexes.country_index, account.country, account.id);
-    update_index(&mut indexes.birth_index, year_from_seconds(account.birth), account.id);
-    update_index(&mut indexes.fname_index, account.fname, account.id);
-    indexes.filter_index.update_account(account, consts);
+}
+
+fn update_recommend_index_all(consts: &Consts, indexes: &mut Indexes, account: &Account) {
+    let recommend_index = if account.sex == consts.male { &mut indexes.recommend_index_male } else { &mut indexes.recommend_index_female };
+    for interest in &account.interests {
+        update_recommend_index(recommend_index, account, interest);
+    }
+}
+
+fn update_phone_code_index(indexes: &mut Indexes, account: &Account) {
+    if account.phone_number != 0 {
+        update_index(&mut indexes.phone_code_index, account.phone_code, account.id);
+    }
 }
 
 fn update_index(index: &mut HashMap<i32, Vec<i32>>, value: i32, id: i32) {
@@ -483,7 +1076,10 @@ fn update_index(index: &mut HashMap<i32, Vec<i32>>, value: i32, id: i32) {
     }
 }
 
-fn update_likes_index(consts: &Consts, indexes: &mut Indexes, account: &Account, likee: i32, ts: i32) {
+fn update_likes_index(consts: &Consts, indexes: &mut Indexes, self_likes: SelfLikes, account: &Account, likee: i32, ts: i32) {
+    if likee == account.id && self_likes != SelfLikes::Accept {
+        return;
+    }
     if account.sex == consts.male {
         let vec = indexes.likes_index_male.entry(likee).or_insert_with(|| Vec::new());
         insert_like_into_sorted_vec(Like { id: account.id, ts }, vec);
@@ -493,6 +1089,18 @@ fn update_likes_index(consts: &Consts, indexes: &mut Indexes, account: &Account,
     }
 }
 
+fn remove_likes_index(consts: &Consts, indexes: &mut Indexes, account: &Account) {
+    let likes_index = if account.sex == consts.male { &mut indexes.likes_index_male } else { &mut indexes.likes_index_female };
+    for likee in &account.likes {
+        if let Some(vec) = likes_index.get_mut(likee) {
+            vec.retain(|like| like.id != account.id);
+            if vec.is_empty() {
+                likes_index.remove(likee);
+            }
+        }
+    }
+}
+
 fn insert_like_into_sorted_vec(value: Like, vec: &mut Vec<Like>) {
     match vec.binary_search_by(|probe| probe.id.cmp(&value.id)) {
         Ok(pos) => vec.insert(pos, value), // чтобы вставить записи с одинаковым id и разным ts, но и полные дубли будут вставлены
@@ -513,43 +1121,702 @@ fn update_recommend_index(index: &mut Vec<[Vec<i32>; 6]>, account: &Account, int
     }
 }
 
+impl Indexes {
+    /// Все лайкнувшие likee, по возрастанию id без повторов - объединение индексов обоих полов.
+    pub fn likers<'a>(&'a self, likee: i32) -> impl Iterator<Item=i32> + 'a {
+        self.likes_index_male.get(&likee).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id)
+            .merge(self.likes_index_female.get(&likee).unwrap_or(&EMPTY_LIKE_LIST).iter().map(|like| like.id))
+            .dedup()
+    }
+
+    /// Длина списков лайкнувших likee в обоих индексах, повторные лайки считаются.
+    pub fn likers_count(&self, likee: i32) -> usize {
+        self.likes_index_male.get(&likee).map_or(0, |likes| likes.len()) + self.likes_index_female.get(&likee).map_or(0, |likes| likes.len())
+    }
+
+    /// Лайкал ли liker likee: двоичный поиск в отсортированных по id списках, без их объединения.
+    pub fn is_liker(&self, likee: i32, liker: i32) -> bool {
+        [&self.likes_index_male, &self.likes_index_female].iter()
+            .any(|index| index.get(&likee).is_some_and(|likes| likes.binary_search_by_key(&liker, |like| like.id).is_ok()))
+    }
+
+    /// Страна, с которой встречался город, если она всегда одна; Some(0) - город встречался только без страны.
+    pub fn country_of_city(&self, city: i32) -> Option<i32> {
+        self.country_of_city.get(&city).cloned().filter(|country| *country != AMBIGUOUS_COUNTRY)
+    }
+
+    pub fn has_likers(&self, likee: i32) -> bool {
+        self.likes_index_male.contains_key(&likee) || self.likes_index_female.contains_key(&likee)
+    }
+}
+
 impl Dict {
     fn new() -> Dict {
         Dict {
-            map: HashMap::new(),
-            list: vec![Arc::new(String::new())],
+            map: CHashMap::new(),
+            list: RwLock::new(vec![Arc::new(String::new())]),
         }
     }
 
-    fn get_key(&mut self, str: &Arc<String>) -> i32 {
-        let option = self.map.get(str);
-        if option.is_some() {
-            *option.unwrap()
-        } else {
-            let key: i32 = self.list.len() as i32;
-            self.map.insert(str.clone(), key);
-            self.list.push(str.clone());
-            key
+    fn get_key(&self, str: &Arc<String>) -> i32 {
+        if let Some(key) = self.get_existing_key(str) {
+            return key;
         }
+        // повторная проверка под блокировкой списка: одну строку могут добавлять два потока сразу
+        let mut list = write_lock(&self.list);
+        if let Some(key) = self.get_existing_key(str) {
+            return key;
+        }
+        let key = list.len() as i32;
+        list.push(str.clone());
+        self.map.insert(str.clone(), key);
+        key
     }
 
-    fn get_key_from_option(&mut self, str: &Option<Arc<String>>) -> i32 {
+    fn get_key_from_option(&self, str: &Option<Arc<String>>) -> i32 {
         str.as_ref().map_or(0, |str| self.get_key(str))
     }
 
     pub fn get_existing_key(&self, str: &String) -> Option<i32> {
-        self.map.get(str).map(|v| *v)
+        self.map.get(str).map(|key| *key)
     }
 
     pub fn get_value(&self, key: i32) -> Option<Arc<String>> {
         if key != 0 {
-            Some(self.list[key as usize].clone())
+            Some(read_lock(&self.list)[key as usize].clone())
         } else {
             None
         }
     }
 
     pub fn max_key(&self) -> i32 {
-        self.list.len() as i32 - 1
+        read_lock(&self.list).len() as i32 - 1
+    }
+
+    /// Значения в порядке ключей по строке, без пустого значения с ключом 0.
+    pub fn write_values(&self, out: &mut dyn Write) -> io::Result<()> {
+        for value in read_lock(&self.list)[1..].iter() {
+            writeln!(out, "{}", value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::Cell;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use crate::utils::params;
+
+    use super::*;
+
+    pub fn make_storage(accounts: &[&str]) -> Storage {
+        let mut storage = Storage::new(1545834028, Config::new(), 1000);
+        for account in accounts {
+            storage.new_account(account.as_bytes(), &mut |_| {}).unwrap();
+        }
+        storage
+    }
+
+    /// Каталог с options.txt и data.zip из переданных файлов.
+    pub fn make_data_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hlc2018_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("options.txt"), "1545834028\n1\n").unwrap();
+        let mut zip = ZipWriter::new(File::create(dir.join("data.zip")).unwrap());
+        for (file_name, content) in files {
+            zip.start_file(*file_name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        dir
+    }
+
+    fn gzip(content: &str) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_load_gzip() {
+        let accounts1 = r#"{"accounts":[{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000},{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}]}"#;
+        let accounts2 = r#"{"accounts":[{"id":3,"email":"a3@a.ru","sex":"m","status":"заняты","birth":600000000,"joined":1300000000}]}"#;
+        let count = |storage: &Storage| storage.accounts.iter().filter(|account| account.is_some()).count();
+
+        let plain_dir = make_data_dir("load_plain", &[("accounts_1.json", accounts1), ("accounts_2.json", accounts2)]);
+        let plain_count = count(&Storage::load(plain_dir.to_str().unwrap(), Config::new()));
+        assert_eq!(plain_count, 3);
+
+        let gz_dir = std::env::temp_dir().join(format!("hlc2018_load_gz_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&gz_dir);
+        fs::create_dir_all(&gz_dir).unwrap();
+        fs::write(gz_dir.join("options.txt"), "1545834028\n1\n").unwrap();
+        fs::write(gz_dir.join("accounts_1.json.gz"), gzip(accounts1)).unwrap();
+        fs::write(gz_dir.join("accounts_2.json"), accounts2).unwrap();
+        let mut config = Config::new();
+        config.data_format = DataFormat::Dir;
+        let storage = Storage::load(gz_dir.to_str().unwrap(), config);
+        assert_eq!(count(&storage), plain_count);
+        assert_eq!(storage.max_id, 3);
+
+        fs::remove_dir_all(plain_dir).unwrap();
+        fs::remove_dir_all(gz_dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_duplicate_id() {
+        let accounts1 = r#"{"accounts":[{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000},{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":1,"ts":1}]},{"id":3,"email":"a3@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":1,"ts":1}]}]}"#;
+        let accounts2 = r#"{"accounts":[{"id":2,"email":"a2@b.ru","sex":"m","status":"заняты","birth":600000000,"joined":1300000000,"city":"Москва","likes":[{"id":3,"ts":1}]}]}"#;
+        let dir = make_data_dir("load_duplicate", &[("accounts_1.json", accounts1), ("accounts_2.json", accounts2)]);
+        let storage = Storage::load(dir.to_str().unwrap(), Config::new());
+
+        assert_eq!(storage.accounts[2].as_ref().unwrap().email.as_ref().unwrap().as_str(), "a2@b.ru");
+        assert_eq!(storage.indexes.likers(1).collect::<Vec<i32>>(), vec![3]);
+        assert_eq!(storage.indexes.likers(3).collect::<Vec<i32>>(), vec![2]);
+        assert!(!storage.indexes.known_emails.contains_key(&Arc::new("a2@a.ru".to_string())));
+        let moscow = storage.dict.get_existing_key(&"Москва".to_string()).unwrap();
+        assert_eq!(storage.indexes.city_index.get(moscow), &vec![2]);
+        drop(storage);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn load_files(name: &str, files_count: usize, accounts_per_file: usize) -> PathBuf {
+        let files: Vec<(String, String)> = (0..files_count).map(|file| {
+            let accounts: Vec<String> = (0..accounts_per_file).map(|i| {
+                // id повторяются между соседними файлами, чтобы проверить порядок замены
+                let id = file * accounts_per_file / 2 + i + 1;
+                format!(r#"{{"id":{},"email":"a{}_{}@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"city":"c{}","interests":["i{}","j{}"],"likes":[{{"id":{},"ts":{}}}]}}"#,
+                        id, id, file, (id + file) % 50, (id * 7 + file) % 90, id % 13, id % 100 + 1, file)
+            }).collect();
+            (format!("accounts_{}.json", file + 1), format!(r#"{{"accounts":[{}]}}"#, accounts.join(",")))
+        }).collect();
+        make_data_dir(name, &files.iter().map(|(name, content)| (name.as_str(), content.as_str())).collect::<Vec<(&str, &str)>>())
+    }
+
+    #[test]
+    fn test_load_threads() {
+        let dir = load_files("load_threads", 7, 200);
+        let load = |threads: usize| -> (Vec<String>, Vec<Option<i32>>, Vec<i32>) {
+            let mut config = Config::new();
+            config.load_threads = threads;
+            let storage = Storage::load(dir.to_str().unwrap(), config);
+            let accounts = storage.accounts.iter().filter_map(|account| account.as_ref())
+                .map(|account| serde_json::to_string(&storage.get_account_json(account)).unwrap())
+                .collect();
+            // ключи словарей назначаются в том же порядке
+            let keys = (0..50).map(|city| storage.dict.get_existing_key(&format!("c{}", city)))
+                .chain((0..90).map(|interest| storage.interest_dict.get_existing_key(&format!("i{}", interest))))
+                .collect();
+            (accounts, keys, storage.indexes.likers(1).collect())
+        };
+        let sequential = load(1);
+        assert_eq!(sequential.0.len(), 7 * 100 + 100);
+        assert!(sequential.0.iter().any(|account| account.contains("a150_1@a.ru")));
+        assert!(!sequential.0.iter().any(|account| account.contains("a150_0@a.ru")));
+        assert_eq!(load(4), sequential);
+        assert_eq!(load(16), sequential);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_files_window() {
+        for threads in &[1, 3] {
+            // прочитано из files минус отдано в consume
+            let in_flight = Cell::new(0);
+            let max_in_flight = Cell::new(0);
+            let files = (0..20).map(|file| {
+                in_flight.set(in_flight.get() + 1);
+                max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+                (format!("accounts_{}.json", file), format!(r#"{{"accounts":[{{"id":{}}}]}}"#, file + 1).into_bytes())
+            });
+            let mut ids = Vec::new();
+            parse_files(files, *threads, |_, accounts_json| {
+                in_flight.set(in_flight.get() - 1);
+                ids.push(accounts_json.accounts[0].id.unwrap());
+            });
+            assert_eq!(ids, (1..21).collect::<Vec<i32>>());
+            assert_eq!(max_in_flight.get(), 2 * threads);
+        }
+    }
+
+    #[test]
+    fn test_interests_dict_stable() {
+        let account1 = r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"interests":["b","c"]}"#;
+        let account2 = r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"interests":["a","b"]}"#;
+        let dir1 = make_data_dir("interests_dict1", &[("accounts_1.json", &format!(r#"{{"accounts":[{},{}]}}"#, account1, account2))]);
+        let dir2 = make_data_dir("interests_dict2", &[("accounts_1.json", &format!(r#"{{"accounts":[{},{}]}}"#, account2, account1))]);
+        let interests_dict_path = dir1.join("interests.txt");
+        let mut config = Config::new();
+        config.interests_dict_path = Some(interests_dict_path.to_str().unwrap().to_string());
+
+        let keys = |storage: &Storage| -> Vec<Option<i32>> {
+            ["a", "b", "c"].iter().map(|interest| storage.interest_dict.get_existing_key(&interest.to_string())).collect()
+        };
+
+        let keys1 = keys(&Storage::load(dir1.to_str().unwrap(), config.clone()));
+        assert_eq!(keys1, vec![Some(3), Some(1), Some(2)]);
+        assert_eq!(fs::read_to_string(&interests_dict_path).unwrap(), "b\nc\na\n");
+
+        // без файла порядок другой, с файлом - как при первой загрузке
+        assert_eq!(keys(&Storage::load(dir2.to_str().unwrap(), Config::new())), vec![Some(1), Some(2), Some(3)]);
+        assert_eq!(keys(&Storage::load(dir2.to_str().unwrap(), config)), keys1);
+
+        fs::remove_dir_all(dir1).unwrap();
+        fs::remove_dir_all(dir2).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_likes() {
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::SmallRng;
+
+        let mut rng = SmallRng::seed_from_u64(2271);
+        let accounts: Vec<String> = (1..201).map(|id| format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"{}","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{{"id":{},"ts":1}}]}}"#,
+                                                              id, id, if id % 3 == 0 { "f" } else { "m" }, id % 7 + 1)).collect();
+        let accounts: Vec<&str> = accounts.iter().map(|account| account.as_str()).collect();
+        // повторы пар и лайки самому себе тоже попадают в индекс
+        let likes: Vec<String> = (0..3000).map(|_| format!(r#"{{"liker":{},"likee":{},"ts":{}}}"#, rng.gen_range(1, 201), rng.gen_range(1, 30), rng.gen_range(0, 5))).collect();
+
+        let mut bulk = make_storage(&accounts);
+        bulk.update_likes(format!(r#"{{"likes":[{}]}}"#, likes.join(",")).as_bytes(), &mut |_| {}).unwrap();
+        let mut incremental = make_storage(&accounts);
+        for like in &likes {
+            incremental.update_likes(format!(r#"{{"likes":[{}]}}"#, like).as_bytes(), &mut |_| {}).unwrap();
+        }
+
+        let dump_index = |index: &HashMap<i32, Vec<Like>>| -> Vec<(i32, Vec<(i32, i32)>)> {
+            let mut dump: Vec<(i32, Vec<(i32, i32)>)> = index.iter().map(|(likee, likes)| {
+                // списки отсортированы по id, порядок повторов внутри id зависит от порядка вставки
+                assert!(likes.windows(2).all(|pair| pair[0].id <= pair[1].id));
+                let mut likes: Vec<(i32, i32)> = likes.iter().map(|like| (like.id, like.ts)).collect();
+                likes.sort();
+                (*likee, likes)
+            }).collect();
+            dump.sort();
+            dump
+        };
+        assert_eq!(dump_index(&bulk.indexes.likes_index_male), dump_index(&incremental.indexes.likes_index_male));
+        assert_eq!(dump_index(&bulk.indexes.likes_index_female), dump_index(&incremental.indexes.likes_index_female));
+        assert_eq!(dump_index(&bulk.indexes.likes_index_male).iter().map(|(_, likes)| likes.len()).sum::<usize>()
+                       + dump_index(&bulk.indexes.likes_index_female).iter().map(|(_, likes)| likes.len()).sum::<usize>(), 3000 + 200);
+        for id in 1..201 {
+            assert_eq!(bulk.accounts[id].as_ref().unwrap().likes, incremental.accounts[id].as_ref().unwrap().likes);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_self_likes() {
+        let account1 = r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}"#;
+        let account2 = r#"{"id":2,"email":"a2@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":1,"ts":1},{"id":2,"ts":1}]}"#;
+        let self_like = br#"{"likes":[{"liker":1,"likee":1,"ts":2}]}"#;
+
+        for self_likes in &[SelfLikes::Accept, SelfLikes::Reject, SelfLikes::Drop] {
+            let mut config = Config::new();
+            config.self_likes = *self_likes;
+            let mut storage = Storage::new(1545834028, config, 1000);
+            storage.new_account(account1.as_bytes(), &mut |_| {}).unwrap();
+
+            let new_result = storage.new_account(account2.as_bytes(), &mut |_| {});
+            let likes_result = storage.update_likes(self_like, &mut |_| {});
+            let likes = |id: usize| storage.accounts[id].as_ref().map(|account| account.likes.clone());
+            let likers = |likee: i32| storage.indexes.likes_index_male.get(&likee).map_or(Vec::new(), |vec| vec.iter().map(|like| like.id).collect::<Vec<i32>>());
+            match self_likes {
+                SelfLikes::Accept => {
+                    assert_eq!((new_result, likes_result), (Ok(()), Ok(())));
+                    assert_eq!(likes(1), Some(vec![1]));
+                    assert_eq!(likes(2), Some(vec![1, 2]));
+                    assert_eq!((likers(1), likers(2)), (vec![1, 2], vec![2]));
+                }
+                SelfLikes::Reject => {
+                    assert_eq!((new_result, likes_result), (Err(StatusCode::BAD_REQUEST), Err(StatusCode::BAD_REQUEST)));
+                    assert_eq!(likes(1), Some(vec![]));
+                    assert_eq!(likes(2), None);
+                    assert_eq!((likers(1), likers(2)), (vec![], vec![]));
+                }
+                SelfLikes::Drop => {
+                    assert_eq!((new_result, likes_result), (Ok(()), Ok(())));
+                    assert_eq!(likes(1), Some(vec![]));
+                    assert_eq!(likes(2), Some(vec![1]));
+                    assert_eq!((likers(1), likers(2)), (vec![2], vec![]));
+                }
+            }
+        }
+
+        // при загрузке reject не может ответить 400, лайк просто отбрасывается
+        let dir = make_data_dir("self_likes", &[("accounts_1.json", &format!(r#"{{"accounts":[{},{}]}}"#, account1, account2))]);
+        let mut config = Config::new();
+        config.self_likes = SelfLikes::Reject;
+        let storage = Storage::load(dir.to_str().unwrap(), config);
+        assert_eq!(storage.accounts[2].as_ref().unwrap().likes, vec![1]);
+        assert!(!storage.indexes.likes_index_male.contains_key(&2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    const UPDATE_FIXTURE: &str = r#"{"id":1,"email":"a1@a.ru","fname":"Иван","sname":"Петров","phone":"8(900)1111111","sex":"m","birth":600000000,"country":"Россия","city":"Москва","joined":1300000000,"status":"свободны","interests":["a","b"],"premium":{"start":1500000000,"finish":1600000000},"likes":[{"id":2,"ts":1}]}"#;
+    const UPDATE_OTHER: &str = r#"{"id":2,"email":"a2@a.ru","phone":"8(900)2222222","sex":"f","birth":600000000,"joined":1300000000,"status":"заняты"}"#;
+
+    fn update_fixture() -> Storage {
+        let mut config = Config::new();
+        config.index_sname = true;
+        config.index_sname_prefix = true;
+        let mut storage = Storage::new(1545834028, config, 1000);
+        for account in &[UPDATE_FIXTURE, UPDATE_OTHER] {
+            storage.new_account(account.as_bytes(), &mut |_| {}).unwrap();
+        }
+        storage
+    }
+
+    /// Поля аккаунта со строками вместо ключей словарей, чтобы сравнивать аккаунты из разных Storage.
+    fn account_view(storage: &Storage, id: i32) -> serde_json::Value {
+        let account = storage.accounts[id as usize].as_ref().unwrap();
+        let str = |key: i32| storage.dict.get_value(key).map(|value| value.to_string());
+        let mut interests: Vec<String> = (&account.interests).into_iter().map(|interest| storage.interest_dict.get_value(interest).unwrap().to_string()).collect();
+        interests.sort();
+        serde_json::json!({
+            "email": account.email.as_ref().map(|email| email.to_string()),
+            "sname": str(account.sname), "fname": str(account.fname),
+            "phone": [account.phone_code, account.phone_number],
+            "sex": str(account.sex), "birth": account.birth,
+            "country": str(account.country), "city": str(account.city),
+            "joined": account.joined, "status": str(account.status),
+            "interests": interests, "likes": account.likes,
+            "premium": [account.premium_start, account.premium_finish], "is_premium": account.is_premium,
+            "recommend_order": account.recommend_order,
+        })
+    }
+
+    /// Ожидаемый результат update: аккаунт, созданный из фикстуры с замененными полями.
+    /// null, пустой массив и likes в update ничего не меняют.
+    fn expected_view(update: &str) -> serde_json::Value {
+        let mut merged: serde_json::Value = serde_json::from_str(UPDATE_FIXTURE).unwrap();
+        let update: serde_json::Value = serde_json::from_str(update).unwrap();
+        for (field, value) in update.as_object().unwrap() {
+            if !value.is_null() && value.as_array().is_none_or(|array| !array.is_empty()) && field != "likes" {
+                merged[field] = value.clone();
+            }
+        }
+        let mut storage = Storage::new(1545834028, Config::new(), 1000);
+        storage.new_account(merged.to_string().as_bytes(), &mut |_| {}).unwrap();
+        account_view(&storage, 1)
+    }
+
+    /// Аккаунт есть во всех индексах по своим текущим значениям.
+    fn assert_indexed(storage: &Storage, id: i32) {
+        let account = storage.accounts[id as usize].as_ref().unwrap();
+        let indexes = &storage.indexes;
+        assert_eq!(indexes.known_emails.get(account.email.as_ref().unwrap()), Some(&id));
+        for (domain, ids) in &indexes.email_domain_index {
+            assert_eq!(ids.contains(&id), domain.as_str() == email_domain(account.email.as_ref().unwrap()), "{}", domain);
+        }
+        if account.phone_number != 0 {
+            assert!(indexes.known_phones.contains(&(account.phone_code, account.phone_number)));
+        }
+        for (index, key) in &[(&indexes.city_index, account.city), (&indexes.country_index, account.country), (&indexes.fname_index, account.fname)] {
+            assert_eq!(index.get(*key).contains(&id), *key != 0);
+        }
+        let interests_index_sex = if account.sex == storage.consts.male { &indexes.interests_index_male } else { &indexes.interests_index_female };
+        for interest in &account.interests {
+            assert!(indexes.interests_index.get(interest).contains(&id));
+            assert!(interests_index_sex.get(interest).contains(&id));
+        }
+        assert!(indexes.birth_index[&year_from_seconds(account.birth)].contains(&id));
+        assert!(indexes.joined_index[&year_from_seconds(account.joined)].contains(&id));
+        assert_eq!(indexes.phone_code_index.get(&account.phone_code).is_some_and(|ids| ids.contains(&id)), account.phone_number != 0);
+        assert!(indexes.sname_index.as_ref().unwrap().get(&account.sname).map_or(account.sname == 0, |ids| ids.contains(&id)));
+        let sname = storage.dict.get_value(account.sname);
+        let prefix = sname_prefix(sname.as_ref().map(|sname| sname.as_str()));
+        for (key, ids) in indexes.sname_prefix_index.as_ref().unwrap() {
+            assert_eq!(ids.contains(&id), Some(key.as_str()) == prefix, "{}", key);
+        }
+        assert_eq!(indexes.premium_now_ids.contains(&id), account.is_premium);
+        for (sex, ids) in &indexes.sex_ids {
+            assert_eq!(ids.contains(id), *sex == account.sex, "{:?}", storage.dict.get_value(*sex));
+        }
+        for (status, ids) in &indexes.status_ids {
+            assert_eq!(ids.contains(id), *status == account.status, "{:?}", storage.dict.get_value(*status));
+        }
+    }
+
+    #[test]
+    fn test_update_sex_status_ids() {
+        let mut storage = update_fixture();
+        storage.update_account(1, r#"{"sex":"f","status":"заняты"}"#.as_bytes(), &mut |_| {}).unwrap();
+        assert_indexed(&storage, 1);
+        let ids = |query: &[(&str, &str)]| -> Vec<i32> {
+            crate::filter::filter(&storage, &params(query)).unwrap().accounts.iter().map(|account| account.id).collect()
+        };
+        // city_eq идет через пересечение city_index с sex_ids и status_ids
+        assert_eq!(ids(&[("city_eq", "Москва"), ("sex_eq", "m"), ("status_eq", "свободны"), ("limit", "10")]), Vec::<i32>::new());
+        assert_eq!(ids(&[("city_eq", "Москва"), ("status_eq", "свободны"), ("limit", "10")]), Vec::<i32>::new());
+        assert_eq!(ids(&[("city_eq", "Москва"), ("sex_eq", "f"), ("status_eq", "заняты"), ("limit", "10")]), vec![1]);
+    }
+
+    #[test]
+    fn test_update_account_fields() {
+        let updates = [
+            // каждое поле по отдельности
+            r#"{"email":"b1@b.ru"}"#,
+            r#"{"fname":"Петр"}"#,
+            r#"{"sname":"Иванов"}"#,
+            r#"{"phone":"8(901)3333333"}"#,
+            r#"{"sex":"f"}"#,
+            r#"{"birth":700000000}"#,
+            r#"{"country":"Испания"}"#,
+            r#"{"city":"Мадрид"}"#,
+            r#"{"joined":1400000000}"#,
+            r#"{"status":"всё сложно"}"#,
+            r#"{"interests":["c"]}"#,
+            r#"{"premium":{"start":1400000000,"finish":1450000000}}"#,
+            // сочетания
+            r#"{"email":"b1@a.ru","sex":"f","status":"заняты","interests":["b","c"]}"#,
+            r#"{"country":"Испания","city":"Мадрид","birth":700000000,"joined":1400000000}"#,
+            r#"{"fname":"Петр","sname":"Иванов","phone":"8(901)3333333","premium":{"start":1545000000,"finish":1546000000}}"#,
+            // ничего не меняют: пустой update, те же значения, null не очищает поле, likes игнорируются
+            r#"{}"#,
+            r#"{"email":"a1@a.ru","phone":"8(900)1111111","city":"Москва"}"#,
+            r#"{"city":null,"fname":null,"premium":null}"#,
+            r#"{"likes":[{"id":2,"ts":5}]}"#,
+            r#"{"interests":[]}"#,
+        ];
+        for update in updates.iter() {
+            let mut storage = update_fixture();
+            let mut status_code = None;
+            assert_eq!(storage.update_account(1, update.as_bytes(), &mut |code| status_code = Some(code)), Ok(()), "{}", update);
+            assert_eq!(status_code, Some(StatusCode::ACCEPTED), "{}", update);
+            assert_eq!(account_view(&storage, 1), expected_view(update), "{}", update);
+            assert_indexed(&storage, 1);
+            assert_indexed(&storage, 2);
+        }
+
+        // телефон не по шаблону не ошибка, но и не меняется
+        let mut storage = update_fixture();
+        let fixture_view = account_view(&storage, 1);
+        storage.update_account(1, r#"{"phone":"8(900"}"#.as_bytes(), &mut |_| {}).unwrap();
+        assert_eq!(account_view(&storage, 1), fixture_view);
+    }
+
+    #[test]
+    fn test_update_account_releases_old_values() {
+        let mut storage = update_fixture();
+        let petrov = storage.dict.get_existing_key(&"Петров".to_string()).unwrap();
+        storage.update_account(1, r#"{"email":"b1@a.ru","phone":"8(901)3333333","sname":"Иванов"}"#.as_bytes(), &mut |_| {}).unwrap();
+        assert!(!storage.indexes.known_emails.contains_key(&Arc::new("a1@a.ru".to_string())));
+        assert!(!storage.indexes.known_phones.contains(&(900, 1111111)));
+        assert!(!storage.indexes.sname_index.as_ref().unwrap()[&petrov].contains(&1));
+        assert!(!storage.indexes.sname_prefix_index.as_ref().unwrap()["Пе"].contains(&1));
+        assert_eq!(storage.indexes.sname_prefix_index.as_ref().unwrap()["Ив"], vec![1]);
+        // старые email и телефон можно занять
+        storage.update_account(2, r#"{"email":"a1@a.ru","phone":"8(900)1111111"}"#.as_bytes(), &mut |_| {}).unwrap();
+        assert_eq!(storage.indexes.known_emails.get(&Arc::new("a1@a.ru".to_string())), Some(&2));
+        assert_indexed(&storage, 1);
+        assert_indexed(&storage, 2);
+    }
+
+    #[test]
+    fn test_update_account_errors() {
+        let errors = [
+            (1, r#"{"email":"a2@a.ru"}"#, StatusCode::BAD_REQUEST),
+            (1, r#"{"phone":"8(900)2222222"}"#, StatusCode::BAD_REQUEST),
+            (1, r#"{"email":"no-at-sign"}"#, StatusCode::BAD_REQUEST),
+            (1, r#"{"birth":"1990"}"#, StatusCode::BAD_REQUEST),
+            (1, r#"{"email":"#, StatusCode::BAD_REQUEST),
+            (3, r#"{"city":"Москва"}"#, StatusCode::NOT_FOUND),
+        ];
+        let fixture_view = account_view(&update_fixture(), 1);
+        for (id, update, expected) in errors.iter() {
+            let mut storage = update_fixture();
+            assert_eq!(storage.update_account(*id, update.as_bytes(), &mut |_| panic!("success response for {}", update)), Err(expected.clone()), "{}", update);
+            assert_eq!(account_view(&storage, 1), fixture_view, "{}", update);
+            assert_indexed(&storage, 1);
+            assert_indexed(&storage, 2);
+        }
+    }
+
+    #[test]
+    fn test_date_bounds() {
+        let account_body = |id: i32, birth: i32, joined: i32| {
+            format!(r#"{{"id":{},"email":"a{}@a.ru","sex":"m","status":"свободны","birth":{},"joined":{}}}"#, id, id, birth, joined)
+        };
+        let new_account = |storage: &mut Storage, id: i32, birth: i32, joined: i32| {
+            let mut status_code = None;
+            let result = storage.new_account(account_body(id, birth, joined).as_bytes(), &mut |code| status_code = Some(code));
+            // отказ приходит вместо 201, а не после него
+            assert_eq!(status_code.is_some(), result.is_ok());
+            result
+        };
+        let mut storage = Storage::new(1545834028, Config::new(), 1000);
+        // границы включаются
+        assert_eq!(new_account(&mut storage, 1, MIN_BIRTH, MIN_JOINED), Ok(()));
+        assert_eq!(new_account(&mut storage, 2, MAX_BIRTH, MAX_JOINED), Ok(()));
+        for (birth, joined) in &[(MIN_BIRTH - 1, MIN_JOINED), (MAX_BIRTH + 1, MIN_JOINED), (MIN_BIRTH, MIN_JOINED - 1), (MIN_BIRTH, MAX_JOINED + 1),
+            (-2000000000, MIN_JOINED), (MIN_BIRTH, 2000000000)] {
+            assert_eq!(new_account(&mut storage, 3, *birth, *joined), Err(StatusCode::BAD_REQUEST), "{} {}", birth, joined);
+            assert!(storage.accounts[3].is_none());
+        }
+
+        // в update дата вне границ не меняется, остальные поля применяются
+        let mut storage = update_fixture();
+        let update = format!(r#"{{"birth":{},"joined":{},"city":"Тула"}}"#, MAX_BIRTH + 1, MIN_JOINED - 1);
+        assert_eq!(storage.update_account(1, update.as_bytes(), &mut |_| {}), Ok(()));
+        let account = storage.accounts[1].as_ref().unwrap();
+        assert_eq!((account.birth, account.joined), (600000000, 1300000000));
+        assert_eq!(storage.dict.get_value(account.city).unwrap().as_str(), "Тула");
+        let update = format!(r#"{{"birth":{},"joined":{}}}"#, MIN_BIRTH, MAX_JOINED);
+        storage.update_account(1, update.as_bytes(), &mut |_| {}).unwrap();
+        let account = storage.accounts[1].as_ref().unwrap();
+        assert_eq!((account.birth, account.joined), (MIN_BIRTH, MAX_JOINED));
+        assert_indexed(&storage, 1);
+
+        // при загрузке аккаунт вне границ пропускается, остальные загружаются
+        let accounts = format!(r#"{{"accounts":[{},{},{}]}}"#, account_body(1, MIN_BIRTH, MIN_JOINED), account_body(2, MAX_BIRTH + 1, MIN_JOINED), account_body(3, MAX_BIRTH, MAX_JOINED));
+        let dir = make_data_dir("load_date_bounds", &[("accounts_1.json", accounts.as_str())]);
+        let storage = Storage::load(dir.to_str().unwrap(), Config::new());
+        assert!(storage.accounts[1].is_some());
+        assert!(storage.accounts[2].is_none());
+        assert!(storage.accounts[3].is_some());
+        assert!(!storage.indexes.known_emails.contains_key(&Arc::new("a2@a.ru".to_string())));
+        drop(storage);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_dict_concurrent() {
+        let dict = Arc::new(Dict::new());
+        let value = |i: usize| Arc::new(format!("s{}", i));
+        assert_eq!(dict.get_key(&value(0)), 1);
+        let readers: Vec<_> = (0..3).map(|_| {
+            let dict = dict.clone();
+            thread::spawn(move || {
+                let mut seen = 0;
+                while seen < 2000 {
+                    seen = dict.max_key() as usize;
+                    // найденный ключ всегда указывает на свою строку
+                    for i in (0..2000).step_by(97) {
+                        if let Some(key) = dict.get_existing_key(&value(i)) {
+                            assert_eq!(dict.get_value(key), Some(value(i)));
+                        }
+                    }
+                }
+            })
+        }).collect();
+        // два писателя добавляют одни и те же строки
+        let writers: Vec<_> = (0..2).map(|_| {
+            let dict = dict.clone();
+            thread::spawn(move || (0..2000).map(|i| dict.get_key(&value(i))).collect::<Vec<i32>>())
+        }).collect();
+        let keys: Vec<Vec<i32>> = writers.into_iter().map(|writer| writer.join().unwrap()).collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(keys[0], keys[1]);
+        assert_eq!(keys[0], (1..2001).collect::<Vec<i32>>());
+        assert_eq!(dict.max_key(), 2000);
+        assert_eq!(dict.get_existing_key(&"s1999".to_string()), Some(2000));
+        assert_eq!(dict.get_value(0), None);
+        let mut values = Vec::new();
+        dict.write_values(&mut values).unwrap();
+        assert_eq!(String::from_utf8(values).unwrap().lines().count(), 2000);
+    }
+
+    #[test]
+    fn test_parse_account_under_read_lock() {
+        let storage = Arc::new(std::sync::RwLock::new(make_storage(&[
+            r#"{"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"city":"c1"}"#,
+        ])));
+        // читатель держит блокировку, пока другой поток интернирует новые строки
+        let reader = read_lock(&storage);
+        let parsed = {
+            let storage = storage.clone();
+            thread::spawn(move || read_lock(&storage).parse_account(r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"заняты","birth":600000000,"joined":1300000000,"city":"c2","interests":["i2"]}"#.as_bytes(), true).ok())
+                .join().unwrap().unwrap()
+        };
+        let city = reader.dict.get_existing_key(&"c2".to_string()).unwrap();
+        assert_eq!(reader.dict.get_value(city).unwrap().as_str(), "c2");
+        drop(reader);
+
+        write_lock(&storage).new_parsed_account(parsed, b"", &mut |_| {}).unwrap();
+        let storage = read_lock(&storage);
+        assert_eq!(storage.accounts[2].as_ref().unwrap().city, city);
+        assert_eq!(storage.indexes.known_emails.get(&Arc::new("a2@a.ru".to_string())), Some(&2));
+    }
+
+    #[test]
+    fn test_rebuild_index() {
+        let account = |id: i32, updated: bool| -> String {
+            let stale = !updated && id % 3 == 0;
+            format!(r#"{{"id":{},"email":"{}{}@a.ru","sex":"{}","status":"{}","birth":{},"joined":{},"fname":"{}","sname":"s{}","city":"{}","country":"k{}","interests":{}{}}}"#,
+                    id, if stale { "old" } else { "a" }, id, if id % 2 == 0 { "m" } else { "f" },
+                    ["свободны", "заняты", "всё сложно"][(id % 3) as usize + if stale { 1 } else { 0 }],
+                    if stale { 400000000 } else { 500000000 + id * 10000000 }, 1300000000 + id * 1000,
+                    if stale { "oldf".to_string() } else { format!("f{}", id % 6) }, id % 4,
+                    if stale { format!("old{}", id) } else { format!("c{}", id % 4) }, id % 3,
+                    if stale { r#"["old"]"#.to_string() } else { format!(r#"["i{}","i{}"]"#, id % 5, (id + 1) % 5) },
+                    if id % 4 == 0 { r#","premium":{"start":1540000000,"finish":1550000000}"# } else { "" })
+        };
+        let new_storage = || {
+            let mut config = Config::new();
+            config.index_sname = true;
+            config.index_sname_prefix = true;
+            Storage::new(1545834028, config, 1000)
+        };
+        // fresh - сразу итоговые аккаунты, updated - часть аккаунтов приходит к ним через update
+        let mut fresh = new_storage();
+        let mut updated = new_storage();
+        for id in 1..31 {
+            fresh.new_account(account(id, true).as_bytes(), &mut |_| {}).unwrap();
+            updated.new_account(account(id, false).as_bytes(), &mut |_| {}).unwrap();
+        }
+        for id in (3..31).step_by(3) {
+            let mut update: serde_json::Value = serde_json::from_str(&account(id, true)).unwrap();
+            update.as_object_mut().unwrap().remove("id");
+            updated.update_account(id, update.to_string().as_bytes(), &mut |_| {}).unwrap();
+        }
+        let city_ids = |storage: &Storage, city: &str| storage.indexes.city_index.get(storage.dict.get_existing_key(&city.to_string()).unwrap()).clone();
+        assert_eq!(city_ids(&updated, "old3"), vec![3]);
+
+        for name in &["recommend", "group", "filter", "interests", "city", "country", "country_of_city", "email_domain", "birth", "phone_code", "fname", "premium_now", "sname", "sname_prefix", "sex", "status"] {
+            updated.rebuild_index(name).unwrap();
+        }
+        assert_eq!(city_ids(&updated, "old3"), Vec::<i32>::new());
+        assert_eq!(city_ids(&updated, "c3"), city_ids(&fresh, "c3"));
+
+        let filters = [
+            vec![("city_eq", "c1")], vec![("country_eq", "k2")], vec![("fname_eq", "f3")], vec![("fname_any", "f1,f2")],
+            vec![("sname_eq", "s2")], vec![("sname_starts", "s2")], vec![("birth_year", "1990")], vec![("interests_contains", "i1,i2")], vec![("interests_any", "i0,i3")],
+            vec![("interests_contains", "i4"), ("sex_eq", "m")], vec![("sex_eq", "f"), ("status_eq", "заняты"), ("city_eq", "c1")],
+            vec![("email_lt", "b"), ("city_null", "0")], vec![("sex_eq", "m"), ("country_null", "0")], vec![("status_neq", "свободны")],
+            vec![("city_eq", "c1"), ("country_eq", "k1")], vec![("email_domain", "a.ru"), ("sex_eq", "m")],
+        ];
+        for query in filters.iter() {
+            let mut query = query.clone();
+            query.push(("limit", "30"));
+            let result = |storage: &Storage| crate::filter::filter(storage, &params(&query)).map(|result| serde_json::to_string(&result).unwrap());
+            assert_eq!(result(&updated), result(&fresh), "{:?}", query);
+        }
+        let groups = [
+            vec![("keys", "city")], vec![("keys", "interests"), ("sex", "m")], vec![("keys", "status"), ("birth", "1990")], vec![("keys", "sex,country")],
+        ];
+        for query in groups.iter() {
+            let mut query = query.clone();
+            query.extend_from_slice(&[("order", "-1"), ("limit", "30")]);
+            let result = |storage: &Storage| crate::group::group(storage, &params(&query)).map(|result| serde_json::to_string(&result).unwrap());
+            assert_eq!(result(&updated), result(&fresh), "{:?}", query);
+        }
+        for id in 1..31 {
+            let result = |storage: &Storage| crate::recommend::recommend(storage, id, &params(&[("limit", "10")])).map(|result| serde_json::to_string(&result).unwrap());
+            assert_eq!(result(&updated), result(&fresh), "recommend {}", id);
+        }
+
+        assert_eq!(updated.rebuild_index("likes"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(make_storage(&[]).rebuild_index("sname"), Err(StatusCode::BAD_REQUEST));
+    }
+}
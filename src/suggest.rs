@@ -1,18 +1,24 @@
 use std::collections::HashMap;
 use std::i64;
 
+use crate::bits::InterestSet;
 use crate::storage::Account;
 use crate::storage::AccountJson;
 use crate::storage::AccountsJson;
 use crate::storage::Like;
+use crate::storage::likes_from;
 use crate::storage::Storage;
+use crate::utils::check_unique_params;
 use crate::utils::EMPTY_LIKE_LIST;
 use crate::utils::insert_into_sorted_vec;
+use crate::utils::parse_limit;
 use crate::utils::StatusCode;
 
+/// Порядок результата: похожие аккаунты по убыванию similarity (при равенстве - по возрастанию id),
+/// внутри каждого похожего - его новые лайки по убыванию ts (при равенстве - по убыванию id), уже выданные id пропускаются.
 #[inline(never)]
 pub fn suggest(storage: &Storage, id: i32, params: &Vec<(String, String)>) -> Result<AccountsJson, StatusCode> {
-    let person = storage.accounts[id as usize].as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let person = storage.accounts.get(id as usize).and_then(|account| account.as_ref()).ok_or(StatusCode::NOT_FOUND)?;
     if person.sex == 0 {
         Err(StatusCode::BAD_REQUEST)?;
     }
@@ -61,11 +67,8 @@ pub fn suggest(storage: &Storage, id: i32, params: &Vec<(String, String)>) -> Re
                 storage.accounts[similar_like.id as usize].as_ref()
             })
             .filter(|account| account.sex == person.sex && matches(account, &matcher))
-            .map(|account| get_new_likes(&person.likes, &account.likes))
-            .flat_map(|new_likes| {
-//                debug!("new_likes {:?}", new_likes.iter().rev().cloned().collect::<Vec<i32>>());
-                new_likes.into_iter().rev()
-            })
+            .map(|account| get_new_likes(&person.likes, account, likes_index))
+            .flat_map(|new_likes| new_likes.into_iter().map(|like| like.id))
             .filter(|id| {
                 if !known_ids.contains(id) {
                     known_ids.push(*id);
@@ -87,8 +90,8 @@ pub fn suggest(storage: &Storage, id: i32, params: &Vec<(String, String)>) -> Re
                 country: None,
                 city: None,
                 joined: None,
-                interests: Vec::new(),
-                likes: Vec::new(),
+                interests: storage.config.hidden_array(account.interests.is_empty()),
+                likes: storage.config.hidden_array(account.likes.is_empty()),
                 premium: None,
 
             })
@@ -98,6 +101,7 @@ pub fn suggest(storage: &Storage, id: i32, params: &Vec<(String, String)>) -> Re
 }
 
 fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Option<Matcher>, StatusCode> {
+    check_unique_params(params)?;
     let mut matcher = Matcher {
         limit: 0,
         country: 0,
@@ -109,12 +113,7 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
     for (key, value) in params {
         match key.as_str() {
             "query_id" => {}
-            "limit" => {
-                matcher.limit = value.parse::<usize>().map_err(|_| StatusCode::BAD_REQUEST)?;
-                if matcher.limit == 0 {
-                    return Err(StatusCode::BAD_REQUEST);
-                }
-            }
+            "limit" => {}
             "country" => {
                 if value.is_empty() {
                     Err(StatusCode::BAD_REQUEST)?
@@ -136,6 +135,7 @@ fn make_matcher(storage: &Storage, params: &Vec<(String, String)>) -> Result<Opt
             _ => return Err(StatusCode::BAD_REQUEST)
         }
     }
+    matcher.limit = parse_limit(params, storage.config.default_limit)?;
     if empty_result {
         return Ok(None);
     }
@@ -183,7 +183,9 @@ fn merge_multiple_likes(likes: &Vec<Like>) -> Vec<Like> {
     result
 }
 
-fn get_new_likes(my_likes: &Vec<i32>, other_likes: &Vec<i32>) -> Vec<i32> {
+/// Лайки other, которых нет у me, от новых к старым. ts берется из likes_index (несколько лайков усредняются).
+fn get_new_likes(my_likes: &[i32], other: &Account, likes_index: &HashMap<i32, Vec<Like>>) -> Vec<Like> {
+    let other_likes = &other.likes;
     let mut new_likes = Vec::new();
     let mut pos1 = 0;
     let mut pos2 = 0;
@@ -203,9 +205,22 @@ fn get_new_likes(my_likes: &Vec<i32>, other_likes: &Vec<i32>) -> Vec<i32> {
             }
         }
     }
+    let mut new_likes: Vec<Like> = new_likes.into_iter()
+        .map(|likee| Like { id: likee, ts: like_ts(likes_index.get(&likee).unwrap_or(&EMPTY_LIKE_LIST), other.id) })
+        .collect();
+    new_likes.sort_by(|a, b| b.ts.cmp(&a.ts).then(b.id.cmp(&a.id)));
     new_likes
 }
 
+/// Средний ts лайков liker в likes_index[likee]; 0, если лайка в индексе нет.
+fn like_ts(likes: &[Like], liker: i32) -> i32 {
+    let likes = likes_from(likes, liker);
+    if likes.is_empty() {
+        return 0;
+    }
+    (likes.iter().map(|like| like.ts as i64).sum::<i64>() / likes.len() as i64) as i32
+}
+
 #[derive(Debug)]
 struct Matcher {
     limit: usize,
@@ -217,4 +232,50 @@ struct Matcher {
 struct SimilarLikes {
     id: i32,
     similarity: f64,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::make_storage;
+    use crate::utils::params;
+
+    use super::*;
+
+    #[test]
+    fn test_suggest_order() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"m1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":10,"ts":1000},{"id":11,"ts":1000}]}"#,
+            // similarity 1.0
+            r#"{"id":2,"email":"m2@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":10,"ts":1001},{"id":20,"ts":1},{"id":21,"ts":2}]}"#,
+            // similarity 0.01
+            r#"{"id":3,"email":"m3@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":11,"ts":1100},{"id":20,"ts":3},{"id":22,"ts":4}]}"#,
+            // similarity 0.01, после 3 по id
+            r#"{"id":4,"email":"m4@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":11,"ts":900},{"id":23,"ts":5}]}"#,
+            r#"{"id":10,"email":"f10@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":11,"email":"f11@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":20,"email":"f20@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":21,"email":"f21@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":22,"email":"f22@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":23,"email":"f23@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        let ids: Vec<i32> = suggest(&storage, 1, &params(&[("limit", "10")])).unwrap()
+            .accounts.iter().map(|account| account.id.unwrap()).collect();
+        assert_eq!(ids, vec![21, 20, 22, 23]);
+    }
+
+    #[test]
+    fn test_suggest_recency() {
+        let storage = make_storage(&[
+            r#"{"id":1,"email":"m1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":10,"ts":1000}]}"#,
+            // новые лайки: 20 самый свежий, 22 и 23 с одинаковым ts, 21 - два лайка со средним ts 150
+            r#"{"id":2,"email":"m2@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":10,"ts":1000},{"id":20,"ts":500},{"id":21,"ts":100},{"id":21,"ts":200},{"id":22,"ts":300},{"id":23,"ts":300}]}"#,
+            r#"{"id":10,"email":"f10@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":20,"email":"f20@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":21,"email":"f21@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":22,"email":"f22@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+            r#"{"id":23,"email":"f23@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000}"#,
+        ]);
+        let ids: Vec<i32> = suggest(&storage, 1, &params(&[("limit", "10")])).unwrap()
+            .accounts.iter().map(|account| account.id.unwrap()).collect();
+        assert_eq!(ids, vec![20, 23, 22, 21]);
+    }
+}
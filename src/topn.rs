@@ -5,9 +5,12 @@ pub struct TopN<T> {
     limit: usize,
 }
 
+// limit приходит из запроса, поэтому заранее выделяется не больше этого, дальше heap растет сам
+const MAX_INITIAL_CAPACITY: usize = 64;
+
 impl<T: Ord> TopN<T> {
     pub fn new(limit: usize) -> TopN<T> {
-        TopN { heap: BinaryHeap::with_capacity(limit + 1), limit }
+        TopN { heap: BinaryHeap::with_capacity(limit.min(MAX_INITIAL_CAPACITY) + 1), limit }
     }
 
     pub fn push(&mut self, t: T) {
@@ -34,3 +37,26 @@ impl<T: Ord> TopN<T> {
         self.heap.clear()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_limit() {
+        let mut top: TopN<i32> = TopN::new(1_000_000);
+        assert!(top.heap.capacity() <= 2 * (MAX_INITIAL_CAPACITY + 1));
+        for i in (0..1000).rev() {
+            top.push(i);
+        }
+        assert_eq!(top.len(), 1000);
+        assert_eq!(top.into_sorted_vec(), (0..1000).collect::<Vec<i32>>());
+
+        let mut top: TopN<i32> = TopN::new(3);
+        for i in &[5, 1, 4, 2, 3] {
+            top.push(*i);
+        }
+        assert_eq!(top.into_sorted_vec(), vec![1, 2, 3]);
+        assert_eq!(TopN::<i32>::new(usize::MAX).len(), 0);
+    }
+}
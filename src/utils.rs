@@ -1,6 +1,4 @@
-use chrono::Datelike;
-use chrono::NaiveDate;
-use chrono::NaiveDateTime;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use crate::storage::Like;
 
@@ -9,12 +7,72 @@ lazy_static! {
     pub static ref EMPTY_LIKE_LIST: Vec<Like> = Vec::new();
 }
 
+/// lock.read() без паники на отравленной блокировке: после паники в записи storage продолжает обслуживать запросы.
+pub fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// lock.write() без паники на отравленной блокировке, см. read_lock.
+pub fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Повторный параметр в запросе - 400, кроме query_id: иначе make_matcher молча взял бы последнее значение.
+pub fn check_unique_params(params: &[(String, String)]) -> Result<(), StatusCode> {
+    for (index, (key, _)) in params.iter().enumerate() {
+        if key != "query_id" && params[..index].iter().any(|(prev, _)| prev == key) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+    Ok(())
+}
+
+// больше аккаунтов в ответ все равно не попадет; заодно recommend_cap_factor * limit не переполняется
+pub const MAX_LIMIT: usize = 1_000_000;
+
+/// limit для filter/group/recommend/suggest: положительное число, иначе 400; без параметра - default_limit, если он задан, иначе 400.
+/// Больше MAX_LIMIT, в том числе не помещающийся в usize, урезается до MAX_LIMIT.
+pub fn parse_limit(params: &[(String, String)], default_limit: usize) -> Result<usize, StatusCode> {
+    let limit = match params.iter().find(|(key, _)| key == "limit") {
+        Some((_, value)) => match value.parse::<usize>() {
+            Ok(limit) if limit != 0 => limit,
+            // переполнение: ненулевое число из одних цифр
+            Err(_) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => MAX_LIMIT,
+            _ => return Err(StatusCode::BAD_REQUEST),
+        },
+        None if default_limit != 0 => default_limit,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+    Ok(limit.min(MAX_LIMIT))
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+// дней от 0000-03-01 до 1970-01-01
+const UNIX_EPOCH_DAYS: i64 = 719468;
+// дней в 400-летнем цикле григорианского календаря
+const DAYS_PER_ERA: i64 = 146097;
+
+/// Год по UTC-времени в секундах, без chrono: вызывается на каждый аккаунт и интерес в group_index.
+/// civil_from_days Howard Hinnant'а: год считается от 1 марта, чтобы 29 февраля было последним днем года.
 pub fn year_from_seconds(seconds: i32) -> i32 {
-    NaiveDateTime::from_timestamp(seconds as i64, 0).year()
+    let days = (seconds as i64).div_euclid(SECONDS_PER_DAY) + UNIX_EPOCH_DAYS;
+    let era = days.div_euclid(DAYS_PER_ERA);
+    let day_of_era = days - era * DAYS_PER_ERA;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // январь и февраль относятся к следующему календарному году
+    let january_or_february = day_of_year >= 306;
+    (era * 400 + year_of_era) as i32 + if january_or_february { 1 } else { 0 }
 }
 
+/// Начало года (1 января 00:00 UTC) в секундах, days_from_civil Howard Hinnant'а.
 pub fn seconds_from_year(year: i32) -> i32 {
-    NaiveDate::from_ymd(year, 1, 1).and_hms(0, 0, 0).timestamp() as i32
+    // 1 января - 306-й день года, начавшегося 1 марта предыдущего
+    let year = year as i64 - 1;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + 306;
+    ((era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS) * SECONDS_PER_DAY) as i32
 }
 
 pub fn insert_into_sorted_vec(value: i32, vec: &mut Vec<i32>) {
@@ -24,6 +82,12 @@ pub fn insert_into_sorted_vec(value: i32, vec: &mut Vec<i32>) {
     }
 }
 
+pub fn remove_from_sorted_vec(value: i32, vec: &mut Vec<i32>) {
+    if let Ok(pos) = vec.binary_search(&value) {
+        vec.remove(pos);
+    }
+}
+
 /// В vec1 оставить только те элементы, которые есть в vec2.
 pub fn retain_all_sorted(vec1: &mut Vec<i32>, vec2: &Vec<i32>) {
     let mut pos1 = 0; // позиция, куда перемещаются элементы первого списка
@@ -90,14 +154,112 @@ pub fn merge_sorted(vec1: &Vec<i32>, vec2: &Vec<i32>) -> Vec<i32> {
     result
 }
 
+/// Отсортированные списки id по ключу словаря. Ключи Dict - небольшие плотные числа с 1,
+/// поэтому вместо HashMap вектор, индекс в котором - ключ; вектор растет до максимального использованного ключа.
+pub struct PostingLists {
+    lists: Vec<Vec<i32>>,
+}
+
+impl PostingLists {
+    pub fn new() -> PostingLists {
+        PostingLists {
+            lists: Vec::new(),
+        }
+    }
+
+    /// Для ключа 0 (значение не задано) и ключей, которых нет в индексе, - пустой список.
+    pub fn get(&self, key: i32) -> &Vec<i32> {
+        if key <= 0 {
+            return &EMPTY_INT_LIST;
+        }
+        self.lists.get(key as usize).unwrap_or(&EMPTY_INT_LIST)
+    }
+
+    /// Ключи с непустыми списками.
+    pub fn keys<'a>(&'a self) -> impl Iterator<Item=i32> + 'a {
+        self.lists.iter().enumerate().filter(|(_, ids)| !ids.is_empty()).map(|(key, _)| key as i32)
+    }
+
+    /// Ключ 0 не индексируется.
+    pub fn insert(&mut self, key: i32, id: i32) {
+        if key <= 0 {
+            return;
+        }
+        if self.lists.len() <= key as usize {
+            self.lists.resize(key as usize + 1, Vec::new());
+        }
+        insert_into_sorted_vec(id, &mut self.lists[key as usize]);
+    }
+}
+
 //pub fn vec_compare<T: PartialEq>(vec1: &[T], vec2: &[T]) -> bool {
 //    (vec1.len() == vec2.len()) && vec1.iter().zip(vec2).all(|(a,b)| a == b)
 //}
 
+/// Параметры запроса в тестах.
+#[cfg(test)]
+pub fn params(query: &[(&str, &str)]) -> Vec<(String, String)> {
+    query.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use chrono::Datelike;
+    use chrono::NaiveDate;
+    use chrono::TimeZone;
+    use chrono::Utc;
+
     use super::*;
 
+    #[test]
+    fn test_year_from_seconds() {
+        let chrono_year = |seconds: i32| Utc.timestamp_opt(seconds as i64, 0).unwrap().year();
+        // границы лет, в том числе високосных (1960, 2000) и невисокосного 1900, плюс-минус секунда
+        for year in 1902..2038 {
+            let start = Utc.from_utc_datetime(&NaiveDate::from_ymd_opt(year, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()).timestamp() as i32;
+            assert_eq!(seconds_from_year(year), start, "{}", year);
+            for seconds in &[start, start.saturating_sub(1), start.saturating_add(1)] {
+                assert_eq!(year_from_seconds(*seconds), chrono_year(*seconds), "{}", seconds);
+            }
+            let feb29 = NaiveDate::from_ymd_opt(year, 2, 29).map(|date| Utc.from_utc_datetime(&date.and_hms_opt(23, 59, 59).unwrap()).timestamp() as i32);
+            if let Some(seconds) = feb29 {
+                assert_eq!(year_from_seconds(seconds), year, "{}", seconds);
+            }
+        }
+        for seconds in (i32::MIN..i32::MAX).step_by(86_399) {
+            assert_eq!(year_from_seconds(seconds), chrono_year(seconds), "{}", seconds);
+        }
+        assert_eq!(year_from_seconds(i32::MAX), chrono_year(i32::MAX));
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        let params = |limit: Option<&str>| {
+            let mut params = vec![("query_id".to_string(), "1".to_string())];
+            if let Some(limit) = limit {
+                params.push(("limit".to_string(), limit.to_string()));
+            }
+            params
+        };
+        assert_eq!(parse_limit(&params(Some("5")), 0), Ok(5));
+        assert_eq!(parse_limit(&params(Some("5")), 20), Ok(5));
+        // без limit
+        assert_eq!(parse_limit(&params(None), 0), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(parse_limit(&params(None), 20), Ok(20));
+        assert_eq!(parse_limit(&params(None), usize::MAX), Ok(MAX_LIMIT));
+        // ноль
+        assert_eq!(parse_limit(&params(Some("0")), 20), Err(StatusCode::BAD_REQUEST));
+        // огромный
+        assert_eq!(parse_limit(&params(Some(&MAX_LIMIT.to_string())), 0), Ok(MAX_LIMIT));
+        assert_eq!(parse_limit(&params(Some(&usize::MAX.to_string())), 0), Ok(MAX_LIMIT));
+        assert_eq!(parse_limit(&params(Some("99999999999999999999999")), 0), Ok(MAX_LIMIT));
+        assert_eq!(parse_limit(&params(Some("-99999999999999999999999")), 0), Err(StatusCode::BAD_REQUEST));
+        // не число
+        for limit in &["", "x", "-1", "1.5", "10 "] {
+            assert_eq!(parse_limit(&params(Some(limit)), 20), Err(StatusCode::BAD_REQUEST), "{}", limit);
+        }
+    }
+
     #[test]
     fn test_retain_all_sorted() {
         {
@@ -160,6 +322,22 @@ mod tests {
             assert_eq!(result, vec![1, 3, 4]);
         }
     }
+
+    #[test]
+    fn test_posting_lists() {
+        let mut lists = PostingLists::new();
+        lists.insert(3, 5);
+        lists.insert(3, 1);
+        lists.insert(3, 5);
+        lists.insert(0, 1);
+        lists.insert(1, 2);
+        assert_eq!(lists.get(3), &vec![1, 5]);
+        assert_eq!(lists.get(1), &vec![2]);
+        assert!(lists.get(2).is_empty());
+        assert!(lists.get(0).is_empty());
+        assert!(lists.get(-1).is_empty());
+        assert!(lists.get(100).is_empty());
+    }
 }
 
 #[derive(Hash, Eq, PartialEq, Debug)]
@@ -237,22 +415,57 @@ impl KeySet {
     }
 }
 
+/// Остальные методы отклоняет can_process_request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpMethod {
+    Get,
+    Head,
+    Post,
+    Delete,
+}
+
+impl HttpMethod {
+    pub fn parse(method: &[u8]) -> HttpMethod {
+        match method {
+            b"HEAD" => HttpMethod::Head,
+            b"POST" => HttpMethod::Post,
+            b"DELETE" => HttpMethod::Delete,
+            _ => HttpMethod::Get,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct StatusCode(u16);
 
 impl StatusCode {
     //    pub const OK: StatusCode = StatusCode(200);
     pub const BAD_REQUEST: StatusCode = StatusCode(400);
     pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const CONFLICT: StatusCode = StatusCode(409);
     pub const CREATED: StatusCode = StatusCode(201);
     pub const ACCEPTED: StatusCode = StatusCode(202);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const UNPROCESSABLE: StatusCode = StatusCode(422);
+    pub const TOO_MANY_REQUESTS: StatusCode = StatusCode(429);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
 
     pub fn as_str(&self) -> &str {
         match self.0 {
             200 => "200",
             400 => "400",
             404 => "404",
+            405 => "405",
+            409 => "409",
             201 => "201",
             202 => "202",
+            413 => "413",
+            422 => "422",
+            429 => "429",
+            500 => "500",
+            503 => "503",
             _ => unimplemented!(),
         }
     }
@@ -0,0 +1,195 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::storage::Storage;
+
+/// Журнал успешных new/update/likes: после загрузки базовых данных --replay применяет его заново,
+/// и упавший сервер восстанавливает изменения, сделанные после загрузки.
+///
+/// Запись - строка `<тип> <id> <длина тела>`, затем тело запроса и перевод строки; id есть только у update.
+/// Запись собирается в один буфер и уходит в файл одним write до ответа клиенту. Гарантии для подтвержденной записи:
+/// - без --wal-fsync запись в page cache ОС: переживает падение процесса, но не падение ОС или питания;
+/// - с --wal-fsync после write идет fdatasync, ответ уходит только после него: запись переживает и падение ОС.
+///
+/// Ошибка записи в журнал только логируется, запрос все равно применяется и подтверждается.
+pub struct Wal {
+    file: File,
+    fsync: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WalKind {
+    New,
+    Update(i32),
+    Likes,
+}
+
+impl Wal {
+    /// Недописанная при падении последняя запись отрезается: иначе новые записи оказались бы внутри ее тела.
+    pub fn open(path: &str, fsync: bool) -> io::Result<Wal> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut reader = BufReader::new(&file);
+        let mut valid_len = 0;
+        while let Some((_, _, len)) = read_record(&mut reader, path)? {
+            valid_len += len;
+        }
+        if valid_len < file.metadata()?.len() {
+            warn!("wal {}: truncated to {} bytes", path, valid_len);
+            file.set_len(valid_len)?;
+        }
+        Ok(Wal { file, fsync })
+    }
+
+    pub fn append(&mut self, kind: WalKind, body: &[u8]) -> io::Result<()> {
+        let mut record = match kind {
+            WalKind::New => format!("new 0 {}\n", body.len()),
+            WalKind::Update(id) => format!("update {} {}\n", id, body.len()),
+            WalKind::Likes => format!("likes 0 {}\n", body.len()),
+        }.into_bytes();
+        record.reserve(body.len() + 1);
+        record.extend_from_slice(body);
+        record.push(b'\n');
+        self.file.write_all(&record)?;
+        if self.fsync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Применяет журнал к storage теми же методами, что и запросы. Возвращает число примененных записей;
+/// недописанная последняя запись (падение во время записи) пропускается.
+pub fn replay(storage: &mut Storage, path: &str) -> io::Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut applied = 0;
+    while let Some((kind, body, _)) = read_record(&mut reader, path)? {
+        let result = match kind {
+            WalKind::New => storage.new_account(&body, &mut |_| {}),
+            WalKind::Update(id) => storage.update_account(id, &body, &mut |_| {}),
+            WalKind::Likes => storage.update_likes(&body, &mut |_| {}),
+        };
+        match result {
+            Ok(()) => applied += 1,
+            Err(status_code) => warn!("wal {}: {:?} record rejected with {}", path, kind, status_code),
+        }
+    }
+    Ok(applied)
+}
+
+/// Следующая запись журнала и ее длина в файле; None - конец журнала или недописанная запись.
+fn read_record<R: BufRead>(reader: &mut R, path: &str) -> io::Result<Option<(WalKind, Vec<u8>, u64)>> {
+    let mut header = String::new();
+    if reader.read_line(&mut header)? == 0 {
+        return Ok(None);
+    }
+    let (kind, len) = match parse_header(&header) {
+        Some(parsed) => parsed,
+        None => {
+            warn!("wal {}: bad record header {:?}, rest of the log skipped", path, header);
+            return Ok(None);
+        }
+    };
+    let mut body = vec![0; len + 1];
+    if reader.read_exact(&mut body).is_err() || body.pop() != Some(b'\n') {
+        warn!("wal {}: truncated last record skipped", path);
+        return Ok(None);
+    }
+    Ok(Some((kind, body, (header.len() + len + 1) as u64)))
+}
+
+fn parse_header(header: &str) -> Option<(WalKind, usize)> {
+    let mut parts = header.trim_end().split(' ');
+    let (tag, id, len) = (parts.next()?, parts.next()?.parse::<i32>().ok()?, parts.next()?.parse::<usize>().ok()?);
+    let kind = match tag {
+        "new" => WalKind::New,
+        "update" => WalKind::Update(id),
+        "likes" => WalKind::Likes,
+        _ => return None,
+    };
+    Some((kind, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::storage;
+    use crate::storage::tests::make_data_dir;
+
+    use super::*;
+
+    fn dump(storage: &Storage) -> String {
+        let accounts: Vec<String> = storage.accounts.iter().filter_map(|account| account.as_ref())
+            .map(|account| serde_json::to_string(&storage.get_account_json(account)).unwrap())
+            .collect();
+        accounts.join("\n")
+    }
+
+    #[test]
+    fn test_replay() {
+        let accounts = r#"{"accounts":[
+            {"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000,"city":"c1","interests":["a"]},
+            {"id":2,"email":"a2@a.ru","sex":"f","status":"свободны","birth":600000000,"joined":1300000000,"likes":[{"id":1,"ts":5}]}
+        ]}"#;
+        let dir = make_data_dir("wal", &[("accounts_1.json", accounts)]);
+        let wal_path = dir.join("wal.log").to_string_lossy().to_string();
+
+        let mut config = storage::Config::new();
+        config.wal_path = Some(wal_path.clone());
+        let mut storage = Storage::load(dir.to_str().unwrap(), config.clone());
+        storage.new_account(r#"{"id":3,"email":"a3@a.ru","sex":"f","status":"заняты","birth":700000000,"joined":1400000000,"interests":["a","b"]}"#.as_bytes(), &mut |_| {}).unwrap();
+        // отклоненная запись в журнал не попадает
+        assert!(storage.new_account(r#"{"id":4,"email":"a1@a.ru","sex":"f","status":"заняты","birth":700000000,"joined":1400000000}"#.as_bytes(), &mut |_| {}).is_err());
+        storage.update_account(1, "{\"city\":\"c2\",\n\"interests\":[\"b\"]}".as_bytes(), &mut |_| {}).unwrap();
+        storage.update_likes(br#"{"likes":[{"liker":1,"likee":3,"ts":10},{"liker":3,"likee":2,"ts":11}]}"#, &mut |_| {}).unwrap();
+        let expected = dump(&storage);
+        drop(storage);
+
+        // "перезапуск": базовые данные плюс журнал
+        config.replay_path = Some(wal_path.clone());
+        let restarted = Storage::load(dir.to_str().unwrap(), config.clone());
+        assert_eq!(dump(&restarted), expected);
+        assert_eq!(restarted.indexes.likers(3).collect::<Vec<i32>>(), vec![1]);
+        drop(restarted);
+
+        // журнал не дублируется повторным применением, недописанная запись пропускается
+        fs::OpenOptions::new().append(true).open(&wal_path).unwrap().write_all(b"likes 0 100\n{\"likes\":").unwrap();
+        let mut storage = Storage::load(dir.to_str().unwrap(), storage::Config::new());
+        assert_eq!(replay(&mut storage, &wal_path).unwrap(), 3);
+        assert_eq!(dump(&storage), expected);
+
+        // перезапуск с --replay равным --wal после падения: недописанная запись отрезается, новые записи не теряются
+        let mut storage = Storage::load(dir.to_str().unwrap(), config.clone());
+        assert_eq!(dump(&storage), expected);
+        storage.update_account(2, r#"{"city":"c3"}"#.as_bytes(), &mut |_| {}).unwrap();
+        let expected = dump(&storage);
+        drop(storage);
+        let restarted = Storage::load(dir.to_str().unwrap(), config.clone());
+        assert_eq!(dump(&restarted), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_replay_rejected() {
+        let accounts = r#"{"accounts":[
+            {"id":1,"email":"a1@a.ru","sex":"m","status":"свободны","birth":600000000,"joined":1300000000}
+        ]}"#;
+        let dir = make_data_dir("wal_rejected", &[("accounts_1.json", accounts)]);
+        let wal_path = dir.join("wal.log").to_string_lossy().to_string();
+
+        // журнал, записанный поверх других базовых данных: вторая запись конфликтует по email и отклоняется
+        let mut wal = Wal::open(&wal_path, false).unwrap();
+        wal.append(WalKind::New, r#"{"id":2,"email":"a2@a.ru","sex":"f","status":"заняты","birth":700000000,"joined":1400000000}"#.as_bytes()).unwrap();
+        wal.append(WalKind::New, r#"{"id":3,"email":"a1@a.ru","sex":"f","status":"заняты","birth":700000000,"joined":1400000000}"#.as_bytes()).unwrap();
+        wal.append(WalKind::Update(2), r#"{"city":"c1"}"#.as_bytes()).unwrap();
+        drop(wal);
+
+        let mut storage = Storage::load(dir.to_str().unwrap(), storage::Config::new());
+        assert_eq!(replay(&mut storage, &wal_path).unwrap(), 2);
+        assert!(storage.accounts[3].is_none());
+        let account = storage.accounts[2].as_ref().unwrap();
+        assert_eq!(storage.dict.get_value(account.city).unwrap().as_str(), "c1");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}